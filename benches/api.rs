@@ -0,0 +1,170 @@
+// Throughput of listing and creating comments, measured against the real
+// binary with the in-memory store (no [storage] path). Run with
+// `cargo bench`, or `cargo bench --no-default-features` for plain HTTP
+use std::{
+    fs,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde_json::json;
+use tokio::runtime::Runtime;
+
+// Comments already stored when listing
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+struct Server {
+    child: Child,
+    dir: PathBuf,
+    base: String,
+}
+
+impl Server {
+    fn start(name: &str) -> Server {
+        let dir =
+            std::env::temp_dir().join(format!("little-nova-bench-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Let the OS pick a free port
+        let addr: SocketAddr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut config = format!("addr = \"{}\"\n", addr);
+        let scheme = if cfg!(feature = "tls") {
+            let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+            fs::write(dir.join("server.crt"), cert.cert.pem()).unwrap();
+            fs::write(dir.join("server.key"), cert.key_pair.serialize_pem()).unwrap();
+            config.push_str(&format!(
+                "[tls]\ncert = {:?}\nkey = {:?}\n",
+                dir.join("server.crt"),
+                dir.join("server.key")
+            ));
+            "https"
+        } else {
+            "http"
+        };
+        let config_path = dir.join("little-nova.toml");
+        fs::write(&config_path, config).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_little-nova"))
+            .env("LITTLE_NOVA_CONFIG", &config_path)
+            .env("RUST_LOG", "little_nova=error")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start little-nova");
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(addr).is_err() {
+            assert!(Instant::now() < deadline, "little-nova didn't start");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        Server {
+            child,
+            dir,
+            base: format!("{}://{}", scheme, addr),
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        // The certificate is the self-signed one made above
+        .danger_accept_invalid_certs(true)
+        // axum-server doesn't set TCP_NODELAY, and HTTP/2 responses then
+        // wait out the peer's delayed ACK, hiding what the server does
+        .http1_only()
+        .build()
+        .unwrap()
+}
+
+// Every comment differs, so the spam checks see no duplicates
+static SEQ: AtomicUsize = AtomicUsize::new(0);
+
+async fn create(client: &reqwest::Client, base: &str) {
+    let n = SEQ.fetch_add(1, Ordering::Relaxed);
+    let response = client
+        .post(format!("{}/create", base))
+        .json(&json!({
+            "name": "bench",
+            "text": format!("comment number {}", n),
+            "utc": "2021-10-10T04:50:40Z",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success(), "{}", response.status());
+}
+
+async fn get(client: &reqwest::Client, url: &str, accept: &str) {
+    let response = client
+        .get(url)
+        .header("accept", accept)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success(), "{}", response.status());
+    response.bytes().await.unwrap();
+}
+
+fn list(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let client = client();
+    let mut group = c.benchmark_group("list");
+    group.throughput(Throughput::Elements(1));
+
+    for size in SIZES {
+        let server = Server::start(&format!("list-{}", size));
+        runtime.block_on(async {
+            for _ in 0..size {
+                create(&client, &server.base).await;
+            }
+        });
+
+        let index = format!("{}/", server.base);
+        group.bench_with_input(BenchmarkId::new("html", size), &index, |b, url| {
+            b.to_async(&runtime).iter(|| get(&client, url, "text/html"))
+        });
+        group.bench_with_input(BenchmarkId::new("json", size), &index, |b, url| {
+            b.to_async(&runtime)
+                .iter(|| get(&client, url, "application/json"))
+        });
+        // Past the cached first page
+        let second = format!("{}/?offset=100", server.base);
+        group.bench_with_input(BenchmarkId::new("json-offset", size), &second, |b, url| {
+            b.to_async(&runtime)
+                .iter(|| get(&client, url, "application/json"))
+        });
+    }
+    group.finish();
+}
+
+fn create_comments(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let client = client();
+    let server = Server::start("create");
+
+    let mut group = c.benchmark_group("create");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("json", |b| {
+        b.to_async(&runtime).iter(|| create(&client, &server.base))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, list, create_comments);
+criterion_main!(benches);
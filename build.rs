@@ -0,0 +1,37 @@
+use std::process::Command;
+
+fn main() {
+    // Embedded into the binary for GET /version
+    let git_commit = command_output("git", &["rev-parse", "--short", "HEAD"]);
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc_version = command_output(&rustc, &["--version"]);
+    let build_timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+
+    println!("cargo:rustc-env=LITTLE_NOVA_GIT_COMMIT={}", git_commit);
+    println!(
+        "cargo:rustc-env=LITTLE_NOVA_RUSTC_VERSION={}",
+        rustc_version
+    );
+    println!(
+        "cargo:rustc-env=LITTLE_NOVA_BUILD_TIMESTAMP={}",
+        build_timestamp
+    );
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=templates");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+// Builds from a source tarball have no git, so fall back to "unknown"
+fn command_output(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|stdout| stdout.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
@@ -0,0 +1,218 @@
+// Load generator for a running little-nova: a number of clients list and
+// post comments as fast as they can for a while, then requests per second,
+// latency percentiles and failures are printed
+//
+//   cargo run --release --example loadgen -- https://127.0.0.1:3000 \
+//       --clients 32 --seconds 10 --create-percent 5 --insecure
+//
+// Posting is subject to the site's rate limit, so leave
+// rate_limit_per_minute unset on the server under test
+use std::{
+    collections::BTreeMap,
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde_json::json;
+
+struct Options {
+    base: String,
+    clients: usize,
+    seconds: u64,
+    // Share of requests which post a comment, the rest list
+    create_percent: u64,
+    // Ask for JSON instead of the HTML index
+    json: bool,
+    // Accept self-signed certificates
+    insecure: bool,
+    site: Option<String>,
+}
+
+const USAGE: &str = "usage: loadgen <base url> [--clients N] [--seconds N] \
+                     [--create-percent N] [--json] [--insecure] [--site KEY]";
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options {
+        base: String::new(),
+        clients: 16,
+        seconds: 10,
+        create_percent: 0,
+        json: false,
+        insecure: false,
+        site: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--clients" => options.clients = parse(&value("--clients")?)?,
+            "--seconds" => options.seconds = parse(&value("--seconds")?)?,
+            "--create-percent" => {
+                options.create_percent = parse::<u64>(&value("--create-percent")?)?.min(100)
+            }
+            "--json" => options.json = true,
+            "--insecure" => options.insecure = true,
+            "--site" => options.site = Some(value("--site")?),
+            "-h" | "--help" => return Err(USAGE.to_owned()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => options.base = arg.trim_end_matches('/').to_owned(),
+        }
+    }
+    if options.base.is_empty() {
+        return Err(USAGE.to_owned());
+    }
+    Ok(options)
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid number \"{}\"", value))
+}
+
+#[derive(Default)]
+struct Report {
+    // (listing?, latency) of the successful requests
+    latencies: Vec<(bool, Duration)>,
+    // Status code, or 0 for connection errors
+    failures: BTreeMap<u16, usize>,
+}
+
+async fn run_client(
+    id: usize,
+    client: reqwest::Client,
+    options: Arc<Options>,
+    until: Instant,
+) -> Report {
+    let mut report = Report::default();
+    let mut n = 0u64;
+    while Instant::now() < until {
+        n += 1;
+        // Spread the posts evenly instead of drawing random numbers
+        let creating = (n * options.create_percent) % 100 < options.create_percent;
+
+        let mut request = if creating {
+            client
+                .post(format!("{}/create", options.base))
+                .json(&json!({
+                    "name": format!("loadgen {}", id),
+                    "text": format!("load test comment {} from client {}", n, id),
+                    "utc": chrono::Utc::now(),
+                }))
+        } else {
+            let accept = if options.json {
+                "application/json"
+            } else {
+                "text/html"
+            };
+            client
+                .get(format!("{}/", options.base))
+                .header("accept", accept)
+        };
+        if let Some(site) = &options.site {
+            request = request.header("x-site-key", site);
+        }
+
+        let started = Instant::now();
+        let result = match request.send().await {
+            Ok(response) => {
+                let status = response.status();
+                match response.bytes().await {
+                    Ok(_) if status.is_success() => Ok(()),
+                    Ok(_) => Err(status.as_u16()),
+                    Err(_) => Err(0),
+                }
+            }
+            Err(_) => Err(0),
+        };
+        match result {
+            Ok(()) => report.latencies.push((!creating, started.elapsed())),
+            Err(status) => *report.failures.entry(status).or_default() += 1,
+        }
+    }
+    report
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+fn print_latencies(name: &str, mut latencies: Vec<Duration>, elapsed: Duration) {
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort();
+    println!(
+        "{:<7} {:>8} requests {:>9.1}/s  p50 {:>8.2?}  p90 {:>8.2?}  p99 {:>8.2?}  max {:>8.2?}",
+        name,
+        latencies.len(),
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.9),
+        percentile(&latencies, 0.99),
+        latencies[latencies.len() - 1],
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(2);
+    });
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(options.insecure)
+        .pool_max_idle_per_host(options.clients)
+        .build()
+        .unwrap();
+
+    println!(
+        "{} clients against {} for {}s, {}% posting",
+        options.clients, options.base, options.seconds, options.create_percent
+    );
+    let options = Arc::new(options);
+    let started = Instant::now();
+    let until = started + Duration::from_secs(options.seconds);
+    let clients = (0..options.clients)
+        .map(|id| tokio::spawn(run_client(id, client.clone(), options.clone(), until)))
+        .collect::<Vec<_>>();
+
+    let mut report = Report::default();
+    for client in clients {
+        let client = client.await.unwrap();
+        report.latencies.extend(client.latencies);
+        for (status, count) in client.failures {
+            *report.failures.entry(status).or_default() += count;
+        }
+    }
+    let elapsed = started.elapsed();
+
+    let (list, create): (Vec<_>, Vec<_>) = report
+        .latencies
+        .into_iter()
+        .partition(|(listing, _)| *listing);
+    print_latencies(
+        "list",
+        list.into_iter().map(|(_, latency)| latency).collect(),
+        elapsed,
+    );
+    print_latencies(
+        "create",
+        create.into_iter().map(|(_, latency)| latency).collect(),
+        elapsed,
+    );
+    for (status, count) in &report.failures {
+        match status {
+            0 => println!("failed  {:>8} connection errors", count),
+            status => println!("failed  {:>8} with status {}", count, status),
+        }
+    }
+    if !report.failures.is_empty() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+};
+
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, Path, RequestParts},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+#[cfg(unix)]
+use crate::control::ControlSocket;
+use crate::{
+    auth_log::Failure,
+    codec::Format,
+    domain::{self, Transition},
+    errors::ApiError,
+    extract::{ParsedJson, ParsedQuery, Validate, ValidatedJson},
+    hal,
+    identity::ClientIp,
+    jsonapi, newest_first,
+    preconditions::{self, IfMatch},
+    rules::{link_hosts, Rule, RuleAction},
+    state::SharedState,
+    CommentStatus,
+};
+
+// Extractor for routes under /admin
+// Requires `Authorization: Bearer <admin.token>`, except on the control socket
+#[derive(Debug, Clone, Copy)]
+pub struct Admin;
+
+#[async_trait]
+impl<B> FromRequest<B> for Admin
+where
+    B: Send,
+{
+    type Rejection = (HeaderMap, ApiError);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        // Whoever can connect to it is trusted, see control.rs
+        #[cfg(unix)]
+        if req
+            .extensions()
+            .is_some_and(|extensions| extensions.get::<ControlSocket>().is_some())
+        {
+            return Ok(Admin);
+        }
+
+        let Extension(state) = Extension::<SharedState>::from_request(req)
+            .await
+            .map_err(|_| {
+                (
+                    HeaderMap::new(),
+                    ApiError::internal("App state is not available"),
+                )
+            })?;
+
+        // The admin API is disabled until a token is configured
+        let expected = match state.secrets.admin_token() {
+            Some(token) => token,
+            None => {
+                return Err((
+                    HeaderMap::new(),
+                    ApiError::new(
+                        StatusCode::FORBIDDEN,
+                        "admin.disabled",
+                        "Admin API is disabled",
+                    ),
+                ))
+            }
+        };
+
+        let given = req
+            .headers()
+            .and_then(|headers| headers.get(header::AUTHORIZATION))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match given {
+            Some(given) if constant_time_eq(given.as_bytes(), expected.as_bytes()) => Ok(Admin),
+            _ => {
+                let client = ClientIp::from_request(req).await.ok();
+                state.auth_log.failed_request(
+                    Failure::AdminToken,
+                    client.map(|ClientIp(ip)| ip),
+                    req,
+                );
+                let mut headers = HeaderMap::new();
+                headers.insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Bearer realm=\"little-nova admin\""),
+                );
+                Err((
+                    headers,
+                    ApiError::new(
+                        StatusCode::UNAUTHORIZED,
+                        "admin.invalid_token",
+                        "Invalid admin token",
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+// Compare without returning early, so the token can't be guessed by timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct LogLevel {
+    // EnvFilter directives, e.g. "little_nova=trace,tower_http=debug"
+    filter: String,
+}
+
+pub async fn set_log_level(
+    _: Admin,
+    ParsedJson(input): ParsedJson<LogLevel>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let filter = EnvFilter::try_new(&input.filter).map_err(|err| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "log.invalid_filter",
+            format!("Invalid filter: {}", err),
+        )
+    })?;
+
+    state.log_reload.reload(filter).map_err(|err| {
+        tracing::error!("failed to reload the log filter: {}", err);
+        ApiError::internal("Failed to reload filter")
+    })?;
+
+    tracing::info!(filter = %input.filter, "log level changed");
+
+    Ok(Json(input))
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CommentFilter {
+    // "approved", "pending" or "draft"
+    status: Option<CommentStatus>,
+    site: Option<String>,
+}
+
+// Comments of every site for moderation, newest first
+pub async fn get_comments(
+    _: Admin,
+    filter: Option<ParsedQuery<CommentFilter>>,
+    format: Format,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let ParsedQuery(filter) = filter.unwrap_or_default();
+    let comments = state.db.read().unwrap();
+
+    let comments = newest_first(
+        comments
+            .values()
+            .filter(|comment| filter.status.is_none_or(|status| comment.status == status))
+            .filter(|comment| {
+                filter
+                    .site
+                    .as_ref()
+                    .is_none_or(|site| comment.site == *site)
+            }),
+    )
+    .into_iter()
+    .cloned()
+    .collect::<Vec<_>>();
+
+    // All of them on one page, so there is no Link header
+    let mut headers = HeaderMap::new();
+    headers.insert("x-total-count", HeaderValue::from(comments.len()));
+    if format == Format::JsonApi {
+        let data = comments.iter().map(|comment| jsonapi::stored(comment));
+        let document = json!({
+            "data": data.collect::<Vec<_>>(),
+            "meta": {"total": comments.len()},
+        });
+        return (headers, format.respond(jsonapi::serialize(&document))).into_response();
+    }
+    let comments = comments
+        .iter()
+        .map(|comment| hal::moderated(&state.config.site, comment, comment.clone()))
+        .collect::<Vec<_>>();
+    (headers, format.encode(comments)).into_response()
+}
+
+pub async fn approve_comment(
+    _: Admin,
+    Path(id): Path<Uuid>,
+    if_match: IfMatch,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut comments = state.db.write().unwrap();
+    if_match.check(comments.get(&id).map(AsRef::as_ref))?;
+    let comment = domain::transition(&state, &mut comments, id, Transition::Approve)?;
+    drop(comments);
+
+    Ok((preconditions::etag(&comment), Json(comment)))
+}
+
+// Removed without a trace in the stats, as with the bulk "delete"
+pub async fn delete_comment(
+    _: Admin,
+    Path(id): Path<Uuid>,
+    if_match: IfMatch,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut comments = state.db.write().unwrap();
+    if_match.check(comments.get(&id).map(AsRef::as_ref))?;
+    domain::transition(&state, &mut comments, id, Transition::Delete)?;
+    drop(comments);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Most comments one bulk request may name
+const MAX_BULK: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkAction {
+    Approve,
+    // Removed and counted as refused, like comments the spam checks reject
+    Reject,
+    // Removed without a trace in the stats
+    Delete,
+    // Rejected, see BulkModeration::block_domains
+    Spam,
+}
+
+impl From<BulkAction> for Transition {
+    fn from(action: BulkAction) -> Self {
+        match action {
+            BulkAction::Approve => Transition::Approve,
+            BulkAction::Reject => Transition::Reject,
+            BulkAction::Delete => Transition::Delete,
+            BulkAction::Spam => Transition::Spam,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct BulkModeration {
+    ids: Vec<Uuid>,
+    action: BulkAction,
+    // The version each comment was decided on, as If-Match has it
+    #[serde(default)]
+    versions: HashMap<Uuid, u64>,
+    // With "spam", also block the domains the comments link to on their
+    // sites with a new rule
+    #[serde(default)]
+    block_domains: bool,
+}
+
+impl Validate for BulkModeration {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        if self.ids.is_empty() || self.ids.len() > MAX_BULK {
+            return Err((
+                "bulk.invalid_ids",
+                format!("Give between 1 and {} ids", MAX_BULK),
+            ));
+        }
+        if let Some(id) = self.ids.iter().find(|id| !self.versions.contains_key(id)) {
+            return Err((
+                "bulk.missing_version",
+                format!("No version is given for {}", id),
+            ));
+        }
+        if self.block_domains && self.action != BulkAction::Spam {
+            return Err((
+                "bulk.invalid_block_domains",
+                "Only spam blocks the linked domains".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BulkFailure {
+    id: Uuid,
+    error: Cow<'static, str>,
+    code: &'static str,
+}
+
+// POST /admin/comments/bulk {"ids": [...], "versions": {...}, "action": "spam"}
+// Applied to every comment it can be, the others are listed with the reason
+pub async fn moderate_comments(
+    _: Admin,
+    ValidatedJson(input): ValidatedJson<BulkModeration>,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let action = input.action;
+    let mut applied = Vec::new();
+    // The same, to skip ids given twice
+    let mut done = HashSet::new();
+    let mut failed = Vec::new();
+    // Linked domains of the spam, by site
+    let mut domains = BTreeMap::<String, BTreeSet<String>>::new();
+
+    let mut comments = state.db.write().unwrap();
+    for id in input.ids {
+        if done.contains(&id) {
+            continue;
+        }
+        let version = comments.get(&id).map(|comment| comment.version);
+        if version.is_some_and(|version| Some(&version) != input.versions.get(&id)) {
+            let err = preconditions::changed();
+            failed.push(BulkFailure {
+                id,
+                error: err.message,
+                code: err.code,
+            });
+            continue;
+        }
+        let comment = match domain::transition(&state, &mut comments, id, action.into()) {
+            Ok(comment) => comment,
+            Err(err) => {
+                let err = ApiError::from(err);
+                failed.push(BulkFailure {
+                    id,
+                    error: err.message,
+                    code: err.code,
+                });
+                continue;
+            }
+        };
+        if input.block_domains {
+            let mut hosts = link_hosts(&comment.text);
+            hosts.extend(comment.title.as_deref().map(link_hosts).unwrap_or_default());
+            domains
+                .entry(comment.site.clone())
+                .or_default()
+                .extend(hosts);
+        }
+        done.insert(id);
+        applied.push(id);
+    }
+    drop(comments);
+
+    let rules = domains
+        .into_iter()
+        .filter(|(_, domains)| !domains.is_empty())
+        .map(|(site, domains)| {
+            state.rules.add(Rule {
+                site: Some(site),
+                max_links: None,
+                blocked_domains: domains.into_iter().collect(),
+                action: RuleAction::Reject,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if !rules.is_empty() {
+        state.storage.mark_dirty();
+    }
+    tracing::info!(
+        ?action,
+        applied = applied.len(),
+        failed = failed.len(),
+        rules = rules.len(),
+        "bulk moderation"
+    );
+
+    Json(json!({
+        "action": action,
+        "applied": applied,
+        "failed": failed,
+        "rules": rules,
+    }))
+}
@@ -0,0 +1,40 @@
+// Files browsers and crawlers ask for on their own
+use axum::{
+    extract::Extension,
+    http::{header, HeaderMap, HeaderValue},
+    response::IntoResponse,
+};
+
+use crate::state::SharedState;
+
+pub async fn get_robots_txt(Extension(state): Extension<SharedState>) -> impl IntoResponse {
+    let site = &state.config.site;
+    let mut robots = site.robots.clone();
+    if let Some(sitemap) = site.url("/sitemap.xml") {
+        if !robots.ends_with('\n') {
+            robots.push('\n');
+        }
+        robots.push_str(&format!("Sitemap: {}\n", sitemap));
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    (headers, robots)
+}
+
+// Served for /favicon.ico too, which browsers request without looking at the page
+pub async fn get_favicon() -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("image/svg+xml"),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=86400"),
+    );
+    (headers, include_str!("../static/favicon.svg"))
+}
@@ -0,0 +1,606 @@
+// Images and other files uploaded with a new comment. POST /create then
+// takes multipart/form-data, with the comment as JSON in a "comment" part and
+// up to [attachments] max_files "file" parts:
+//
+//   curl -F 'comment={"name": "Jane", "text": "Look", "utc": "..."}' \
+//        -F file=@photo.jpg https://comments.example.com/create
+//
+// A file's type is told by its first bytes, not by what the client claims.
+// Accepted files go through the scan command, then to the store under a
+// random key, images with a thumbnail next to them. They are served from
+// GET /attachments/<key> and deleted when their comment is removed.
+// Thumbnails need the `thumbnails` feature, without it the pages scale the
+// images down
+use std::{fmt, io, path::PathBuf, process::Stdio, sync::Arc};
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{multipart::Field, Extension, FromRequest, Multipart, Path, RequestParts},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+};
+use futures_util::StreamExt;
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::{
+    codec::{Protobuf, ValidatedPayload},
+    config::AttachmentsConfig,
+    domain::{State, TransitionHook},
+    errors::ApiError,
+    extract::{self, Validate},
+    state::{AppState, SharedState},
+    Comment,
+};
+
+// Types which can be told by their first bytes, with the extension of keys
+const TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("application/pdf", "pdf"),
+];
+
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    let content_type = match data {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => "image/png",
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        [b'%', b'P', b'D', b'F', b'-', ..] => "application/pdf",
+        _ => return None,
+    };
+    Some(content_type)
+}
+
+fn extension(content_type: &str) -> Option<&'static str> {
+    TYPES
+        .iter()
+        .find(|(known, _)| *known == content_type)
+        .map(|(_, extension)| *extension)
+}
+
+// Keys are "<uuid>.<extension>" or "<uuid>.thumb.<extension>", anything
+// else is no attachment. Also keeps paths out of the store
+fn content_type_of(key: &str) -> Option<&'static str> {
+    let (id, extension) = key.split_once('.')?;
+    Uuid::parse_str(id).ok()?;
+    let extension = extension.strip_prefix("thumb.").unwrap_or(extension);
+    TYPES
+        .iter()
+        .find(|(_, known)| *known == extension)
+        .map(|(content_type, _)| *content_type)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    // Where the file is stored and served, see content_type_of
+    pub key: String,
+    // As uploaded, without directories
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    // Missing for files which aren't images the thumbnailer could read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<String>,
+}
+
+impl Attachment {
+    fn keys(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.key).chain(&self.thumbnail)
+    }
+
+    // What the pages show, images are scaled down by the theme
+    pub fn preview(&self) -> Option<&str> {
+        match &self.thumbnail {
+            Some(thumbnail) => Some(thumbnail),
+            None if self.content_type.starts_with("image/") => Some(&self.key),
+            None => None,
+        }
+    }
+}
+
+// Where attached files are kept. Async so stores can be remote services
+#[async_trait]
+pub trait AttachmentStore: Send + Sync {
+    async fn put(&self, key: &str, content_type: &str, data: Bytes) -> io::Result<()>;
+
+    // None for an unknown key
+    async fn get(&self, key: &str) -> io::Result<Option<Bytes>>;
+
+    // Deleting what isn't there succeeds
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    // Where clients can download the file from instead, see s3.rs
+    fn presigned_url(&self, _key: &str) -> Option<String> {
+        None
+    }
+}
+
+// A file per key in [attachments] dir
+struct Disk {
+    dir: PathBuf,
+}
+
+#[async_trait]
+impl AttachmentStore for Disk {
+    async fn put(&self, key: &str, _: &str, data: Bytes) -> io::Result<()> {
+        // Renamed into place, so a half-written file is never served
+        let tmp = self.dir.join(format!("{}.tmp", key));
+        tokio::fs::write(&tmp, &data).await?;
+        tokio::fs::rename(&tmp, self.dir.join(key)).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Option<Bytes>> {
+        match tokio::fs::read(self.dir.join(key)).await {
+            Ok(data) => Ok(Some(data.into())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.dir.join(key)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+type Error = ApiError;
+
+fn failed(err: impl fmt::Display) -> Error {
+    tracing::error!("failed to store an attachment: {}", err);
+    ApiError::internal("Failed to store the attachments")
+}
+
+pub struct Attachments {
+    // None while [attachments] dir is unset, which refuses uploads
+    store: Option<Arc<dyn AttachmentStore>>,
+    config: AttachmentsConfig,
+}
+
+impl Attachments {
+    pub fn open(config: &AttachmentsConfig) -> Result<Attachments, String> {
+        for content_type in &config.types {
+            if extension(content_type).is_none() {
+                tracing::warn!(
+                    "[attachments] types: {} can't be recognized and is refused",
+                    content_type
+                );
+            }
+        }
+
+        #[cfg(feature = "s3")]
+        if let Some(s3) = &config.s3 {
+            let store: Arc<dyn AttachmentStore> = Arc::new(crate::s3::S3::new(s3)?);
+            return Ok(Attachments {
+                store: Some(store),
+                config: config.clone(),
+            });
+        }
+        #[cfg(not(feature = "s3"))]
+        if config.s3.is_some() {
+            tracing::warn!(
+                "[attachments.s3] is set, but little-nova was built without the `s3` feature"
+            );
+        }
+
+        let store = match &config.dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)
+                    .map_err(|err| format!("failed to create {}: {}", dir.display(), err))?;
+                let store: Arc<dyn AttachmentStore> = Arc::new(Disk { dir: dir.clone() });
+                Some(store)
+            }
+            None => None,
+        };
+        Ok(Attachments {
+            store,
+            config: config.clone(),
+        })
+    }
+
+    // Scans and stores the files of a new comment. Remove them again if the
+    // comment isn't stored after all
+    pub async fn store(&self, uploads: Vec<Upload>) -> Result<Vec<Attachment>, Error> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(Vec::new()),
+        };
+        for upload in &uploads {
+            self.scan(upload).await?;
+        }
+
+        let mut attachments = Vec::new();
+        for upload in uploads {
+            match self.put(store.as_ref(), upload).await {
+                Ok(attachment) => attachments.push(attachment),
+                Err(err) => {
+                    self.remove(&attachments);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(attachments)
+    }
+
+    async fn put(&self, store: &dyn AttachmentStore, upload: Upload) -> Result<Attachment, Error> {
+        let id = Uuid::new_v4();
+        let key = format!("{}.{}", id, extension(upload.content_type).unwrap_or("bin"));
+        let size = upload.data.len() as u64;
+
+        let thumbnail = match thumbnail(&upload, self.config.thumbnail_size).await {
+            Some((content_type, data)) => {
+                let key = format!("{}.thumb.{}", id, extension(content_type).unwrap_or("bin"));
+                store.put(&key, content_type, data).await.map_err(failed)?;
+                Some(key)
+            }
+            None => None,
+        };
+        if let Err(err) = store.put(&key, upload.content_type, upload.data).await {
+            if let Some(thumbnail) = &thumbnail {
+                let _ = store.delete(thumbnail).await;
+            }
+            return Err(failed(err));
+        }
+
+        Ok(Attachment {
+            key,
+            name: upload.name,
+            content_type: upload.content_type.to_owned(),
+            size,
+            thumbnail,
+        })
+    }
+
+    // Passes the file to the scan command on stdin, which exits with 0 for
+    // clean files and 1 for infected ones, as clamdscan does. Anything else
+    // breaks the upload too, so nothing unscanned is stored
+    async fn scan(&self, upload: &Upload) -> Result<(), Error> {
+        let (program, args) = match self.config.scan_command.split_first() {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        let unavailable = |err: &dyn fmt::Display| {
+            tracing::error!("failed to scan an attachment with {}: {}", program, err);
+            ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "attachment.scan_unavailable",
+                "The attachments couldn't be scanned, try again later",
+            )
+        };
+
+        let mut child = tokio::process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| unavailable(&err))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A scanner which stops reading has made up its mind
+            let _ = stdin.write_all(&upload.data).await;
+        }
+        let status = child.wait().await.map_err(|err| unavailable(&err))?;
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(1) => {
+                tracing::warn!(name = %upload.name, "attachment refused by the scan");
+                Err(ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "attachment.infected",
+                    format!("{} was refused by the virus scan", upload.name),
+                ))
+            }
+            _ => Err(unavailable(&status)),
+        }
+    }
+
+    // In the background, failures are only logged
+    pub fn remove(&self, attachments: &[Attachment]) {
+        let store = match &self.store {
+            Some(store) if !attachments.is_empty() => store.clone(),
+            _ => return,
+        };
+        let keys = attachments
+            .iter()
+            .flat_map(Attachment::keys)
+            .cloned()
+            .collect::<Vec<_>>();
+        tokio::spawn(async move {
+            for key in keys {
+                if let Err(err) = store.delete(&key).await {
+                    tracing::error!(%key, "failed to delete an attachment: {}", err);
+                }
+            }
+        });
+    }
+}
+
+// Deletes the files of removed comments
+pub struct Cleanup;
+
+impl TransitionHook for Cleanup {
+    fn on_transition(&self, state: &AppState, comment: &Comment, _: Option<State>, to: State) {
+        if matches!(to, State::Rejected | State::Spam | State::Deleted) {
+            state.attachments.remove(&comment.attachments);
+        }
+    }
+}
+
+// GET /attachments/:key
+pub async fn get_attachment(
+    Path(key): Path<String>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let not_found = || {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "attachment.not_found",
+            "No such attachment",
+        )
+    };
+    let content_type = content_type_of(&key).ok_or_else(not_found)?;
+    let store = state.attachments.store.as_ref().ok_or_else(not_found)?;
+    let mut headers = HeaderMap::new();
+    if let Some(url) = store.presigned_url(&key) {
+        let location = HeaderValue::from_str(&url)
+            .map_err(|_| ApiError::internal("Failed to sign the attachment URL"))?;
+        headers.insert(header::LOCATION, location);
+        return Ok((StatusCode::FOUND, headers, Bytes::new()));
+    }
+
+    let data = store
+        .get(&key)
+        .await
+        .map_err(|err| {
+            tracing::error!(%key, "failed to read an attachment: {}", err);
+            ApiError::internal("Failed to read the attachment")
+        })?
+        .ok_or_else(not_found)?;
+
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    // Keys are never reused
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=31536000, immutable"),
+    );
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    if !content_type.starts_with("image/") {
+        headers.insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment"),
+        );
+    }
+    Ok((StatusCode::OK, headers, data))
+}
+
+// A file of a multipart request, checked but not stored yet
+pub struct Upload {
+    name: String,
+    content_type: &'static str,
+    data: Bytes,
+}
+
+// The body of POST /create: the comment as codec::Payload takes it, or
+// multipart with files, see the top of this file
+pub struct WithUploads<T>(pub T, pub Vec<Upload>);
+
+fn is_multipart(headers: Option<&HeaderMap>) -> bool {
+    headers
+        .and_then(|headers| headers.get(header::CONTENT_TYPE))
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"))
+}
+
+#[async_trait]
+impl<T, B> FromRequest<B> for WithUploads<T>
+where
+    T: DeserializeOwned + JsonSchema + Protobuf + Validate + Send,
+    B: axum::body::HttpBody<Data = Bytes> + Default + Unpin + Send + 'static,
+    B::Error: Into<axum::BoxError>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        if !is_multipart(req.headers()) {
+            let ValidatedPayload(value) = ValidatedPayload::from_request(req).await?;
+            return Ok(WithUploads(value, Vec::new()));
+        }
+
+        let Extension(state) = Extension::<SharedState>::from_request(req)
+            .await
+            .map_err(|_| ApiError::internal("App state is not available"))?;
+        if state.attachments.store.is_none() {
+            return Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "attachment.not_accepted",
+                "This server takes no attachments",
+            ));
+        }
+        multipart(req, &state.attachments.config).await
+    }
+}
+
+// Enough for any comment
+pub const MAX_COMMENT_BYTES: usize = 64 * 1024;
+
+async fn multipart<T, B>(
+    req: &mut RequestParts<B>,
+    config: &AttachmentsConfig,
+) -> Result<WithUploads<T>, ApiError>
+where
+    T: DeserializeOwned + Validate + Send,
+    B: axum::body::HttpBody<Data = Bytes> + Default + Unpin + Send + 'static,
+    B::Error: Into<axum::BoxError>,
+{
+    let mut multipart = Multipart::from_request(req)
+        .await
+        .map_err(|err| extract::unreadable(&err))?;
+
+    let mut comment = None;
+    let mut uploads = Vec::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| extract::unreadable(&err))?
+    {
+        match field.name() {
+            Some("comment") => {
+                let data = read(field, MAX_COMMENT_BYTES).await?.ok_or_else(|| {
+                    ApiError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "request.too_large",
+                        "The comment part is too long",
+                    )
+                })?;
+                comment = Some(serde_json::from_slice(&data).map_err(|err| {
+                    ApiError::new(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "request.invalid_body",
+                        format!("Failed to parse the comment part: {}", err),
+                    )
+                })?);
+            }
+            Some("file") => {
+                let name = file_name(field.file_name());
+                let data = read(field, config.max_bytes).await?.ok_or_else(|| {
+                    ApiError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "attachment.too_large",
+                        format!("{} is larger than {} bytes", name, config.max_bytes),
+                    )
+                })?;
+                // What browsers send for an empty file input
+                if data.is_empty() {
+                    continue;
+                }
+                if uploads.len() == config.max_files {
+                    return Err(ApiError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "attachment.too_many",
+                        format!("At most {} files can be attached", config.max_files),
+                    ));
+                }
+                let content_type = sniff(&data)
+                    .filter(|content_type| config.types.iter().any(|known| known == content_type))
+                    .ok_or_else(|| {
+                        ApiError::new(
+                            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                            "attachment.unsupported_type",
+                            format!(
+                                "{} isn't one of the accepted types: {}",
+                                name,
+                                config.types.join(", ")
+                            ),
+                        )
+                    })?;
+                uploads.push(Upload {
+                    name,
+                    content_type,
+                    data,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let comment = comment.ok_or_else(|| {
+        ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "request.invalid_body",
+            "Expected the comment as JSON in a \"comment\" part",
+        )
+    })?;
+    Ok(WithUploads(extract::validated(comment)?, uploads))
+}
+
+// None once it's longer than `limit`
+async fn read(mut field: Field<'_>, limit: usize) -> Result<Option<Bytes>, ApiError> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|err| extract::unreadable(&err))?;
+        if data.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some(data.into()))
+}
+
+// The last path segment, as shown in the pages
+fn file_name(name: Option<&str>) -> String {
+    const MAX_NAME_LEN: usize = 100;
+    let name = name
+        .and_then(|name| name.rsplit(['/', '\\']).next())
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect::<String>();
+    if name.trim().is_empty() {
+        "attachment".to_owned()
+    } else {
+        name
+    }
+}
+
+// Scaled to fit `size` pixels and encoded as JPEG for photos and PNG for the
+// rest, off the async threads
+#[cfg(feature = "thumbnails")]
+async fn thumbnail(upload: &Upload, size: u32) -> Option<(&'static str, Bytes)> {
+    use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+
+    if !upload.content_type.starts_with("image/") {
+        return None;
+    }
+    let data = upload.data.clone();
+    let jpeg = upload.content_type == "image/jpeg";
+    let encoded = tokio::task::spawn_blocking(move || {
+        let mut reader = ImageReader::new(io::Cursor::new(data)).with_guessed_format()?;
+        // Against images which are small files but huge pictures
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(16_384);
+        limits.max_image_height = Some(16_384);
+        limits.max_alloc = Some(256 * 1024 * 1024);
+        reader.limits(limits);
+        let thumbnail = reader.decode()?.thumbnail(size, size);
+
+        let mut encoded = io::Cursor::new(Vec::new());
+        if jpeg {
+            DynamicImage::ImageRgb8(thumbnail.to_rgb8())
+                .write_to(&mut encoded, ImageFormat::Jpeg)?;
+            Ok::<_, image::ImageError>(("image/jpeg", encoded.into_inner()))
+        } else {
+            thumbnail.write_to(&mut encoded, ImageFormat::Png)?;
+            Ok(("image/png", encoded.into_inner()))
+        }
+    })
+    .await;
+
+    match encoded {
+        Ok(Ok((content_type, data))) => Some((content_type, data.into())),
+        Ok(Err(err)) => {
+            tracing::debug!(name = %upload.name, "no thumbnail: {}", err);
+            None
+        }
+        Err(err) => {
+            tracing::error!("thumbnailing failed: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "thumbnails"))]
+async fn thumbnail(_: &Upload, _: u32) -> Option<(&'static str, Bytes)> {
+    None
+}
@@ -0,0 +1,105 @@
+// Failed logins and refused clients, for fail2ban or a WAF to ban repeat
+// offenders at the firewall. Each one is a warning of the little_nova::auth
+// target, and also a line of [auth_log] path while set, in a format which
+// stays put:
+//
+//   2026-10-14T10:11:19Z auth-failure client=203.0.113.7 reason=admin-token method=GET path=/admin/comments
+//
+// The client is "-" when its address is not known, and the reasons are
+//
+//   admin-token     missing or wrong admin token, also over gRPC
+//   basic-auth      wrong [basic_auth] credentials
+//   signature       missing, stale or wrong [signing] signature
+//   proof-of-work   missing or wrong [pow] answer
+//   rate-limit      more comments than the site's rate_limit_per_minute
+//   tarpit          refused while in the [tarpit]
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    sync::Mutex,
+};
+
+use axum::extract::RequestParts;
+use chrono::{SecondsFormat, Utc};
+
+use crate::config::AuthLogConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    AdminToken,
+    BasicAuth,
+    Signature,
+    ProofOfWork,
+    RateLimit,
+    Tarpit,
+}
+
+impl Failure {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Failure::AdminToken => "admin-token",
+            Failure::BasicAuth => "basic-auth",
+            Failure::Signature => "signature",
+            Failure::ProofOfWork => "proof-of-work",
+            Failure::RateLimit => "rate-limit",
+            Failure::Tarpit => "tarpit",
+        }
+    }
+}
+
+pub struct AuthLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuthLog {
+    pub fn open(config: &AuthLogConfig) -> Result<AuthLog, String> {
+        let file = match &config.path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|err| format!("failed to open {}: {}", path.display(), err))?,
+            )),
+            None => None,
+        };
+        Ok(AuthLog { file })
+    }
+
+    pub fn failure(&self, reason: Failure, client: Option<IpAddr>, method: &str, path: &str) {
+        let client = client.map_or_else(|| "-".to_owned(), |ip| ip.to_string());
+        tracing::warn!(
+            target: "little_nova::auth",
+            client = %client,
+            reason = %reason.as_str(),
+            method = %method,
+            path = %path,
+            "auth failure"
+        );
+
+        if let Some(file) = &self.file {
+            let line = format!(
+                "{} auth-failure client={} reason={} method={} path={}\n",
+                Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                client,
+                reason.as_str(),
+                method,
+                path
+            );
+            if let Err(err) = file.lock().unwrap().write_all(line.as_bytes()) {
+                tracing::error!("failed to write the auth log: {}", err);
+            }
+        }
+    }
+
+    // From an extractor, which knows the client but not yet the handler
+    pub fn failed_request<B>(
+        &self,
+        reason: Failure,
+        client: Option<IpAddr>,
+        req: &RequestParts<B>,
+    ) {
+        self.failure(reason, client, req.method().as_str(), req.uri().path());
+    }
+}
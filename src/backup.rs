@@ -0,0 +1,255 @@
+// Backups of everything in the snapshot, taken while the server runs:
+//
+//   GET /admin/backup       downloads one
+//   POST /admin/backup      saves one in [backup] dir
+//   POST /admin/restore     replaces everything with an uploaded one
+//
+// Restoring refuses new comments and votes as maintenance mode does until
+// it is done, and keeps what it replaced next to the snapshot first. The
+// comments it changes are recorded in the event log as one unit, Restored
+// for those of the backup and Deleted for the others
+use std::{
+    io,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+};
+
+use axum::{
+    body::Bytes,
+    extract::Extension,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use serde_json::json;
+
+use crate::{
+    admin::Admin,
+    errors::ApiError,
+    events::{self, CommentEvent, DeleteReason},
+    state::SharedState,
+    storage::{self, StorageError},
+    Comment,
+};
+
+type Error = ApiError;
+
+fn failed(err: impl std::fmt::Display) -> Error {
+    tracing::error!("backup failed: {}", err);
+    ApiError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "server.failed",
+        err.to_string(),
+    )
+}
+
+fn file_name() -> String {
+    format!(
+        "little-nova-backup-{}.json",
+        Utc::now().format("%Y%m%d%H%M%S")
+    )
+}
+
+async fn snapshot(state: &SharedState) -> Result<Vec<u8>, Error> {
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || storage::snapshot(&state))
+        .await
+        .map_err(failed)?
+        .map_err(failed)
+}
+
+// GET /admin/backup
+pub async fn download_backup(
+    _: Admin,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, Error> {
+    let json = snapshot(&state).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    let disposition = format!("attachment; filename=\"{}\"", file_name());
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok((headers, json))
+}
+
+// POST /admin/backup
+pub async fn save_backup(
+    _: Admin,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, Error> {
+    let dir = state.config.backup.dir.clone().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::FORBIDDEN,
+            "backup.disabled",
+            "Backups to disk are disabled, see [backup] dir",
+        )
+    })?;
+    let json = snapshot(&state).await?;
+    let bytes = json.len();
+    let path = dir.join(file_name());
+
+    let written = path.clone();
+    tokio::task::spawn_blocking(move || storage::write_atomically(&written, json))
+        .await
+        .map_err(failed)?
+        .map_err(failed)?;
+
+    tracing::info!(path = %path.display(), bytes, "saved a backup");
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "path": path, "bytes": bytes })),
+    ))
+}
+
+// POST /admin/restore with the body of a backup, which may be from an older
+// version of little-nova
+pub async fn restore_backup(
+    _: Admin,
+    body: Bytes,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, Error> {
+    let (contents, version) = storage::parse(&body, Path::new("upload")).map_err(|err| {
+        let (status, code) = match err {
+            StorageError::NewerSchema(..) => (StatusCode::CONFLICT, "backup.newer_schema"),
+            _ => (StatusCode::UNPROCESSABLE_ENTITY, "backup.invalid"),
+        };
+        ApiError::new(status, code, err.to_string())
+    })?;
+
+    let maintenance = state.maintenance.swap(true, Ordering::Relaxed);
+    let result = restore(&state, contents).await;
+    state.maintenance.store(maintenance, Ordering::Relaxed);
+    let (comments, removed) = result?;
+
+    tracing::info!(comments, removed, version, "restored a backup");
+    Ok(Json(json!({
+        "comments": comments,
+        "removed": removed,
+        "schema_version": version,
+    })))
+}
+
+// Swaps everything in memory for `contents`. Returns the number of comments,
+// and of comments there were which `contents` doesn't have. For replicas,
+// see replication.rs, restores go through record
+#[cfg(feature = "replication")]
+pub fn replace(state: &SharedState, contents: storage::Contents) -> (usize, usize) {
+    let (comments, removed) = {
+        let mut db = state.db.write().unwrap();
+        state.tombstones.restore(contents.tombstones);
+        let mut removed = 0;
+        for comment in db.values() {
+            state.stats.remove(comment);
+            state.trending.remove(comment);
+            if !contents.comments.contains_key(&comment.id) {
+                // Lets clients following /changes drop it
+                state.tombstones.add(comment);
+                state.redis.delete(comment.id);
+                state.storage.mark_changed(comment.id);
+                removed += 1;
+            }
+        }
+        for comment in contents.comments.values() {
+            state.stats.add(comment);
+            state.trending.add(comment);
+            state.redis.put(comment);
+            state.storage.mark_changed(comment.id);
+        }
+        state
+            .search_index
+            .rebuild(contents.comments.values().map(Arc::as_ref));
+        *db = contents.comments;
+
+        state.sites.restore(&state.config, contents.sites);
+        state.rules.restore(contents.rules);
+        state.blocked_names.restore(contents.blocked_names);
+        state.notifications.restore(contents.notifications);
+        state.storage.mark_dirty();
+        (db.len(), removed)
+    };
+    state.published.notify_waiters();
+    (comments, removed)
+}
+
+// Like replace, but through the event log, so the history and whatever
+// follows the log see the restore
+fn record(state: &SharedState, contents: storage::Contents) -> io::Result<(usize, usize)> {
+    let (comments, removed) = {
+        let mut db = state.db.write().unwrap();
+        let mut events = db
+            .keys()
+            .filter(|id| !contents.comments.contains_key(id))
+            .map(|id| CommentEvent::Deleted {
+                id: *id,
+                reason: Some(DeleteReason::Restored),
+            })
+            .collect::<Vec<_>>();
+        let removed = events.len();
+        events.extend(
+            contents
+                .comments
+                .values()
+                .filter(|comment| {
+                    db.get(&comment.id)
+                        .is_none_or(|current| !unchanged(current, comment))
+                })
+                .map(|comment| CommentEvent::Restored {
+                    comment: comment.clone(),
+                }),
+        );
+
+        // Deleting adds the tombstones to those of the backup
+        let tombstones = state.tombstones.all();
+        state.tombstones.restore(contents.tombstones);
+        if !events.is_empty() {
+            if let Err(err) = events::commit_all(state, &mut db, events) {
+                state.tombstones.restore(tombstones);
+                return Err(err);
+            }
+        }
+
+        state.sites.restore(&state.config, contents.sites);
+        state.rules.restore(contents.rules);
+        state.blocked_names.restore(contents.blocked_names);
+        state.notifications.restore(contents.notifications);
+        state.storage.mark_dirty();
+        (db.len(), removed)
+    };
+    state.published.notify_waiters();
+    Ok((comments, removed))
+}
+
+fn unchanged(current: &Comment, restored: &Comment) -> bool {
+    std::ptr::eq(current, restored)
+        || serde_json::to_value(current).ok() == serde_json::to_value(restored).ok()
+}
+
+async fn restore(
+    state: &SharedState,
+    contents: storage::Contents,
+) -> Result<(usize, usize), Error> {
+    // What is replaced, so a wrong upload can be undone
+    if let Some(path) = state.storage.path() {
+        let previous = snapshot(state).await?;
+        let path = path.with_extension("pre-restore.bak");
+        tokio::task::spawn_blocking(move || storage::write_atomically(&path, previous))
+            .await
+            .map_err(failed)?
+            .map_err(failed)?;
+    }
+
+    let (comments, removed) = record(state, contents).map_err(failed)?;
+
+    let flushed = Arc::clone(state);
+    tokio::task::spawn_blocking(move || flushed.storage.flush(&flushed))
+        .await
+        .map_err(failed)?
+        .map_err(failed)?;
+    Ok((comments, removed))
+}
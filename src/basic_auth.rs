@@ -0,0 +1,190 @@
+// A password for the whole site, see [basic_auth], for staging deployments
+// which shouldn't be public yet. GET /ready stays open for the load
+// balancer, and the admin routes keep their token instead, since both use
+// the Authorization header
+//
+// bcrypt takes a while on purpose, so credentials which passed are
+// remembered by their SHA-256 until a restart
+use std::{
+    collections::HashSet,
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{self, BoxBody, Full},
+    http::{header, HeaderValue, Request, Response, StatusCode},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use tower::{Layer, Service};
+
+use crate::{
+    admin::constant_time_eq, auth_log::Failure, config::BasicAuthConfig, identity,
+    state::SharedState,
+};
+
+// Forget the credentials which passed once this many are kept
+const FORGET_AT: usize = 1000;
+
+pub struct BasicAuth {
+    config: BasicAuthConfig,
+    // The WWW-Authenticate header
+    challenge: HeaderValue,
+    // Digests of Authorization headers which passed
+    passed: Mutex<HashSet<[u8; 32]>>,
+}
+
+fn digest(authorization: &str) -> [u8; 32] {
+    Sha256::digest(authorization.as_bytes()).into()
+}
+
+fn is_exempt(path: &str) -> bool {
+    path == "/ready" || path == "/admin" || path.starts_with("/admin/")
+}
+
+impl BasicAuth {
+    pub fn new(config: &BasicAuthConfig) -> Self {
+        let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", config.realm);
+        BasicAuth {
+            config: config.clone(),
+            challenge: HeaderValue::from_str(&challenge)
+                .unwrap_or_else(|_| HeaderValue::from_static("Basic realm=\"little-nova\"")),
+            passed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.user.is_some()
+    }
+
+    fn passed_before(&self, authorization: &str) -> bool {
+        self.passed.lock().unwrap().contains(&digest(authorization))
+    }
+
+    // Runs bcrypt, so not on the async runtime
+    fn check(&self, authorization: &str) -> bool {
+        let credentials = authorization
+            .split_once(' ')
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+            .and_then(|(_, encoded)| STANDARD.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let (user, password) = match credentials.as_deref().and_then(|text| text.split_once(':')) {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        let expected = self.config.user.as_deref().unwrap_or_default();
+        let hash = self.config.password_hash.as_deref().unwrap_or_default();
+        // The password is checked for any user, so a wrong one takes as long
+        let user_matches = constant_time_eq(user.as_bytes(), expected.as_bytes());
+        if !(bcrypt::verify(password, hash).unwrap_or(false) && user_matches) {
+            return false;
+        }
+
+        let mut passed = self.passed.lock().unwrap();
+        if passed.len() >= FORGET_AT {
+            passed.clear();
+        }
+        passed.insert(digest(authorization));
+        true
+    }
+}
+
+pub struct BasicAuthLayer {
+    state: SharedState,
+}
+
+impl BasicAuthLayer {
+    pub fn new(state: SharedState) -> Self {
+        BasicAuthLayer { state }
+    }
+}
+
+impl<S> Layer<S> for BasicAuthLayer {
+    type Service = BasicAuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BasicAuthService {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BasicAuthService<S> {
+    inner: S,
+    state: SharedState,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for BasicAuthService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let auth = &self.state.basic_auth;
+        if !auth.is_enabled() || is_exempt(req.uri().path()) {
+            return Box::pin(self.inner.call(req));
+        }
+        let authorization = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        if authorization
+            .as_deref()
+            .is_some_and(|authorization| auth.passed_before(authorization))
+        {
+            return Box::pin(self.inner.call(req));
+        }
+
+        // The one which was polled ready goes along, a fresh clone stays
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let state = self.state.clone();
+        Box::pin(async move {
+            // Browsers ask without credentials first
+            let passed = match authorization {
+                Some(authorization) => {
+                    let checked = state.clone();
+                    let passed = tokio::task::spawn_blocking(move || {
+                        checked.basic_auth.check(&authorization)
+                    })
+                    .await
+                    .unwrap_or(false);
+                    if !passed {
+                        let client = identity::client_ip(&state, &req);
+                        state.auth_log.failure(
+                            Failure::BasicAuth,
+                            client,
+                            req.method().as_str(),
+                            req.uri().path(),
+                        );
+                    }
+                    passed
+                }
+                None => false,
+            };
+            if passed {
+                return inner.call(req).await;
+            }
+            Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, state.basic_auth.challenge.clone())
+                .body(body::boxed(Full::from("This site needs a password")))
+                .unwrap())
+        })
+    }
+}
@@ -0,0 +1,213 @@
+// Names commenters can't use, e.g. "admin" or the site owner's name, so
+// nobody can pass as them. Like the spam rules they come from
+// [[blocked_names]] in the config and from the admin API
+//
+// Names are compared without case, spaces or punctuation, so blocking
+// "admin" also blocks "Admin", "A.D.M.I.N" and "ad min". A `pattern` may
+// use `*` for any run of characters: "*admin*" blocks "the admin team" too
+use std::sync::RwLock;
+
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    admin::Admin,
+    errors::ApiError,
+    extract::{Validate, ValidatedJson},
+    state::SharedState,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BlockedName {
+    // Only applies to this site, to every site while unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site: Option<String>,
+    // The name itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // With `*` for anything, e.g. "*moderator*"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+// Added through the admin API, the id is for deleting it again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddedBlockedName {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub blocked: BlockedName,
+}
+
+impl Validate for BlockedName {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        match (&self.name, &self.pattern) {
+            (Some(_), Some(_)) | (None, None) => Err((
+                "blocked_name.invalid",
+                "A blocked name needs either name or pattern".to_owned(),
+            )),
+            (Some(name), None) if normalize(name).is_empty() => Err((
+                "blocked_name.invalid",
+                format!("\"{}\" has no letters or digits", name),
+            )),
+            (None, Some(pattern)) if normalize(pattern).is_empty() => Err((
+                "blocked_name.invalid",
+                format!(
+                    "\"{}\" would block every name, it needs letters or digits",
+                    pattern
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// What names are compared as
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Like normalize, keeping the `*`s
+fn normalize_pattern(pattern: &str) -> String {
+    pattern
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == '*')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// `*` matches any run of characters, everything else itself
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // No `*` at all
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+impl BlockedName {
+    fn matches(&self, site: &str, name: &str) -> bool {
+        if self.site.as_ref().is_some_and(|only| only != site) {
+            return false;
+        }
+        match (&self.name, &self.pattern) {
+            (Some(blocked), _) => normalize(blocked) == name,
+            (None, Some(pattern)) => glob_matches(&normalize_pattern(pattern), name),
+            (None, None) => false,
+        }
+    }
+}
+
+pub struct BlockedNames {
+    configured: Vec<BlockedName>,
+    added: RwLock<Vec<AddedBlockedName>>,
+}
+
+impl BlockedNames {
+    pub fn new(configured: Vec<BlockedName>, added: Vec<AddedBlockedName>) -> Self {
+        BlockedNames {
+            configured,
+            added: RwLock::new(added),
+        }
+    }
+
+    // Persisted in the snapshot
+    pub fn added(&self) -> Vec<AddedBlockedName> {
+        self.added.read().unwrap().clone()
+    }
+
+    // Replaces the names added through the admin API, see backup.rs
+    pub fn restore(&self, added: Vec<AddedBlockedName>) {
+        *self.added.write().unwrap() = added;
+    }
+
+    pub fn is_blocked(&self, site: &str, name: &str) -> bool {
+        let name = normalize(name);
+        let added = self.added.read().unwrap();
+        self.configured
+            .iter()
+            .chain(added.iter().map(|added| &added.blocked))
+            .any(|blocked| blocked.matches(site, &name))
+    }
+}
+
+pub async fn get_blocked_names(
+    _: Admin,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    Json(json!({
+        "configured": state.blocked_names.configured,
+        "added": state.blocked_names.added(),
+    }))
+}
+
+pub async fn add_blocked_name(
+    _: Admin,
+    ValidatedJson(blocked): ValidatedJson<BlockedName>,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let added = AddedBlockedName {
+        id: Uuid::new_v4(),
+        blocked,
+    };
+    state
+        .blocked_names
+        .added
+        .write()
+        .unwrap()
+        .push(added.clone());
+    state.storage.mark_dirty();
+    tracing::info!(id = %added.id, "blocked name added");
+
+    (StatusCode::CREATED, Json(added))
+}
+
+// Names from the config can only be removed there
+pub async fn delete_blocked_name(
+    _: Admin,
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut added = state.blocked_names.added.write().unwrap();
+    let index = added
+        .iter()
+        .position(|added| added.id == id)
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "blocked_name.not_found",
+                "No such blocked name",
+            )
+        })?;
+    added.remove(index);
+    drop(added);
+
+    state.storage.mark_dirty();
+    tracing::info!(%id, "blocked name deleted");
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,25 @@
+use axum::{response::IntoResponse, Json};
+use serde::Serialize;
+
+// Values are embedded at compile time by build.rs
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub build_timestamp: &'static str,
+    pub rustc_version: &'static str,
+    // Of the snapshot this build reads and writes, see storage.rs
+    pub schema_version: u64,
+}
+
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    git_commit: env!("LITTLE_NOVA_GIT_COMMIT"),
+    build_timestamp: env!("LITTLE_NOVA_BUILD_TIMESTAMP"),
+    rustc_version: env!("LITTLE_NOVA_RUSTC_VERSION"),
+    schema_version: crate::storage::SCHEMA_VERSION,
+};
+
+pub async fn get_version() -> impl IntoResponse {
+    Json(BUILD_INFO)
+}
@@ -0,0 +1,232 @@
+// Comments changed since a point in time, for caches and static site builds
+// which sync incrementally. Deleted comments are reported through tombstones
+use std::sync::{Arc, RwLock};
+
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use uuid::Uuid;
+
+use crate::{
+    attachments::Attachment,
+    codec::Format,
+    errors::ApiError,
+    extract::ParsedQuery,
+    markup::{Markup, Mention},
+    previews::LinkPreview,
+    sites::{self, Site},
+    state::{AppState, SharedState},
+    Comment,
+};
+
+// Clients syncing less often than this have to start over
+const TOMBSTONE_DAYS: i64 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub id: Uuid,
+    pub site: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    pub deleted_at: DateTime<Utc>,
+    // When the comment was listed, so /poll can go on from it, see poll.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listed_at: Option<DateTime<Utc>>,
+    // Of a comment visitors couldn't see, only for replicas
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unlisted: bool,
+}
+
+#[derive(Default)]
+pub struct Tombstones {
+    tombstones: RwLock<Vec<Tombstone>>,
+}
+
+impl Tombstones {
+    pub fn new(tombstones: Vec<Tombstone>) -> Self {
+        Tombstones {
+            tombstones: RwLock::new(tombstones),
+        }
+    }
+
+    // Persisted in the snapshot
+    pub fn all(&self) -> Vec<Tombstone> {
+        self.tombstones.read().unwrap().clone()
+    }
+
+    // See backup.rs
+    pub fn restore(&self, tombstones: Vec<Tombstone>) {
+        *self.tombstones.write().unwrap() = tombstones;
+    }
+
+    // Deletions after `since`, None when it is too long ago to tell
+    pub fn since(&self, since: DateTime<Utc>) -> Option<Vec<Tombstone>> {
+        if since < Utc::now() - Duration::days(TOMBSTONE_DAYS) {
+            return None;
+        }
+        let tombstones = self.tombstones.read().unwrap();
+        Some(
+            tombstones
+                .iter()
+                .filter(|tombstone| tombstone.deleted_at > since)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Tombstone> {
+        let tombstones = self.tombstones.read().unwrap();
+        tombstones
+            .iter()
+            .rev()
+            .find(|tombstone| tombstone.id == *id)
+            .cloned()
+    }
+
+    pub fn add(&self, comment: &Comment) {
+        let now = Utc::now();
+        let mut tombstones = self.tombstones.write().unwrap();
+        tombstones.retain(|tombstone| tombstone.deleted_at > now - Duration::days(TOMBSTONE_DAYS));
+        tombstones.push(Tombstone {
+            id: comment.id,
+            site: comment.site.clone(),
+            slug: comment.slug.clone(),
+            deleted_at: now,
+            listed_at: comment.listed_at(),
+            unlisted: !comment.is_listed(&comment.site),
+        });
+    }
+}
+
+// What visitors see of a comment. Holds on to the stored comment and
+// serializes the public fields from it, so listings copy no strings
+#[derive(Debug)]
+pub struct PublicComment(Arc<Comment>, Arc<Markup>);
+
+#[derive(Serialize)]
+struct PublicFields<'a> {
+    id: Uuid,
+    title: &'a Option<String>,
+    name: &'a str,
+    text: &'a str,
+    // As on the pages, see markup.rs
+    text_html: String,
+    utc: DateTime<Utc>,
+    tags: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slug: &'a Option<String>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    mentions: &'a [Mention],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    attachments: &'a [Attachment],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    previews: &'a [LinkPreview],
+    score: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<DateTime<Utc>>,
+}
+
+impl PublicComment {
+    pub fn new(comment: &Arc<Comment>, state: &AppState) -> Self {
+        PublicComment(comment.clone(), state.markup.clone())
+    }
+}
+
+impl Serialize for PublicComment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let comment = &self.0;
+        PublicFields {
+            id: comment.id,
+            title: &comment.title,
+            name: &comment.name,
+            text: &comment.text,
+            text_html: comment.text_html(&sites::root(&comment.site), &self.1),
+            utc: comment.utc,
+            tags: &comment.tags,
+            slug: &comment.slug,
+            mentions: &comment.mentions,
+            attachments: &comment.attachments,
+            previews: &comment.previews,
+            score: comment.score(),
+            created_at: comment.created_at,
+            updated_at: comment.updated_at,
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Change {
+    Created { comment: PublicComment },
+    Updated { comment: PublicComment },
+    Deleted(Tombstone),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    // RFC 3339, e.g. "2021-10-01T00:00:00Z"
+    since: DateTime<Utc>,
+}
+
+// Changes after `since`, oldest first. `until` is the `since` of the next call
+pub async fn get_changes(
+    ParsedQuery(query): ParsedQuery<ChangesQuery>,
+    site: Site,
+    format: Format,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let now = Utc::now();
+    if query.since < now - Duration::days(TOMBSTONE_DAYS) {
+        // Deletions before that are forgotten, a full sync is needed
+        return Err(ApiError::new(
+            StatusCode::GONE,
+            "changes.too_old",
+            "since is too long ago, fetch every comment instead",
+        ));
+    }
+
+    let comments = state.db.read().unwrap();
+    let until = Utc::now();
+    let mut changes = comments
+        .values()
+        .filter(|comment| comment.is_listed(&site.key))
+        .filter(|comment| comment.updated_at() > query.since)
+        .map(|comment| {
+            let at = comment.updated_at();
+            let created = comment
+                .created_at
+                .is_some_and(|created_at| created_at > query.since);
+            let comment = PublicComment::new(comment, &state);
+            if created {
+                (at, Change::Created { comment })
+            } else {
+                (at, Change::Updated { comment })
+            }
+        })
+        .collect::<Vec<_>>();
+    changes.extend(
+        state
+            .tombstones
+            .tombstones
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|tombstone| !tombstone.unlisted && tombstone.site == site.key)
+            .filter(|tombstone| tombstone.deleted_at > query.since)
+            .map(|tombstone| (tombstone.deleted_at, Change::Deleted(tombstone.clone()))),
+    );
+    drop(comments);
+    changes.sort_by_key(|(at, _)| *at);
+
+    Ok((
+        site.cors_headers(),
+        format.encode(serde_json::json!({
+            "since": query.since,
+            "until": until,
+            "changes": changes.into_iter().map(|(_, change)| change).collect::<Vec<_>>(),
+        })),
+    ))
+}
@@ -0,0 +1,95 @@
+// Fault injection for testing clients and their retry logic against this
+// server: requests are delayed or fail at the rates set in [chaos], and so
+// do snapshot writes. Only built with the `chaos` feature
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{self, BoxBody},
+    http::{Request, Response},
+    response::IntoResponse,
+};
+use tower::{Layer, Service};
+
+use crate::{config::ChaosConfig, errors::ApiError};
+
+// true for a share of `rate` of the calls
+pub fn roll(rate: f64) -> bool {
+    rate > 0.0 && fastrand::f64() < rate
+}
+
+#[derive(Debug, Clone)]
+pub struct ChaosLayer {
+    config: ChaosConfig,
+}
+
+impl ChaosLayer {
+    pub fn new(config: ChaosConfig) -> Self {
+        ChaosLayer { config }
+    }
+}
+
+impl<S> Layer<S> for ChaosLayer {
+    type Service = ChaosService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ChaosService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChaosService<S> {
+    inner: S,
+    config: ChaosConfig,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for ChaosService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // Left alone so the server can still be inspected and managed
+        if req.uri().path().starts_with("/admin") {
+            return Box::pin(self.inner.call(req));
+        }
+
+        let delay = roll(self.config.latency_rate)
+            .then(|| Duration::from_millis(fastrand::u64(0..=self.config.latency_ms)));
+        let fail = roll(self.config.error_rate);
+        let path = req.uri().path().to_owned();
+        // Not polled until the delay is over, so the handler runs late too
+        let response = (!fail).then(|| self.inner.call(req));
+
+        Box::pin(async move {
+            if let Some(delay) = delay {
+                tracing::debug!(%path, ?delay, "[chaos] delaying request");
+                tokio::time::sleep(delay).await;
+            }
+            match response {
+                Some(response) => response.await,
+                None => {
+                    tracing::debug!(%path, "[chaos] failing request");
+                    Ok(ApiError::internal("Failure injected by [chaos]")
+                        .into_response()
+                        .map(body::boxed))
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,106 @@
+// Spam check asking an external classification service, e.g. a machine
+// learning model, about every new comment. POSTs it to [spam.classifier] url
+// as JSON and takes the `score` of the answer, from 0.0 for clean to 1.0 for
+// spam, times `weight`:
+//
+//   {"site": "default", "title": null, "name": "Jane", "text": "Hello"}
+//   -> {"score": 0.93}
+//
+// When the service fails or takes longer than timeout_ms, `on_failure`
+// decides: "open" lets the comment through as if it scored 0.0, "closed"
+// holds it for an admin. Only built with the `classifier` feature
+use std::time::Duration;
+
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{ClassifierConfig, FailurePolicy},
+    spam::{Candidate, SpamCheck},
+};
+
+#[derive(Serialize)]
+struct Request<'a> {
+    site: &'a str,
+    title: Option<&'a str>,
+    name: &'a str,
+    text: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Classification {
+    score: f32,
+}
+
+pub struct Classifier {
+    client: reqwest::Client,
+    url: String,
+    weight: f32,
+    on_failure: FailurePolicy,
+    // Scored when the service fails closed
+    queue_score: f32,
+}
+
+impl Classifier {
+    pub fn new(config: &ClassifierConfig, url: &str, queue_score: f32) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .user_agent(concat!("little-nova/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|err| format!("failed to build the classifier client: {}", err))?;
+        Ok(Classifier {
+            client,
+            url: url.to_owned(),
+            weight: config.weight,
+            on_failure: config.on_failure,
+            queue_score,
+        })
+    }
+
+    async fn classify(&self, candidate: &Candidate<'_>) -> Result<f32, String> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&Request {
+                site: candidate.site,
+                title: candidate.title,
+                name: candidate.name,
+                text: candidate.text,
+            })
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("answered with {}", response.status()));
+        }
+        let classification = response
+            .json::<Classification>()
+            .await
+            .map_err(|err| err.to_string())?;
+        if !classification.score.is_finite() {
+            return Err(format!("answered with score {}", classification.score));
+        }
+        Ok(classification.score.clamp(0.0, 1.0))
+    }
+}
+
+#[async_trait]
+impl SpamCheck for Classifier {
+    fn name(&self) -> &'static str {
+        "classifier"
+    }
+
+    async fn score(&self, candidate: &Candidate<'_>) -> f32 {
+        match self.classify(candidate).await {
+            Ok(score) => score * self.weight,
+            Err(err) => {
+                let (score, outcome) = match self.on_failure {
+                    FailurePolicy::Open => (0.0, "letting the comment through"),
+                    FailurePolicy::Closed => (self.queue_score, "holding the comment"),
+                };
+                tracing::warn!("the spam classifier failed, {}: {}", outcome, err);
+                score
+            }
+        }
+    }
+}
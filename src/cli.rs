@@ -0,0 +1,270 @@
+// `little-nova admin ...`, for recovery and scripts: works on the snapshot
+// in [storage] path directly instead of through the admin API
+//
+//   little-nova admin list [--site KEY] [--status pending|approved|draft]
+//   little-nova admin export [--site KEY] [--status pending|approved|draft]
+//   little-nova admin approve <id>...
+//   little-nova admin delete <id>...
+//   little-nova admin migrate
+//
+// migrate upgrades the snapshot to the current schema, which the server
+// otherwise does when it starts, e.g. to run it as a deploy step
+//
+// The server keeps the comments in memory and would overwrite changes on its
+// next flush, so approve, delete and migrate refuse to run while it answers on `addr`
+use std::{
+    io::{self, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use uuid::Uuid;
+
+use crate::{
+    changes::Tombstones,
+    config::Config,
+    domain::Transition,
+    events::{self, CommentEvent, Recorded},
+    newest_first,
+    storage::{Contents, Storage, SCHEMA_VERSION},
+    Comment, CommentStatus,
+};
+
+const USAGE: &str = "usage: little-nova admin list|export [--site KEY] [--status STATUS]
+       little-nova admin approve|delete <id>... [--force]
+       little-nova admin migrate [--force]";
+
+// Exit code of the command
+pub fn run(args: &[String]) -> i32 {
+    match parse(args).and_then(|command| execute(command).map_err(Error::Failed)) {
+        Ok(()) => 0,
+        Err(Error::Usage(message)) => {
+            eprintln!("{}\n{}", message, USAGE);
+            2
+        }
+        Err(Error::Failed(message)) => {
+            eprintln!("{}", message);
+            1
+        }
+    }
+}
+
+enum Error {
+    Usage(String),
+    Failed(String),
+}
+
+enum Command {
+    List(Filter),
+    Export(Filter),
+    Approve(Vec<Uuid>, bool),
+    Delete(Vec<Uuid>, bool),
+    Migrate(bool),
+}
+
+#[derive(Default)]
+struct Filter {
+    site: Option<String>,
+    status: Option<CommentStatus>,
+}
+
+impl Filter {
+    fn matches(&self, comment: &Comment) -> bool {
+        self.site.as_ref().is_none_or(|site| comment.site == *site)
+            && self.status.is_none_or(|status| comment.status == status)
+    }
+}
+
+fn parse(args: &[String]) -> Result<Command, Error> {
+    let (command, rest) = args
+        .split_first()
+        .ok_or_else(|| Error::Usage("missing command".to_owned()))?;
+
+    let mut filter = Filter::default();
+    let mut ids = Vec::new();
+    let mut force = false;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = |name: &str| {
+            rest.next()
+                .ok_or_else(|| Error::Usage(format!("{} needs a value", name)))
+        };
+        match arg.as_str() {
+            "--site" => filter.site = Some(value("--site")?.clone()),
+            "--status" => {
+                let status = value("--status")?;
+                filter.status = Some(
+                    serde_json::from_value(status.as_str().into())
+                        .map_err(|_| Error::Usage(format!("unknown status \"{}\"", status)))?,
+                );
+            }
+            "--force" => force = true,
+            _ if arg.starts_with("--") => {
+                return Err(Error::Usage(format!("unknown option {}", arg)))
+            }
+            _ => ids.push(
+                Uuid::parse_str(arg)
+                    .map_err(|_| Error::Usage(format!("\"{}\" is not a comment id", arg)))?,
+            ),
+        }
+    }
+
+    match command.as_str() {
+        "list" | "export" | "migrate" if !ids.is_empty() => {
+            Err(Error::Usage(format!("{} takes no ids", command)))
+        }
+        "list" => Ok(Command::List(filter)),
+        "export" => Ok(Command::Export(filter)),
+        "approve" | "delete" if ids.is_empty() => {
+            Err(Error::Usage(format!("{} needs comment ids", command)))
+        }
+        "approve" => Ok(Command::Approve(ids, force)),
+        "delete" => Ok(Command::Delete(ids, force)),
+        "migrate" => Ok(Command::Migrate(force)),
+        _ => Err(Error::Usage(format!("unknown command \"{}\"", command))),
+    }
+}
+
+fn execute(command: Command) -> Result<(), String> {
+    let config = Config::load().map_err(|err| err.to_string())?;
+    if config.storage.path.is_none() {
+        return Err("[storage] path is not set, there is nothing to manage".to_owned());
+    }
+    let (storage, mut contents) = Storage::open(&config.storage)
+        .map_err(|err| format!("failed to load comments: {}", err))?;
+
+    match command {
+        Command::List(filter) => output(|out| {
+            for comment in newest_first(contents.comments.values().filter(|c| filter.matches(c))) {
+                writeln!(
+                    out,
+                    "{}\t{}\t{:?}\t{}\t{}\t{}",
+                    comment.id,
+                    comment.site,
+                    comment.status,
+                    comment.utc.to_rfc3339(),
+                    comment.name,
+                    excerpt(&comment.text)
+                )?;
+            }
+            Ok(())
+        }),
+        Command::Export(filter) => output(|out| {
+            let comments = newest_first(contents.comments.values().filter(|c| filter.matches(c)));
+            serde_json::to_writer_pretty(&mut *out, &comments)?;
+            writeln!(out)
+        }),
+        Command::Approve(ids, force) => {
+            refuse_while_running(&config, force)?;
+            let changed = change(&mut contents, &ids, |contents, id| {
+                // Approving again changes nothing, see domain.rs
+                if Transition::Approve.is_allowed(contents.comments.get(id)?.status.into()) {
+                    let recorded = record(&storage, CommentEvent::Approved { id: *id })?;
+                    events::fold(&mut contents.comments, &recorded);
+                }
+                Some(())
+            })?;
+            save(&storage, &contents, changed, "approved")
+        }
+        Command::Delete(ids, force) => {
+            refuse_while_running(&config, force)?;
+            let tombstones = Tombstones::new(std::mem::take(&mut contents.tombstones));
+            let changed = change(&mut contents, &ids, |contents, id| {
+                let recorded = record(
+                    &storage,
+                    CommentEvent::Deleted {
+                        id: *id,
+                        reason: None,
+                    },
+                )?;
+                let (comment, _) = events::fold(&mut contents.comments, &recorded);
+                tombstones.add(comment.as_deref()?);
+                Some(())
+            });
+            contents.tombstones = tombstones.all();
+            save(&storage, &contents, changed?, "deleted")
+        }
+        Command::Migrate(force) => {
+            let version = match storage.migrated_from() {
+                Some(version) => version,
+                None => {
+                    println!("already at schema version {}", SCHEMA_VERSION);
+                    return Ok(());
+                }
+            };
+            refuse_while_running(&config, force)?;
+            storage
+                .save(&contents)
+                .map_err(|err| format!("failed to save comments: {}", err))?;
+            println!(
+                "migrated from schema version {} to {}",
+                version, SCHEMA_VERSION
+            );
+            Ok(())
+        }
+    }
+}
+
+// A closed pipe, e.g. into `head`, is fine
+fn output(write: impl FnOnce(&mut io::StdoutLock) -> io::Result<()>) -> Result<(), String> {
+    match write(&mut io::stdout().lock()) {
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => Err(err.to_string()),
+        _ => Ok(()),
+    }
+}
+
+// Every id has to exist, so a typo doesn't go unnoticed
+fn change(
+    contents: &mut Contents,
+    ids: &[Uuid],
+    mut apply: impl FnMut(&mut Contents, &Uuid) -> Option<()>,
+) -> Result<usize, String> {
+    if let Some(missing) = ids.iter().find(|id| !contents.comments.contains_key(id)) {
+        return Err(format!("no comment {}, nothing was changed", missing));
+    }
+    let mut changed = 0;
+    for id in ids {
+        if apply(contents, id).is_some() {
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+// A comment whose change fails to be recorded is left as it is
+fn record(storage: &Storage, event: CommentEvent) -> Option<Recorded> {
+    storage
+        .events
+        .append(event)
+        .map_err(|err| eprintln!("failed to record the change: {}", err))
+        .ok()
+}
+
+fn save(storage: &Storage, contents: &Contents, changed: usize, done: &str) -> Result<(), String> {
+    storage
+        .save(contents)
+        .map_err(|err| format!("failed to save comments: {}", err))?;
+    println!("{} {} comments", done, changed);
+    Ok(())
+}
+
+fn refuse_while_running(config: &Config, force: bool) -> Result<(), String> {
+    if !force && TcpStream::connect_timeout(&config.addr, Duration::from_secs(1)).is_ok() {
+        return Err(format!(
+            "little-nova is running on {}, stop it first or it overwrites the change \
+             (--force to go ahead anyway)",
+            config.addr
+        ));
+    }
+    Ok(())
+}
+
+// First line of the text, shortened
+fn excerpt(text: &str) -> String {
+    let line = text.lines().next().unwrap_or("");
+    match line.char_indices().nth(60) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None if text.lines().nth(1).is_some() => format!("{}...", line),
+        None => line.to_owned(),
+    }
+}
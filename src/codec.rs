@@ -0,0 +1,283 @@
+// JSON, MessagePack or protobuf for the API routes: MessagePack for clients
+// which poll often and care about bandwidth, protobuf for other services.
+// Requests pick the format with Content-Type, responses with Accept; JSON
+// stays the default both ways. The listings also answer in JSON:API, see
+// jsonapi.rs
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    body::{Bytes, Full, HttpBody},
+    extract::{FromRequest, RequestParts},
+    http::{header, HeaderValue, Response, StatusCode},
+    response::IntoResponse,
+    BoxError,
+};
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    errors::ApiError,
+    extract::{self, json_body, Validate},
+};
+
+const MSGPACK: &str = "application/msgpack";
+// Still seen in the wild
+const MSGPACK_LEGACY: &str = "application/x-msgpack";
+const PROTOBUF: &str = "application/x-protobuf";
+// The registered name
+const PROTOBUF_IETF: &str = "application/protobuf";
+pub const JSON_API: &str = "application/vnd.api+json";
+
+// Types with a protobuf form, see proto.rs. Protobuf needs a schema, so the
+// others answer protobuf requests with 406 or 415
+pub trait Protobuf: Sized {
+    fn to_protobuf(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn from_protobuf(_bytes: &[u8]) -> Option<Result<Self, String>> {
+        None
+    }
+}
+
+// Nothing has a protobuf form without the feature
+#[cfg(not(feature = "protobuf"))]
+impl<T> Protobuf for T {}
+
+// Maps with field names and ids and timestamps as strings, so the documents
+// look the same as the JSON ones
+fn to_msgpack<T: Serialize>(value: &T) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    let mut buf = Vec::new();
+    let mut serializer = rmp_serde::Serializer::new(&mut buf)
+        .with_struct_map()
+        .with_human_readable();
+    value.serialize(&mut serializer)?;
+    Ok(buf)
+}
+
+fn from_msgpack<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, rmp_serde::decode::Error> {
+    let mut deserializer = rmp_serde::Deserializer::new(bytes).with_human_readable();
+    T::deserialize(&mut deserializer)
+}
+
+fn media_type(value: Option<&HeaderValue>) -> Option<Format> {
+    let value = value?.to_str().ok()?;
+    if value.contains(MSGPACK) || value.contains(MSGPACK_LEGACY) {
+        Some(Format::MsgPack)
+    } else if value.contains(PROTOBUF) || value.contains(PROTOBUF_IETF) {
+        Some(Format::Protobuf)
+    } else if value.contains(JSON_API) {
+        Some(Format::JsonApi)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+    Json,
+    MsgPack,
+    Protobuf,
+    // Only for responses of the endpoints building documents
+    JsonApi,
+}
+
+// Negotiated from the Accept header
+#[async_trait]
+impl<B> FromRequest<B> for Format
+where
+    B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let accept = req
+            .headers()
+            .and_then(|headers| headers.get(header::ACCEPT));
+        Ok(media_type(accept).unwrap_or(Format::Json))
+    }
+}
+
+// Format the client asked for by name, for routes which serve HTML to
+// browsers and everyone else. None unless Accept names one of the formats
+pub struct Requested(pub Option<Format>);
+
+#[async_trait]
+impl<B> FromRequest<B> for Requested
+where
+    B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let accept = req
+            .headers()
+            .and_then(|headers| headers.get(header::ACCEPT));
+        let json = accept
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("application/json"));
+        Ok(Requested(
+            media_type(accept).or_else(|| json.then_some(Format::Json)),
+        ))
+    }
+}
+
+impl Format {
+    pub fn encode<T: Serialize + Protobuf>(self, value: T) -> Encoded<T> {
+        Encoded {
+            format: self,
+            value,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MsgPack => MSGPACK,
+            Format::Protobuf => PROTOBUF,
+            Format::JsonApi => JSON_API,
+        }
+    }
+
+    // encode in two steps, for bodies which are kept around and sent again
+    pub fn serialize<T: Serialize + Protobuf>(self, value: &T) -> Result<Bytes, EncodeError> {
+        let body = match self {
+            Format::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            Format::MsgPack => to_msgpack(value).map_err(|err| err.to_string()),
+            Format::Protobuf => {
+                return value
+                    .to_protobuf()
+                    .map(Bytes::from)
+                    .ok_or(EncodeError::NoProtobuf)
+            }
+            Format::JsonApi => return Err(EncodeError::NoJsonApi),
+        };
+        body.map(Bytes::from).map_err(EncodeError::Failed)
+    }
+
+    pub fn respond(self, body: Result<Bytes, EncodeError>) -> Response<Full<Bytes>> {
+        match body {
+            Ok(body) => Response::builder()
+                .header(header::CONTENT_TYPE, self.content_type())
+                // Caches must not hand JSON to msgpack clients or the other way around
+                .header(header::VARY, "accept")
+                .body(Full::from(body))
+                .unwrap(),
+            Err(EncodeError::NoProtobuf) => ApiError::new(
+                StatusCode::NOT_ACCEPTABLE,
+                "request.not_acceptable",
+                "This endpoint has no protobuf form",
+            )
+            .into_response(),
+            Err(EncodeError::NoJsonApi) => ApiError::new(
+                StatusCode::NOT_ACCEPTABLE,
+                "request.not_acceptable",
+                "This endpoint has no JSON:API form",
+            )
+            .into_response(),
+            Err(EncodeError::Failed(err)) => ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "server.failed",
+                format!("Failed to serialize the response: {}", err),
+            )
+            .into_response(),
+        }
+    }
+}
+
+pub enum EncodeError {
+    NoProtobuf,
+    NoJsonApi,
+    Failed(String),
+}
+
+pub struct Encoded<T> {
+    format: Format,
+    value: T,
+}
+
+impl<T: Serialize + Protobuf> IntoResponse for Encoded<T> {
+    type Body = Full<Bytes>;
+    type BodyError = Infallible;
+
+    fn into_response(self) -> Response<Self::Body> {
+        self.format.respond(self.format.serialize(&self.value))
+    }
+}
+
+// Request body in any of the formats
+pub struct Payload<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for Payload<T>
+where
+    T: DeserializeOwned + JsonSchema + Protobuf,
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let content_type = req
+            .headers()
+            .and_then(|headers| headers.get(header::CONTENT_TYPE));
+        let format = match media_type(content_type) {
+            Some(format) => format,
+            None => return json_body(req).await.map(Payload),
+        };
+
+        let body = Bytes::from_request(req)
+            .await
+            .map_err(|err| extract::unreadable(&err))?;
+        match format {
+            Format::Protobuf => match T::from_protobuf(&body) {
+                Some(result) => result.map(Payload).map_err(|err| {
+                    ApiError::new(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "request.invalid_body",
+                        format!("Failed to parse the request body as protobuf: {}", err),
+                    )
+                }),
+                None => Err(ApiError::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "request.unsupported_type",
+                    "This endpoint takes no protobuf",
+                )),
+            },
+            Format::JsonApi => Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "request.unsupported_type",
+                "This endpoint takes no JSON:API documents",
+            )),
+            _ => from_msgpack(&body).map(Payload).map_err(|err| {
+                ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "request.invalid_body",
+                    format!("Failed to parse the request body as MessagePack: {}", err),
+                )
+            }),
+        }
+    }
+}
+
+// Request body in any of the formats, answered with 422 when a rule is
+// broken, see extract.rs
+pub struct ValidatedPayload<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for ValidatedPayload<T>
+where
+    T: DeserializeOwned + JsonSchema + Protobuf + Validate,
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Payload(value) = Payload::from_request(req).await?;
+        extract::validated(value).map(ValidatedPayload)
+    }
+}
@@ -0,0 +1,144 @@
+// Admin API on a unix socket, for operators scripting the server from the
+// same machine without exposing /admin on the public port. There is no TLS
+// and no token, the socket is only accessible to its owner instead:
+//
+//   curl --unix-socket /run/little-nova.sock http://localhost/health
+use std::{
+    fs, io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+    path::Path,
+    sync::atomic::Ordering,
+};
+
+use axum::{
+    extract::Extension,
+    handler::Handler,
+    http::StatusCode,
+    routing::{get, post},
+    AddExtensionLayer, Json, Router,
+};
+use axum_server::Handle;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::UnixListener;
+
+use crate::{
+    build_info::BUILD_INFO,
+    drain,
+    errors::{self, ApiError},
+    extract::ParsedJson,
+    state::SharedState,
+    stats,
+};
+
+// Marks requests which came in on the socket, see admin::Admin
+#[derive(Debug, Clone, Copy)]
+pub struct ControlSocket;
+
+// Bound in a directory only we can enter and moved into place once only we
+// can connect, so nobody gets in while the socket still has the umask's mode
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let private = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = bind_in(path, &private);
+    fs::remove_dir_all(&private)?;
+    bound
+}
+
+fn bind_in(path: &Path, private: &Path) -> io::Result<UnixListener> {
+    let uid = fs::metadata(private)?.uid();
+    match fs::symlink_metadata(path) {
+        Ok(existing) if existing.file_type().is_symlink() => {
+            return Err(io::Error::other("it is a symlink"));
+        }
+        Ok(existing) if !existing.file_type().is_socket() => {
+            return Err(io::Error::other("something other than a socket is there"));
+        }
+        Ok(existing) if existing.uid() != uid => {
+            return Err(io::Error::other("it belongs to another user"));
+        }
+        // Left behind by a server which didn't shut down cleanly
+        Ok(_) => fs::remove_file(path)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    let socket = private.join("socket");
+    let listener = UnixListener::bind(&socket)?;
+    fs::set_permissions(&socket, fs::Permissions::from_mode(0o600))?;
+    fs::rename(&socket, path)?;
+    Ok(listener)
+}
+
+pub async fn serve(listener: UnixListener, state: SharedState, handle: Handle) {
+    let app = Router::new()
+        .route("/health", get(get_health))
+        .route("/stats", get(stats::get_stats))
+        .route("/maintenance", get(get_maintenance).put(set_maintenance))
+        .route("/drain", post(drain::drain))
+        .fallback(
+            (|| async { ApiError::new(StatusCode::NOT_FOUND, "request.not_found", "Not found") })
+                .into_service(),
+        )
+        .layer(errors::ProblemLayer::new(state.config.errors.problem_json))
+        .layer(AddExtensionLayer::new(ControlSocket))
+        .layer(AddExtensionLayer::new(state))
+        .layer(AddExtensionLayer::new(handle));
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::error!("failed to accept on the control socket: {}", err);
+                continue;
+            }
+        };
+        let app = app.clone();
+        tokio::spawn(async move {
+            let result = hyper::server::conn::Http::new()
+                .http1_only(true)
+                .serve_connection(stream, app)
+                .await;
+            if let Err(err) = result {
+                tracing::debug!("control socket connection failed: {}", err);
+            }
+        });
+    }
+}
+
+async fn get_health(Extension(state): Extension<SharedState>) -> Json<serde_json::Value> {
+    Json(json!({
+        "status": "ok",
+        "version": BUILD_INFO.version,
+        "maintenance": state.maintenance.load(Ordering::Relaxed),
+        "draining": state.drain.is_draining(),
+        "comments": state.db.read().unwrap().len(),
+    }))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct Maintenance {
+    enabled: bool,
+}
+
+async fn get_maintenance(Extension(state): Extension<SharedState>) -> Json<serde_json::Value> {
+    Json(json!({ "enabled": state.maintenance.load(Ordering::Relaxed) }))
+}
+
+// While enabled new comments and votes are refused with 503, see main.rs
+async fn set_maintenance(
+    ParsedJson(input): ParsedJson<Maintenance>,
+    Extension(state): Extension<SharedState>,
+) -> Json<serde_json::Value> {
+    state.maintenance.store(input.enabled, Ordering::Relaxed);
+    tracing::info!(enabled = input.enabled, "maintenance mode toggled");
+    Json(json!({ "enabled": input.enabled }))
+}
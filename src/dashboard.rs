@@ -0,0 +1,256 @@
+// Admin dashboard at /admin with charts of the comment statistics and the
+// notification preferences. The page itself holds no data, it asks for the
+// admin token and loads the charts from /admin/charts, which are rendered
+// here as SVG, and the preferences from /admin/notifications
+use askama::Template;
+use axum::{extract::Extension, response::IntoResponse};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+
+use crate::{
+    admin::Admin, extract::ParsedQuery, notifications::Notification, sites::DEFAULT_SITE,
+    state::SharedState, stats::Stats, HtmlTemplate,
+};
+
+const WIDTH: f64 = 600.0;
+const HEIGHT: f64 = 200.0;
+// Room for the labels below and left of the bars
+const BOTTOM: f64 = 20.0;
+const LEFT: f64 = 160.0;
+const TOP_PAGES: usize = 10;
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+pub struct DashboardTemplate {
+    // Rows of the preferences form, see notifications.rs
+    pub notifications: [Notification; 4],
+}
+
+impl Default for DashboardTemplate {
+    fn default() -> Self {
+        DashboardTemplate {
+            notifications: Notification::ALL,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin-charts.html")]
+pub struct ChartsTemplate {
+    pub site: String,
+    pub days: i64,
+    pub charts: Vec<Chart>,
+    pub width: f64,
+    pub height: f64,
+}
+
+pub struct Chart {
+    pub title: String,
+    pub bars: Vec<Bar>,
+    // Points of a line, "x,y x,y ..."
+    pub line: Option<String>,
+    pub labels: Vec<Label>,
+}
+
+pub struct Bar {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    // Shown on hover
+    pub title: String,
+}
+
+pub struct Label {
+    pub x: f64,
+    pub y: f64,
+    pub text: String,
+    // "start", "middle" or "end"
+    pub anchor: &'static str,
+}
+
+impl Chart {
+    pub fn is_empty(&self) -> bool {
+        self.bars.is_empty() && self.line.is_none()
+    }
+}
+
+// SVG coordinates don't need more precision
+fn round(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+pub async fn get_dashboard() -> impl IntoResponse {
+    HtmlTemplate(DashboardTemplate::default())
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ChartsQuery {
+    site: Option<String>,
+    // Range of the volume and top pages charts, 30 by default
+    days: Option<i64>,
+}
+
+pub async fn get_charts(
+    _: Admin,
+    query: Option<ParsedQuery<ChartsQuery>>,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let ParsedQuery(query) = query.unwrap_or_default();
+    let site = query.site.unwrap_or_else(|| DEFAULT_SITE.to_owned());
+    let days = query.days.unwrap_or(30).clamp(1, 366);
+
+    HtmlTemplate(charts(&state.stats, site, days))
+}
+
+pub fn charts(stats: &Stats, site: String, days: i64) -> ChartsTemplate {
+    let today = Utc::now().date_naive();
+    let first = today - Duration::days(days - 1);
+
+    let charts = vec![
+        volume_chart(stats, &site, first, today),
+        top_pages_chart(stats, &site, first),
+        queue_chart(stats, &site),
+    ];
+
+    ChartsTemplate {
+        site,
+        days,
+        charts,
+        width: WIDTH,
+        height: HEIGHT,
+    }
+}
+
+// One bar per day
+fn volume_chart(
+    stats: &Stats,
+    site: &str,
+    first: chrono::NaiveDate,
+    last: chrono::NaiveDate,
+) -> Chart {
+    let daily = stats.daily(site, first, last);
+    let max = daily.iter().map(|(_, count)| *count).max().unwrap_or(0);
+    let slot = WIDTH / daily.len() as f64;
+    let scale = (HEIGHT - BOTTOM) / max.max(1) as f64;
+
+    let mut labels = Vec::new();
+    let bars = daily
+        .iter()
+        .enumerate()
+        .map(|(index, (date, count))| {
+            let x = index as f64 * slot;
+            // Label the first day of every week so the axis stays readable
+            if index % 7 == 0 {
+                labels.push(Label {
+                    x: round(x),
+                    y: HEIGHT - 5.0,
+                    text: date.format("%m-%d").to_string(),
+                    anchor: "start",
+                });
+            }
+            let height = *count as f64 * scale;
+            Bar {
+                x: round(x + slot * 0.1),
+                y: round(HEIGHT - BOTTOM - height),
+                width: round(slot * 0.8),
+                height: round(height),
+                title: format!("{}: {}", date, count),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Chart {
+        title: "Comments per day".to_owned(),
+        // Nothing to draw without any comment
+        bars: if max == 0 { Vec::new() } else { bars },
+        line: None,
+        labels,
+    }
+}
+
+// Horizontal bars, the slug on the left
+fn top_pages_chart(stats: &Stats, site: &str, first: chrono::NaiveDate) -> Chart {
+    let pages = stats.top_pages(site, first, TOP_PAGES);
+    let max = pages.first().map_or(1, |(_, count)| *count).max(1);
+    let slot = HEIGHT / TOP_PAGES as f64;
+    let scale = (WIDTH - LEFT) / max as f64;
+
+    let mut labels = Vec::new();
+    let mut bars = Vec::new();
+    for (index, (slug, count)) in pages.iter().enumerate() {
+        let y = index as f64 * slot;
+        labels.push(Label {
+            x: LEFT - 5.0,
+            y: round(y + slot * 0.7),
+            text: slug.clone(),
+            anchor: "end",
+        });
+        bars.push(Bar {
+            x: LEFT,
+            y: round(y + slot * 0.1),
+            width: round(*count as f64 * scale),
+            height: round(slot * 0.8),
+            title: format!("{}: {}", slug, count),
+        });
+    }
+
+    Chart {
+        title: "Top pages".to_owned(),
+        bars,
+        line: None,
+        labels,
+    }
+}
+
+// Pending comments over the last week, sampled hourly
+fn queue_chart(stats: &Stats, site: &str) -> Chart {
+    let samples = stats.queue_samples(site);
+    let max = samples
+        .iter()
+        .map(|(_, pending)| *pending)
+        .max()
+        .unwrap_or(0);
+    let scale = (HEIGHT - BOTTOM) / max.max(1) as f64;
+    let step = WIDTH / samples.len().saturating_sub(1).max(1) as f64;
+
+    let mut points = samples
+        .iter()
+        .enumerate()
+        .map(|(index, (_, pending))| {
+            let x = round(index as f64 * step);
+            let y = round(HEIGHT - BOTTOM - *pending as f64 * scale);
+            format!("{},{}", x, y)
+        })
+        .collect::<Vec<_>>();
+    // Right after the start there is a single sample, drawn as a flat line
+    if let [point] = points.as_slice() {
+        let (_, y) = point.split_once(',').unwrap_or_default();
+        points.push(format!("{},{}", WIDTH, y));
+    }
+    let line = (!points.is_empty()).then(|| points.join(" "));
+    let labels = match (samples.first(), samples.last()) {
+        (Some((first, _)), Some((last, pending))) => vec![
+            Label {
+                x: 0.0,
+                y: HEIGHT - 5.0,
+                text: first.format("%m-%d %H:%M").to_string(),
+                anchor: "start",
+            },
+            Label {
+                x: WIDTH,
+                y: HEIGHT - 5.0,
+                text: format!("{} ({} pending)", last.format("%m-%d %H:%M"), pending),
+                anchor: "end",
+            },
+        ],
+        _ => Vec::new(),
+    };
+
+    Chart {
+        title: "Moderation queue".to_owned(),
+        bars: Vec::new(),
+        line,
+        labels,
+    }
+}
@@ -0,0 +1,198 @@
+// Daily or weekly email of what waits for moderation, see [email] digest:
+// how many comments each site received, held and refused as spam since the
+// last digest, and the oldest held comments with the signed links of
+// moderation_links.rs. Sent at [email] digest_hour in [display] timezone,
+// on Mondays for weekly ones, and not at all when there is nothing to tell.
+// A digest due while the server was down is skipped. Only built with the
+// `email` feature
+use std::{collections::BTreeMap, sync::Arc};
+
+use askama::Template;
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+
+use crate::{
+    config::DigestFrequency,
+    email::Mailer,
+    moderation_links::{self, Action},
+    state::SharedState,
+    Comment, CommentStatus,
+};
+
+// Held comments listed, the oldest first
+const MAX_HELD: usize = 20;
+
+// Of a held comment's text
+const EXCERPT_CHARS: usize = 200;
+
+#[derive(Template)]
+#[template(path = "email-digest.txt")]
+struct DigestEmail {
+    // "day" or "week"
+    period: &'static str,
+    sites: Vec<(String, Activity)>,
+    // Of all sites, more than are listed
+    pending: usize,
+    held: Vec<Held>,
+}
+
+#[derive(Default)]
+struct Activity {
+    received: usize,
+    // Now, not only of the received ones
+    pending: usize,
+    refused: usize,
+}
+
+struct Held {
+    comment: Arc<Comment>,
+    // " on <slug>", or empty
+    page: String,
+    // When it was received, the way [display] locale writes it
+    received: String,
+    excerpt: String,
+    links: Vec<(&'static str, String)>,
+}
+
+// The first digest time after `now`
+fn next(state: &SharedState, frequency: DigestFrequency, at: NaiveTime) -> DateTime<Utc> {
+    let tz = state.config.display.timezone;
+    let now = Utc::now();
+    let mut date = tz.at(&now).date_naive();
+    loop {
+        let due = tz.when_local(date.and_time(at));
+        let on_day = frequency == DigestFrequency::Daily || date.weekday() == Weekday::Mon;
+        if let Some(due) = due.filter(|due| *due > now && on_day) {
+            return due;
+        }
+        date = date.succ_opt().expect("dates go on for a while");
+    }
+}
+
+// None when nothing happened and nothing waits
+fn digest(state: &SharedState, frequency: DigestFrequency) -> Option<(String, String)> {
+    let period = match frequency {
+        DigestFrequency::Daily => Duration::days(1),
+        DigestFrequency::Weekly => Duration::weeks(1),
+    };
+    let since = Utc::now() - period;
+
+    let mut sites = BTreeMap::<String, Activity>::new();
+    let mut held = Vec::new();
+    for comment in state.db.read().unwrap().values() {
+        if comment.created_at.is_some_and(|at| at >= since)
+            && comment.status != CommentStatus::Draft
+        {
+            sites.entry(comment.site.clone()).or_default().received += 1;
+        }
+        if comment.status == CommentStatus::Pending {
+            sites.entry(comment.site.clone()).or_default().pending += 1;
+            held.push(comment.clone());
+        }
+    }
+    for (site, refused) in state.stats.rejected_since(since.date_naive()) {
+        if refused > 0 {
+            sites.entry(site).or_default().refused += refused;
+        }
+    }
+    if sites.is_empty() {
+        return None;
+    }
+
+    let pending = held.len();
+    held.sort_by_key(|comment| (comment.created_at.unwrap_or(comment.utc), comment.id));
+    held.truncate(MAX_HELD);
+    let i18n = state.config.display.locale;
+    let held = held
+        .into_iter()
+        .map(|comment| {
+            let mut links = Vec::new();
+            links.extend(
+                moderation_links::link(state, comment.id, Action::Approve)
+                    .map(|link| ("Approve", link)),
+            );
+            links.extend(
+                moderation_links::link(state, comment.id, Action::Delete)
+                    .map(|link| ("Delete", link)),
+            );
+            let received = comment.created_at.unwrap_or(comment.utc);
+            Held {
+                page: comment
+                    .slug
+                    .as_ref()
+                    .map(|slug| format!(" on {}", slug))
+                    .unwrap_or_default(),
+                received: i18n.datetime(&state.config.display.timezone.at(&received)),
+                excerpt: excerpt(&comment.text),
+                links,
+                comment,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let email = DigestEmail {
+        period: match frequency {
+            DigestFrequency::Daily => "day",
+            DigestFrequency::Weekly => "week",
+        },
+        sites: sites.into_iter().collect(),
+        pending,
+        held,
+    };
+    let subject = match pending {
+        0 => "Comments digest, nothing held".to_owned(),
+        1 => "Comments digest, 1 comment held".to_owned(),
+        pending => format!("Comments digest, {} comments held", pending),
+    };
+    match email.render() {
+        Ok(body) => Some((subject, body)),
+        Err(err) => {
+            tracing::error!("failed to render the digest email: {}", err);
+            None
+        }
+    }
+}
+
+fn excerpt(text: &str) -> String {
+    match text.char_indices().nth(EXCERPT_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_owned(),
+    }
+}
+
+// Runs for the lifetime of the server
+pub async fn send_periodically(state: SharedState, smtp_url: String, frequency: DigestFrequency) {
+    let at = match NaiveTime::from_hms_opt(state.config.email.digest_hour, 0, 0) {
+        Some(at) => at,
+        None => {
+            tracing::error!(
+                "not sending digests, [email] digest_hour {} is not an hour of the day",
+                state.config.email.digest_hour
+            );
+            return;
+        }
+    };
+    let mailer = match Mailer::new(&state.config.email, &smtp_url) {
+        Ok(mailer) => mailer,
+        Err(err) => {
+            tracing::error!("not sending digests: {}", err);
+            return;
+        }
+    };
+
+    loop {
+        let due = next(&state, frequency, at);
+        let wait = (due - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        // The primary sends them, see replication.rs
+        if state.replication.is_replica() {
+            continue;
+        }
+        if let Some((subject, body)) = digest(&state, frequency) {
+            // Not retried, the next one has the same and more
+            match mailer.send(&subject, body).await {
+                Ok(()) => tracing::info!("sent the comments digest"),
+                Err(err) => tracing::error!("failed to send the comments digest: {}", err),
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+// The lifecycle of a comment. Stored comments are pending or approved, the
+// other states are final and leave only a tombstone:
+//
+//   new -> pending | approved, by moderation and the spam checks
+//   new -> draft -> pending | approved, when its author publishes it
+//   draft -> deleted
+//   pending -> approved
+//   pending -> rejected, counted as refused like what the spam checks reject
+//   pending | approved -> spam, counted as refused too
+//   pending | approved -> deleted, also once past the retention of the site
+//
+// Every change of status goes through `create` or `transition`, which refuse
+// anything else and then run the hooks. Edits such as votes don't change the
+// status and go to events::commit directly
+use std::{collections::HashMap, fmt, io, sync::Arc};
+
+use axum::http::StatusCode;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    attachments,
+    errors::ApiError,
+    events::{self, CommentEvent, DeleteReason},
+    previews,
+    state::AppState,
+    stats, trace_context, Comment, CommentStatus,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Draft,
+    Pending,
+    Approved,
+    Rejected,
+    Spam,
+    Deleted,
+}
+
+impl From<CommentStatus> for State {
+    fn from(status: CommentStatus) -> Self {
+        match status {
+            CommentStatus::Draft => State::Draft,
+            CommentStatus::Pending => State::Pending,
+            CommentStatus::Approved => State::Approved,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            State::Draft => "draft",
+            State::Pending => "pending",
+            State::Approved => "approved",
+            State::Rejected => "rejected",
+            State::Spam => "spam",
+            State::Deleted => "deleted",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    // Held or approved as moderation decides, see drafts.rs
+    Publish,
+    Approve,
+    Reject,
+    Spam,
+    Delete,
+    // Past the retention of its site, see sites.rs
+    Expire,
+}
+
+impl Transition {
+    // Of the transitions which remove the comment
+    fn removed_as(self) -> Option<State> {
+        match self {
+            Transition::Publish | Transition::Approve => None,
+            Transition::Reject => Some(State::Rejected),
+            Transition::Spam => Some(State::Spam),
+            Transition::Delete | Transition::Expire => Some(State::Deleted),
+        }
+    }
+
+    pub fn is_allowed(self, from: State) -> bool {
+        match from {
+            State::Draft => matches!(
+                self,
+                Transition::Publish | Transition::Delete | Transition::Expire
+            ),
+            State::Pending => !matches!(self, Transition::Publish),
+            State::Approved => matches!(
+                self,
+                Transition::Spam | Transition::Delete | Transition::Expire
+            ),
+            State::Rejected | State::Spam | State::Deleted => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransitionError {
+    NotFound,
+    // Not allowed from the state the comment is in
+    Invalid { from: State, transition: Transition },
+    Failed(io::Error),
+}
+
+impl TransitionError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            TransitionError::NotFound => StatusCode::NOT_FOUND,
+            TransitionError::Invalid { .. } => StatusCode::CONFLICT,
+            TransitionError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransitionError::NotFound => "comment.not_found",
+            TransitionError::Invalid { .. } => "comment.invalid_transition",
+            TransitionError::Failed(_) => "server.failed",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            TransitionError::NotFound => "No such comment",
+            TransitionError::Invalid {
+                from: State::Draft, ..
+            } => "Drafts are published by their author",
+            TransitionError::Invalid {
+                transition: Transition::Publish,
+                ..
+            } => "The comment is not a draft",
+            TransitionError::Invalid {
+                from: State::Approved,
+                transition: Transition::Approve,
+            } => "The comment is already approved",
+            TransitionError::Invalid {
+                from: State::Approved,
+                ..
+            } => "Only held comments can be rejected",
+            TransitionError::Invalid { .. } => "The comment was already removed",
+            TransitionError::Failed(_) => "Failed to record the change",
+        }
+    }
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransitionError::Failed(err) => write!(f, "{}: {}", self.message(), err),
+            _ => f.write_str(self.message()),
+        }
+    }
+}
+
+impl From<TransitionError> for ApiError {
+    fn from(err: TransitionError) -> Self {
+        if let TransitionError::Failed(err) = err {
+            return events::failed(err);
+        }
+        ApiError::new(err.status(), err.code(), err.message())
+    }
+}
+
+// Runs after every change of status, with the Db write lock held; hooks that
+// take long, e.g. calling a webhook, should spawn a task
+pub trait TransitionHook: Send + Sync {
+    // `from` is None for new comments
+    fn on_transition(&self, state: &AppState, comment: &Comment, from: Option<State>, to: State);
+}
+
+pub struct Hooks {
+    hooks: Vec<Box<dyn TransitionHook>>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        let hooks: Vec<Box<dyn TransitionHook>> = vec![
+            Box::new(Logged),
+            Box::new(Traced),
+            Box::new(Published),
+            Box::new(attachments::Cleanup),
+            Box::new(previews::Fetch),
+        ];
+        Hooks { hooks }
+    }
+
+    fn run(&self, state: &AppState, comment: &Comment, from: Option<State>, to: State) {
+        for hook in &self.hooks {
+            hook.on_transition(state, comment, from, to);
+        }
+    }
+}
+
+struct Logged;
+
+impl TransitionHook for Logged {
+    fn on_transition(&self, _: &AppState, comment: &Comment, from: Option<State>, to: State) {
+        match from {
+            Some(from) => tracing::info!(id = %comment.id, %from, %to, "comment {}", to),
+            None => tracing::debug!(id = %comment.id, %to, "new comment"),
+        }
+    }
+}
+
+// Onto the request span, see trace_context.rs. A bulk moderation shows the
+// last comment
+struct Traced;
+
+impl TransitionHook for Traced {
+    fn on_transition(&self, _: &AppState, comment: &Comment, _: Option<State>, to: State) {
+        trace_context::record_comment(comment.id, comment.slug.as_deref());
+        trace_context::record_moderation(to, comment.spam_score);
+    }
+}
+
+// Wakes GET /poll and the gRPC stream, see poll.rs
+struct Published;
+
+impl TransitionHook for Published {
+    fn on_transition(&self, state: &AppState, _: &Comment, _: Option<State>, to: State) {
+        if to == State::Approved {
+            state.published.notify_waiters();
+        }
+    }
+}
+
+type Comments = HashMap<Uuid, Arc<Comment>>;
+
+// Stores a new comment. Call with the Db write lock held
+pub fn create(
+    state: &AppState,
+    comments: &mut Comments,
+    comment: Arc<Comment>,
+) -> io::Result<Arc<Comment>> {
+    let event = CommentEvent::Created {
+        comment: comment.clone(),
+    };
+    events::commit(state, comments, event)?;
+    state
+        .hooks
+        .run(state, &comment, None, comment.status.into());
+    Ok(comment)
+}
+
+// Moves a comment on in its lifecycle if that is allowed. Call with the Db
+// write lock held, returns the comment after or, if it is gone, before
+pub fn transition(
+    state: &AppState,
+    comments: &mut Comments,
+    id: Uuid,
+    transition: Transition,
+) -> Result<Arc<Comment>, TransitionError> {
+    let from = State::from(comments.get(&id).ok_or(TransitionError::NotFound)?.status);
+    if !transition.is_allowed(from) {
+        return Err(TransitionError::Invalid { from, transition });
+    }
+    let event = match transition {
+        Transition::Publish => {
+            let draft = &comments[&id];
+            let settings = state.sites.get(&draft.site).unwrap_or_default();
+            let verdict = state.spam.verdict(draft.spam_score.unwrap_or_default());
+            CommentEvent::Published {
+                id,
+                status: crate::moderated_status(&settings, draft.country.as_deref(), verdict),
+            }
+        }
+        Transition::Approve => CommentEvent::Approved { id },
+        Transition::Reject => CommentEvent::Deleted {
+            id,
+            reason: Some(DeleteReason::Rejected),
+        },
+        Transition::Spam => CommentEvent::Deleted {
+            id,
+            reason: Some(DeleteReason::Spam),
+        },
+        Transition::Delete => CommentEvent::Deleted { id, reason: None },
+        Transition::Expire => CommentEvent::Deleted {
+            id,
+            reason: Some(DeleteReason::Retention),
+        },
+    };
+    let old = comments[&id].clone();
+    let (comment, to) =
+        match events::commit(state, comments, event).map_err(TransitionError::Failed)? {
+            Some(comment) => {
+                let to = comment.status.into();
+                (comment, to)
+            }
+            None => (old, transition.removed_as().unwrap_or(State::Deleted)),
+        };
+    if matches!(transition, Transition::Reject | Transition::Spam) {
+        state.stats.reject(&comment.site, stats::received(&comment));
+    }
+    state.hooks.run(state, &comment, Some(from), to);
+    Ok(comment)
+}
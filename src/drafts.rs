@@ -0,0 +1,95 @@
+// Drafts: a comment posted with `"draft": true` is kept for its author, the
+// browser with the visitor cookie (see identity.rs), and shown to nobody
+// else. Publishing it runs moderation as for a new comment, so it is then
+// held or approved. Drafts don't count in the statistics until published
+use std::sync::Arc;
+
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use uuid::Uuid;
+
+use crate::{
+    codec::Format,
+    domain::{self, Transition},
+    errors::ApiError,
+    identity::Visitor,
+    newest_first,
+    sites::Site,
+    state::SharedState,
+    Comment, CommentStatus,
+};
+
+// As the author sees it, see create_comment
+fn own(comment: &Arc<Comment>) -> Comment {
+    Comment {
+        ip: None,
+        country: None,
+        spam_score: None,
+        ..Comment::clone(comment)
+    }
+}
+
+fn is_draft_of(comment: &Comment, site: &Site, visitor: &Visitor) -> bool {
+    !visitor.is_new
+        && comment.site == site.key
+        && comment.status == CommentStatus::Draft
+        && comment.visitor == Some(visitor.token)
+}
+
+// GET /mine/drafts, newest first
+pub async fn get_drafts(
+    site: Site,
+    visitor: Visitor,
+    format: Format,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let comments = state.db.read().unwrap();
+    let drafts = newest_first(
+        comments
+            .values()
+            .filter(|comment| is_draft_of(comment, &site, &visitor)),
+    )
+    .into_iter()
+    .map(|comment| Arc::new(own(comment)))
+    .collect::<Vec<_>>();
+    drop(comments);
+
+    format.encode(drafts)
+}
+
+// POST /mine/drafts/:id/publish
+pub async fn publish_draft(
+    Path(id): Path<Uuid>,
+    site: Site,
+    visitor: Visitor,
+    format: Format,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    if !site.allows_origin() {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "site.origin_not_allowed",
+            "Drafts can't be published from this origin",
+        ));
+    }
+
+    let mut comments = state.db.write().unwrap();
+    let draft = comments
+        .get(&id)
+        .filter(|comment| is_draft_of(comment, &site, &visitor))
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "draft.not_found", "No such draft"))?;
+    if site.settings.is_closed(draft.slug.as_deref()) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "page.closed",
+            "Comments are closed for this page",
+        ));
+    }
+    let comment = domain::transition(&state, &mut comments, id, Transition::Publish)?;
+    drop(comments);
+
+    Ok(format.encode(own(&comment)))
+}
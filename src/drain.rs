@@ -0,0 +1,100 @@
+// Draining for blue/green deploys: POST /admin/drain makes GET /ready fail so
+// the load balancer stops sending traffic, waits for the requests in flight,
+// saves the comments and then shuts down as SIGTERM does
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse, Json};
+use axum_server::Handle;
+use serde_json::json;
+use tower_http::metrics::in_flight_requests::InFlightRequestsCounter;
+
+use crate::{admin::Admin, state::SharedState};
+
+pub struct Drain {
+    draining: AtomicBool,
+    // Requests on the public port, until their whole response was sent
+    pub in_flight: InFlightRequestsCounter,
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        Drain {
+            draining: AtomicBool::new(false),
+            in_flight: InFlightRequestsCounter::new(),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+// Starts draining unless it already is, returns right away
+pub fn start(state: &SharedState, handle: &Handle) -> bool {
+    if state.drain.draining.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    tracing::info!(in_flight = state.drain.in_flight.get(), "draining");
+    tokio::spawn(run(state.clone(), handle.clone()));
+    true
+}
+
+async fn run(state: SharedState, handle: Handle) {
+    let config = &state.config.drain;
+    // Time for the load balancer to notice GET /ready failing
+    tokio::time::sleep(Duration::from_secs(config.delay_secs)).await;
+
+    let deadline = Instant::now() + Duration::from_secs(config.timeout_secs);
+    while state.drain.in_flight.get() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let left = state.drain.in_flight.get();
+    if left > 0 {
+        tracing::warn!(in_flight = left, "drain timed out, shutting down anyway");
+    }
+
+    let flushed = state.clone();
+    match tokio::task::spawn_blocking(move || flushed.storage.flush(&flushed)).await {
+        Ok(Err(err)) => tracing::error!("failed to save comments: {}", err),
+        Err(err) => tracing::error!("failed to save comments: {}", err),
+        Ok(Ok(())) => {}
+    }
+
+    tracing::info!("drained, shutting down");
+    handle.graceful_shutdown(Some(Duration::from_secs(config.timeout_secs)));
+}
+
+fn status(state: &SharedState) -> Json<serde_json::Value> {
+    Json(json!({
+        "draining": state.drain.is_draining(),
+        "in_flight": state.drain.in_flight.get(),
+    }))
+}
+
+// GET /ready, for the load balancer
+pub async fn get_ready(Extension(state): Extension<SharedState>) -> impl IntoResponse {
+    if state.drain.is_draining() {
+        (StatusCode::SERVICE_UNAVAILABLE, "draining")
+    } else if !state.replication.is_synced() {
+        (StatusCode::SERVICE_UNAVAILABLE, "replicating")
+    } else {
+        (StatusCode::OK, "ready")
+    }
+}
+
+// POST /admin/drain, answered with 202 while still serving the other requests
+pub async fn drain(
+    _: Admin,
+    Extension(state): Extension<SharedState>,
+    Extension(handle): Extension<Handle>,
+) -> impl IntoResponse {
+    let status_code = if start(&state, &handle) {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    (status_code, status(&state))
+}
@@ -0,0 +1,319 @@
+// New comments mailed to [email] to through the SMTP server at
+// [email] smtp_url, with signed links to approve or delete them right from
+// the inbox, see moderation_links.rs. Like webhooks.rs this follows the event
+// log, keeping the seq of the last event mailed in <snapshot>.email, so a
+// message is retried until the server takes it and none is lost in a crash.
+// Comments refused as spam and deleted past retention are mailed too when
+// chosen for the emails, see notifications.rs. Only built with the `email`
+// feature
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use askama::Template;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+use crate::{
+    config::EmailConfig,
+    events::{CommentEvent, Recorded},
+    moderation_links::{self, Action},
+    notifications::{self, Notification},
+    sites,
+    state::{AppState, SharedState},
+    storage, Comment, CommentStatus,
+};
+
+// Between looking for new events
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Read from the log at once
+const BATCH: usize = 100;
+
+// Which holds the event log, see EventLog::hold
+const FOLLOWER: &str = "email";
+
+type Transport = AsyncSmtpTransport<Tokio1Executor>;
+
+#[derive(Template)]
+#[template(path = "email-new-comment.txt")]
+struct NewCommentEmail {
+    // "Jane wrote on my-post"
+    author: String,
+    // Empty without one
+    title: String,
+    comment: Arc<Comment>,
+    // The comment once listed, approving it while held, deleting it
+    links: Vec<(&'static str, String)>,
+}
+
+// Also sends the digests of digest.rs
+pub struct Mailer {
+    transport: Transport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl Mailer {
+    pub fn new(config: &EmailConfig, smtp_url: &str) -> Result<Mailer, String> {
+        let transport = Transport::from_url(smtp_url)
+            .map_err(|err| format!("invalid [email] smtp_url: {}", err))?
+            .timeout(Some(Duration::from_secs(config.timeout_secs)))
+            .build();
+        let mailbox = |address: &str| {
+            address
+                .parse::<Mailbox>()
+                .map_err(|err| format!("invalid address \"{}\": {}", address, err))
+        };
+        let to = config
+            .to
+            .iter()
+            .map(|address| mailbox(address))
+            .collect::<Result<Vec<_>, _>>()?;
+        if to.is_empty() {
+            return Err("[email] to has no addresses".to_owned());
+        }
+        Ok(Mailer {
+            transport,
+            from: mailbox(&config.from)?,
+            to,
+        })
+    }
+
+    pub async fn send(&self, subject: &str, body: String) -> Result<(), String> {
+        let mut message = Message::builder().from(self.from.clone());
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        let message = message
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|err| err.to_string())?;
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+// Also lets the event log be compacted up to `seq`
+fn save_cursor(state: &AppState, path: &Path, seq: u64) {
+    state.storage.events.hold(FOLLOWER, seq);
+    if let Err(err) = storage::write_atomically(path, seq.to_string().into_bytes()) {
+        tracing::error!("failed to save the email position: {}", err);
+    }
+}
+
+// The subject and body of the message about an event, None for events which
+// aren't about a new comment
+fn new_comment(state: &SharedState, recorded: &Recorded) -> Option<(String, String)> {
+    let comment = match &recorded.event {
+        CommentEvent::Created { comment } if comment.status != CommentStatus::Draft => {
+            comment.clone()
+        }
+        // Drafts are new once their author publishes them
+        CommentEvent::Published { id, .. } => state.db.read().unwrap().get(id)?.clone(),
+        _ => return None,
+    };
+    let held = comment.status == CommentStatus::Pending;
+    let page = comment
+        .slug
+        .as_ref()
+        .map(|slug| format!(" on {}", slug))
+        .unwrap_or_default();
+    let subject = format!(
+        "{} comment by {}{}",
+        if held { "Held" } else { "New" },
+        comment.name,
+        page
+    );
+
+    let mut links = Vec::new();
+    if comment.status == CommentStatus::Approved {
+        let path = format!("{}/{}", sites::root(&comment.site), comment.id);
+        links.extend(state.config.site.url(&path).map(|url| ("Comment", url)));
+    }
+    if held {
+        let approve = moderation_links::link(state, comment.id, Action::Approve);
+        links.extend(approve.map(|link| ("Approve", link)));
+    }
+    let delete = moderation_links::link(state, comment.id, Action::Delete);
+    links.extend(delete.map(|link| ("Delete", link)));
+
+    let email = NewCommentEmail {
+        author: format!("{} wrote{}", comment.name, page),
+        title: comment.title.clone().unwrap_or_default(),
+        comment,
+        links,
+    };
+    match email.render() {
+        Ok(body) => Some((subject, body)),
+        Err(err) => {
+            tracing::error!("failed to render the new comment email: {}", err);
+            None
+        }
+    }
+}
+
+fn refused(recorded: &Recorded) -> Option<(String, String)> {
+    let (site, slug, spam_score) = match &recorded.event {
+        CommentEvent::Refused {
+            site,
+            slug,
+            spam_score,
+            ..
+        } => (site, slug, spam_score),
+        _ => return None,
+    };
+    let page = slug
+        .as_ref()
+        .map(|slug| format!(" on {}", slug))
+        .unwrap_or_default();
+    let subject = format!("Refused a comment as spam{}", page);
+    let body = format!(
+        "The spam checks refused a comment{} of site {}, with a score of {:.2}. Nothing of it was kept.\n",
+        page, site, spam_score
+    );
+    Some((subject, body))
+}
+
+fn purged(count: usize) -> (String, String) {
+    match count {
+        1 => (
+            "Deleted a comment past retention".to_owned(),
+            "A comment was older than the retention_days of its site.\n".to_owned(),
+        ),
+        count => (
+            format!("Deleted {} comments past retention", count),
+            format!(
+                "{} comments were older than the retention_days of their site.\n",
+                count
+            ),
+        ),
+    }
+}
+
+// The subject and body of the message about an event, None for the events
+// which aren't mailed alone
+fn message(
+    state: &SharedState,
+    recorded: &Recorded,
+    kind: Notification,
+) -> Option<(String, String)> {
+    match kind {
+        Notification::NewComment | Notification::Held => new_comment(state, recorded),
+        Notification::Spam => refused(recorded),
+        Notification::RetentionPurge => None,
+    }
+}
+
+// Runs for the lifetime of the server
+pub async fn deliver(state: SharedState, smtp_url: String) {
+    let cursor: PathBuf = match state.storage.path() {
+        Some(path) => path.with_extension("email"),
+        None => {
+            tracing::warn!("[email] smtp_url is set, but without [storage] path there is no event log to mail from");
+            return;
+        }
+    };
+    let mailer = match Mailer::new(&state.config.email, &smtp_url) {
+        Ok(mailer) => mailer,
+        Err(err) => {
+            tracing::error!("not sending emails: {}", err);
+            state.storage.events.release(FOLLOWER);
+            return;
+        }
+    };
+    if state.secrets.admin_token().is_none() {
+        tracing::warn!("without [admin] token the emails have no moderation links");
+    }
+
+    // The first start mails what happens from now on, not the history
+    let mut delivered = match storage::read_cursor(&cursor) {
+        Ok(Some(seq)) => {
+            state.storage.events.hold(FOLLOWER, seq);
+            seq
+        }
+        Ok(None) => {
+            let seq = state.storage.events.seq();
+            save_cursor(&state, &cursor, seq);
+            seq
+        }
+        Err(err) => {
+            tracing::error!("failed to read {}: {}", cursor.display(), err);
+            state.storage.events.release(FOLLOWER);
+            return;
+        }
+    };
+
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let latest = state.storage.events.seq();
+        if latest <= delivered {
+            continue;
+        }
+        let reading = state.clone();
+        let pending =
+            tokio::task::spawn_blocking(move || reading.storage.events.since(delivered, BATCH))
+                .await
+                .map_err(io::Error::other)
+                .and_then(|result| result);
+        let pending = match pending {
+            Ok(pending) => pending,
+            Err(err) => {
+                tracing::error!("failed to read the events to mail: {}", err);
+                continue;
+            }
+        };
+        if pending.is_empty() {
+            // Dropped from the log meanwhile, see EventLog::forget
+            delivered = latest;
+            save_cursor(&state, &cursor, delivered);
+        }
+
+        let chosen = |recorded: &Recorded| {
+            notifications::kind(&recorded.event)
+                .filter(|kind| state.notifications.get().email.contains(kind))
+        };
+        // One message for the comments a purge deleted, per batch
+        let mut purge = 0;
+        for (index, recorded) in pending.iter().enumerate() {
+            let message = match chosen(recorded) {
+                Some(Notification::RetentionPurge) => {
+                    purge += 1;
+                    let next = pending.get(index + 1).and_then(chosen);
+                    if next == Some(Notification::RetentionPurge) {
+                        // Mailed with the last one, so a crash mails them again
+                        continue;
+                    }
+                    Some(purged(std::mem::take(&mut purge)))
+                }
+                Some(kind) => message(&state, recorded, kind),
+                None => None,
+            };
+            if let Some((subject, body)) = message {
+                let mut delay = storage::FIRST_RETRY_DELAY;
+                while let Err(err) = mailer.send(&subject, body.clone()).await {
+                    tracing::warn!(
+                        seq = recorded.seq,
+                        "failed to send an email, retrying in {:?}: {}",
+                        delay,
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(storage::MAX_RETRY_DELAY);
+                }
+            }
+            delivered = recorded.seq;
+            save_cursor(&state, &cursor, delivered);
+        }
+    }
+}
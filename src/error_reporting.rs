@@ -0,0 +1,114 @@
+// Sentry integration, compiled in with the `sentry` feature
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::MatchedPath,
+    http::{Request, Response},
+};
+use sentry::{Hub, Level, SentryFutureExt};
+use tower::{Layer, Service};
+
+use crate::{config::SentryConfig, request_id::RequestId};
+
+// Returns None when no DSN is configured
+// The guard must be kept alive until shutdown so pending events are flushed
+pub fn init(config: &SentryConfig) -> Option<sentry::ClientInitGuard> {
+    let dsn = config.dsn.as_deref()?;
+
+    let mut options = sentry::ClientOptions::new()
+        .maybe_release(sentry::release_name!())
+        .sample_rate(config.sample_rate);
+    if let Some(environment) = &config.environment {
+        options = options.environment(environment.clone());
+    }
+
+    let guard = sentry::init((dsn, options));
+
+    Some(guard)
+}
+
+// Render failures are reported with the tags of the request being handled
+pub fn capture_template_error(err: &askama::Error) {
+    sentry::capture_error(err);
+}
+
+// Runs every request on its own Hub tagged with the route and request id,
+// and reports 5xx responses which were not already reported
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SentryLayer;
+
+impl SentryLayer {
+    pub fn new() -> Self {
+        SentryLayer
+    }
+}
+
+impl<S> Layer<S> for SentryLayer {
+    type Service = SentryService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SentryService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SentryService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for SentryService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let hub = Arc::new(Hub::new_from_top(Hub::current()));
+
+        // Unmatched requests have no MatchedPath
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_owned())
+            .unwrap_or_else(|| req.uri().path().to_owned());
+        let method = req.method().clone();
+
+        hub.configure_scope(|scope| {
+            scope.set_tag("route", &route);
+            scope.set_tag("method", &method);
+            if let Some(request_id) = req.extensions().get::<RequestId>() {
+                scope.set_tag("request_id", request_id);
+            }
+        });
+
+        let future = self.inner.call(req).bind_hub(hub.clone());
+
+        Box::pin(async move {
+            let result = future.await;
+
+            if let Ok(response) = &result {
+                let status = response.status();
+                if status.is_server_error() && hub.last_event_id().is_none() {
+                    hub.capture_message(
+                        &format!("{} {} responded with {}", method, route, status),
+                        Level::Error,
+                    );
+                }
+            }
+
+            result
+        })
+    }
+}
@@ -0,0 +1,193 @@
+// Errors of the API, answered as JSON with a code which stays put, so
+// clients can tell them apart and word them in their own language without
+// matching on the message, which may change:
+//
+//   {"error": "Title is longer than 100 characters", "code": "comment.title_too_long"}
+//
+// Codes are <subject>.<what>, see "Error codes" in the README for all of
+// them. Some errors say more in other fields, e.g. where a JSON body broke,
+// see extract.rs.
+//
+// Requests with `Accept: application/problem+json`, or all of them with
+// [errors] problem_json, get an RFC 7807 problem document instead, with the
+// code and the other fields as extension members:
+//
+//   {"type": "urn:little-nova:error:comment.not_found", "title": "Not Found",
+//    "status": 404, "detail": "No such comment", "instance": "/admin/comments/...",
+//    "code": "comment.not_found"}
+//
+// Handlers don't know what was asked for, ProblemLayer rewrites their answers
+use std::{
+    borrow::Cow,
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{self, BoxBody, Bytes, Full},
+    http::{header, HeaderMap, HeaderValue, Request, Response, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde_json::{json, Value};
+use tower::{Layer, Service};
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: Cow<'static, str>,
+    // Object of more fields of the body
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new(
+        status: StatusCode,
+        code: &'static str,
+        message: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    // Of unexpected failures, whose cause is logged instead
+    pub fn internal(message: &'static str) -> Self {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "server.failed", message)
+    }
+
+    fn body(&self) -> Value {
+        self.extended(json!({
+            "error": self.message,
+            "code": self.code,
+        }))
+    }
+
+    // `instance` is the path of the request
+    fn problem(&self, instance: &str) -> Value {
+        self.extended(json!({
+            "type": format!("urn:little-nova:error:{}", self.code),
+            "title": self.status.canonical_reason().unwrap_or("Error"),
+            "status": self.status.as_u16(),
+            "detail": self.message,
+            "instance": instance,
+            "code": self.code,
+        }))
+    }
+
+    fn extended(&self, mut body: Value) -> Value {
+        if let (Some(body), Some(Value::Object(details))) = (body.as_object_mut(), &self.details) {
+            body.extend(details.clone());
+        }
+        body
+    }
+}
+
+impl IntoResponse for ApiError {
+    type Body = Full<Bytes>;
+    type BodyError = Infallible;
+
+    fn into_response(self) -> Response<Self::Body> {
+        let mut response = (self.status, Json(self.body())).into_response();
+        // For ProblemLayer
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+fn accepts_problem(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| {
+            let media_type = range.split(';').next().unwrap_or_default().trim();
+            media_type.eq_ignore_ascii_case(PROBLEM_JSON)
+        })
+}
+
+#[derive(Clone)]
+pub struct ProblemLayer {
+    // Whatever the request accepts
+    always: bool,
+}
+
+impl ProblemLayer {
+    pub fn new(always: bool) -> Self {
+        ProblemLayer { always }
+    }
+}
+
+impl<S> Layer<S> for ProblemLayer {
+    type Service = ProblemService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ProblemService {
+            inner,
+            always: self.always,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ProblemService<S> {
+    inner: S,
+    always: bool,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for ProblemService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let instance =
+            (self.always || accepts_problem(req.headers())).then(|| req.uri().path().to_owned());
+        let response = self.inner.call(req);
+
+        Box::pin(async move {
+            let response = response.await?;
+            let instance = match instance {
+                Some(instance) => instance,
+                None => return Ok(response),
+            };
+            let (mut parts, body) = response.into_parts();
+            let err = match parts.extensions.remove::<ApiError>() {
+                Some(err) => err,
+                None => return Ok(Response::from_parts(parts, body)),
+            };
+            let problem = serde_json::to_vec(&err.problem(&instance)).unwrap_or_default();
+            // The other headers, e.g. WWW-Authenticate or Retry-After, stay
+            parts
+                .headers
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Ok(Response::from_parts(
+                parts,
+                body::boxed(Full::from(problem)),
+            ))
+        })
+    }
+}
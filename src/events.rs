@@ -0,0 +1,663 @@
+// Every change to a comment is a CommentEvent, appended to the event log
+// before it is applied. The comments in memory are what folding the events
+// gives, and the snapshot is that fold up to its `last_event`, so on start
+// only the events after it are replayed. The log is kept next to the
+// snapshot as <snapshot>.events.jsonl, one event per line, and gives the
+// history of every comment at GET /admin/comments/:id/history
+//
+// Once a flushed snapshot holds them and every follower of the log, see
+// webhooks.rs and email.rs, is past them, events are moved out of the log
+// into <snapshot>.history.jsonl, which only the history reads. Votes are
+// dropped then, the snapshot has them
+//
+// Erasing a commenter and deleting comments past retention also drop the
+// earlier events of those comments from both, that data must not survive
+//
+// Changes which belong together are appended as one unit, see commit_all:
+// every event of it but the last has `more` set, and a unit cut off by a
+// crash is dropped on replay as a whole
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{
+    extract::{Extension, Path as UrlPath},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    admin::Admin,
+    errors::ApiError,
+    state::{AppState, SharedState},
+    storage::Contents,
+    trace_context,
+    votes::Vote,
+    Comment, CommentStatus,
+};
+
+// Events a flushed snapshot holds before the log is compacted
+const COMPACT_AT: usize = 1000;
+
+type Comments = HashMap<Uuid, Arc<Comment>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CommentEvent {
+    Created {
+        comment: Arc<Comment>,
+    },
+    // Any other change, e.g. anonymizing, with the whole result
+    Edited {
+        comment: Arc<Comment>,
+    },
+    // As a restored backup has it, see backup.rs
+    Restored {
+        comment: Arc<Comment>,
+    },
+    Approved {
+        id: Uuid,
+    },
+    // A draft, held or approved as moderation decided
+    Published {
+        id: Uuid,
+        status: CommentStatus,
+    },
+    Deleted {
+        id: Uuid,
+        // None when a moderator deleted it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<DeleteReason>,
+    },
+    // A visitor's vote, None when they took it back
+    Voted {
+        id: Uuid,
+        visitor: Uuid,
+        vote: Option<Vote>,
+    },
+    // Refused by the spam checks when posted, nothing of it is stored but
+    // where it was posted. The id is the one it would have had
+    Refused {
+        id: Uuid,
+        site: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        slug: Option<String>,
+        spam_score: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteReason {
+    // Held and turned down
+    Rejected,
+    Spam,
+    // Past the retention_days of its site, see sites.rs
+    Retention,
+    // Not in a restored backup
+    Restored,
+}
+
+impl CommentEvent {
+    pub fn id(&self) -> Uuid {
+        match self {
+            CommentEvent::Created { comment }
+            | CommentEvent::Edited { comment }
+            | CommentEvent::Restored { comment } => comment.id,
+            CommentEvent::Approved { id }
+            | CommentEvent::Published { id, .. }
+            | CommentEvent::Deleted { id, .. }
+            | CommentEvent::Voted { id, .. }
+            | CommentEvent::Refused { id, .. } => *id,
+        }
+    }
+
+    // Whether it holds what the commenter or a voter gave
+    fn has_content(&self) -> bool {
+        matches!(
+            self,
+            CommentEvent::Created { .. }
+                | CommentEvent::Edited { .. }
+                | CommentEvent::Restored { .. }
+                | CommentEvent::Voted { .. }
+        )
+    }
+}
+
+// An event as it is in the log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recorded {
+    // Counts up from 1
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+    // More events of the same unit follow
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub more: bool,
+    // Of the request it came from, sent on with its webhook, see
+    // trace_context.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracestate: Option<String>,
+    #[serde(flatten)]
+    pub event: CommentEvent,
+}
+
+// Applies an event to the comments, returns the comment before and after.
+// Events about comments which aren't there change nothing, except Edited and
+// Restored
+pub fn fold(
+    comments: &mut Comments,
+    recorded: &Recorded,
+) -> (Option<Arc<Comment>>, Option<Arc<Comment>>) {
+    match &recorded.event {
+        CommentEvent::Created { comment }
+        | CommentEvent::Edited { comment }
+        | CommentEvent::Restored { comment } => {
+            let old = comments.insert(comment.id, comment.clone());
+            (old, Some(comment.clone()))
+        }
+        CommentEvent::Approved { id } => {
+            set_status(comments, *id, CommentStatus::Approved, recorded.at)
+        }
+        CommentEvent::Published { id, status } => set_status(comments, *id, *status, recorded.at),
+        CommentEvent::Deleted { id, .. } => (comments.remove(id), None),
+        CommentEvent::Voted { id, visitor, vote } => match comments.get_mut(id) {
+            Some(comment) => {
+                let old = comment.clone();
+                let changed = Arc::make_mut(comment);
+                match vote {
+                    Some(vote) => changed.votes.insert(*visitor, *vote),
+                    None => changed.votes.remove(visitor),
+                };
+                changed.updated_at = Some(recorded.at);
+                (Some(old), Some(comment.clone()))
+            }
+            None => (None, None),
+        },
+        CommentEvent::Refused { .. } => (None, None),
+    }
+}
+
+fn set_status(
+    comments: &mut Comments,
+    id: Uuid,
+    status: CommentStatus,
+    at: DateTime<Utc>,
+) -> (Option<Arc<Comment>>, Option<Arc<Comment>>) {
+    match comments.get_mut(&id) {
+        Some(comment) => {
+            let old = comment.clone();
+            let changed = Arc::make_mut(comment);
+            if status == CommentStatus::Approved && changed.status != CommentStatus::Approved {
+                changed.listed_at = Some(at);
+            }
+            changed.status = status;
+            changed.updated_at = Some(at);
+            changed.version += 1;
+            (Some(old), Some(comment.clone()))
+        }
+        None => (None, None),
+    }
+}
+
+pub struct EventLog {
+    // None keeps no log, as with comments in memory only
+    path: Option<PathBuf>,
+    // Of the last event, 0 before the first
+    seq: AtomicU64,
+    // For appending, readers open the file themselves
+    file: Mutex<Option<File>>,
+    // Taken after `file` when both are
+    index: Mutex<Index>,
+    // Where compacted events go. Its lock is taken before the others
+    archive: Mutex<Option<PathBuf>>,
+    // The last event each follower handled, see hold
+    followers: Mutex<HashMap<&'static str, u64>>,
+}
+
+// Where each event of the file starts and where the last one ends, so
+// readers seek to the events they want and never wait for a write
+#[derive(Default)]
+struct Index {
+    // Seq and byte offset, in the order of the file
+    offsets: Vec<(u64, u64)>,
+    end: u64,
+}
+
+impl EventLog {
+    // Replays the events after `contents.last_event` onto it, returns the log
+    // and whether there were any
+    pub fn open(snapshot: Option<&Path>, contents: &mut Contents) -> io::Result<(EventLog, bool)> {
+        let path = snapshot.map(|path| path.with_extension("events.jsonl"));
+        let archive = snapshot.map(|path| path.with_extension("history.jsonl"));
+        let mut seq = contents.last_event;
+        let mut replayed = false;
+        let mut index = Index::default();
+
+        if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut line = String::new();
+            // Up to the end of the last whole unit, and the events after it
+            let mut valid = 0;
+            let mut read = 0;
+            let mut unit = Vec::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    break;
+                }
+                match serde_json::from_str::<Recorded>(&line) {
+                    Ok(recorded) if line.ends_with('\n') => unit.push((recorded, read)),
+                    // Cut off by a crash while it was written, it was never applied
+                    _ => break,
+                }
+                read += line.len() as u64;
+                if unit.last().is_some_and(|(recorded, _)| recorded.more) {
+                    continue;
+                }
+                for (recorded, offset) in unit.drain(..) {
+                    if recorded.seq > contents.last_event {
+                        fold(&mut contents.comments, &recorded);
+                        replayed = true;
+                    }
+                    seq = seq.max(recorded.seq);
+                    index.offsets.push((recorded.seq, offset));
+                }
+                valid = read;
+            }
+            index.end = valid;
+            if valid < fs::metadata(path)?.len() {
+                tracing::warn!("dropping broken events at the end of {}", path.display());
+                OpenOptions::new().write(true).open(path)?.set_len(valid)?;
+            }
+        }
+        contents.last_event = seq;
+
+        let log = EventLog {
+            path,
+            seq: AtomicU64::new(seq),
+            file: Mutex::new(None),
+            index: Mutex::new(index),
+            archive: Mutex::new(archive),
+            followers: Mutex::new(HashMap::new()),
+        };
+        Ok((log, replayed))
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq.load(Ordering::Relaxed)
+    }
+
+    // Call with the Db write lock held, so events are in the order applied
+    pub fn append(&self, event: CommentEvent) -> io::Result<Recorded> {
+        let mut recorded = self.append_all(vec![event])?;
+        Ok(recorded.remove(0))
+    }
+
+    // As one unit, all of them or none are recorded
+    pub fn append_all(&self, events: Vec<CommentEvent>) -> io::Result<Vec<Recorded>> {
+        let at = Utc::now();
+        let trace = trace_context::current();
+        let count = events.len();
+        let recorded = events
+            .into_iter()
+            .enumerate()
+            .map(|(i, event)| Recorded {
+                seq: self.seq() + 1 + i as u64,
+                at,
+                more: i + 1 < count,
+                traceparent: trace.as_ref().map(|trace| trace.traceparent.clone()),
+                tracestate: trace.as_ref().and_then(|trace| trace.tracestate.clone()),
+                event,
+            })
+            .collect::<Vec<_>>();
+        if let Some(path) = &self.path {
+            let mut lines = Vec::new();
+            let mut starts = Vec::new();
+            for recorded in &recorded {
+                starts.push((recorded.seq, lines.len() as u64));
+                serde_json::to_writer(&mut lines, recorded)?;
+                lines.push(b'\n');
+            }
+            let mut file = self.file.lock().unwrap();
+            if file.is_none() {
+                *file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+            }
+            let file = file.as_mut().unwrap();
+            let len = file.metadata()?.len();
+            if let Err(err) = file.write_all(&lines) {
+                // Not even part of the unit stays
+                file.set_len(len)?;
+                return Err(err);
+            }
+            let mut index = self.index.lock().unwrap();
+            index
+                .offsets
+                .extend(starts.into_iter().map(|(seq, start)| (seq, len + start)));
+            index.end = len + lines.len() as u64;
+        }
+        if let Some(last) = recorded.last() {
+            self.seq.store(last.seq, Ordering::Relaxed);
+        }
+        Ok(recorded)
+    }
+
+    // The file from the first event after `seq` to the end of the last one
+    // written, and where that is. Opened under the index lock, so a rewrite
+    // can't come between
+    fn read_after(&self, seq: u64) -> io::Result<Option<(io::Take<BufReader<File>>, u64)>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let index = self.index.lock().unwrap();
+        let first = index
+            .offsets
+            .partition_point(|(recorded, _)| *recorded <= seq);
+        let start = match index.offsets.get(first) {
+            Some((_, start)) => *start,
+            None => return Ok(None),
+        };
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Some((
+            BufReader::new(file).take(index.end - start),
+            index.end,
+        )))
+    }
+
+    // Events of one comment, oldest first
+    pub fn history(&self, id: Uuid) -> io::Result<Vec<Recorded>> {
+        let mut history = Vec::new();
+        // Held throughout, so compacting can't move events between the two
+        let archive = self.archive.lock().unwrap();
+        if let Some(archive) = archive.as_ref().filter(|archive| archive.exists()) {
+            collect(&mut history, id, BufReader::new(File::open(archive)?))?;
+        }
+        if let Some((reader, _)) = self.read_after(0)? {
+            collect(&mut history, id, reader)?;
+        }
+        Ok(history)
+    }
+
+    // Keeps the events after `seq` in the log for `follower`, until it
+    // reports a later one. Hold 0 before anything may compact the log
+    #[cfg(any(feature = "webhooks", feature = "email"))]
+    pub fn hold(&self, follower: &'static str, seq: u64) {
+        self.followers.lock().unwrap().insert(follower, seq);
+    }
+
+    // For a follower which stopped
+    #[cfg(any(feature = "webhooks", feature = "email"))]
+    pub fn release(&self, follower: &'static str) {
+        self.followers.lock().unwrap().remove(follower);
+    }
+
+    // Moves the events up to `seq`, which a flushed snapshot holds, to the
+    // archive. Waits for COMPACT_AT of them, so the log isn't rewritten on
+    // every flush
+    pub fn compact(&self, seq: u64) -> io::Result<()> {
+        let archive = self.archive.lock().unwrap();
+        let (path, archive) = match (&self.path, archive.as_ref()) {
+            (Some(path), Some(archive)) => (path, archive),
+            _ => return Ok(()),
+        };
+        let through = self
+            .followers
+            .lock()
+            .unwrap()
+            .values()
+            .fold(seq, |through, held| through.min(*held));
+        let end = {
+            let index = self.index.lock().unwrap();
+            let moved = index
+                .offsets
+                .partition_point(|(recorded, _)| *recorded <= through);
+            if moved < COMPACT_AT {
+                return Ok(());
+            }
+            index
+                .offsets
+                .get(moved)
+                .map_or(index.end, |(_, offset)| *offset)
+        };
+
+        let mut moved = Vec::new();
+        for line in BufReader::new(File::open(path)?.take(end)).lines() {
+            let line = line?;
+            let vote = serde_json::from_str::<Recorded>(&line)
+                .is_ok_and(|recorded| matches!(recorded.event, CommentEvent::Voted { .. }));
+            if !vote {
+                moved.extend_from_slice(line.as_bytes());
+                moved.push(b'\n');
+            }
+        }
+        // Twice in the archive after a crash in between, history skips those
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(archive)?
+            .write_all(&moved)?;
+        self.replace(path, Vec::new(), Vec::new(), end)?;
+        tracing::debug!(through, "compacted the event log");
+        Ok(())
+    }
+
+    // The first `limit` events after `seq`, see webhooks.rs and email.rs
+    #[cfg(any(feature = "webhooks", feature = "email"))]
+    pub fn since(&self, seq: u64, limit: usize) -> io::Result<Vec<Recorded>> {
+        let reader = match self.read_after(seq)? {
+            Some((reader, _)) => reader,
+            None => return Ok(Vec::new()),
+        };
+        let mut events = Vec::new();
+        for line in reader.lines().take(limit) {
+            if let Ok(recorded) = serde_json::from_str::<Recorded>(&line?) {
+                events.push(recorded);
+            }
+        }
+        Ok(events)
+    }
+
+    // Drops what the commenters gave from the events of `ids` up to `seq`,
+    // by rewriting the log. Approvals and deletions stay, they hold nothing
+    pub fn forget(&self, ids: &HashSet<Uuid>, seq: u64) -> io::Result<()> {
+        let forgotten = |recorded: &Recorded| {
+            recorded.seq <= seq
+                && recorded.event.has_content()
+                && ids.contains(&recorded.event.id())
+        };
+        let archive = self.archive.lock().unwrap();
+        if let Some(archive) = archive.as_ref().filter(|archive| archive.exists()) {
+            let mut kept = Vec::new();
+            for line in BufReader::new(File::open(archive)?).lines() {
+                let line = line?;
+                if !serde_json::from_str::<Recorded>(&line)
+                    .is_ok_and(|recorded| forgotten(&recorded))
+                {
+                    kept.extend_from_slice(line.as_bytes());
+                    kept.push(b'\n');
+                }
+            }
+            let tmp = archive.with_extension("tmp");
+            fs::write(&tmp, kept).and_then(|_| fs::rename(&tmp, archive))?;
+        }
+
+        let (path, (reader, end)) = match (&self.path, self.read_after(0)?) {
+            (Some(path), Some(read)) => (path, read),
+            _ => return Ok(()),
+        };
+        // Written meanwhile is only copied over once the rest is done
+        let mut kept = Vec::new();
+        let mut offsets = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let recorded = serde_json::from_str::<Recorded>(&line).ok();
+            if !recorded.as_ref().is_some_and(forgotten) {
+                if let Some(recorded) = recorded {
+                    offsets.push((recorded.seq, kept.len() as u64));
+                }
+                kept.extend_from_slice(line.as_bytes());
+                kept.push(b'\n');
+            }
+        }
+        self.replace(path, kept, offsets, end)
+    }
+
+    // Swaps the file for `kept`, which replaces everything up to `end`, and
+    // what was appended after it. Offsets are those of the events in `kept`
+    fn replace(
+        &self,
+        path: &Path,
+        mut kept: Vec<u8>,
+        mut offsets: Vec<(u64, u64)>,
+        end: u64,
+    ) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &kept)?;
+        let mut file = self.file.lock().unwrap();
+        let mut index = self.index.lock().unwrap();
+        let mut appended = File::open(path)?;
+        appended.seek(SeekFrom::Start(end))?;
+        let mut tail = Vec::new();
+        appended.read_to_end(&mut tail)?;
+        OpenOptions::new()
+            .append(true)
+            .open(&tmp)?
+            .write_all(&tail)?;
+        fs::rename(&tmp, path)?;
+
+        let shift = |offset: u64| offset - end + kept.len() as u64;
+        offsets.extend(
+            index
+                .offsets
+                .iter()
+                .filter(|(_, offset)| *offset >= end)
+                .map(|(seq, offset)| (*seq, shift(*offset))),
+        );
+        index.end = shift(index.end);
+        index.offsets = offsets;
+        kept.clear();
+        // Appends go to the new file from now on
+        *file = None;
+        Ok(())
+    }
+}
+
+// Adds the events of `id` after those already there
+fn collect(history: &mut Vec<Recorded>, id: Uuid, reader: impl BufRead) -> io::Result<()> {
+    for line in reader.lines() {
+        if let Ok(recorded) = serde_json::from_str::<Recorded>(&line?) {
+            let seen = history.last().is_some_and(|last| recorded.seq <= last.seq);
+            if recorded.event.id() == id && !seen {
+                history.push(recorded);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Records `event` and applies it to the comments and everything derived from
+// them. Call with the Db write lock held, returns the comment after. Changes
+// of status go through domain.rs instead
+pub fn commit(
+    state: &AppState,
+    comments: &mut Comments,
+    event: CommentEvent,
+) -> io::Result<Option<Arc<Comment>>> {
+    let recorded = state.storage.events.append(event)?;
+    Ok(apply(state, comments, &recorded))
+}
+
+// Like commit, with the events recorded as one unit, so after a crash either
+// all of them are replayed or none. Returns the comments after
+pub fn commit_all(
+    state: &AppState,
+    comments: &mut Comments,
+    events: Vec<CommentEvent>,
+) -> io::Result<Vec<Option<Arc<Comment>>>> {
+    let recorded = state.storage.events.append_all(events)?;
+    Ok(recorded
+        .iter()
+        .map(|recorded| apply(state, comments, recorded))
+        .collect())
+}
+
+fn apply(state: &AppState, comments: &mut Comments, recorded: &Recorded) -> Option<Arc<Comment>> {
+    let (old, new) = fold(comments, recorded);
+    applied(state, old.as_ref(), new.as_ref());
+    match (&old, &new) {
+        (_, Some(new)) => state.redis.put(new),
+        (Some(old), None) => state.redis.delete(old.id),
+        (None, None) => {}
+    }
+    new
+}
+
+// Updates what is derived from the comments after one changed, also for the
+// changes of other instances, see redis_store.rs
+pub fn applied(state: &AppState, old: Option<&Arc<Comment>>, new: Option<&Arc<Comment>>) {
+    match (old, new) {
+        (None, Some(new)) => {
+            state.stats.add(new);
+            state.trending.add(new);
+            state.search_index.add(new);
+        }
+        (Some(old), Some(new)) => {
+            state.stats.replace(old, new);
+            state.trending.replace(old, new);
+            state.search_index.replace(old, new);
+        }
+        (Some(old), None) => {
+            state.tombstones.add(old);
+            state.stats.remove(old);
+            state.trending.remove(old);
+            state.search_index.remove(old);
+        }
+        (None, None) => {}
+    }
+    if let Some(comment) = new.or(old) {
+        state.storage.mark_changed(comment.id);
+    }
+    state.storage.mark_dirty();
+}
+
+// For handlers, which only say that it failed
+pub fn failed(err: io::Error) -> ApiError {
+    tracing::error!("failed to record a change: {}", err);
+    ApiError::internal("Failed to record the change")
+}
+
+// GET /admin/comments/:id/history
+pub async fn get_history(
+    _: Admin,
+    UrlPath(id): UrlPath<Uuid>,
+    Extension(state): Extension<SharedState>,
+) -> Result<Json<Vec<Recorded>>, ApiError> {
+    let history = tokio::task::spawn_blocking(move || state.storage.events.history(id))
+        .await
+        .map_err(|err| failed(io::Error::other(err)))?
+        .map_err(|err| {
+            tracing::error!("failed to read the event log: {}", err);
+            ApiError::internal("Failed to read the event log")
+        })?;
+    if history.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "comment.not_found",
+            "No history of such a comment",
+        ));
+    }
+    Ok(Json(history))
+}
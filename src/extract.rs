@@ -0,0 +1,188 @@
+// Json and Query which also check the rules of what they parsed, so
+// handlers only ever see valid input. A broken rule is answered with the
+// status, its code and a message for the client. JSON which doesn't parse
+// gets an answer saying where it broke and what was expected:
+//
+//   {"error": "expected `,` or `}` at line 1 column 13", "code": "request.invalid_json",
+//    "line": 1, "column": 13, "expected": <JSON schema of the body>}
+//
+// Input with no rules beyond its type comes through ParsedJson and
+// ParsedQuery, which answer the same way when it doesn't parse
+use axum::{
+    async_trait,
+    body::{Bytes, HttpBody},
+    extract::{FromRequest, Query, RequestParts},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    BoxError,
+};
+use schemars::{schema_for, JsonSchema};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+use crate::errors::ApiError;
+
+// Rules beyond what deserializing checks
+pub trait Validate {
+    // The code and a description for the client of the first broken rule
+    fn validate(&self) -> Result<(), (&'static str, String)>;
+}
+
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    essence == "application/json"
+        || essence.starts_with("application/") && essence.ends_with("+json")
+}
+
+// Body of a request which must be JSON. Syntax errors are answered with 400
+// and bodies of the wrong shape with 422, as Json does
+pub async fn json_body<T, B>(req: &mut RequestParts<B>) -> Result<T, ApiError>
+where
+    T: DeserializeOwned + JsonSchema,
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let json = req
+        .headers()
+        .and_then(|headers| headers.get(header::CONTENT_TYPE))
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(is_json);
+    if !json {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "request.unsupported_type",
+            "Expected a request with `Content-Type: application/json`",
+        ));
+    }
+    let body = Bytes::from_request(req)
+        .await
+        .map_err(|err| unreadable(&err))?;
+
+    serde_json::from_slice(&body).map_err(|err| {
+        let (status, code) = match err.classify() {
+            serde_json::error::Category::Data => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "request.invalid_body")
+            }
+            _ => (StatusCode::BAD_REQUEST, "request.invalid_json"),
+        };
+        ApiError::new(status, code, err.to_string()).with_details(json!({
+            "line": err.line(),
+            "column": err.column(),
+            "expected": schema_for!(T),
+        }))
+    })
+}
+
+// A body which couldn't be read whole
+pub fn unreadable(err: &dyn std::fmt::Display) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "request.unreadable",
+        err.to_string(),
+    )
+}
+
+// A body which breaks a rule is answered with 422
+pub fn validated<T: Validate>(value: T) -> Result<T, ApiError> {
+    value.validate().map_err(|(code, message)| {
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, code, message)
+    })?;
+    Ok(value)
+}
+
+// Request body, answered with 422 when a rule is broken
+#[derive(Debug)]
+pub struct ValidatedJson<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for ValidatedJson<T>
+where
+    T: DeserializeOwned + JsonSchema + Validate,
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        json_body::<T, B>(req)
+            .await
+            .and_then(validated)
+            .map(ValidatedJson)
+    }
+}
+
+// Request body without rules
+#[derive(Debug)]
+pub struct ParsedJson<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for ParsedJson<T>
+where
+    T: DeserializeOwned + JsonSchema,
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        json_body::<T, B>(req).await.map(ParsedJson)
+    }
+}
+
+async fn query<T, B>(req: &mut RequestParts<B>) -> Result<T, ApiError>
+where
+    T: DeserializeOwned,
+    B: Send,
+{
+    let Query(value) = Query::<T>::from_request(req).await.map_err(|rejection| {
+        let message = rejection.to_string();
+        ApiError::new(
+            rejection.into_response().status(),
+            "request.invalid_query",
+            message,
+        )
+    })?;
+    Ok(value)
+}
+
+// Query string, answered with 400 when a rule is broken. As with Query,
+// Option<ValidatedQuery<T>> falls back to None instead
+#[derive(Debug, Default)]
+pub struct ValidatedQuery<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for ValidatedQuery<T>
+where
+    T: DeserializeOwned + Validate,
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let value = query::<T, B>(req).await?;
+        value
+            .validate()
+            .map_err(|(code, message)| ApiError::new(StatusCode::BAD_REQUEST, code, message))?;
+        Ok(ValidatedQuery(value))
+    }
+}
+
+// Query string without rules, Option<ParsedQuery<T>> falls back to None
+#[derive(Debug, Default)]
+pub struct ParsedQuery<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for ParsedQuery<T>
+where
+    T: DeserializeOwned,
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        query::<T, B>(req).await.map(ParsedQuery)
+    }
+}
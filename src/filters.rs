@@ -0,0 +1,43 @@
+// Custom askama filters, found by the templates through `crate::filters`
+use chrono::{prelude::*, Duration, SecondsFormat};
+
+use crate::{i18n::Locale, timezone::Timezone};
+
+// "3 minutes ago", "2 days ago", ... in the given locale
+pub fn relative_time(utc: &DateTime<Utc>, i18n: &Locale) -> askama::Result<String> {
+    Ok(format_relative(
+        Utc::now().signed_duration_since(*utc),
+        i18n,
+    ))
+}
+
+// ISO timestamp in the display timezone, e.g. for a datetime attribute
+pub fn local_time(utc: &DateTime<Utc>, tz: &Timezone) -> askama::Result<String> {
+    Ok(tz.at(utc).to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+// Date and time in the display timezone, the way the locale writes them
+pub fn datetime(utc: &DateTime<Utc>, tz: &Timezone, i18n: &Locale) -> askama::Result<String> {
+    Ok(i18n.datetime(&tz.at(utc)))
+}
+
+fn format_relative(elapsed: Duration, i18n: &Locale) -> String {
+    // Clients send their own timestamps, which may be slightly ahead of ours
+    if elapsed < Duration::minutes(1) {
+        return i18n.t("time.just_now").to_owned();
+    }
+
+    let (count, unit) = if elapsed < Duration::hours(1) {
+        (elapsed.num_minutes(), "time.minutes")
+    } else if elapsed < Duration::days(1) {
+        (elapsed.num_hours(), "time.hours")
+    } else if elapsed < Duration::days(30) {
+        (elapsed.num_days(), "time.days")
+    } else if elapsed < Duration::days(365) {
+        (elapsed.num_days() / 30, "time.months")
+    } else {
+        (elapsed.num_days() / 365, "time.years")
+    };
+
+    i18n.tn(unit, count as usize)
+}
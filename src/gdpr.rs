@@ -0,0 +1,312 @@
+// Data subject requests: everything stored about a commenter, found by the
+// email address they gave or their visitor token
+use std::{
+    collections::{HashMap, HashSet},
+    fs::OpenOptions,
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+};
+
+use axum::{
+    extract::Extension,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    admin::Admin,
+    domain::{self, Transition},
+    errors::ApiError,
+    events::{self, CommentEvent},
+    extract::{Validate, ValidatedJson, ValidatedQuery},
+    newest_first,
+    privacy::IpPolicy,
+    state::SharedState,
+    votes::Vote,
+    Comment,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Subject {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visitor: Option<Uuid>,
+    // Given as the plain address, matched in the stored form
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+}
+
+impl Validate for Subject {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        if self.email.is_none() && self.visitor.is_none() && self.ip.is_none() {
+            return Err((
+                "privacy.no_subject",
+                "Give an email, a visitor token or an IP address".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Subject {
+    // Whether it can be looked for depends on how addresses are stored
+    fn resolve(self, ip_policy: &IpPolicy) -> Result<Subject, ApiError> {
+        if self.ip.is_some() && !ip_policy.identifies() {
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "privacy.ip_not_identifying",
+                "IP addresses are not stored in a form identifying a commenter",
+            ));
+        }
+        Ok(Subject {
+            email: self.email.map(|email| email.trim().to_lowercase()),
+            ..self
+        })
+    }
+
+    fn matched_by(&self) -> Vec<&'static str> {
+        let mut matched_by = Vec::new();
+        if self.email.is_some() {
+            matched_by.push("email");
+        }
+        if self.visitor.is_some() {
+            matched_by.push("visitor");
+        }
+        if self.ip.is_some() {
+            matched_by.push("ip");
+        }
+        matched_by
+    }
+
+    pub fn matches(&self, comment: &Comment, ip_policy: &IpPolicy) -> bool {
+        let email = self.email.is_some() && comment.email == self.email;
+        let visitor = self.visitor.is_some() && comment.visitor == self.visitor;
+        let ip = self.ip.is_some() && comment.ip == self.ip.and_then(|ip| ip_policy.store(ip));
+        email || visitor || ip
+    }
+}
+
+#[derive(Serialize)]
+struct Export {
+    generated_at: DateTime<Utc>,
+    subject: Subject,
+    // With every stored field, of every site
+    comments: Vec<Comment>,
+    // Votes of the visitor, by comment id
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    votes: HashMap<Uuid, Vote>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ErasureMode {
+    // Remove the comments altogether
+    #[default]
+    Delete,
+    // Keep the text, drop everything identifying the commenter
+    Anonymize,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Erasure {
+    #[serde(flatten)]
+    subject: Subject,
+    #[serde(default)]
+    mode: ErasureMode,
+}
+
+impl Validate for Erasure {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        self.subject.validate()
+    }
+}
+
+// Written to [privacy] audit_log. Holds no personal data itself, only what
+// was matched on and which comments were affected
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    at: DateTime<Utc>,
+    action: &'static str,
+    mode: ErasureMode,
+    matched_by: Vec<&'static str>,
+    comments: &'a [Uuid],
+}
+
+#[derive(Serialize)]
+struct ErasureResult {
+    mode: ErasureMode,
+    comments: Vec<Uuid>,
+}
+
+// Shown instead of the name of anonymized comments
+const ANONYMOUS: &str = "anonymous";
+
+// POST /admin/erase {"email": ..., "visitor": ..., "mode": "delete" | "anonymize"}
+pub async fn erase(
+    _: Admin,
+    ValidatedJson(input): ValidatedJson<Erasure>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let subject = input.subject.resolve(&state.ip_policy)?;
+    let mode = input.mode;
+
+    let (ids, forgotten, before) = {
+        let mut comments = state.db.write().unwrap();
+        let ids = comments
+            .values()
+            .filter(|comment| subject.matches(comment, &state.ip_policy))
+            .map(|comment| comment.id)
+            .collect::<Vec<_>>();
+
+        // Nothing is erased unless the audit record could be written
+        let record = AuditRecord {
+            at: Utc::now(),
+            action: "erase",
+            mode,
+            matched_by: subject.matched_by(),
+            comments: &ids,
+        };
+        append_audit(&state.config.privacy.audit_log, &record).map_err(|err| {
+            tracing::error!("failed to write audit record: {}", err);
+            ApiError::internal("Failed to write the audit record")
+        })?;
+
+        // The events so far hold what is erased, see events.rs
+        let before = state.storage.events.seq();
+        let mut forgotten = ids.iter().copied().collect::<HashSet<_>>();
+        // Recorded as one unit, so a crash doesn't leave a commenter half
+        // anonymized
+        let mut edited = HashMap::new();
+        for id in &ids {
+            match mode {
+                ErasureMode::Delete => {
+                    domain::transition(&state, &mut comments, *id, Transition::Delete)?;
+                }
+                ErasureMode::Anonymize => {
+                    let mut anonymized = Comment::clone(&comments[id]);
+                    anonymized.name = ANONYMOUS.to_owned();
+                    anonymized.email = None;
+                    anonymized.visitor = None;
+                    anonymized.ip = None;
+                    anonymized.country = None;
+                    anonymized.touch();
+                    edited.insert(*id, anonymized);
+                }
+            }
+        }
+        // Votes can't be anonymized, a visitor's votes are always removed
+        if let Some(visitor) = &subject.visitor {
+            for comment in comments.values() {
+                if comment.votes.contains_key(visitor) {
+                    let comment = edited
+                        .entry(comment.id)
+                        .or_insert_with(|| Comment::clone(comment));
+                    comment.votes.remove(visitor);
+                    comment.touch_unversioned();
+                    forgotten.insert(comment.id);
+                }
+            }
+        }
+        let edits = edited
+            .into_values()
+            .map(|comment| CommentEvent::Edited {
+                comment: Arc::new(comment),
+            })
+            .collect();
+        events::commit_all(&state, &mut comments, edits).map_err(events::failed)?;
+        (ids, forgotten, before)
+    };
+
+    // Gone from the log and the snapshot right away, not on the next flush
+    let erased = state.clone();
+    tokio::task::spawn_blocking(move || {
+        erased.storage.events.forget(&forgotten, before)?;
+        erased.storage.flush(&erased).map_err(io::Error::other)
+    })
+    .await
+    .map_err(io::Error::other)
+    .and_then(|result| result)
+    .map_err(events::failed)?;
+
+    tracing::info!(comments = ids.len(), ?mode, "erased commenter data");
+
+    Ok(Json(ErasureResult {
+        mode,
+        comments: ids,
+    }))
+}
+
+fn append_audit(path: &Path, record: &AuditRecord) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+// GET /admin/export?email=... or ?visitor=..., downloaded as a JSON file
+pub async fn export(
+    _: Admin,
+    ValidatedQuery(subject): ValidatedQuery<Subject>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let subject = subject.resolve(&state.ip_policy)?;
+
+    let comments = newest_first(
+        state
+            .db
+            .read()
+            .unwrap()
+            .values()
+            .filter(|comment| subject.matches(comment, &state.ip_policy)),
+    )
+    .into_iter()
+    // The votes on the comments are other people's
+    .map(|comment| Comment {
+        votes: HashMap::new(),
+        ..Comment::clone(comment)
+    })
+    .collect::<Vec<_>>();
+
+    // The subject's own votes, by comment id
+    let votes = match &subject.visitor {
+        Some(visitor) => state
+            .db
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|comment| Some((comment.id, *comment.votes.get(visitor)?)))
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    tracing::info!(comments = comments.len(), "exported commenter data");
+
+    let generated_at = Utc::now();
+    let mut headers = HeaderMap::new();
+    let disposition = format!(
+        "attachment; filename=\"little-nova-export-{}.json\"",
+        generated_at.format("%Y%m%d%H%M%S")
+    );
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+
+    Ok((
+        headers,
+        Json(Export {
+            generated_at,
+            subject,
+            comments,
+            votes,
+        }),
+    ))
+}
@@ -0,0 +1,53 @@
+// Country of the commenter from a MaxMind database, see [geoip] in the config
+// Looked up when a comment is posted, only the ISO code is kept
+use std::net::IpAddr;
+
+use crate::config::GeoIpConfig;
+
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    reader: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    #[cfg(feature = "geoip")]
+    pub fn open(config: &GeoIpConfig) -> Result<GeoIp, String> {
+        let reader = match &config.database {
+            Some(path) => Some(
+                maxminddb::Reader::open_readfile(path)
+                    .map_err(|err| format!("failed to open {}: {}", path.display(), err))?,
+            ),
+            None => None,
+        };
+        Ok(GeoIp { reader })
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(config: &GeoIpConfig) -> Result<GeoIp, String> {
+        if config.database.is_some() {
+            tracing::warn!(
+                "[geoip] database is set, but little-nova was built without the `geoip` feature"
+            );
+        }
+        Ok(GeoIp {})
+    }
+
+    // ISO 3166 code like "JP", None for unknown and private addresses
+    #[cfg(feature = "geoip")]
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.as_ref()?;
+        let record = reader
+            .lookup::<maxminddb::geoip2::Country>(ip)
+            .map_err(|err| tracing::debug!("no country for {}: {}", ip, err))
+            .ok()?;
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_owned)
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+}
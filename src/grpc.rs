@@ -0,0 +1,324 @@
+// CommentService of proto/little_nova.proto over gRPC, for backend services
+// which would otherwise scrape the HTTP API. Served on its own port, see
+// [grpc] in the config, and written out by hand like the messages in proto.rs
+// tonic::Status is large, but it is what every gRPC call fails with
+#![allow(clippy::result_large_err)]
+use std::{convert::Infallible, net::SocketAddr, str::FromStr};
+
+use chrono::Utc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    body::BoxBody,
+    codec::ProstCodec,
+    codegen::{http, BoxFuture, Context, Poll, Service},
+    server::{Grpc, NamedService},
+    transport::server::TcpConnectInfo,
+    Request, Response, Status,
+};
+use uuid::Uuid;
+
+use crate::{
+    admin::constant_time_eq,
+    auth_log::Failure,
+    domain::{self, Transition, TransitionError},
+    errors::ApiError,
+    extract::Validate,
+    insert_comment, newest_first,
+    poll::{self, published_since},
+    proto,
+    sites::DEFAULT_SITE,
+    state::SharedState,
+    trace_context::{self, TraceContext, TRACEPARENT, TRACESTATE},
+    CommentStatus, CreateComment,
+};
+
+// Comments waiting to be sent on a Watch stream
+const WATCH_BUFFER: usize = 16;
+
+pub async fn serve(state: SharedState, addr: SocketAddr) {
+    tracing::debug!("gRPC listening on {}", addr);
+    let result = tonic::transport::Server::builder()
+        .add_service(CommentService { state })
+        .serve(addr)
+        .await;
+    if let Err(err) = result {
+        tracing::error!("gRPC server failed: {}", err);
+    }
+}
+
+#[derive(Clone)]
+struct CommentService {
+    state: SharedState,
+}
+
+impl NamedService for CommentService {
+    const NAME: &'static str = "little_nova.CommentService";
+}
+
+impl Service<http::Request<BoxBody>> for CommentService {
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<BoxBody>) -> Self::Future {
+        if let Err(status) = authorize(&self.state, req.headers()) {
+            if status.code() == tonic::Code::Unauthenticated {
+                let client = req
+                    .extensions()
+                    .get::<TcpConnectInfo>()
+                    .and_then(TcpConnectInfo::remote_addr)
+                    .map(|peer| peer.ip());
+                self.state.auth_log.failure(
+                    Failure::AdminToken,
+                    client,
+                    req.method().as_str(),
+                    req.uri().path(),
+                );
+            }
+            return Box::pin(async move { Ok(status.into_http()) });
+        }
+
+        let state = self.state.clone();
+        let headers = req.headers();
+        let context = TraceContext::from_values(
+            &state.config.tracing,
+            headers
+                .get(TRACEPARENT)
+                .and_then(|value| value.to_str().ok()),
+            headers
+                .get_all(TRACESTATE)
+                .iter()
+                .filter_map(|value| value.to_str().ok()),
+        );
+        Box::pin(trace_context::scope(Some(context), async move {
+            let response = match req.uri().path() {
+                "/little_nova.CommentService/List" => {
+                    let list = tower::service_fn(|request| list(state.clone(), request));
+                    Grpc::new(ProstCodec::default()).unary(list, req).await
+                }
+                "/little_nova.CommentService/Get" => {
+                    let get = tower::service_fn(|request| get(state.clone(), request));
+                    Grpc::new(ProstCodec::default()).unary(get, req).await
+                }
+                "/little_nova.CommentService/Create" => {
+                    let create = tower::service_fn(|request| create(state.clone(), request));
+                    Grpc::new(ProstCodec::default()).unary(create, req).await
+                }
+                "/little_nova.CommentService/Delete" => {
+                    let delete = tower::service_fn(|request| delete(state.clone(), request));
+                    Grpc::new(ProstCodec::default()).unary(delete, req).await
+                }
+                "/little_nova.CommentService/Watch" => {
+                    let watch = tower::service_fn(|request| watch(state.clone(), request));
+                    Grpc::new(ProstCodec::default())
+                        .server_streaming(watch, req)
+                        .await
+                }
+                _ => Status::unimplemented("No such method").into_http(),
+            };
+            Ok(response)
+        }))
+    }
+}
+
+// Same token as the /admin routes
+fn authorize(state: &SharedState, headers: &http::HeaderMap) -> Result<(), Status> {
+    let expected = state
+        .secrets
+        .admin_token()
+        .ok_or_else(|| Status::permission_denied("Set [admin] token to enable gRPC"))?;
+    let given = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if constant_time_eq(given.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err(Status::unauthenticated("Invalid admin token")),
+    }
+}
+
+fn site_key(site: String) -> String {
+    if site.is_empty() {
+        DEFAULT_SITE.to_owned()
+    } else {
+        site
+    }
+}
+
+fn parse_id(id: &str) -> Result<Uuid, Status> {
+    Uuid::from_str(id).map_err(|_| Status::invalid_argument(format!("Invalid id \"{}\"", id)))
+}
+
+// The HTTP status of insert_comment's errors, as a gRPC status
+fn status(err: ApiError) -> Status {
+    use axum::http::StatusCode;
+    let message = err.message.into_owned();
+    match err.status {
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::FORBIDDEN => Status::failed_precondition(message),
+        StatusCode::TOO_MANY_REQUESTS => Status::resource_exhausted(message),
+        code if code.is_client_error() => Status::invalid_argument(message),
+        _ => Status::internal(message),
+    }
+}
+
+async fn list(
+    state: SharedState,
+    request: Request<proto::ListRequest>,
+) -> Result<Response<proto::CommentList>, Status> {
+    let request = request.into_inner();
+    let site = site_key(request.site);
+    let status = request
+        .status
+        .map(|status| {
+            proto::Status::try_from(status)
+                .map(CommentStatus::from)
+                .map_err(|_| Status::invalid_argument("Unknown status"))
+        })
+        .transpose()?;
+    let limit = match request.limit {
+        0 => usize::MAX,
+        limit => limit as usize,
+    };
+
+    let comments = state.db.read().unwrap();
+    let comments = newest_first(
+        comments
+            .values()
+            .filter(|comment| comment.site == site)
+            .filter(|comment| status.is_none_or(|status| comment.status == status))
+            .filter(|comment| {
+                request
+                    .slug
+                    .as_ref()
+                    .is_none_or(|slug| comment.slug.as_ref() == Some(slug))
+            }),
+    )
+    .into_iter()
+    .skip(request.offset as usize)
+    .take(limit)
+    .map(|comment| proto::Comment::from(comment.as_ref()))
+    .collect();
+
+    Ok(Response::new(proto::CommentList { comments }))
+}
+
+async fn get(
+    state: SharedState,
+    request: Request<proto::GetRequest>,
+) -> Result<Response<proto::Comment>, Status> {
+    let id = parse_id(&request.into_inner().id)?;
+    state
+        .db
+        .read()
+        .unwrap()
+        .get(&id)
+        .map(|comment| Response::new(proto::Comment::from(comment.as_ref())))
+        .ok_or_else(|| Status::not_found("No such comment"))
+}
+
+async fn create(
+    state: SharedState,
+    request: Request<proto::CreateRequest>,
+) -> Result<Response<proto::Comment>, Status> {
+    let request = request.into_inner();
+    let site = site_key(request.site);
+    let settings = state
+        .sites
+        .get(&site)
+        .ok_or_else(|| Status::not_found("Unknown site"))?;
+    let input = request
+        .comment
+        .ok_or_else(|| Status::invalid_argument("comment is missing"))?;
+    let input = CreateComment::try_from(input).map_err(Status::invalid_argument)?;
+    input
+        .validate()
+        .map_err(|(_, message)| Status::invalid_argument(message))?;
+
+    let comment = insert_comment(
+        &state,
+        &site,
+        &settings,
+        input,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .await
+    .map_err(status)?;
+    tracing::info!(id = %comment.id, %site, "comment created over gRPC");
+    Ok(Response::new(proto::Comment::from(&comment)))
+}
+
+async fn delete(
+    state: SharedState,
+    request: Request<proto::DeleteRequest>,
+) -> Result<Response<proto::DeleteResponse>, Status> {
+    let id = parse_id(&request.into_inner().id)?;
+    let mut comments = state.db.write().unwrap();
+    domain::transition(&state, &mut comments, id, Transition::Delete).map_err(|err| match err {
+        TransitionError::NotFound => Status::not_found(err.message()),
+        _ => {
+            let err = ApiError::from(err);
+            Status::internal(err.message.into_owned())
+        }
+    })?;
+    drop(comments);
+    tracing::info!(%id, "comment deleted over gRPC");
+
+    Ok(Response::new(proto::DeleteResponse {}))
+}
+
+async fn watch(
+    state: SharedState,
+    request: Request<proto::WatchRequest>,
+) -> Result<Response<ReceiverStream<Result<proto::Comment, Status>>>, Status> {
+    let request = request.into_inner();
+    let site = site_key(request.site);
+    let mut after = match request.since {
+        Some(id) => {
+            let id = parse_id(&id)?;
+            poll::since(&state, &site, &id).ok_or_else(|| Status::not_found("No such comment"))?
+        }
+        None => (Utc::now(), Uuid::nil()),
+    };
+
+    let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+    tokio::spawn(async move {
+        loop {
+            // Listen before looking, as in poll.rs
+            let published = state.published.notified();
+            tokio::pin!(published);
+            published.as_mut().enable();
+
+            let (comments, more) = published_since(&state, &site, request.slug.as_deref(), after);
+            for comment in comments {
+                after = poll::cursor(&comment).unwrap_or(after);
+                if tx
+                    .send(Ok(proto::Comment::from(comment.as_ref())))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            if more {
+                continue;
+            }
+
+            tokio::select! {
+                _ = published => {}
+                // The client went away
+                _ = tx.closed() => return,
+            }
+        }
+    });
+
+    Ok(Response::new(ReceiverStream::new(rx)))
+}
@@ -0,0 +1,76 @@
+// HAL links (https://stateless.group/hal_specification.html) in the JSON
+// listings, so hypermedia clients follow them instead of building URLs. The
+// index links the pages around it, and every comment itself and what can be
+// done with it:
+//
+//   {"comments": [{"id": "0190...", ..., "_links": {"self": {"href": "/0190..."},
+//                                                   "vote": {"href": "/0190.../vote"}}}],
+//    "_links": {"self": {"href": "/?offset=100"}, "prev": {"href": "/"}, ...}}
+//
+// Links are absolute with [site] base_url. Comments aren't threaded, so
+// there are no parent or replies links
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{config::SiteConfig, sites, Comment};
+
+#[derive(Debug, Serialize)]
+pub struct Link {
+    pub href: String,
+}
+
+pub type Links = BTreeMap<&'static str, Link>;
+
+// `inner` with `_links` next to its fields
+#[derive(Debug, Serialize)]
+pub struct Linked<T> {
+    #[serde(flatten)]
+    pub inner: T,
+    #[serde(rename = "_links")]
+    pub links: Links,
+}
+
+// From paths, by relation
+pub fn links(config: &SiteConfig, paths: Vec<(&'static str, String)>) -> Links {
+    paths
+        .into_iter()
+        .map(|(rel, path)| {
+            let href = config.link(&path);
+            (rel, Link { href })
+        })
+        .collect()
+}
+
+// Of a comment listed on the site at `root`
+pub fn public<T>(config: &SiteConfig, root: &str, comment: &Comment, inner: T) -> Linked<T> {
+    let permalink = format!("{}/{}", root, comment.id);
+    Linked {
+        inner,
+        links: links(
+            config,
+            vec![("vote", format!("{}/vote", permalink)), ("self", permalink)],
+        ),
+    }
+}
+
+// Of a comment in the admin API, with the moderation endpoints
+pub fn moderated<T>(config: &SiteConfig, comment: &Comment, inner: T) -> Linked<T> {
+    let admin = format!("/admin/comments/{}", comment.id);
+    Linked {
+        inner,
+        links: links(
+            config,
+            vec![
+                (
+                    "self",
+                    format!("{}/{}", sites::root(&comment.site), comment.id),
+                ),
+                ("approve", format!("{}/approve", admin)),
+                ("schedule", format!("{}/schedule", admin)),
+                ("history", format!("{}/history", admin)),
+                ("delete", admin),
+            ],
+        ),
+    }
+}
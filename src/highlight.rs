@@ -0,0 +1,79 @@
+// Fenced code blocks of comments as HTML, see markup.rs. Highlighted on the
+// server with syntect in the colours of [display] code_theme, for languages
+// it knows by the name or file extension after the opening fence:
+//
+//   ```rust
+//   fn main() {}
+//   ```
+//
+// Other blocks, and all of them unless built with the `syntect` feature, are
+// a plain escaped <pre><code>
+#[cfg(feature = "syntect")]
+use syntect::{
+    highlighting::{Theme, ThemeSet},
+    html::highlighted_html_for_string,
+    parsing::SyntaxSet,
+};
+
+use crate::{config::DisplayConfig, markup};
+
+#[derive(Debug)]
+pub struct Highlighter {
+    #[cfg(feature = "syntect")]
+    syntaxes: SyntaxSet,
+    // None for plain blocks
+    #[cfg(feature = "syntect")]
+    theme: Option<Theme>,
+}
+
+impl Highlighter {
+    #[cfg(feature = "syntect")]
+    pub fn new(config: &DisplayConfig) -> Self {
+        let mut themes = ThemeSet::load_defaults().themes;
+        let theme = themes.remove(&config.code_theme);
+        if theme.is_none() {
+            let mut names = themes.keys().map(String::as_str).collect::<Vec<_>>();
+            names.sort_unstable();
+            tracing::warn!(
+                "[display] code_theme {:?} is none of {}, code blocks won't be highlighted",
+                config.code_theme,
+                names.join(", ")
+            );
+        }
+        Highlighter {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            theme,
+        }
+    }
+
+    #[cfg(not(feature = "syntect"))]
+    pub fn new(_config: &DisplayConfig) -> Self {
+        Highlighter {}
+    }
+
+    // Appends the block to `html`
+    pub fn render(&self, language: &str, code: &str, html: &mut String) {
+        if let Some(highlighted) = self.highlight(language, code) {
+            html.push_str(&highlighted);
+            return;
+        }
+        html.push_str("<pre><code>");
+        markup::escape(code, html);
+        html.push_str("</code></pre>");
+    }
+
+    // Escaped by syntect, in a <pre> with the theme's colours
+    #[cfg(feature = "syntect")]
+    fn highlight(&self, language: &str, code: &str) -> Option<String> {
+        let theme = self.theme.as_ref()?;
+        let syntax = self.syntaxes.find_syntax_by_token(language)?;
+        highlighted_html_for_string(code, &self.syntaxes, syntax, theme)
+            .map_err(|err| tracing::warn!("failed to highlight a {} block: {}", language, err))
+            .ok()
+    }
+
+    #[cfg(not(feature = "syntect"))]
+    fn highlight(&self, _language: &str, _code: &str) -> Option<String> {
+        None
+    }
+}
@@ -0,0 +1,81 @@
+// Pages too large to build in memory, rendered straight into the response
+// body. The template runs on a blocking thread and is sent in CHUNK_SIZE
+// pieces as it renders; a slow client holds up the rendering instead of the
+// page piling up in memory
+use std::fmt;
+
+use askama::Template;
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderValue, Response},
+};
+use tokio::runtime::Handle;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+pub fn render<T: Template + Send + 'static>(template: T) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let runtime = Handle::current();
+
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChunkWriter {
+            buf: String::with_capacity(CHUNK_SIZE),
+            send: |chunk| runtime.block_on(sender.send_data(chunk)).is_ok(),
+            closed: false,
+        };
+        let result = template
+            .render_into(&mut writer)
+            .map_err(|err| err.to_string())
+            .and_then(|()| writer.flush().map_err(|err| err.to_string()));
+        match result {
+            Ok(()) => {}
+            Err(_) if writer.closed => tracing::debug!("client left while a page was streamed"),
+            Err(err) => {
+                // The status is long sent, cutting the body off is all that's left
+                tracing::error!("failed to stream a template: {}", err);
+                drop(writer);
+                sender.abort();
+            }
+        }
+    });
+
+    let mut response = Response::new(body);
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    response
+}
+
+struct ChunkWriter<F> {
+    buf: String,
+    // false once the client is gone
+    send: F,
+    // Set then, and the rendering stops
+    closed: bool,
+}
+
+impl<F: FnMut(Bytes) -> bool> ChunkWriter<F> {
+    fn flush(&mut self) -> fmt::Result {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, String::with_capacity(CHUNK_SIZE));
+        if (self.send)(Bytes::from(chunk)) {
+            Ok(())
+        } else {
+            self.closed = true;
+            Err(fmt::Error)
+        }
+    }
+}
+
+impl<F: FnMut(Bytes) -> bool> fmt::Write for ChunkWriter<F> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.buf.push_str(s);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+// HTTP/3 over QUIC next to the HTTPS listener, for mobile clients on lossy
+// networks. Requests go through the same Router as HTTPS, whose responses
+// point clients here with Alt-Svc. See [http3] in the config
+use std::{net::SocketAddr, path::Path, sync::Arc};
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::ConnectInfo,
+    http::HeaderValue,
+    Router,
+};
+use bytes::{Buf, Bytes};
+use h3::server::RequestStream;
+use quinn::{
+    crypto::rustls::QuicServerConfig,
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    },
+};
+use tower::ServiceExt;
+
+use crate::config::TlsConfig;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// How long clients may remember the HTTP/3 listener
+const ALT_SVC_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+pub fn alt_svc(addr: SocketAddr) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "h3=\":{}\"; ma={}",
+        addr.port(),
+        ALT_SVC_MAX_AGE_SECS
+    ))
+    .unwrap()
+}
+
+// Same certificate as HTTPS
+pub fn bind(addr: SocketAddr, tls: &TlsConfig) -> Result<quinn::Endpoint, Error> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| describe(&tls.cert, err))?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key).map_err(|err| describe(&tls.key, err))?;
+
+    let mut crypto = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+
+    let config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    Ok(quinn::Endpoint::server(config, addr)?)
+}
+
+fn describe(path: &Path, err: impl std::fmt::Display) -> Error {
+    format!("{}: {}", path.display(), err).into()
+}
+
+// Runs for the lifetime of the server
+pub async fn serve(endpoint: quinn::Endpoint, app: Router) {
+    while let Some(incoming) = endpoint.accept().await {
+        let app = app.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_connection(incoming, app).await {
+                tracing::debug!("HTTP/3 connection failed: {}", err);
+            }
+        });
+    }
+}
+
+async fn serve_connection(incoming: quinn::Incoming, app: Router) -> Result<(), Error> {
+    let connection = incoming.await?;
+    let peer = connection.remote_address();
+    let mut connection = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+
+    loop {
+        let resolver = match connection.accept().await {
+            Ok(Some(resolver)) => resolver,
+            Ok(None) => return Ok(()),
+            // The client closed the connection
+            Err(err) if err.is_h3_no_error() => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let app = app.clone();
+        tokio::spawn(async move {
+            let result = match resolver.resolve_request().await {
+                Ok((request, stream)) => serve_request(request, stream, peer, app).await,
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = result {
+                tracing::debug!("HTTP/3 request failed: {}", err);
+            }
+        });
+    }
+}
+
+async fn serve_request(
+    request: http1::Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    peer: SocketAddr,
+    app: Router,
+) -> Result<(), Error> {
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+
+    // Rebuilt with the http 0.2 types of axum
+    let mut builder = axum::http::Request::builder()
+        .method(request.method().as_str())
+        .uri(request.uri().to_string())
+        .version(axum::http::Version::HTTP_3);
+    for (name, value) in request.headers() {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    let mut request = builder.body(Body::from(body))?;
+    // For ClientIp, as with into_make_service_with_connect_info
+    request.extensions_mut().insert(ConnectInfo(peer));
+
+    let response = app.oneshot(request).await?;
+    let (parts, mut body) = response.into_parts();
+
+    let mut builder = http1::Response::builder().status(parts.status.as_u16());
+    for (name, value) in &parts.headers {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    stream.send_response(builder.body(())?).await?;
+    // Streamed, so long polls work as over HTTPS
+    while let Some(chunk) = body.data().await {
+        stream.send_data(chunk?).await?;
+    }
+    stream.finish().await?;
+    Ok(())
+}
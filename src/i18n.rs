@@ -0,0 +1,243 @@
+// Translations of the user facing strings, gettext style:
+// templates look messages up by id and fall back to English. Dates and counts
+// are formatted the locale's way too, see `datetime` and `number`
+use std::{borrow::Borrow, convert::Infallible};
+
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, RequestParts},
+    http::header::ACCEPT_LANGUAGE,
+};
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+
+use crate::state::SharedState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Ja,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::En, Locale::Ja];
+
+    // Language tag for <html lang="...">
+    pub fn code(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Ja => "ja",
+        }
+    }
+
+    fn from_tag(tag: &str) -> Option<Locale> {
+        // Only the primary subtag matters, "ja-JP" is "ja"
+        let primary = tag.split('-').next().unwrap_or(tag);
+        Locale::ALL
+            .into_iter()
+            .find(|locale| primary.eq_ignore_ascii_case(locale.code()))
+    }
+
+    // Pick the supported language with the highest q value
+    // e.g. "ja-JP,ja;q=0.9,en;q=0.8", None if there is none
+    pub fn negotiate(accept_language: &str) -> Option<Locale> {
+        let mut ranges = accept_language
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.trim().split(';');
+                let tag = parts.next()?.trim();
+                let q = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+                Some((tag, q))
+            })
+            .filter(|(_, q)| *q > 0.0)
+            .collect::<Vec<_>>();
+        // Stable, so equal q values keep the client's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .into_iter()
+            .find_map(|(tag, _)| Locale::from_tag(tag))
+    }
+
+    fn catalog(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Locale::En => EN,
+            Locale::Ja => JA,
+        }
+    }
+
+    // Message for `id`, falling back to English
+    pub fn t(&self, id: &str) -> &'static str {
+        lookup(self.catalog(), id)
+            .or_else(|| lookup(EN, id))
+            .unwrap_or_else(|| {
+                tracing::warn!("missing translation for {}", id);
+                ""
+            })
+    }
+
+    // Message with a count, using the `<id>.one` or `<id>.other` form
+    // and replacing `{n}` with the count
+    // Borrow because templates pass arguments by reference
+    pub fn tn(&self, id: &str, n: impl Borrow<usize>) -> String {
+        let n = *n.borrow();
+        let form = if n == 1 { "one" } else { "other" };
+        self.t(&format!("{}.{}", id, form))
+            .replace("{n}", &self.number(n))
+    }
+
+    // Date and time, e.g. "Oct 14, 2026, 9:05 PM" or "2026年10月14日 21:05"
+    pub fn datetime(&self, time: &DateTime<FixedOffset>) -> String {
+        time.format(self.t("format.datetime")).to_string()
+    }
+
+    // With the digits grouped, e.g. "12,345"
+    // Borrow because templates pass arguments by reference
+    pub fn number(&self, n: impl Borrow<usize>) -> String {
+        let digits = n.borrow().to_string();
+        let separator = self.t("format.group_separator");
+        let mut grouped = String::with_capacity(digits.len() * 2);
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i) % 3 == 0 {
+                grouped.push_str(separator);
+            }
+            grouped.push(digit);
+        }
+        grouped
+    }
+}
+
+fn lookup(catalog: &[(&str, &'static str)], id: &str) -> Option<&'static str> {
+    catalog
+        .iter()
+        .find(|(key, _)| *key == id)
+        .map(|(_, message)| *message)
+}
+
+// Negotiated from the Accept-Language header, [display] locale if nothing
+// matches
+#[async_trait]
+impl<B> FromRequest<B> for Locale
+where
+    B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let negotiated = req
+            .headers()
+            .and_then(|headers| headers.get(ACCEPT_LANGUAGE))
+            .and_then(|value| value.to_str().ok())
+            .and_then(Locale::negotiate);
+        if let Some(locale) = negotiated {
+            return Ok(locale);
+        }
+        Ok(Extension::<SharedState>::from_request(req)
+            .await
+            .map(|Extension(state)| state.config.display.locale)
+            .unwrap_or_default())
+    }
+}
+
+const EN: &[(&str, &str)] = &[
+    ("comments.count.one", "{n} comment"),
+    ("comments.count.other", "{n} comments"),
+    ("comments.tagged", "tagged"),
+    ("comments.show_all", "show all"),
+    ("comments.prev", "newer"),
+    ("comments.next", "older"),
+    ("comments.page", "page"),
+    ("comments.per_page.one", "{n} per page"),
+    ("comments.per_page.other", "{n} per page"),
+    ("comments.sort_newest", "newest first"),
+    ("comments.sort_top", "top first"),
+    ("comment.name", "NAME"),
+    ("comment.permalink", "permalink"),
+    ("comment.copy_link", "copy link"),
+    ("comment.copied", "Copied"),
+    ("comment.back", "back to the list"),
+    ("comment.vote_up", "vote up"),
+    ("comment.vote_down", "vote down"),
+    ("form.title", "title"),
+    ("form.name", "name"),
+    ("form.email", "email"),
+    ("form.email_placeholder", "optional, never shown"),
+    ("form.text", "text"),
+    ("form.tags", "tags"),
+    ("form.tags_placeholder", "comma separated"),
+    ("form.send", "send"),
+    ("form.reset", "reset"),
+    ("form.return", "Return to list of comments"),
+    ("form.sent", "The comment was sent successfully"),
+    ("form.failed", "Failed to send comment"),
+    ("time.just_now", "just now"),
+    ("time.minutes.one", "{n} minute ago"),
+    ("time.minutes.other", "{n} minutes ago"),
+    ("time.hours.one", "{n} hour ago"),
+    ("time.hours.other", "{n} hours ago"),
+    ("time.days.one", "{n} day ago"),
+    ("time.days.other", "{n} days ago"),
+    ("time.months.one", "{n} month ago"),
+    ("time.months.other", "{n} months ago"),
+    ("time.years.one", "{n} year ago"),
+    ("time.years.other", "{n} years ago"),
+    // chrono's strftime
+    ("format.datetime", "%b %-d, %Y, %-I:%M %p"),
+    ("format.group_separator", ","),
+    ("embed.closed", "Comments are closed."),
+    ("error.not_found", "404 not found"),
+    ("error.back", "Return to list of comments"),
+];
+
+const JA: &[(&str, &str)] = &[
+    ("comments.count.one", "{n} 件のコメント"),
+    ("comments.count.other", "{n} 件のコメント"),
+    ("comments.tagged", "タグ"),
+    ("comments.show_all", "すべて表示"),
+    ("comments.prev", "新しいコメント"),
+    ("comments.next", "古いコメント"),
+    ("comments.page", "ページ"),
+    ("comments.per_page.one", "1 ページ {n} 件"),
+    ("comments.per_page.other", "1 ページ {n} 件"),
+    ("comments.sort_newest", "新しい順"),
+    ("comments.sort_top", "評価順"),
+    ("comment.name", "名前"),
+    ("comment.permalink", "パーマリンク"),
+    ("comment.copy_link", "リンクをコピー"),
+    ("comment.copied", "コピーしました"),
+    ("comment.back", "一覧に戻る"),
+    ("comment.vote_up", "高評価"),
+    ("comment.vote_down", "低評価"),
+    ("form.title", "タイトル"),
+    ("form.name", "名前"),
+    ("form.email", "メールアドレス"),
+    ("form.email_placeholder", "任意・公開されません"),
+    ("form.text", "本文"),
+    ("form.tags", "タグ"),
+    ("form.tags_placeholder", "カンマ区切り"),
+    ("form.send", "送信"),
+    ("form.reset", "リセット"),
+    ("form.return", "コメント一覧に戻る"),
+    ("form.sent", "コメントを送信しました"),
+    ("form.failed", "コメントの送信に失敗しました"),
+    ("time.just_now", "たった今"),
+    ("time.minutes.one", "{n} 分前"),
+    ("time.minutes.other", "{n} 分前"),
+    ("time.hours.one", "{n} 時間前"),
+    ("time.hours.other", "{n} 時間前"),
+    ("time.days.one", "{n} 日前"),
+    ("time.days.other", "{n} 日前"),
+    ("time.months.one", "{n} か月前"),
+    ("time.months.other", "{n} か月前"),
+    ("time.years.one", "{n} 年前"),
+    ("time.years.other", "{n} 年前"),
+    ("format.datetime", "%Y年%-m月%-d日 %H:%M"),
+    ("format.group_separator", ","),
+    ("embed.closed", "コメントの受付は終了しました。"),
+    ("error.not_found", "404 ページが見つかりません"),
+    ("error.back", "コメント一覧に戻る"),
+];
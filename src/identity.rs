@@ -0,0 +1,142 @@
+// Who wrote a comment, for answering data subject requests (see gdpr.rs)
+// Neither is ever shown on the pages
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, Extension, FromRequest, RequestParts},
+    http::{header, HeaderMap, HeaderValue, Request},
+};
+use uuid::Uuid;
+
+use crate::{errors::ApiError, state::SharedState};
+
+const VISITOR_COOKIE: &str = "little_nova_visitor";
+// About two years, like other long lived preference cookies
+const VISITOR_COOKIE_MAX_AGE: u64 = 2 * 365 * 24 * 60 * 60;
+
+pub const MAX_EMAIL_LEN: usize = 254;
+
+// Optional, only checked for the shape of an address
+pub fn normalize_email(email: Option<String>) -> Result<Option<String>, String> {
+    let email = match email.map(|email| email.trim().to_lowercase()) {
+        Some(email) if !email.is_empty() => email,
+        _ => return Ok(None),
+    };
+    if email.chars().count() > MAX_EMAIL_LEN {
+        return Err(format!("Email is longer than {} characters", MAX_EMAIL_LEN));
+    }
+    match email.split_once('@') {
+        Some((local, domain)) if !local.is_empty() && domain.contains('.') => Ok(Some(email)),
+        _ => Err(format!("\"{}\" is not an email address", email)),
+    }
+}
+
+// Random token kept in a cookie, so comments of the same browser can be found
+// without asking for an email address
+#[derive(Debug, Clone, Copy)]
+pub struct Visitor {
+    pub token: Uuid,
+    // The cookie still has to be sent
+    pub is_new: bool,
+}
+
+impl Visitor {
+    pub fn set_cookie(&self) -> Option<HeaderValue> {
+        if !self.is_new {
+            return None;
+        }
+        // SameSite=None so the cookie also works inside the /embed iframe
+        HeaderValue::from_str(&format!(
+            "{}={}; Max-Age={}; Path=/; HttpOnly; Secure; SameSite=None",
+            VISITOR_COOKIE, self.token, VISITOR_COOKIE_MAX_AGE
+        ))
+        .ok()
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for Visitor
+where
+    B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let token = req
+            .headers()
+            .into_iter()
+            .flat_map(|headers| headers.get_all(header::COOKIE))
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == VISITOR_COOKIE)
+            .and_then(|(_, value)| Uuid::parse_str(value).ok());
+
+        Ok(match token {
+            Some(token) => Visitor {
+                token,
+                is_new: false,
+            },
+            None => Visitor {
+                token: Uuid::new_v4(),
+                is_new: true,
+            },
+        })
+    }
+}
+
+// The proxy appends the address it saw, so the last entry is ours
+pub fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+}
+
+// Address of the commenter, the peer unless `trust_forwarded_for` is on
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<B> FromRequest<B> for ClientIp
+where
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<SharedState>::from_request(req)
+            .await
+            .map_err(|_| ApiError::internal("App state is not available"))?;
+
+        if state.config.trust_forwarded_for {
+            if let Some(ip) = req.headers().and_then(forwarded_for) {
+                return Ok(ClientIp(ip));
+            }
+        }
+
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request(req)
+            .await
+            .map_err(|_| ApiError::internal("Peer address is not available"))?;
+        Ok(ClientIp(peer.ip()))
+    }
+}
+
+// As for ClientIp, from a request in a middleware
+pub fn client_ip<B>(state: &SharedState, req: &Request<B>) -> Option<IpAddr> {
+    let forwarded = state
+        .config
+        .trust_forwarded_for
+        .then(|| forwarded_for(req.headers()))
+        .flatten();
+    forwarded.or_else(|| {
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| peer.ip())
+    })
+}
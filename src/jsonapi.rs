@@ -0,0 +1,81 @@
+// JSON:API documents (https://jsonapi.org) of the comment listings, for
+// frontends built on JSON:API tooling. Asked for with
+// `Accept: application/vnd.api+json` on `/`, `/<id>` and `/admin/comments`:
+//
+//   {"data": [{"type": "comments", "id": "...", "attributes": {"name": ..., ...},
+//              "relationships": {"site": {"data": {"type": "sites", "id": "default"}}},
+//              "links": {"self": "https://example.com/..."}}],
+//    "meta": {"total": 42, "offset": 0, "limit": 100},
+//    "links": {"self": ..., "first": ..., "next": ..., "last": ...}}
+//
+// Links are absolute with [site] base_url. Other endpoints answer JSON:API
+// requests with 406, see codec.rs
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    changes::PublicComment, codec::EncodeError, config::SiteConfig, state::AppState, Comment,
+};
+
+// The fields of `attributes` but the id and the site, which JSON:API keeps
+// apart
+fn resource(comment: &Comment, attributes: impl Serialize, link: Option<String>) -> Value {
+    let mut attributes = serde_json::to_value(attributes).unwrap_or_default();
+    if let Some(attributes) = attributes.as_object_mut() {
+        attributes.remove("id");
+        attributes.remove("site");
+    }
+    let mut resource = json!({
+        "type": "comments",
+        "id": comment.id,
+        "attributes": attributes,
+        "relationships": {
+            "site": {"data": {"type": "sites", "id": comment.site}},
+        },
+    });
+    if let Some(link) = link {
+        resource["links"] = json!({ "self": link });
+    }
+    resource
+}
+
+// What visitors see of it, linked to its page below the site's `root`
+pub fn public(state: &AppState, root: &str, comment: &Arc<Comment>) -> Value {
+    let link = state.config.site.link(&format!("{}/{}", root, comment.id));
+    resource(comment, PublicComment::new(comment, state), Some(link))
+}
+
+// Everything stored, for moderation
+pub fn stored(comment: &Comment) -> Value {
+    resource(comment, comment, None)
+}
+
+// One page of a listing, with the paths of the pages by relation
+pub fn page(
+    data: Vec<Value>,
+    total: usize,
+    offset: usize,
+    limit: usize,
+    config: &SiteConfig,
+    links: Vec<(&'static str, String)>,
+) -> Value {
+    let links = links
+        .into_iter()
+        .map(|(rel, path)| (rel.to_owned(), Value::String(config.link(&path))))
+        .collect::<serde_json::Map<_, _>>();
+    json!({
+        "data": data,
+        "meta": {"total": total, "offset": offset, "limit": limit},
+        "links": links,
+    })
+}
+
+// For Format::respond
+pub fn serialize(document: &Value) -> Result<Bytes, EncodeError> {
+    serde_json::to_vec(document)
+        .map(Bytes::from)
+        .map_err(|err| EncodeError::Failed(err.to_string()))
+}
@@ -0,0 +1,16 @@
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter, Registry};
+
+// Used to swap the EnvFilter while the server is running
+pub type ReloadHandle = reload::Handle<EnvFilter, Registry>;
+
+// Setup tracing with a reloadable filter taken from RUST_LOG
+pub fn init() -> ReloadHandle {
+    let (filter, handle) = reload::Layer::new(EnvFilter::from_default_env());
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .init();
+
+    handle
+}
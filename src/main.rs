@@ -1,35 +1,192 @@
-use std::{convert::Infallible, net::SocketAddr};
+use std::convert::Infallible;
 
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc, RwLock,
+    },
     time::Duration,
 };
 
 use axum::{
-    body::{Bytes, Full},
+    body::{Body, BoxBody, Bytes, Full},
     error_handling::HandleErrorLayer,
-    extract::{Extension, Path, Query},
+    extract::{Extension, Path},
     handler::Handler,
-    http::{Response, StatusCode},
+    http::{header, HeaderMap, Method, Request, Response, StatusCode},
     response::{Html, IntoResponse},
-    routing::{get, post},
-    Json, Router,
+    routing::{delete, get, post, put},
+    Router,
 };
+#[cfg(feature = "tls")]
 use axum_server::tls_rustls::RustlsConfig;
 use axum_server::Handle;
 
 use tower::{BoxError, ServiceBuilder};
-use tower_http::{add_extension::AddExtensionLayer, trace::TraceLayer};
+use tower_http::{
+    add_extension::AddExtensionLayer, metrics::InFlightRequestsLayer,
+    propagate_header::PropagateHeaderLayer, trace::TraceLayer,
+};
 
 use askama::Template;
 
 use chrono::prelude::*;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+mod admin;
+mod assets;
+mod attachments;
+mod auth_log;
+mod backup;
+mod basic_auth;
+mod blocked_names;
+mod build_info;
+mod changes;
+#[cfg(feature = "chaos")]
+mod chaos;
+#[cfg(feature = "classifier")]
+mod classifier;
+mod cli;
+mod codec;
+mod config;
+#[cfg(unix)]
+mod control;
+mod dashboard;
+#[cfg(feature = "email")]
+mod digest;
+mod domain;
+mod drafts;
+mod drain;
+#[cfg(feature = "email")]
+mod email;
+#[cfg(feature = "sentry")]
+mod error_reporting;
+mod errors;
+mod events;
+mod extract;
+mod filters;
+mod gdpr;
+mod geoip;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hal;
+mod highlight;
+mod html_stream;
+#[cfg(feature = "http3")]
+mod http3;
+mod i18n;
+mod identity;
+mod jsonapi;
+mod logging;
+mod markup;
+mod metrics;
+mod moderation_links;
+mod notifications;
+mod oembed;
+mod page_cache;
+mod pages;
+mod poll;
+mod pow;
+mod preconditions;
+mod previews;
+mod privacy;
+#[cfg(feature = "protobuf")]
+mod proto;
+mod rate_limit;
+mod recording;
+mod redis_store;
+mod replication;
+mod request_id;
+mod rules;
+#[cfg(feature = "s3")]
+mod s3;
+mod schedule;
+mod search;
+mod search_index;
+mod secrets;
+mod self_check;
+mod signing;
+mod similar;
+mod sitemap;
+mod sites;
+#[cfg(feature = "sled")]
+mod sled_store;
+mod spam;
+mod startup;
+mod state;
+mod stats;
+mod storage;
+mod tags;
+mod tarpit;
+mod theme;
+mod timezone;
+#[cfg(feature = "tls")]
+mod tls;
+mod trace_context;
+mod trending;
+#[cfg(feature = "vault")]
+mod vault;
+mod votes;
+#[cfg(feature = "webhooks")]
+mod webhooks;
+
+use attachments::{Attachment, Attachments, WithUploads};
+use auth_log::{AuthLog, Failure};
+use basic_auth::BasicAuth;
+use blocked_names::BlockedNames;
+use changes::Tombstones;
+use codec::{Format, Requested};
+use config::{Config, ModerationMode, SiteConfig, SiteSettings};
+use drain::Drain;
+use errors::ApiError;
+use extract::{ParsedQuery, Validate};
+use geoip::GeoIp;
+use i18n::Locale;
+use identity::{ClientIp, Visitor};
+use markup::{Markup, Mention};
+use notifications::Notifications;
+use page_cache::{ListCache, PageCache, PageKey};
+use pow::{Pow, ProofOfWork};
+use previews::{LinkPreview, Previews};
+use privacy::IpPolicy;
+use rate_limit::RateLimiter;
+use recording::Recorder;
+use redis_store::RedisStore;
+use replication::Replication;
+use request_id::REQUEST_ID_HEADER;
+use rules::Rules;
+use search_index::SearchIndex;
+use secrets::Secrets;
+use signing::Signed;
+use sitemap::SitemapCache;
+use sites::{Site, Sites};
+use spam::{Candidate, SpamFilter, Verdict};
+use startup::Startup;
+use state::{AppState, SharedState};
+use stats::Stats;
+use tarpit::Tarpit;
+use theme::Theme;
+use timezone::{DisplayTimezone, Timezone};
+use trending::Trending;
+use votes::Vote;
+
+#[cfg(feature = "sentry")]
+use error_reporting::SentryLayer;
+// Without the feature the layer does nothing
+#[cfg(not(feature = "sentry"))]
+type SentryLayer = tower::layer::util::Identity;
+
 #[tokio::main]
 async fn main() {
+    // Offline management, see cli.rs
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("admin") {
+        std::process::exit(cli::run(&args[1..]));
+    }
 
     // Set the RUST_LOG, if it hasn't been explicitly defined
     if std::env::var_os("RUST_LOG").is_none() {
@@ -39,173 +196,1413 @@ async fn main() {
     println!("RUST_LOG = {:?}", &tmp);
 
     // Setup tracing
-    tracing_subscriber::fmt::init();
+    let log_reload = logging::init();
 
-    let db = Db::default();
+    let mut config = Config::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+
+    // Over the config, before anything uses them
+    let secrets_provider = secrets::provider(&config.vault).unwrap_or_else(|err| {
+        tracing::error!("{} (see [vault] in the config)", err);
+        std::process::exit(1);
+    });
+    if let Some(provider) = &secrets_provider {
+        let fetched = provider.fetch().await.unwrap_or_else(|err| {
+            tracing::error!(
+                "failed to fetch secrets: {} (see [vault] in the config)",
+                err
+            );
+            std::process::exit(1);
+        });
+        if let Err(err) = secrets::apply(&mut config, &fetched) {
+            tracing::error!("failed to apply secrets: {}", err);
+            std::process::exit(1);
+        }
+        tracing::debug!("{} secrets fetched", fetched.len());
+    }
+
+    // Keep the guard alive so queued events are sent before exiting
+    #[cfg(feature = "sentry")]
+    let _sentry = error_reporting::init(&config.sentry);
+
+    // Listen right away, see startup.rs
+    let handle = Handle::new();
+    let startup = Startup::new();
+    #[cfg(feature = "tls")]
+    let tls_config = tls::server_config(&config.tls).map_or_else(
+        |err| {
+            tracing::error!("{} (see [tls] in the config)", err);
+            std::process::exit(1);
+        },
+        RustlsConfig::from_config,
+    );
+    let server = {
+        let addr = config.addr;
+        let app = Router::new()
+            .fallback(startup.clone())
+            .into_make_service_with_connect_info::<SocketAddr, _>();
+        tracing::debug!(
+            "listening on {} (version {}, commit {})",
+            addr,
+            build_info::BUILD_INFO.version,
+            build_info::BUILD_INFO.git_commit
+        );
+
+        // HTTPS (HTTP/2) communication
+        #[cfg(feature = "tls")]
+        let server = axum_server::bind_rustls(addr, tls_config.clone())
+            .handle(handle.clone())
+            .serve(app);
+        // Plain HTTP, TLS is expected to be terminated in front of us
+        #[cfg(not(feature = "tls"))]
+        let server = axum_server::bind(addr).handle(handle.clone()).serve(app);
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                tracing::error!("failed to listen on {}: {}", addr, err);
+                std::process::exit(1);
+            }
+        })
+    };
+
+    // Spawn a task to shutdown server.
+    tokio::spawn(graceful_shutdown(handle.clone()));
+
+    let (storage, mut contents) = storage::open_with_retry(&config.storage, &startup)
+        .await
+        .unwrap_or_else(|err| {
+            tracing::error!("failed to load comments: {}", err);
+            std::process::exit(1);
+        });
+    startup.waiting_for("starting".to_owned());
+    let redis = RedisStore::connect(&config, &mut contents.comments)
+        .await
+        .unwrap_or_else(|err| {
+            tracing::error!("{} (see [redis] in the config)", err);
+            std::process::exit(1);
+        });
+    let stats = Stats::new(contents.comments.values().map(Arc::as_ref));
+    let trending = Trending::new(contents.comments.values().map(Arc::as_ref));
+    let search_index =
+        SearchIndex::open(&config.search, contents.comments.values().map(Arc::as_ref))
+            .unwrap_or_else(|err| {
+                tracing::error!("{} (see [search] in the config)", err);
+                std::process::exit(1);
+            });
+    let tombstones = Tombstones::new(contents.tombstones);
+    let db = Db::new(contents.comments);
+
+    let theme = Theme::load(&config.theme).unwrap_or_else(|err| {
+        tracing::error!("{} (see [theme] in the config)", err);
+        std::process::exit(1);
+    });
+
+    // Fail fast on broken templates, certificates or storage
+    if let Err(errors) = self_check::run(&config, &storage) {
+        for error in errors {
+            tracing::error!("startup check failed: {}", error);
+        }
+        std::process::exit(1);
+    }
+
+    let sites = Sites::new(&config, contents.sites);
+    let rules = Arc::new(Rules::new(config.rules.clone(), contents.rules));
+    let blocked_names = BlockedNames::new(config.blocked_names.clone(), contents.blocked_names);
+    let notifications = Notifications::new(config.notifications.clone(), contents.notifications);
+    let spam = SpamFilter::new(&config.spam, rules.clone());
+    let ip_policy = IpPolicy::new(&config.privacy);
+    let recorder = Recorder::new(&config.recording);
+    let tarpit = Tarpit::new(&config.tarpit);
+    let basic_auth = BasicAuth::new(&config.basic_auth);
+    let auth_log = AuthLog::open(&config.auth_log).unwrap_or_else(|err| {
+        tracing::error!("{} (see [auth_log] in the config)", err);
+        std::process::exit(1);
+    });
+    let pow = Pow::new(&config.pow);
+    let secrets = Secrets::new(&config);
+    let markup = Arc::new(Markup::new(&config.display));
+    let previews = Previews::new(&config.previews);
+    let attachments = Attachments::open(&config.attachments).unwrap_or_else(|err| {
+        tracing::error!("{} (see [attachments] in the config)", err);
+        std::process::exit(1);
+    });
+    let geoip = GeoIp::open(&config.geoip).unwrap_or_else(|err| {
+        tracing::error!("{} (see [geoip] in the config)", err);
+        std::process::exit(1);
+    });
+
+    let state = Arc::new(AppState {
+        db,
+        storage,
+        sites,
+        config,
+        theme,
+        log_reload,
+        sitemap: SitemapCache::new(),
+        pages: PageCache::new(),
+        lists: ListCache::new(),
+        rate_limiter: RateLimiter::new(),
+        tarpit,
+        basic_auth,
+        auth_log,
+        pow,
+        secrets,
+        ip_policy,
+        geoip,
+        rules,
+        blocked_names,
+        notifications,
+        spam,
+        stats,
+        trending,
+        search_index,
+        tombstones,
+        recorder,
+        maintenance: AtomicBool::new(false),
+        drain: Drain::new(),
+        tls_expires: AtomicI64::new(0),
+        replication: Replication::default(),
+        redis,
+        published: tokio::sync::Notify::new(),
+        markup,
+        attachments,
+        previews,
+        hooks: domain::Hooks::new(),
+    });
+
+    let chaos_enabled = state.config.chaos.is_enabled();
+    #[cfg(feature = "chaos")]
+    let chaos = chaos_enabled.then(|| {
+        tracing::warn!("[chaos] is on, requests and snapshot writes will fail on purpose");
+        chaos::ChaosLayer::new(state.config.chaos.clone())
+    });
+    #[cfg(not(feature = "chaos"))]
+    let chaos: Option<tower::layer::util::Identity> = {
+        if chaos_enabled {
+            tracing::warn!("[chaos] is set, but little-nova was built without the `chaos` feature");
+        }
+        None
+    };
 
     let app = Router::new()
         .route("/", get(get_comment_entries))
         .route("/create", post(create_comment))
+        .route("/challenge", get(pow::get_challenge))
+        .route("/version", get(build_info::get_version))
+        .route("/ready", get(drain::get_ready))
+        .route("/tags", get(tags::get_tag_cloud))
+        .route("/trending", get(trending::get_trending))
+        .route("/search", get(search::search))
+        .route("/changes", get(changes::get_changes))
+        .route("/poll", get(poll::poll))
+        .route("/theme.css", get(theme::get_theme_css))
+        .route("/embed/:slug", get(pages::get_embed))
+        .route("/static/embed.js", get(pages::get_embed_loader))
+        .route("/count/:slug", get(pages::get_comment_count))
+        .route("/oembed", get(oembed::get_oembed))
+        .route("/sitemap.xml", get(sitemap::get_sitemap))
+        .route("/robots.txt", get(assets::get_robots_txt))
+        .route("/favicon.ico", get(assets::get_favicon))
+        .route("/favicon.svg", get(assets::get_favicon))
+        .route("/attachments/:key", get(attachments::get_attachment))
+        .route("/mine/drafts", get(drafts::get_drafts))
+        .route("/mine/drafts/:id/publish", post(drafts::publish_draft))
         .route("/:id", get(get_comment))
+        .route("/:id/vote", post(votes::vote))
+        .route("/:id/similar", get(similar::get_similar))
+        .route(
+            "/moderate/:id/:action",
+            get(moderation_links::get_moderate).post(moderation_links::moderate),
+        )
+        .route("/admin/log-level", put(admin::set_log_level))
+        .route("/admin/drain", post(drain::drain))
+        .route(
+            "/admin/backup",
+            get(backup::download_backup).post(backup::save_backup),
+        )
+        .route("/admin/restore", post(backup::restore_backup))
+        .route("/admin/replication", get(replication::get_replication))
+        .route("/admin/comments", get(admin::get_comments))
+        .route("/admin/comments/bulk", post(admin::moderate_comments))
+        .route("/admin/comments/:id", delete(admin::delete_comment))
+        .route("/admin/comments/:id/approve", post(admin::approve_comment))
+        .route("/admin/comments/:id/history", get(events::get_history))
+        .route(
+            "/admin/comments/:id/schedule",
+            put(schedule::schedule_comment),
+        )
+        .route("/admin", get(dashboard::get_dashboard))
+        .route("/admin/charts", get(dashboard::get_charts))
+        .route("/admin/stats", get(stats::get_stats))
+        .route("/admin/stats/summary", get(stats::get_admin_summary))
+        .route("/api/v1/comments/summary", get(stats::get_summary))
+        .route("/admin/metrics", get(metrics::get_metrics))
+        .route("/admin/export", get(gdpr::export))
+        .route("/admin/erase", post(gdpr::erase))
+        .route("/admin/sites", get(sites::get_sites))
+        .route(
+            "/admin/sites/:key",
+            get(sites::get_site)
+                .put(sites::put_site)
+                .delete(sites::delete_site),
+        )
+        .route("/admin/rules", get(rules::get_rules).post(rules::add_rule))
+        .route("/admin/rules/:id", delete(rules::delete_rule))
+        .route(
+            "/admin/blocked-names",
+            get(blocked_names::get_blocked_names).post(blocked_names::add_blocked_name),
+        )
+        .route(
+            "/admin/blocked-names/:id",
+            delete(blocked_names::delete_blocked_name),
+        )
+        .route(
+            "/admin/notifications",
+            get(notifications::get_notifications)
+                .put(notifications::put_notifications)
+                .delete(notifications::delete_notifications),
+        )
+        .route(
+            "/admin/recent-requests",
+            get(recording::get_recent_requests)
+                .put(recording::set_recording)
+                .delete(recording::clear_recent_requests),
+        )
         // Add a handler_404 for routes to unknown paths
         .fallback(handler_404.into_service())
         // Add middleware to all routes
         .layer(
             ServiceBuilder::new()
+                .layer(InFlightRequestsLayer::new(state.drain.in_flight.clone()))
+                .map_request(request_id::set_request_id)
+                .layer(PropagateHeaderLayer::new(REQUEST_ID_HEADER.clone()))
+                .layer(SentryLayer::new())
+                .layer(errors::ProblemLayer::new(state.config.errors.problem_json))
                 .layer(HandleErrorLayer::new(|error: BoxError| {
                     if error.is::<tower::timeout::error::Elapsed>() {
-                        Ok(StatusCode::REQUEST_TIMEOUT)
+                        ApiError::new(
+                            StatusCode::REQUEST_TIMEOUT,
+                            "server.timeout",
+                            "The request took too long",
+                        )
+                    } else if let Some(read_only) = error.downcast_ref::<ReadOnly>() {
+                        ApiError::new(
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            read_only.code(),
+                            read_only.to_string(),
+                        )
                     } else {
-                        Err((
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("Unhandled internal error: {}", error),
-                        ))
+                        tracing::error!("unhandled internal error: {}", error);
+                        ApiError::internal("Unhandled internal error")
                     }
                 }))
-                .timeout(Duration::from_secs(10))
-                .layer(TraceLayer::new_for_http())
-                .layer(AddExtensionLayer::new(db))
+                .timeout(REQUEST_TIMEOUT)
+                .filter({
+                    let state = state.clone();
+                    move |req| refuse_writes(&state, req)
+                })
+                .layer(trace_context::TraceContextLayer::new(&state.config.tracing))
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(trace_context::make_span)
+                        .on_request(trace_context::on_request)
+                        .on_response(trace_context::on_response),
+                )
+                .layer(recording::RecordingLayer::new(state.clone()))
+                .layer(tarpit::TarpitLayer::new(state.clone()))
+                .layer(basic_auth::BasicAuthLayer::new(state.clone()))
+                .option_layer(chaos)
+                .layer(AddExtensionLayer::new(state.clone()))
+                .layer(AddExtensionLayer::new(handle.clone()))
                 .into_inner(),
         );
 
-    // run it
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    // The site prefix has to be gone before the router matches the path,
+    // so every request goes through the fallback of an outer router
+    // Points clients which speak HTTP/3 at the QUIC listener
+    #[cfg(feature = "http3")]
+    let alt_svc = state.config.http3.addr.map(http3::alt_svc);
+    #[cfg(not(feature = "http3"))]
+    let alt_svc: Option<header::HeaderValue> = None;
 
-    tracing::debug!("listening on {}", addr);
+    let app = Router::new().fallback(
+        ServiceBuilder::new()
+            .map_request(sites::resolve_site)
+            .map_response(move |mut response: Response<BoxBody>| {
+                if let Some(alt_svc) = &alt_svc {
+                    response
+                        .headers_mut()
+                        .insert(header::ALT_SVC, alt_svc.clone());
+                }
+                response
+            })
+            .service(app),
+    );
+    // HTTP/3 serves the same router, see http3.rs
+    #[cfg(feature = "http3")]
+    let router = app.clone();
 
-    // Rustls
-    // Need private key and crt file
-    let config = RustlsConfig::from_pem_file("./certs/server.crt", "./certs/server.key")
-        .await
-        .unwrap();
+    // Compacting waits for the followers of the event log from the start
+    #[cfg(feature = "email")]
+    if state.config.email.smtp_url.is_some() {
+        state.storage.events.hold("email", 0);
+    }
+    #[cfg(feature = "webhooks")]
+    if state.config.webhooks.url.is_some() {
+        state.storage.events.hold("webhooks", 0);
+    }
 
-    let handle = Handle::new();
+    // Spawn a task to save comments in the background
+    tokio::spawn(storage::flush_periodically(state.clone()));
+    #[cfg(feature = "redis")]
+    tokio::spawn(redis_store::run(state.clone()));
+    tokio::spawn(stats::sample_queue_periodically(state.clone()));
+    tokio::spawn(sites::purge_expired_periodically(state.clone()));
+    tokio::spawn(schedule::publish_scheduled_periodically(state.clone()));
+    #[cfg(feature = "previews")]
+    tokio::spawn(previews::fetch_queued(state.clone()));
+    if let Some(provider) = secrets_provider {
+        tokio::spawn(secrets::refresh(state.clone(), provider));
+    }
 
-    // Spawn a task to shutdown server.
-    tokio::spawn(graceful_shutdown(handle.clone()));
+    if let Some(grpc_addr) = state.config.grpc.addr {
+        #[cfg(feature = "grpc")]
+        tokio::spawn(grpc::serve(state.clone(), grpc_addr));
+        #[cfg(not(feature = "grpc"))]
+        tracing::warn!(
+            "[grpc] addr {} is set, but little-nova was built without the `grpc` feature",
+            grpc_addr
+        );
+    }
 
-    // HTTPS (HTTP/2) communication
-    axum_server::bind_rustls(addr, config)
-        .handle(handle)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    if let Some(path) = &state.config.control.socket {
+        #[cfg(unix)]
+        match control::bind(path) {
+            Ok(listener) => {
+                tracing::debug!("control socket listening on {}", path.display());
+                tokio::spawn(control::serve(listener, state.clone(), handle.clone()));
+            }
+            Err(err) => {
+                tracing::error!(
+                    "failed to open the control socket {}: {} (see [control] in the config)",
+                    path.display(),
+                    err
+                );
+                std::process::exit(1);
+            }
+        }
+        #[cfg(not(unix))]
+        tracing::warn!(
+            "[control] socket {} is set, but unix sockets are not available here",
+            path.display()
+        );
+    }
 
-}
+    if let Some(primary) = &state.config.replication.primary {
+        #[cfg(feature = "replication")]
+        {
+            tracing::debug!("replicating from {}", primary);
+            tokio::spawn(replication::follow(state.clone(), primary.clone()));
+        }
+        #[cfg(not(feature = "replication"))]
+        tracing::warn!(
+            "[replication] primary {} is set, but little-nova was built without the `replication` feature",
+            primary
+        );
+    }
 
-//Structure for create comment
-#[derive(Debug, Deserialize)]
-struct CreateTodo {
-    text: String,
+    #[cfg(feature = "email")]
+    if let Some(url) = &state.config.email.smtp_url {
+        tokio::spawn(email::deliver(state.clone(), url.clone()));
+        if let Some(frequency) = state.config.email.digest {
+            tokio::spawn(digest::send_periodically(
+                state.clone(),
+                url.clone(),
+                frequency,
+            ));
+        }
+    }
+    #[cfg(not(feature = "email"))]
+    if state.config.email.smtp_url.is_some() {
+        // Without the URL, it may hold the password
+        tracing::warn!(
+            "[email] smtp_url is set, but little-nova was built without the `email` feature"
+        );
+    }
+
+    if let Some(url) = &state.config.webhooks.url {
+        #[cfg(feature = "webhooks")]
+        tokio::spawn(webhooks::deliver(state.clone(), url.clone()));
+        #[cfg(not(feature = "webhooks"))]
+        tracing::warn!(
+            "[webhooks] url {} is set, but little-nova was built without the `webhooks` feature",
+            url
+        );
+    }
+
+    if let Some(http3_addr) = state.config.http3.addr {
+        #[cfg(feature = "http3")]
+        match http3::bind(http3_addr, &state.config.tls) {
+            Ok(endpoint) => {
+                tracing::debug!("HTTP/3 listening on {}", http3_addr);
+                tokio::spawn(http3::serve(endpoint, router));
+            }
+            Err(err) => {
+                tracing::error!(
+                    "failed to start HTTP/3: {} (see [http3] in the config)",
+                    err
+                );
+                std::process::exit(1);
+            }
+        }
+        #[cfg(not(feature = "http3"))]
+        tracing::warn!(
+            "[http3] addr {} is set, but little-nova was built without the `http3` feature",
+            http3_addr
+        );
+    }
+
+    #[cfg(feature = "tls")]
+    {
+        state.secrets.serve_tls(tls_config);
+        tls::inspect(&state);
+        tokio::spawn(tls::watch(state.clone()));
+    }
+
+    // Until it shuts down
+    startup.serve(app);
+    if let Err(err) = server.await {
+        tracing::error!("the server failed: {}", err);
+    }
+
+    // Save what the last requests changed
+    if let Err(err) = state.storage.flush(&state) {
+        tracing::error!("failed to save comments: {}", err);
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &state.config.control.socket {
+        let _ = std::fs::remove_file(path);
+    }
 }
 
+// Number of comments per index page unless ?limit= is given
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+// Pages with more comments are streamed, see html_stream.rs
+const STREAM_ENTRIES: usize = 1000;
+
+// Requests taking longer are answered with 408
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
 // The query parameters for comment index
-#[derive(Debug, Deserialize, Default)]
+// Serialized again for the prev/next links
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Pagination {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub offset: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub limit: Option<usize>,
+    // Only list comments with this tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<Sort>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    #[default]
+    Newest,
+    // Highest score first, see votes.rs
+    Top,
+}
+
+impl Pagination {
+    // Link to the same listing starting at `offset`, below the site's `root`
+    fn href(&self, root: &str, offset: usize) -> String {
+        let query = Pagination {
+            // Left out on the first page so it links to the same URL as "/"
+            offset: Some(offset).filter(|offset| *offset > 0),
+            ..self.clone()
+        };
+        match serde_urlencoded::to_string(&query).unwrap_or_default() {
+            query if query.is_empty() => format!("{}/", root),
+            query => format!("{}/?{}", root, query),
+        }
+    }
+
+    // Paths of the listing's page at `offset` and those around it, by relation
+    fn links(
+        &self,
+        root: &str,
+        total: usize,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<(&'static str, String)> {
+        let mut pages = vec![
+            ("self", offset),
+            ("first", 0),
+            ("last", total.saturating_sub(1) / limit * limit),
+        ];
+        if offset > 0 {
+            pages.push(("prev", offset.saturating_sub(limit)));
+        }
+        if offset + limit < total {
+            pages.push(("next", offset + limit));
+        }
+        pages
+            .into_iter()
+            .map(|(rel, offset)| (rel, self.href(root, offset)))
+            .collect()
+    }
 }
 
 async fn get_comment(
     Path(id): Path<Uuid>,
-    Extension(db): Extension<Db>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let comment = db
+    site: Site,
+    i18n: Locale,
+    DisplayTimezone(tz): DisplayTimezone,
+    Requested(requested): Requested,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ErrorPage> {
+    if requested == Some(Format::JsonApi) {
+        return Ok(comment_resource(&state, &site, id));
+    }
+
+    let comment = state
+        .db
         .read()
         .unwrap()
         .get(&id)
+        .filter(|comment| comment.is_listed(&site.key))
         .cloned()
-        .ok_or(StatusCode::NOT_FOUND)?;
+        .ok_or(ErrorPage::not_found(i18n))?;
+    trace_context::record_comment(comment.id, comment.slug.as_deref());
 
+    // Offset of the index page this comment is listed on
+    let position = newest_first(
+        state
+            .db
+            .read()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.is_listed(&site.key)),
+    )
+    .iter()
+    .position(|entry| entry.id == id)
+    .unwrap_or(0);
+    let index_offset = position / DEFAULT_PAGE_SIZE * DEFAULT_PAGE_SIZE;
+
+    let score = comment.score();
     let id = comment.id;
-    let name = comment.name;
-    let text = comment.text;
+    let title = comment.title.clone();
+    let name = comment.name.clone();
+    let text = comment.text.clone();
+    let text_html = comment.text_html(&site.root, &state.markup);
     let utc = comment.utc;
+    let tags = comment.tags.clone();
+
+    let meta = PageMeta::new(
+        &state.config.site,
+        title.as_deref().unwrap_or(&name),
+        &text,
+        &format!("{}/{}", site.root, id),
+    )
+    .with_oembed();
 
     let template = CommentTemplate {
+        meta,
+        root: site.root,
         id,
+        title,
         name,
-        text,
+        text_html,
         utc,
+        tags,
+        score,
+        index_offset,
+        tz,
+        visitor_timezone: state.config.display.visitor_timezone,
+        i18n,
     };
 
     Ok(HtmlTemplate(template).into_response())
 }
 
 async fn get_comment_entries(
-    pagination: Option<Query<Pagination>>, // Query string
-    Extension(db): Extension<Db>,
-) -> impl IntoResponse {
-    let comment = db.read().unwrap();
+    pagination: Option<ParsedQuery<Pagination>>, // Query string
+    site: Site,
+    i18n: Locale,
+    DisplayTimezone(tz): DisplayTimezone,
+    Requested(requested): Requested,
+    Extension(state): Extension<SharedState>,
+) -> Response<BoxBody> {
+    let ParsedQuery(mut pagination) = pagination.unwrap_or_default();
+    let tag = pagination.tag.as_ref().map(|tag| tag.trim().to_lowercase());
+    pagination.tag = tag.clone();
 
-    let Query(pagination) = pagination.unwrap_or_default();
+    if let Some(format) = requested {
+        return comment_list(&state, &site, &pagination, format).map(axum::body::boxed);
+    }
 
-    let total = comment.len();
+    // Read before the comments, see PageCache::insert
+    let revision = state.storage.revision();
+    let key = PageKey {
+        site: site.key.clone(),
+        root: site.root.clone(),
+        locale: i18n,
+        tz,
+        href: pagination.href(&site.root, pagination.offset.unwrap_or(0)),
+    };
+    if let Some(html) = state.pages.get(&key, revision) {
+        return Html(html).into_response().map(axum::body::boxed);
+    }
+
+    let comment = state.db.read().unwrap();
+    let sort = pagination.sort.unwrap_or_default();
+    let matching = index_entries(&comment, &site.key, tag.as_ref(), sort);
+
+    let total = matching.len();
+    let offset = pagination.offset.unwrap_or(0);
+    // A page always holds at least one comment
+    let page_size = pagination.limit.unwrap_or(DEFAULT_PAGE_SIZE).max(1);
 
-    let mut comment_entries = comment
-        .values()
+    // Sort before paging so every page continues where the previous one ended
+    let entries = matching
+        .into_iter()
+        .skip(offset)
+        .take(page_size)
         .cloned()
-        .skip(pagination.offset.unwrap_or(0))
-        .take(pagination.limit.unwrap_or(100_usize))
         .collect::<Vec<_>>();
-    // Sort by newest transmission date  (descending order)
-    comment_entries.sort_by(|a, b| b.utc.cmp(&a.utc));
-    let entries = comment_entries;
-    let template = CommentEntriesTemplate { total, entries };
 
-    HtmlTemplate(template).into_response()
+    let prev_href =
+        (offset > 0).then(|| pagination.href(&site.root, offset.saturating_sub(page_size)));
+    let next_href =
+        (offset + page_size < total).then(|| pagination.href(&site.root, offset + page_size));
+
+    let page_title = match &tag {
+        Some(tag) => format!("#{}", tag),
+        None => state.config.site.name.clone(),
+    };
+    let meta = PageMeta::new(
+        &state.config.site,
+        &page_title,
+        &i18n.tn("comments.count", total),
+        &pagination.href(&site.root, offset),
+    );
+
+    // The other order, from the first page
+    let other_sort = match sort {
+        Sort::Newest => Sort::Top,
+        Sort::Top => Sort::Newest,
+    };
+    let sort_href = Pagination {
+        sort: Some(other_sort).filter(|sort| *sort != Sort::default()),
+        ..pagination.clone()
+    }
+    .href(&site.root, 0);
+
+    let template = CommentEntriesTemplate {
+        meta,
+        root: site.root,
+        total,
+        entries,
+        tag,
+        sort,
+        sort_href,
+        page: offset / page_size + 1,
+        page_size,
+        total_pages: total.div_ceil(page_size).max(1),
+        prev_href,
+        next_href,
+        markup: state.markup.clone(),
+        tz,
+        visitor_timezone: state.config.display.visitor_timezone,
+        i18n,
+    };
+    drop(comment);
+
+    // Not cached either, holding on to the page is what streaming avoids
+    if template.entries.len() > STREAM_ENTRIES {
+        return html_stream::render(template).map(axum::body::boxed);
+    }
+
+    let response = match template.render() {
+        Ok(html) => {
+            state.pages.insert(key, revision, html.clone());
+            Html(html).into_response()
+        }
+        Err(err) => template_error(err),
+    };
+    response.map(axum::body::boxed)
+}
+
+// The index as JSON or MessagePack, for clients which render comments
+// themselves. The first page is what they poll, so its body is kept until
+// the next write
+fn comment_list(
+    state: &AppState,
+    site: &Site,
+    pagination: &Pagination,
+    format: Format,
+) -> Response<Full<Bytes>> {
+    // The default listing, no offset, tag, sort or page size in the query
+    let offset = pagination.offset.unwrap_or(0);
+    let first_page = pagination.href(&site.root, offset) == format!("{}/", site.root);
+    let limit = pagination.limit.unwrap_or(DEFAULT_PAGE_SIZE).max(1);
+    let revision = state.storage.revision();
+    if first_page {
+        if let Some((body, total)) = state.lists.get(&site.key, format, revision) {
+            let links = pagination.links(&site.root, total, offset, limit);
+            let headers = page_headers(state, site, total, &links);
+            return (headers, format.respond(Ok(body))).into_response();
+        }
+    }
+
+    let comment = state.db.read().unwrap();
+    let sort = pagination.sort.unwrap_or_default();
+    let matching = index_entries(&comment, &site.key, pagination.tag.as_ref(), sort);
+    let total = matching.len();
+    let links = pagination.links(&site.root, total, offset, limit);
+    let page = matching.iter().skip(offset).take(limit);
+    let body = match format {
+        Format::JsonApi => jsonapi::serialize(&jsonapi::page(
+            page.map(|entry| jsonapi::public(state, &site.root, entry))
+                .collect(),
+            total,
+            offset,
+            limit,
+            &state.config.site,
+            links.clone(),
+        )),
+        _ => format.serialize(&serde_json::json!({
+            "total": total,
+            "offset": offset,
+            "limit": limit,
+            "comments": page
+                .map(|entry| {
+                    let comment = changes::PublicComment::new(entry, state);
+                    hal::public(&state.config.site, &site.root, entry, comment)
+                })
+                .collect::<Vec<_>>(),
+            "_links": hal::links(&state.config.site, links.clone()),
+        })),
+    };
+    drop(comment);
+
+    if let (true, Ok(body)) = (first_page, &body) {
+        state
+            .lists
+            .insert(&site.key, format, revision, body.clone(), total);
+    }
+    let headers = page_headers(state, site, total, &links);
+    (headers, format.respond(body)).into_response()
+}
+
+// X-Total-Count and an RFC 8288 Link header, as admin UIs like react-admin
+// expect of listings
+fn page_headers(
+    state: &AppState,
+    site: &Site,
+    total: usize,
+    links: &[(&'static str, String)],
+) -> HeaderMap {
+    let mut headers = site.cors_headers();
+    headers.insert("x-total-count", header::HeaderValue::from(total));
+    let link = links
+        .iter()
+        .filter(|(rel, _)| *rel != "self")
+        .map(|(rel, path)| format!("<{}>; rel=\"{}\"", state.config.site.link(path), rel))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(link) = header::HeaderValue::from_str(&link) {
+        headers.insert(header::LINK, link);
+    }
+    // Readable by scripts on other origins too
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        header::HeaderValue::from_static("x-total-count, link"),
+    );
+    headers
 }
 
-#[derive(Debug, Deserialize)]
+// The comment as a JSON:API document, for get_comment
+fn comment_resource(state: &AppState, site: &Site, id: Uuid) -> Response<Full<Bytes>> {
+    let comments = state.db.read().unwrap();
+    let comment = match comments
+        .get(&id)
+        .filter(|comment| comment.is_listed(&site.key))
+    {
+        Some(comment) => comment,
+        None => {
+            return ApiError::new(
+                StatusCode::NOT_FOUND,
+                "comment.not_found",
+                "No such comment",
+            )
+            .into_response()
+        }
+    };
+    let body = jsonapi::serialize(&serde_json::json!({
+        "data": jsonapi::public(state, &site.root, comment),
+    }));
+    drop(comments);
+    (site.cors_headers(), Format::JsonApi.respond(body)).into_response()
+}
+
+// Listed comments of a site, with `tag` if given, in the index's order
+fn index_entries<'a>(
+    comments: &'a HashMap<Uuid, Arc<Comment>>,
+    site: &str,
+    tag: Option<&String>,
+    sort: Sort,
+) -> Vec<&'a Arc<Comment>> {
+    let mut matching = newest_first(
+        comments
+            .values()
+            .filter(|entry| entry.is_listed(site))
+            .filter(|entry| tag.is_none_or(|tag| entry.tags.contains(tag))),
+    );
+    if sort == Sort::Top {
+        // Stable, so comments with the same score stay newest first
+        matching.sort_by_key(|entry| std::cmp::Reverse(entry.score()));
+    }
+    matching
+}
+
+// Sort by newest transmission date (descending order)
+// The id breaks ties so the order is stable between requests
+fn newest_first<'a>(comments: impl Iterator<Item = &'a Arc<Comment>>) -> Vec<&'a Arc<Comment>> {
+    let mut comments = comments.collect::<Vec<_>>();
+    comments.sort_by(|a, b| b.utc.cmp(&a.utc).then_with(|| b.id.cmp(&a.id)));
+    comments
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 struct CreateComment {
+    #[serde(default)]
+    title: Option<String>,
     name: String,
     text: String,
     utc: DateTime<Utc>,
+    #[serde(default)]
+    tags: Vec<String>,
+    // Page of the embedding site, see pages.rs
+    #[serde(default)]
+    slug: Option<String>,
+    // Never shown, see identity.rs
+    #[serde(default)]
+    email: Option<String>,
+    // Honeypot, hidden from people, see spam.rs
+    #[serde(default)]
+    website: String,
+    // Saved for the commenter only, see drafts.rs
+    #[serde(default)]
+    draft: bool,
+}
+
+// In characters. The title's is [comments] max_title_len, see insert_comment
+const MAX_NAME_LEN: usize = 100;
+const MAX_TEXT_LEN: usize = 10_000;
+
+impl Validate for CreateComment {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        if self.name.trim().is_empty() {
+            return Err(("comment.empty_name", "The name is empty".to_owned()));
+        }
+        if self.name.chars().count() > MAX_NAME_LEN {
+            return Err((
+                "comment.name_too_long",
+                format!("Name is longer than {} characters", MAX_NAME_LEN),
+            ));
+        }
+        if self.text.trim().is_empty() {
+            return Err(("comment.empty_text", "The text is empty".to_owned()));
+        }
+        if self.text.chars().count() > MAX_TEXT_LEN {
+            return Err((
+                "comment.text_too_long",
+                format!("Text is longer than {} characters", MAX_TEXT_LEN),
+            ));
+        }
+        Ok(())
+    }
 }
 
 async fn create_comment(
-    Json(input): Json<CreateComment>,
-    Extension(db): Extension<Db>,
-) -> impl IntoResponse {
+    site: Site,
+    visitor: Visitor,
+    ClientIp(client_ip): ClientIp,
+    format: Format,
+    // Refused early without them, see signing.rs and pow.rs
+    (_, proof): (Signed, ProofOfWork),
+    WithUploads(input, uploads): WithUploads<CreateComment>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    if !site.allows_origin() {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "site.origin_not_allowed",
+            "Comments can't be posted from this origin",
+        ));
+    }
+    if let Some(per_minute) = site.settings.rate_limit_per_minute {
+        if !state.rate_limiter.allow(&site.key, client_ip, per_minute) {
+            state.tarpit.strike(client_ip, "rate limit");
+            state
+                .auth_log
+                .failure(Failure::RateLimit, Some(client_ip), "POST", "/create");
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "comment.rate_limited",
+                "Too many comments, try again in a minute",
+            ));
+        }
+    }
+
+    let attachments = state.attachments.store(uploads).await?;
+    let comment = insert_comment(
+        &state,
+        &site.key,
+        &site.settings,
+        input,
+        attachments.clone(),
+        Some(visitor.token),
+        Some(client_ip),
+        Some(&proof),
+    )
+    .await
+    .inspect_err(|_| state.attachments.remove(&attachments))?;
+
+    let mut headers = HeaderMap::new();
+    if let Some(cookie) = visitor.set_cookie() {
+        headers.insert(header::SET_COOKIE, cookie);
+    }
+
+    // The address is for admins only, even the commenter doesn't get it back
     let comment = Comment {
-        id: Uuid::new_v4(),
+        ip: None,
+        country: None,
+        spam_score: None,
+        ..comment
+    };
+
+    Ok((StatusCode::CREATED, headers, format.encode(comment)))
+}
+
+// Validate, check and store a new comment, for the HTTP and gRPC APIs
+// Comments from other services have no visitor, client address or proof of
+// work
+#[allow(clippy::too_many_arguments)]
+async fn insert_comment(
+    state: &AppState,
+    site: &str,
+    settings: &SiteSettings,
+    input: CreateComment,
+    attachments: Vec<Attachment>,
+    visitor: Option<Uuid>,
+    client_ip: Option<IpAddr>,
+    proof: Option<&ProofOfWork>,
+) -> Result<Comment, ApiError> {
+    // A blank title is the same as no title
+    let title = input
+        .title
+        .map(|title| title.trim().to_owned())
+        .filter(|title| !title.is_empty());
+    let max_title_len = state.config.comments.max_title_len;
+    if let Some(title) = &title {
+        if title.chars().count() > max_title_len {
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "comment.title_too_long",
+                format!("Title is longer than {} characters", max_title_len),
+            ));
+        }
+    }
+
+    let invalid = |code| move |err| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, code, err);
+    let tags = tags::normalize(input.tags).map_err(invalid("comment.invalid_tags"))?;
+    let slug = pages::normalize(input.slug).map_err(invalid("comment.invalid_slug"))?;
+    let email = identity::normalize_email(input.email).map_err(invalid("comment.invalid_email"))?;
+    if settings.is_closed(slug.as_deref()) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "page.closed",
+            "Comments are closed for this page",
+        ));
+    }
+    if state.blocked_names.is_blocked(site, &input.name) {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "comment.name_blocked",
+            format!("The name \"{}\" can't be used here", input.name),
+        ));
+    }
+    // Spam still costs the proof
+    if let Some(proof) = proof {
+        proof.spend(state, client_ip)?;
+    }
+
+    let spam_score = state
+        .spam
+        .score(&Candidate {
+            site,
+            title: title.as_deref(),
+            name: &input.name,
+            text: &input.text,
+            honeypot: &input.website,
+        })
+        .await;
+    let verdict = state.spam.verdict(spam_score);
+    if verdict == Verdict::Reject {
+        tracing::info!(site, spam_score, "comment rejected as spam");
+        trace_context::record_moderation(domain::State::Rejected, Some(spam_score));
+        state.stats.reject(site, Utc::now());
+        // For the notifications, see notifications.rs
+        let event = events::CommentEvent::Refused {
+            id: state.config.comments.id_version.new_id(),
+            site: site.to_owned(),
+            slug: slug.clone(),
+            spam_score,
+        };
+        if let Err(err) = events::commit(state, &mut state.db.write().unwrap(), event) {
+            tracing::error!("failed to record a refused comment: {}", err);
+        }
+        if let Some(client_ip) = client_ip {
+            state.tarpit.strike(client_ip, "spam");
+        }
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "comment.spam",
+            "The comment was rejected as spam",
+        ));
+    }
+
+    if input.draft && visitor.is_none() {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "comment.draft_without_visitor",
+            "Drafts need the visitor cookie",
+        ));
+    }
+
+    let country = client_ip.and_then(|client_ip| state.geoip.country(client_ip));
+    let status = if input.draft {
+        CommentStatus::Draft
+    } else {
+        moderated_status(settings, country.as_deref(), verdict)
+    };
+
+    // Against the page as it is now, see markup.rs
+    let mentions = markup::resolve(
+        &input.text,
+        state
+            .db
+            .read()
+            .unwrap()
+            .values()
+            .filter(|comment| comment.is_listed(site) && comment.slug == slug),
+    );
+
+    let mut comment = Comment {
+        id: state.config.comments.id_version.new_id(),
+        title,
         name: input.name,
         text: input.text,
         utc: input.utc,
+        tags,
+        slug,
+        email,
+        visitor,
+        ip: client_ip.and_then(|client_ip| state.ip_policy.store(client_ip)),
+        country,
+        spam_score: Some(spam_score),
+        votes: HashMap::new(),
+        created_at: None,
+        updated_at: None,
+        site: site.to_owned(),
+        status,
+        publish_at: None,
+        listed_at: None,
+        mentions,
+        attachments,
+        previews: Vec::new(),
+        version: 0,
     };
 
-    db.write().unwrap().insert(comment.id, comment.clone());
+    let mut comments = state.db.write().unwrap();
+    // Taken under the lock, so /changes never misses the comment
+    comment.created_at = Some(Utc::now());
+    if comment.status == CommentStatus::Approved {
+        comment.listed_at = comment.created_at;
+    }
+    comment.updated_at = comment.created_at;
+    let comment =
+        domain::create(state, &mut comments, Arc::new(comment)).map_err(events::failed)?;
+    drop(comments);
 
-    (StatusCode::CREATED, Json(comment))
+    Ok(Comment::clone(&comment))
+}
+
+// What a new or published comment becomes
+fn moderated_status(
+    settings: &SiteSettings,
+    country: Option<&str>,
+    verdict: Verdict,
+) -> CommentStatus {
+    let queued = country.is_some_and(|country| {
+        settings
+            .queue_countries
+            .iter()
+            .any(|queued| queued.eq_ignore_ascii_case(country))
+    });
+    match settings.moderation {
+        ModerationMode::Off if !queued && verdict == Verdict::Approve => CommentStatus::Approved,
+        _ => CommentStatus::Pending,
+    }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct Comment {
     id: Uuid,
+    // Optional subject line
+    title: Option<String>,
     name: String,
     text: String,
     // Receive in ISO format
     utc: DateTime<Utc>,
+    tags: Vec<String>,
+    // Page of the embedding site the comment was posted on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    slug: Option<String>,
+    // Identity of the commenter, see identity.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    visitor: Option<Uuid>,
+    // Hashed or truncated, see privacy.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    // ISO country code of the address, see geoip.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    country: Option<String>,
+    // Combined score of the spam checks, see spam.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spam_score: Option<f32>,
+    // Keyed by visitor token, see votes.rs
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    votes: HashMap<Uuid, Vote>,
+    // Server time, unlike `utc`. Missing on comments from before /changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated_at: Option<DateTime<Utc>>,
+    // Key of the site the comment belongs to, see sites.rs
+    site: String,
+    status: CommentStatus,
+    // When a held comment is approved by itself, see schedule.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    publish_at: Option<DateTime<Utc>>,
+    // When it was last approved, or posted approved. Server time, see poll.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    listed_at: Option<DateTime<Utc>>,
+    // Resolved when posted, see markup.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mentions: Vec<Mention>,
+    // Uploaded with the comment, see attachments.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+    // Of the links in the text, see previews.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    previews: Vec<LinkPreview>,
+    // Counts the changes moderators decide on, for If-Match, see
+    // preconditions.rs
+    #[serde(default)]
+    version: u64,
+}
+
+impl Comment {
+    // Shown to visitors of `site`
+    fn is_listed(&self, site: &str) -> bool {
+        self.site == site && self.status == CommentStatus::Approved
+    }
+
+    // Comments from before /changes never changed as far as it knows,
+    // the client's `utc` can't be trusted for that
+    fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
+            .or(self.created_at)
+            .unwrap_or(DateTime::UNIX_EPOCH)
+    }
+
+    // Comments from before `listed_at` count as listed when received
+    fn listed_at(&self) -> Option<DateTime<Utc>> {
+        self.listed_at.or(self.created_at)
+    }
+
+    // Call while holding the Db write lock, see changes.rs
+    fn touch(&mut self) {
+        self.touch_unversioned();
+        self.version += 1;
+    }
+
+    // For changes no moderator decided on, e.g. votes, which leave the
+    // version moderators sent with If-Match as it was
+    fn touch_unversioned(&mut self) {
+        self.updated_at = Some(Utc::now());
+    }
 }
 
-type Db = Arc<RwLock<HashMap<Uuid, Comment>>>;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CommentStatus {
+    #[default]
+    Approved,
+    // Waiting for an admin, see ModerationMode::Pre
+    Pending,
+    // Seen only by its author until published, see drafts.rs
+    Draft,
+}
+
+// Shared so listings hand out references instead of copying every comment;
+// writers go through Arc::make_mut
+type Db = RwLock<HashMap<Uuid, Arc<Comment>>>;
+
+// Title, description and canonical URL for link previews, see meta.html
+struct PageMeta {
+    site_name: String,
+    title: String,
+    description: String,
+    url: Option<String>,
+    // Base URL without the trailing '/'
+    site_url: String,
+    // Link to the oEmbed document of this page
+    oembed: bool,
+}
+
+// Roughly what link previews show before cutting the text off
+const META_DESCRIPTION_LEN: usize = 160;
+
+impl PageMeta {
+    fn new(site: &SiteConfig, title: &str, description: &str, path: &str) -> Self {
+        let mut description = description.split_whitespace().collect::<Vec<_>>().join(" ");
+        if description.chars().count() > META_DESCRIPTION_LEN {
+            description = description.chars().take(META_DESCRIPTION_LEN - 1).collect();
+            description.push('…');
+        }
+
+        PageMeta {
+            site_name: site.name.clone(),
+            title: if title == site.name {
+                site.name.clone()
+            } else {
+                format!("{} - {}", title, site.name)
+            },
+            description,
+            url: site.url(path),
+            site_url: site.url("").unwrap_or_default(),
+            oembed: false,
+        }
+    }
+
+    fn with_oembed(self) -> Self {
+        PageMeta {
+            oembed: true,
+            ..self
+        }
+    }
+}
 
 #[derive(Template)]
 #[template(path = "comment-entries.html")]
 struct CommentEntriesTemplate {
+    meta: PageMeta,
+    // Prefix of links into the site, see sites.rs
+    root: String,
     // Total number of comments
     total: usize,
     // Comment entries
-    entries: Vec<Comment>,
+    entries: Vec<Arc<Comment>>,
+    // Tag the entries are filtered by
+    tag: Option<String>,
+    sort: Sort,
+    // First page in the other sort order
+    sort_href: String,
+    // 1-based
+    page: usize,
+    page_size: usize,
+    total_pages: usize,
+    // None on the first and last page
+    prev_href: Option<String>,
+    next_href: Option<String>,
+    // Shortcodes and code blocks, see markup.rs
+    markup: Arc<Markup>,
+    // Display timezone
+    tz: Timezone,
+    // Have the script keep the visitor's timezone, see timezone.rs
+    visitor_timezone: bool,
+    i18n: Locale,
 }
 
 #[derive(Template)]
 #[template(path = "comment.html")]
 struct CommentTemplate {
+    meta: PageMeta,
+    // Prefix of links into the site, see sites.rs
+    root: String,
     id: Uuid,
+    title: Option<String>,
     name: String,
-    text: String,
+    // Escaped, see markup.rs
+    text_html: String,
     utc: DateTime<Utc>,
+    tags: Vec<String>,
+    // Up votes minus down votes
+    score: i64,
+    // Offset of the index page listing this comment
+    index_offset: usize,
+    // Display timezone
+    tz: Timezone,
+    // Have the script keep the visitor's timezone, see timezone.rs
+    visitor_timezone: bool,
+    i18n: Locale,
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+struct ErrorTemplate {
+    message: &'static str,
+    i18n: Locale,
+}
+
+// Error page rendered in the visitor's language
+struct ErrorPage {
+    status: StatusCode,
+    template: ErrorTemplate,
+}
+
+impl ErrorPage {
+    fn not_found(i18n: Locale) -> Self {
+        ErrorPage {
+            status: StatusCode::NOT_FOUND,
+            template: ErrorTemplate {
+                message: i18n.t("error.not_found"),
+                i18n,
+            },
+        }
+    }
+}
+
+impl IntoResponse for ErrorPage {
+    type Body = Full<Bytes>;
+    type BodyError = Infallible;
+
+    fn into_response(self) -> Response<Self::Body> {
+        (self.status, HtmlTemplate(self.template)).into_response()
+    }
 }
 
 struct HtmlTemplate<T>(T);
@@ -220,17 +1617,74 @@ where
     fn into_response(self) -> Response<Self::Body> {
         match self.0.render() {
             Ok(html) => Html(html).into_response(),
-            Err(err) => Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::from(format!(
-                    "Failed to render template. Error: {}",
-                    err
-                )))
-                .unwrap(),
+            Err(err) => template_error(err),
+        }
+    }
+}
+
+fn template_error(err: askama::Error) -> Response<Full<Bytes>> {
+    #[cfg(feature = "sentry")]
+    error_reporting::capture_template_error(&err);
+
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Full::from(format!(
+            "Failed to render template. Error: {}",
+            err
+        )))
+        .unwrap()
+}
+
+// Rejection of writes in maintenance mode and on replicas, answered with 503
+#[derive(Debug)]
+enum ReadOnly {
+    Maintenance,
+    Replica,
+}
+
+impl std::fmt::Display for ReadOnly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadOnly::Maintenance => f.write_str("In maintenance mode, try again later"),
+            ReadOnly::Replica => f.write_str("This is a read replica, send writes to the primary"),
+        }
+    }
+}
+
+impl std::error::Error for ReadOnly {}
+
+impl ReadOnly {
+    fn code(&self) -> &'static str {
+        match self {
+            ReadOnly::Maintenance => "server.maintenance",
+            ReadOnly::Replica => "server.read_only",
         }
     }
 }
 
+// Admin writes which change nothing a replica mirrors
+const REPLICA_WRITES: [&str; 4] = [
+    "/admin/drain",
+    "/admin/log-level",
+    "/admin/recent-requests",
+    "/admin/backup",
+];
+
+// Reads keep working, and the admin API in maintenance mode
+fn refuse_writes(state: &SharedState, req: Request<Body>) -> Result<Request<Body>, ReadOnly> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(req);
+    }
+    let path = req.uri().path();
+    if state.replication.is_replica() && !REPLICA_WRITES.contains(&path) {
+        return Err(ReadOnly::Replica);
+    }
+    if state.maintenance.load(Ordering::Relaxed) && !path.starts_with("/admin") {
+        return Err(ReadOnly::Maintenance);
+    }
+    Ok(req)
+}
+
 #[cfg(unix)]
 async fn graceful_shutdown(handle: Handle) {
     use std::io;
@@ -266,6 +1720,6 @@ async fn graceful_shutdown(handle: Handle) {
 }
 
 // The global 404 handler
-async fn handler_404() -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, "404 not found")
+async fn handler_404(i18n: Locale) -> impl IntoResponse {
+    ErrorPage::not_found(i18n)
 }
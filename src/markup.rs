@@ -0,0 +1,291 @@
+// Comment text as HTML for the pages: escaped, with @mentions linked,
+// :shortcodes: shown as emoji and fenced code blocks highlighted
+//
+// `@name` mentions the commenter of that name, without spaces and in any
+// case, so "@janedoe" is Jane Doe. It is resolved once when the comment is
+// posted, to the newest earlier comment of that commenter on the same page,
+// and links to its permalink. Mentions of nobody stay plain text
+//
+// Shortcodes are expanded whenever the text is shown, so changes to
+// [display] emoji_shortcodes apply to every comment. The JSON has the text as
+// HTML too, as `text_html`, rendered as on the pages
+//
+// Lines between a line opening with ``` and one of only ``` are a code block,
+// left as they are but for escaping, see highlight.rs. A block which isn't
+// closed runs to the end of the text
+use std::{collections::HashMap, ops::Range, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{config::DisplayConfig, highlight::Highlighter, Comment};
+
+// The common ones, in GitHub's names
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("blush", "😊"),
+    ("clap", "👏"),
+    ("coffee", "☕"),
+    ("confused", "😕"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("ok_hand", "👌"),
+    ("pray", "🙏"),
+    ("rocket", "🚀"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("sunglasses", "😎"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("warning", "⚠️"),
+    ("wave", "👋"),
+    ("white_check_mark", "✅"),
+    ("wink", "😉"),
+    ("x", "❌"),
+];
+
+// Longest shortcode looked up, longer :...: runs are left alone
+const MAX_SHORTCODE_LEN: usize = 32;
+
+#[derive(Debug)]
+pub struct Markup {
+    shortcodes: HashMap<String, String>,
+    code: Highlighter,
+}
+
+impl Markup {
+    pub fn new(config: &DisplayConfig) -> Self {
+        let mut shortcodes = HashMap::new();
+        if config.emoji {
+            shortcodes.extend(
+                SHORTCODES
+                    .iter()
+                    .map(|(code, emoji)| (code.to_string(), emoji.to_string())),
+            );
+            for (code, emoji) in &config.emoji_shortcodes {
+                if emoji.is_empty() {
+                    shortcodes.remove(code);
+                } else {
+                    shortcodes.insert(code.clone(), emoji.clone());
+                }
+            }
+        }
+        Markup {
+            shortcodes,
+            code: Highlighter::new(config),
+        }
+    }
+
+    // Escapes `text` into `html`, with the shortcodes expanded
+    fn expand(&self, text: &str, html: &mut String) {
+        let mut rest = text;
+        while let Some(start) = rest.find(':') {
+            escape(&rest[..start], html);
+            rest = &rest[start..];
+            let emoji = rest[1..]
+                .find(':')
+                .filter(|&len| len <= MAX_SHORTCODE_LEN)
+                .and_then(|len| Some((len, self.shortcodes.get(&rest[1..len + 1])?)));
+            match emoji {
+                Some((len, emoji)) => {
+                    escape(emoji, html);
+                    rest = &rest[len + 2..];
+                }
+                None => {
+                    html.push(':');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        escape(rest, html);
+    }
+
+    // Renders the code blocks of `text` into `html`, and the ranges of
+    // text between them with `prose`
+    fn render(
+        &self,
+        text: &str,
+        html: &mut String,
+        mut prose: impl FnMut(Range<usize>, &mut String),
+    ) {
+        let mut written = 0;
+        for block in code_blocks(text) {
+            prose(written..block.start, html);
+            self.code
+                .render(&text[block.language], &text[block.code], html);
+            written = block.end;
+        }
+        prose(written..text.len(), html);
+    }
+}
+
+struct CodeBlock {
+    // Of the fences too
+    start: usize,
+    end: usize,
+    // After the opening ```
+    language: Range<usize>,
+    code: Range<usize>,
+}
+
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with("```")
+}
+
+fn code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    // Lines with their start, the line break included
+    let mut lines = text.split_inclusive('\n').scan(0, |at, line| {
+        let start = *at;
+        *at += line.len();
+        Some((start, line))
+    });
+    while let Some((start, line)) = lines.next() {
+        if !is_fence(line) {
+            continue;
+        }
+        let info = line.trim_start().trim_start_matches('`').trim_start();
+        let language = info.split_whitespace().next().unwrap_or_default();
+        let language_start = start + line.len() - info.len();
+        let code_start = start + line.len();
+        let (mut code_end, mut end) = (text.len(), text.len());
+        for (start, line) in lines.by_ref() {
+            if is_fence(line) && line.trim().trim_start_matches('`').is_empty() {
+                (code_end, end) = (start, start + line.len());
+                break;
+            }
+        }
+        blocks.push(CodeBlock {
+            start,
+            end,
+            language: language_start..language_start + language.len(),
+            code: code_start..code_end,
+        });
+    }
+    blocks
+}
+
+// Resolved per comment, more are left as they are
+const MAX_MENTIONS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mention {
+    // As matched, see mention_key
+    pub name: String,
+    // The comment linked to
+    pub id: Uuid,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+fn mention_key(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// The `@name` tokens of a text by byte range, without the trailing dots of a
+// sentence. Not in the middle of a word, so addresses aren't mentions
+fn tokens(text: &str) -> Vec<(usize, usize)> {
+    let mut tokens = Vec::new();
+    let mut previous = None;
+    for (at, c) in text.char_indices() {
+        if c == '@' && !previous.is_some_and(is_name_char) {
+            let rest = &text[at + 1..];
+            let len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            let name = rest[..len].trim_end_matches(['.', '-']);
+            if !name.is_empty() {
+                tokens.push((at, at + 1 + name.len()));
+            }
+        }
+        previous = Some(c);
+    }
+    tokens
+}
+
+// Mentions of a new comment among the comments already on its page
+pub fn resolve<'a>(
+    text: &str,
+    page: impl Iterator<Item = &'a Arc<Comment>> + Clone,
+) -> Vec<Mention> {
+    let mut mentions = Vec::<Mention>::new();
+    for (start, end) in tokens(text) {
+        let name = mention_key(&text[start + 1..end]);
+        if mentions.len() == MAX_MENTIONS || mentions.iter().any(|m| m.name == name) {
+            continue;
+        }
+        let newest = page
+            .clone()
+            .filter(|comment| mention_key(&comment.name) == name)
+            .max_by_key(|comment| (comment.utc, comment.id));
+        if let Some(comment) = newest {
+            mentions.push(Mention {
+                name,
+                id: comment.id,
+            });
+        }
+    }
+    mentions
+}
+
+pub fn escape(text: &str, html: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#x27;"),
+            c => html.push(c),
+        }
+    }
+}
+
+impl Comment {
+    // For templates, as `{{ entry.text_html(root, markup)|safe }}`, and the JSON
+    pub fn text_html(&self, root: &str, markup: &Markup) -> String {
+        let mut html = String::with_capacity(self.text.len());
+        markup.render(&self.text, &mut html, |range, html| {
+            self.prose_html(&self.text[range], root, markup, html)
+        });
+        html
+    }
+
+    // Of text outside code blocks
+    fn prose_html(&self, text: &str, root: &str, markup: &Markup, html: &mut String) {
+        let mut written = 0;
+        for (start, end) in tokens(text) {
+            let key = mention_key(&text[start + 1..end]);
+            let mention = match self.mentions.iter().find(|m| m.name == key) {
+                Some(mention) => mention,
+                None => continue,
+            };
+            markup.expand(&text[written..start], html);
+            html.push_str(&format!(
+                "<a class=\"mention\" href=\"{}/{}\">",
+                root, mention.id
+            ));
+            escape(&text[start..end], html);
+            html.push_str("</a>");
+            written = end;
+        }
+        markup.expand(&text[written..], html);
+    }
+}
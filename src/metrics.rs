@@ -0,0 +1,45 @@
+// GET /admin/metrics, gauges in the Prometheus text format for alerting,
+// e.g. on the TLS certificate running out:
+//
+//   little_nova_tls_certificate_expiry_timestamp_seconds - time() < 7 * 86400
+use std::{fmt::Write, sync::atomic::Ordering};
+
+use axum::{
+    extract::Extension,
+    http::{header, HeaderMap, HeaderValue},
+    response::IntoResponse,
+};
+
+use crate::{admin::Admin, state::SharedState};
+
+fn gauge(body: &mut String, name: &str, help: &str, value: i64) {
+    let _ = writeln!(body, "# HELP {} {}", name, help);
+    let _ = writeln!(body, "# TYPE {} gauge", name);
+    let _ = writeln!(body, "{} {}", name, value);
+}
+
+pub async fn get_metrics(_: Admin, Extension(state): Extension<SharedState>) -> impl IntoResponse {
+    let mut body = String::new();
+    gauge(
+        &mut body,
+        "little_nova_in_flight_requests",
+        "Requests on the public port being answered",
+        state.drain.in_flight.get() as i64,
+    );
+    let expires = state.tls_expires.load(Ordering::Relaxed);
+    if expires != 0 {
+        gauge(
+            &mut body,
+            "little_nova_tls_certificate_expiry_timestamp_seconds",
+            "Unix time the first certificate of the TLS chain expires",
+            expires,
+        );
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    (headers, body)
+}
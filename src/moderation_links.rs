@@ -0,0 +1,190 @@
+// One-click moderation from a notification, e.g. the new comment emails of
+// email.rs. A link names the comment, what to do with it and until when,
+// signed with HMAC-SHA256 keyed by the admin token, so changing the token
+// revokes every link sent:
+//
+//   /moderate/<id>/approve?expires=<unix seconds>&signature=<hex>
+//
+// Opening it shows the comment with a button, which POSTs to the same URL.
+// Mail providers open the links of messages to scan them, and that mustn't
+// delete anything
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    domain::{self, Transition, TransitionError},
+    extract::ParsedQuery,
+    signing,
+    state::{AppState, SharedState},
+    Comment, HtmlTemplate,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Approve,
+    Delete,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Approve => "approve",
+            Action::Delete => "delete",
+        }
+    }
+
+    fn transition(self) -> Transition {
+        match self {
+            Action::Approve => Transition::Approve,
+            Action::Delete => Transition::Delete,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LinkQuery {
+    expires: i64,
+    signature: String,
+}
+
+#[derive(Template)]
+#[template(path = "moderate.html")]
+struct ModerateTemplate {
+    heading: &'static str,
+    comment: Option<Arc<Comment>>,
+    // Label of the button, none once there is nothing left to do
+    confirm: Option<&'static str>,
+}
+
+fn page(status: StatusCode, heading: &'static str) -> (StatusCode, HtmlTemplate<ModerateTemplate>) {
+    let template = ModerateTemplate {
+        heading,
+        comment: None,
+        confirm: None,
+    };
+    (status, HtmlTemplate(template))
+}
+
+#[cfg(feature = "email")]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn mac(secret: &str, id: Uuid, action: Action, expires: i64) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.{}.{}", id, action.as_str(), expires).as_bytes());
+    mac
+}
+
+// Valid for [admin] link_max_age_hours, None without an admin token to sign
+// with. Absolute with [site] base_url. Only the emails have links so far
+#[cfg(feature = "email")]
+pub fn link(state: &AppState, id: Uuid, action: Action) -> Option<String> {
+    let secret = state.secrets.admin_token()?;
+    let max_age = chrono::Duration::hours(state.config.admin.link_max_age_hours as i64);
+    let expires = (Utc::now() + max_age).timestamp();
+    let signature = hex(&mac(&secret, id, action, expires).finalize().into_bytes());
+    Some(state.config.site.link(&format!(
+        "/moderate/{}/{}?expires={}&signature={}",
+        id,
+        action.as_str(),
+        expires,
+        signature
+    )))
+}
+
+fn verify(
+    state: &AppState,
+    id: Uuid,
+    action: Action,
+    query: &LinkQuery,
+) -> Result<(), (StatusCode, HtmlTemplate<ModerateTemplate>)> {
+    let invalid = || page(StatusCode::FORBIDDEN, "This link is not valid");
+    let secret = state.secrets.admin_token().ok_or_else(invalid)?;
+    let signature = signing::unhex(&query.signature).ok_or_else(invalid)?;
+    mac(&secret, id, action, query.expires)
+        .verify_slice(&signature)
+        .map_err(|_| invalid())?;
+    if query.expires < Utc::now().timestamp() {
+        return Err(page(StatusCode::FORBIDDEN, "This link has expired"));
+    }
+    Ok(())
+}
+
+pub async fn get_moderate(
+    Path((id, action)): Path<(Uuid, Action)>,
+    ParsedQuery(query): ParsedQuery<LinkQuery>,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    if let Err(page) = verify(&state, id, action, &query) {
+        return page;
+    }
+    let comment = match state.db.read().unwrap().get(&id) {
+        Some(comment) => comment.clone(),
+        None => return page(StatusCode::NOT_FOUND, TransitionError::NotFound.message()),
+    };
+    let transition = action.transition();
+    let from = comment.status.into();
+    let template = if transition.is_allowed(from) {
+        ModerateTemplate {
+            heading: match action {
+                Action::Approve => "Approve this comment?",
+                Action::Delete => "Delete this comment?",
+            },
+            comment: Some(comment),
+            confirm: Some(match action {
+                Action::Approve => "Approve",
+                Action::Delete => "Delete",
+            }),
+        }
+    } else {
+        ModerateTemplate {
+            heading: TransitionError::Invalid { from, transition }.message(),
+            comment: Some(comment),
+            confirm: None,
+        }
+    };
+    (StatusCode::OK, HtmlTemplate(template))
+}
+
+pub async fn moderate(
+    Path((id, action)): Path<(Uuid, Action)>,
+    ParsedQuery(query): ParsedQuery<LinkQuery>,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    if let Err(page) = verify(&state, id, action, &query) {
+        return page;
+    }
+    let mut comments = state.db.write().unwrap();
+    let result = domain::transition(&state, &mut comments, id, action.transition());
+    drop(comments);
+
+    match result {
+        Ok(_) => page(
+            StatusCode::OK,
+            match action {
+                Action::Approve => "The comment was approved",
+                Action::Delete => "The comment was deleted",
+            },
+        ),
+        Err(err) => {
+            if let TransitionError::Failed(err) = &err {
+                tracing::error!("failed to {} comment {}: {}", action.as_str(), id, err);
+            }
+            page(err.status(), err.message())
+        }
+    }
+}
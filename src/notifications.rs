@@ -0,0 +1,173 @@
+// Which notifications go to which channel, chosen on the dashboard at /admin
+// or through GET and PUT /admin/notifications:
+//
+//   new_comment      a comment was posted or a draft published, and listed
+//   held             one is held for moderation, by the site's moderation,
+//                    a spam rule or its spam score
+//   spam             one was refused by the spam checks when posted
+//   retention_purge  comments were deleted past the retention of their site
+//
+// The channels follow the event log, see webhooks.rs and email.rs, and skip
+// the events of notifications not chosen for them. Webhooks get every other
+// change too, e.g. approvals and edits, the emails nothing else. The choices
+// start as [notifications] in the config, and are kept in the snapshot once
+// changed through the admin API
+use std::sync::RwLock;
+
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse, Json};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{admin::Admin, extract::ParsedJson, state::SharedState};
+#[cfg(any(feature = "webhooks", feature = "email"))]
+use crate::{
+    events::{CommentEvent, DeleteReason},
+    CommentStatus,
+};
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Notification {
+    NewComment,
+    Held,
+    Spam,
+    RetentionPurge,
+}
+
+impl Notification {
+    pub const ALL: [Notification; 4] = [
+        Notification::NewComment,
+        Notification::Held,
+        Notification::Spam,
+        Notification::RetentionPurge,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Notification::NewComment => "new_comment",
+            Notification::Held => "held",
+            Notification::Spam => "spam",
+            Notification::RetentionPurge => "retention_purge",
+        }
+    }
+
+    // On the dashboard
+    pub fn label(self) -> &'static str {
+        match self {
+            Notification::NewComment => "new comment",
+            Notification::Held => "held for moderation",
+            Notification::Spam => "refused as spam",
+            Notification::RetentionPurge => "deleted past retention",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct NotificationPreferences {
+    pub email: Vec<Notification>,
+    pub webhooks: Vec<Notification>,
+}
+
+// Webhooks get everything, the emails new and held comments as before
+impl Default for NotificationPreferences {
+    fn default() -> Self {
+        NotificationPreferences {
+            email: vec![Notification::NewComment, Notification::Held],
+            webhooks: Notification::ALL.to_vec(),
+        }
+    }
+}
+
+pub struct Notifications {
+    configured: NotificationPreferences,
+    changed: RwLock<Option<NotificationPreferences>>,
+}
+
+impl Notifications {
+    pub fn new(
+        configured: NotificationPreferences,
+        changed: Option<NotificationPreferences>,
+    ) -> Self {
+        Notifications {
+            configured,
+            changed: RwLock::new(changed),
+        }
+    }
+
+    pub fn get(&self) -> NotificationPreferences {
+        self.changed
+            .read()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| self.configured.clone())
+    }
+
+    // Persisted in the snapshot
+    pub fn changed(&self) -> Option<NotificationPreferences> {
+        self.changed.read().unwrap().clone()
+    }
+
+    // Replaces the choices made through the admin API, see backup.rs
+    pub fn restore(&self, changed: Option<NotificationPreferences>) {
+        *self.changed.write().unwrap() = changed;
+    }
+}
+
+// What an event notifies of, None for the other changes
+#[cfg(any(feature = "webhooks", feature = "email"))]
+pub fn kind(event: &CommentEvent) -> Option<Notification> {
+    let status = match event {
+        CommentEvent::Created { comment } => comment.status,
+        CommentEvent::Published { status, .. } => *status,
+        CommentEvent::Refused { .. } => return Some(Notification::Spam),
+        CommentEvent::Deleted {
+            reason: Some(DeleteReason::Retention),
+            ..
+        } => return Some(Notification::RetentionPurge),
+        _ => return None,
+    };
+    match status {
+        CommentStatus::Approved => Some(Notification::NewComment),
+        CommentStatus::Pending => Some(Notification::Held),
+        CommentStatus::Draft => None,
+    }
+}
+
+pub async fn get_notifications(
+    _: Admin,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    Json(state.notifications.get())
+}
+
+// Applies from the next event on. A channel left out gets the default
+pub async fn put_notifications(
+    _: Admin,
+    ParsedJson(mut preferences): ParsedJson<NotificationPreferences>,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    for chosen in [&mut preferences.email, &mut preferences.webhooks] {
+        chosen.sort();
+        chosen.dedup();
+    }
+    state.notifications.restore(Some(preferences.clone()));
+    state.storage.mark_dirty();
+    tracing::info!("notification preferences changed");
+
+    Json(preferences)
+}
+
+// Back to [notifications] in the config
+pub async fn delete_notifications(
+    _: Admin,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    state.notifications.restore(None);
+    state.storage.mark_dirty();
+    tracing::info!("notification preferences reset");
+
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,153 @@
+// oEmbed provider for comment permalinks (https://oembed.com)
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse, Json};
+
+use askama::Template;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::SiteConfig, errors::ApiError, extract::ParsedQuery, filters, i18n::Locale, sites::Site,
+    state::SharedState, timezone::Timezone,
+};
+
+// Card width unless the consumer asks for less
+const DEFAULT_WIDTH: u32 = 550;
+// Rich embeds need a height, this fits a short comment
+const HEIGHT: u32 = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct OEmbedQuery {
+    pub url: String,
+    pub maxwidth: Option<u32>,
+    // Only "json" is supported
+    pub format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    author_name: String,
+    provider_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider_url: Option<String>,
+    html: String,
+    width: u32,
+    height: u32,
+}
+
+// Quoted comment card, see oembed.html
+#[derive(Template)]
+#[template(path = "oembed.html")]
+pub struct OEmbedTemplate {
+    pub url: String,
+    pub name: String,
+    pub text: String,
+    pub utc: DateTime<Utc>,
+    pub site_name: String,
+    // Display timezone
+    pub tz: Timezone,
+    // [display] locale, the request is the consumer's rather than the
+    // visitor's
+    pub i18n: Locale,
+}
+
+pub async fn get_oembed(
+    ParsedQuery(query): ParsedQuery<OEmbedQuery>,
+    site: Site,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    if query.format.as_deref().unwrap_or("json") != "json" {
+        return Err(ApiError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "oembed.unsupported_format",
+            "Only format=json is supported",
+        ));
+    }
+
+    let config = &state.config.site;
+    let (key, id) = comment_id(config, &query.url).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "oembed.not_a_comment",
+            "Not a comment URL",
+        )
+    })?;
+    // Permalinks without a /s/<key> prefix belong to the site of the request
+    let (key, root) = match key {
+        Some(key) => (key.to_owned(), format!("/s/{}", key)),
+        None => (site.key, site.root),
+    };
+    let comment = state
+        .db
+        .read()
+        .unwrap()
+        .get(&id)
+        .filter(|comment| comment.is_listed(&key))
+        .cloned()
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "comment.not_found",
+                "No such comment",
+            )
+        })?;
+
+    let html = OEmbedTemplate {
+        url: config
+            .url(&format!("{}/{}", root, id))
+            .unwrap_or_else(|| query.url.clone()),
+        name: comment.name.clone(),
+        text: comment.text.clone(),
+        utc: comment.utc,
+        site_name: config.name.clone(),
+        tz: state.config.display.timezone,
+        i18n: state.config.display.locale,
+    }
+    .render()
+    .map_err(|err| {
+        tracing::error!("failed to render oEmbed card: {}", err);
+        ApiError::internal("Failed to render card")
+    })?;
+
+    Ok(Json(OEmbed {
+        version: "1.0",
+        kind: "rich",
+        title: comment
+            .title
+            .clone()
+            .unwrap_or_else(|| comment.name.clone()),
+        author_name: comment.name.clone(),
+        provider_name: config.name.clone(),
+        provider_url: config.url(&format!("{}/", root)),
+        html,
+        width: query
+            .maxwidth
+            .map_or(DEFAULT_WIDTH, |max| max.min(DEFAULT_WIDTH)),
+        height: HEIGHT,
+    }))
+}
+
+// Permalinks look like <base_url>[/s/<key>]/<id>, any host is accepted while
+// base_url is unset
+fn comment_id<'a>(site: &SiteConfig, url: &'a str) -> Option<(Option<&'a str>, Uuid)> {
+    let path = match &site.base_url {
+        Some(base_url) => url.strip_prefix(base_url.trim_end_matches('/'))?,
+        None => {
+            let (_, rest) = url.split_once("://")?;
+            &rest[rest.find('/')?..]
+        }
+    };
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+    let (key, path) = match path.strip_prefix("/s/") {
+        Some(rest) => {
+            let (key, path) = rest.split_at(rest.find('/')?);
+            (Some(key), path)
+        }
+        None => (None, path),
+    };
+    Some((key, path.strip_prefix('/')?.parse().ok()?))
+}
@@ -0,0 +1,124 @@
+// Rendered index pages, so busy read-mostly sites don't sort and render
+// thousands of comments on every request. A page is reused until the next
+// write (see Storage::revision), and for at most MAX_AGE because it shows
+// relative times like "5 minutes ago"
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::body::Bytes;
+
+use crate::{codec::Format, i18n::Locale, timezone::Timezone};
+
+const MAX_AGE: Duration = Duration::from_secs(60);
+
+// Every tag and page size is a key of its own, so the number is capped
+const MAX_PAGES: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PageKey {
+    pub site: String,
+    // Sites reached through a /s/<key> prefix link differently
+    pub root: String,
+    pub locale: Locale,
+    // Visitors may have their own, see timezone.rs
+    pub tz: Timezone,
+    // Canonical query, see Pagination::href
+    pub href: String,
+}
+
+struct CachedPage {
+    revision: u64,
+    rendered_at: Instant,
+    html: String,
+}
+
+impl CachedPage {
+    fn is_fresh(&self, revision: u64) -> bool {
+        self.revision == revision && self.rendered_at.elapsed() < MAX_AGE
+    }
+}
+
+#[derive(Default)]
+pub struct PageCache {
+    pages: Mutex<HashMap<PageKey, CachedPage>>,
+}
+
+impl PageCache {
+    pub fn new() -> Self {
+        PageCache::default()
+    }
+
+    pub fn get(&self, key: &PageKey, revision: u64) -> Option<String> {
+        self.pages
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|page| page.is_fresh(revision))
+            .map(|page| page.html.clone())
+    }
+
+    // `revision` is the one read before rendering, so a write racing the
+    // render leaves the page stale rather than wrongly fresh
+    pub fn insert(&self, key: PageKey, revision: u64, html: String) {
+        let mut pages = self.pages.lock().unwrap();
+        if pages.len() >= MAX_PAGES {
+            pages.retain(|_, page| page.is_fresh(revision));
+            if pages.len() >= MAX_PAGES {
+                pages.clear();
+            }
+        }
+        pages.insert(
+            key,
+            CachedPage {
+                revision,
+                rendered_at: Instant::now(),
+                html,
+            },
+        );
+    }
+}
+
+// Serialized first page of each site's API listing, the one clients poll.
+// It shows no relative times, so unlike the HTML it only goes stale on a write
+#[derive(Default)]
+pub struct ListCache {
+    lists: Mutex<HashMap<(String, Format), CachedList>>,
+}
+
+struct CachedList {
+    revision: u64,
+    body: Bytes,
+    // Of comments listed, for X-Total-Count
+    total: usize,
+}
+
+impl ListCache {
+    pub fn new() -> Self {
+        ListCache::default()
+    }
+
+    pub fn get(&self, site: &str, format: Format, revision: u64) -> Option<(Bytes, usize)> {
+        self.lists
+            .lock()
+            .unwrap()
+            .get(&(site.to_owned(), format))
+            .filter(|list| list.revision == revision)
+            .map(|list| (list.body.clone(), list.total))
+    }
+
+    // `revision` as in PageCache::insert. One entry per site and format, so
+    // nothing needs evicting
+    pub fn insert(&self, site: &str, format: Format, revision: u64, body: Bytes, total: usize) {
+        self.lists.lock().unwrap().insert(
+            (site.to_owned(), format),
+            CachedList {
+                revision,
+                body,
+                total,
+            },
+        );
+    }
+}
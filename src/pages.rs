@@ -0,0 +1,204 @@
+// Comments can belong to a page of the site embedding little-nova,
+// identified by a slug such as "2021-10-hello-world"
+use std::sync::Arc;
+
+use axum::{
+    extract::{Extension, Path},
+    http::{header, HeaderMap, HeaderValue},
+    response::IntoResponse,
+};
+
+use askama::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::Format,
+    extract::ParsedQuery,
+    filters,
+    i18n::Locale,
+    markup::Markup,
+    newest_first,
+    sites::Site,
+    state::SharedState,
+    timezone::{DisplayTimezone, Timezone},
+    trace_context, Comment, ErrorPage, HtmlTemplate,
+};
+
+pub const MAX_SLUG_LEN: usize = 100;
+
+// Slugs are used as a single path segment, e.g. /embed/:slug
+pub fn normalize(slug: Option<String>) -> Result<Option<String>, String> {
+    let slug = match slug.map(|slug| slug.trim().to_owned()) {
+        Some(slug) if !slug.is_empty() => slug,
+        _ => return Ok(None),
+    };
+    if slug.chars().count() > MAX_SLUG_LEN {
+        return Err(format!("Slug is longer than {} characters", MAX_SLUG_LEN));
+    }
+    if !slug
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return Err(format!(
+            "Slug \"{}\" may only contain letters, digits, '-', '_' and '.'",
+            slug
+        ));
+    }
+    Ok(Some(slug))
+}
+
+// Minimal page meant to be shown in an iframe by static/embed.js
+#[derive(Template)]
+#[template(path = "embed.html")]
+pub struct EmbedTemplate {
+    // Prefix of links into the site, see sites.rs
+    pub root: String,
+    pub slug: String,
+    pub entries: Vec<Arc<Comment>>,
+    // No form while the thread is closed
+    pub closed: bool,
+    // Shortcodes and code blocks, see markup.rs
+    pub markup: Arc<Markup>,
+    // Display timezone
+    pub tz: Timezone,
+    // Have the script keep the visitor's timezone, see timezone.rs
+    pub visitor_timezone: bool,
+    pub i18n: Locale,
+}
+
+pub async fn get_embed(
+    Path(slug): Path<String>,
+    site: Site,
+    i18n: Locale,
+    DisplayTimezone(tz): DisplayTimezone,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ErrorPage> {
+    let slug = normalize(Some(slug))
+        .ok()
+        .flatten()
+        .ok_or(ErrorPage::not_found(i18n))?;
+    trace_context::record_page(&slug);
+
+    let entries = newest_first(
+        state
+            .db
+            .read()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.is_listed(&site.key))
+            .filter(|entry| entry.slug.as_deref() == Some(slug.as_str())),
+    )
+    .into_iter()
+    .cloned()
+    .collect();
+
+    // Only the allowed origins may show the page in a frame
+    let mut headers = HeaderMap::new();
+    if !site.settings.allowed_origins.is_empty() {
+        let policy = format!(
+            "frame-ancestors 'self' {}",
+            site.settings.allowed_origins.join(" ")
+        );
+        if let Ok(policy) = HeaderValue::from_str(&policy) {
+            headers.insert(header::CONTENT_SECURITY_POLICY, policy);
+        }
+    }
+
+    let template = EmbedTemplate {
+        closed: site.is_closed(Some(&slug)),
+        root: site.root,
+        slug,
+        entries,
+        markup: state.markup.clone(),
+        tz,
+        visitor_timezone: state.config.display.visitor_timezone,
+        i18n,
+    };
+
+    Ok((headers, HtmlTemplate(template)).into_response())
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CountQuery {
+    // "svg" for a badge, JSON otherwise
+    pub format: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CommentCount {
+    slug: String,
+    count: usize,
+}
+
+// Number of approved comments on a page, for article listings
+pub async fn get_comment_count(
+    Path(slug): Path<String>,
+    query: Option<ParsedQuery<CountQuery>>,
+    site: Site,
+    i18n: Locale,
+    format: Format,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let ParsedQuery(query) = query.unwrap_or_default();
+    trace_context::record_page(&slug);
+    let count = state
+        .db
+        .read()
+        .unwrap()
+        .values()
+        .filter(|entry| entry.is_listed(&site.key))
+        .filter(|entry| entry.slug.as_deref() == Some(slug.as_str()))
+        .count();
+
+    // Listings are usually on another origin
+    let mut headers = site.cors_headers();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+
+    if query.format.as_deref() == Some("svg") {
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("image/svg+xml"),
+        );
+        (headers, badge(&i18n.tn("comments.count", count))).into_response()
+    } else {
+        (headers, format.encode(CommentCount { slug, count })).into_response()
+    }
+}
+
+// Flat badge in the style of shields.io
+fn badge(label: &str) -> String {
+    // Rough text width, CJK characters are about twice as wide
+    let width = label
+        .chars()
+        .map(|c| if c.is_ascii() { 7 } else { 12 })
+        .sum::<usize>()
+        + 12;
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}"><rect width="{width}" height="20" rx="3" fill="#24476b"/><text x="{x}" y="14" fill="#fff" font-family="Verdana,sans-serif" font-size="11" text-anchor="middle">{label}</text></svg>"##,
+        width = width,
+        x = width / 2,
+        label = escape_xml(label),
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Loader snippet creating the iframe, see README.md
+pub async fn get_embed_loader() -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/javascript; charset=utf-8"),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=3600"),
+    );
+
+    (headers, include_str!("../static/embed.js"))
+}
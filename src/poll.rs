@@ -0,0 +1,134 @@
+// Long polling for new comments, where WebSockets and server-sent events
+// don't get through. The request is held until a comment is listed after
+// `since` or the wait runs out. Comments count from when they were listed,
+// so those approved, scheduled or published as drafts later come too. At
+// most MAX_COMMENTS come at once, with `more` set while there are others
+use std::sync::Arc;
+
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    changes::PublicComment,
+    codec::Format,
+    errors::ApiError,
+    extract::ParsedQuery,
+    sites::Site,
+    state::{AppState, SharedState},
+    Comment, REQUEST_TIMEOUT,
+};
+
+// The request timeout would cut a longer wait off
+const MAX_WAIT_SECS: u64 = REQUEST_TIMEOUT.as_secs() - 2;
+
+// Per answer, the client asks again from the last one for the rest
+pub const MAX_COMMENTS: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct PollQuery {
+    // Newest comment the client has, from now on while unset
+    since: Option<Uuid>,
+    // Only comments on this page
+    slug: Option<String>,
+    // Seconds to wait at most
+    timeout: Option<u64>,
+}
+
+pub async fn poll(
+    ParsedQuery(query): ParsedQuery<PollQuery>,
+    site: Site,
+    format: Format,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let after = match query.since {
+        Some(id) => since(&state, &site.key, &id).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "comment.not_found",
+                "No such comment",
+            )
+        })?,
+        None => (Utc::now(), Uuid::nil()),
+    };
+    let wait =
+        std::time::Duration::from_secs(query.timeout.unwrap_or(MAX_WAIT_SECS).min(MAX_WAIT_SECS));
+    let deadline = tokio::time::Instant::now() + wait;
+
+    loop {
+        // Listen before looking, so a comment published in between still wakes us
+        let published = state.published.notified();
+        tokio::pin!(published);
+        published.as_mut().enable();
+
+        let (comments, more) = published_since(&state, &site.key, query.slug.as_deref(), after);
+        if !comments.is_empty() {
+            let comments = comments
+                .iter()
+                .map(|comment| PublicComment::new(comment, &state))
+                .collect::<Vec<_>>();
+            return Ok((
+                site.cors_headers(),
+                format.encode(serde_json::json!({ "comments": comments, "more": more })),
+            ));
+        }
+
+        if tokio::time::timeout_at(deadline, published).await.is_err() {
+            return Ok((
+                site.cors_headers(),
+                format.encode(serde_json::json!({ "comments": [], "more": false })),
+            ));
+        }
+    }
+}
+
+// Where a comment is in the order of listing: when it was listed, then its
+// id for those listed at the same time
+pub type Cursor = (DateTime<Utc>, Uuid);
+
+pub fn cursor(comment: &Comment) -> Option<Cursor> {
+    comment.listed_at().map(|listed_at| (listed_at, comment.id))
+}
+
+// Where to go on from the comment with this id on the site. Also from one
+// which has been unlisted since, or deleted while its tombstone is kept
+pub fn since(state: &AppState, site: &str, id: &Uuid) -> Option<Cursor> {
+    if let Some(comment) = state.db.read().unwrap().get(id) {
+        return cursor(comment).filter(|_| comment.site == site);
+    }
+    state
+        .tombstones
+        .get(id)
+        .filter(|tombstone| tombstone.site == site)
+        .and_then(|tombstone| tombstone.listed_at)
+        .map(|listed_at| (listed_at, *id))
+}
+
+// The first MAX_COMMENTS comments listed after `after`, in the order they
+// were, and whether there are more. Also used by the gRPC Watch stream
+pub fn published_since(
+    state: &AppState,
+    site: &str,
+    slug: Option<&str>,
+    after: Cursor,
+) -> (Vec<Arc<Comment>>, bool) {
+    let mut comments = state
+        .db
+        .read()
+        .unwrap()
+        .values()
+        .filter(|comment| comment.is_listed(site))
+        .filter(|comment| cursor(comment).is_some_and(|cursor| cursor > after))
+        .filter(|comment| slug.is_none_or(|slug| comment.slug.as_deref() == Some(slug)))
+        .cloned()
+        .collect::<Vec<_>>();
+    let more = comments.len() > MAX_COMMENTS;
+    if more {
+        // Only the first ones need sorting
+        comments.select_nth_unstable_by_key(MAX_COMMENTS, |comment| cursor(comment));
+        comments.truncate(MAX_COMMENTS);
+    }
+    comments.sort_by_key(|comment| cursor(comment));
+    (comments, more)
+}
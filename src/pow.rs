@@ -0,0 +1,280 @@
+// Proof of work instead of a captcha, see [pow]. Before posting, the form
+// gets a challenge from GET /challenge and looks for a nonce whose
+// SHA-256("<challenge>:<nonce>") starts with `difficulty` zero bits, which
+// takes a browser a second or so and costs a bot the same for every comment.
+// The answer goes along in the X-Proof-Of-Work header as "<challenge>:<nonce>"
+//
+// Challenges are signed, so the server keeps nothing but the ones already
+// used until they expire. A challenge counts as used once the comment passed
+// validation, so one refused for its fields can be fixed and sent again. The more proofs come in within a minute, the more
+// bits the next challenges take, up to max_difficulty. Asking for challenges
+// costs nothing, so that alone doesn't make them harder
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, RequestParts},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    admin::constant_time_eq,
+    auth_log::Failure,
+    config::PowConfig,
+    errors::ApiError,
+    identity::ClientIp,
+    state::{AppState, SharedState},
+};
+
+pub const POW_HEADER: &str = "x-proof-of-work";
+
+const WINDOW: Duration = Duration::from_secs(60);
+// Prune the used challenges once this many are kept
+const PRUNE_AT: usize = 1000;
+// Beyond this no browser finds a nonce in time
+const MAX_BITS: u32 = 32;
+
+const USED: (&str, &str) = (
+    "pow.expired",
+    "The challenge was used already, get a new one",
+);
+
+pub struct Pow {
+    config: PowConfig,
+    secret: Vec<u8>,
+    // Proofs accepted in this and the last window
+    solved: Mutex<(Instant, u32, u32)>,
+    // Until they expire
+    used: Mutex<HashMap<String, Instant>>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+impl Pow {
+    pub fn new(config: &PowConfig) -> Self {
+        // Without one, challenges end with a restart
+        let secret = config
+            .secret
+            .clone()
+            .unwrap_or_else(|| format!("{}{}", Uuid::new_v4(), Uuid::new_v4()));
+        Pow {
+            config: config.clone(),
+            secret: secret.into_bytes(),
+            solved: Mutex::new((Instant::now(), 0, 0)),
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any length");
+        mac.update(payload.as_bytes());
+        hex(&mac.finalize().into_bytes()[..16])
+    }
+
+    // Of the proofs accepted in this and the last window, with `solved` one
+    // more counted
+    fn load(&self, solved: bool) -> u32 {
+        let now = Instant::now();
+        let mut window = self.solved.lock().unwrap();
+        let (start, last, count) = &mut *window;
+        if now.duration_since(*start) >= WINDOW {
+            // Nothing in the last window either after two of them
+            *last = if now.duration_since(*start) < WINDOW * 2 {
+                *count
+            } else {
+                0
+            };
+            *start = now;
+            *count = 0;
+        }
+        if solved {
+            *count += 1;
+        }
+        (*count).max(*last)
+    }
+
+    fn difficulty(&self) -> u32 {
+        let extra = self
+            .load(false)
+            .checked_div(self.config.busy_per_minute)
+            .unwrap_or_default();
+        let max = self.config.max_difficulty.min(MAX_BITS);
+        self.config.difficulty.saturating_add(extra).min(max)
+    }
+
+    // "<issued>.<difficulty>.<random>.<signature>"
+    pub fn challenge(&self) -> (String, u32) {
+        let difficulty = self.difficulty();
+        let payload = format!(
+            "{}.{}.{}",
+            Utc::now().timestamp(),
+            difficulty,
+            Uuid::new_v4().simple()
+        );
+        let signature = self.sign(&payload);
+        (format!("{}.{}", payload, signature), difficulty)
+    }
+
+    // Checks "<challenge>:<nonce>" and returns the challenge, which is good
+    // for one comment, see spend. Errs with the code and the message
+    pub fn verify(&self, answer: &str) -> Result<String, (&'static str, &'static str)> {
+        let malformed = ("pow.invalid", "Malformed proof of work");
+        let (challenge, _nonce) = answer.rsplit_once(':').ok_or(malformed)?;
+        let (payload, signature) = challenge.rsplit_once('.').ok_or(malformed)?;
+        if !constant_time_eq(self.sign(payload).as_bytes(), signature.as_bytes()) {
+            return Err((
+                "pow.invalid",
+                "The proof of work is not for a challenge of this server",
+            ));
+        }
+        let mut fields = payload.split('.');
+        let issued = fields.next().and_then(|issued| issued.parse::<i64>().ok());
+        let difficulty = fields
+            .next()
+            .and_then(|difficulty| difficulty.parse::<u32>().ok());
+        let (issued, difficulty) = issued.zip(difficulty).ok_or(malformed)?;
+
+        let age = Utc::now().timestamp() - issued;
+        if !(0..=self.config.ttl_secs as i64).contains(&age) {
+            return Err(("pow.expired", "The challenge expired, get a new one"));
+        }
+        let hash = Sha256::digest(answer.as_bytes());
+        if leading_zero_bits(&hash) < difficulty {
+            return Err(("pow.invalid", "The proof of work is wrong"));
+        }
+        if self.used.lock().unwrap().contains_key(challenge) {
+            return Err(USED);
+        }
+        Ok(challenge.to_owned())
+    }
+
+    // Marks a verified challenge used, unless another comment took it since
+    pub fn spend(&self, challenge: &str) -> Result<(), (&'static str, &'static str)> {
+        let now = Instant::now();
+        let mut used = self.used.lock().unwrap();
+        if used.len() >= PRUNE_AT {
+            used.retain(|_, expires| now < *expires);
+        }
+        let expires = now + Duration::from_secs(self.config.ttl_secs);
+        if used.insert(challenge.to_owned(), expires).is_some() {
+            return Err(USED);
+        }
+        drop(used);
+        self.load(true);
+        Ok(())
+    }
+}
+
+// GET /challenge
+pub async fn get_challenge(
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    if !state.pow.is_enabled() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "pow.disabled",
+            "No proof of work is needed",
+        ));
+    }
+    let (challenge, difficulty) = state.pow.challenge();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok((
+        headers,
+        Json(json!({
+            "challenge": challenge,
+            "difficulty": difficulty,
+            "expires_in": state.pow.config.ttl_secs,
+        })),
+    ))
+}
+
+// A valid X-Proof-Of-Work while [pow] is on, with its challenge
+pub struct ProofOfWork(Option<String>);
+
+impl ProofOfWork {
+    // Once the comment passed validation, see insert_comment
+    pub fn spend(&self, state: &AppState, client_ip: Option<IpAddr>) -> Result<(), ApiError> {
+        let challenge = match &self.0 {
+            Some(challenge) => challenge,
+            None => return Ok(()),
+        };
+        state.pow.spend(challenge).map_err(|(code, message)| {
+            state
+                .auth_log
+                .failure(Failure::ProofOfWork, client_ip, "POST", "/create");
+            ApiError::new(StatusCode::FORBIDDEN, code, message)
+        })
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for ProofOfWork
+where
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<SharedState>::from_request(req)
+            .await
+            .map_err(|_| ApiError::internal("App state is not available"))?;
+        if !state.pow.is_enabled() {
+            return Ok(ProofOfWork(None));
+        }
+
+        let answer = req
+            .headers()
+            .and_then(|headers| headers.get(POW_HEADER))
+            .and_then(|value| value.to_str().ok());
+        let verified = match answer {
+            Some(answer) => state.pow.verify(answer),
+            None => Err((
+                "pow.missing",
+                "Comments need a proof of work, see GET /challenge",
+            )),
+        };
+        match verified {
+            Ok(challenge) => Ok(ProofOfWork(Some(challenge))),
+            Err((code, message)) => {
+                let client = ClientIp::from_request(req).await.ok();
+                state.auth_log.failed_request(
+                    Failure::ProofOfWork,
+                    client.map(|ClientIp(ip)| ip),
+                    req,
+                );
+                Err(ApiError::new(StatusCode::FORBIDDEN, code, message))
+            }
+        }
+    }
+}
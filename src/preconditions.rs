@@ -0,0 +1,89 @@
+// If-Match on the moderation of one comment, so two moderators don't clobber
+// each other's changes. Every change a moderator decides on bumps a
+// comment's `version`, votes and link previews don't, and the answers carry
+// it as the ETag "<version>":
+//
+//   curl -X POST -H 'If-Match: "3"' .../admin/comments/$ID/approve
+//
+// A request naming another version changes nothing and is answered with 412,
+// one without If-Match with 428. `*` goes ahead whatever the version. Bulk
+// moderation names the versions in its body instead, see admin.rs
+use axum::{
+    async_trait,
+    extract::{FromRequest, RequestParts},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+};
+
+use crate::{errors::ApiError, Comment};
+
+// The versions a request names, None for any
+#[derive(Debug, Clone)]
+pub struct IfMatch(Option<Vec<u64>>);
+
+#[async_trait]
+impl<B> FromRequest<B> for IfMatch
+where
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let value = match req
+            .headers()
+            .and_then(|headers| headers.get(header::IF_MATCH))
+        {
+            Some(value) => value.to_str().map_err(|_| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "request.invalid_header",
+                    "Invalid If-Match header",
+                )
+            })?,
+            None => {
+                return Err(ApiError::new(
+                    StatusCode::PRECONDITION_REQUIRED,
+                    "request.precondition_required",
+                    "Send the comment's version as If-Match, or * for any",
+                ))
+            }
+        };
+        if value.trim() == "*" {
+            return Ok(IfMatch(None));
+        }
+        // Tags which aren't ours match no version
+        let versions = value
+            .split(',')
+            .filter_map(|tag| tag.trim().trim_matches('"').parse().ok())
+            .collect();
+        Ok(IfMatch(Some(versions)))
+    }
+}
+
+impl IfMatch {
+    // Call with the Db write lock held, so the comment can't change before
+    // the request changes it
+    pub fn check(&self, comment: Option<&Comment>) -> Result<(), ApiError> {
+        match (&self.0, comment) {
+            (Some(versions), Some(comment)) if !versions.contains(&comment.version) => {
+                Err(changed())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn changed() -> ApiError {
+    ApiError::new(
+        StatusCode::PRECONDITION_FAILED,
+        "comment.changed",
+        "The comment was changed meanwhile",
+    )
+}
+
+pub fn etag(comment: &Comment) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Ok(etag) = HeaderValue::from_str(&format!("\"{}\"", comment.version)) {
+        headers.insert(header::ETAG, etag);
+    }
+    headers
+}
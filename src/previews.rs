@@ -0,0 +1,525 @@
+// Link preview cards: the title, description and og:image of the pages a
+// comment links to, shown beneath it. Fetched once the comment is approved,
+// by a single worker so a comment full of links can't start a crowd of
+// requests, and kept on the comment. Pages are cached for everyone linking
+// to them for PREVIEW_CACHE_TTL.
+//
+// The server fetches what commenters link to, so only http(s) URLs to public
+// addresses are followed: names are resolved by a resolver which drops
+// loopback, private, link-local and other internal addresses, so redirects
+// and DNS rebinding can't reach them either. Proxies from the environment are
+// ignored for the same reason. Needs the `previews` feature and [previews]
+// enabled = true
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    domain::{State, TransitionHook},
+    state::AppState,
+    Comment,
+};
+
+#[cfg(feature = "previews")]
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "previews")]
+use tokio::sync::mpsc;
+#[cfg(feature = "previews")]
+use uuid::Uuid;
+
+#[cfg(feature = "previews")]
+use crate::{
+    config::PreviewsConfig,
+    events::{self, CommentEvent},
+    state::SharedState,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkPreview {
+    // As linked in the text
+    pub url: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // Only https images, so the pages stay free of mixed content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    // og:site_name, or the host of the URL
+    pub site: String,
+}
+
+// Fetched pages and the pages which had no preview, by URL
+#[cfg(feature = "previews")]
+const PREVIEW_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+#[cfg(feature = "previews")]
+const PREVIEW_CACHE_CAPACITY: usize = 1000;
+// Enough for the <head> of any page
+#[cfg(feature = "previews")]
+const MAX_PAGE_BYTES: usize = 256 * 1024;
+#[cfg(feature = "previews")]
+const MAX_REDIRECTS: usize = 3;
+#[cfg(feature = "previews")]
+const MAX_TITLE_LEN: usize = 120;
+#[cfg(feature = "previews")]
+const MAX_DESCRIPTION_LEN: usize = 200;
+
+pub struct Previews {
+    #[cfg(feature = "previews")]
+    queue: Option<mpsc::UnboundedSender<Uuid>>,
+    #[cfg(feature = "previews")]
+    queued: Mutex<Option<mpsc::UnboundedReceiver<Uuid>>>,
+    #[cfg(feature = "previews")]
+    cache: Mutex<HashMap<String, (Instant, Option<LinkPreview>)>>,
+}
+
+impl Previews {
+    #[cfg(feature = "previews")]
+    pub fn new(config: &PreviewsConfig) -> Self {
+        let (queue, queued) = match config.enabled {
+            true => {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                (Some(sender), Some(receiver))
+            }
+            false => (None, None),
+        };
+        Previews {
+            queue,
+            queued: Mutex::new(queued),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[cfg(not(feature = "previews"))]
+    pub fn new(config: &crate::config::PreviewsConfig) -> Self {
+        if config.enabled {
+            tracing::warn!(
+                "[previews] is enabled, but little-nova was built without the `previews` feature"
+            );
+        }
+        Previews {}
+    }
+
+    #[cfg(feature = "previews")]
+    fn queue(&self, comment: &Comment) {
+        if let Some(queue) = &self.queue {
+            if !links(&comment.text, 1).is_empty() {
+                let _ = queue.send(comment.id);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "previews"))]
+    fn queue(&self, _comment: &Comment) {}
+
+    #[cfg(feature = "previews")]
+    fn cached(&self, url: &str) -> Option<Option<LinkPreview>> {
+        let cache = self.cache.lock().unwrap();
+        let (fetched_at, preview) = cache.get(url)?;
+        (fetched_at.elapsed() < PREVIEW_CACHE_TTL).then(|| preview.clone())
+    }
+
+    #[cfg(feature = "previews")]
+    fn cache(&self, url: &str, preview: Option<LinkPreview>) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= PREVIEW_CACHE_CAPACITY {
+            cache.retain(|_, (fetched_at, _)| fetched_at.elapsed() < PREVIEW_CACHE_TTL);
+        }
+        if cache.len() >= PREVIEW_CACHE_CAPACITY {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, (fetched_at, _))| *fetched_at)
+                .map(|(url, _)| url.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+        cache.insert(url.to_owned(), (Instant::now(), preview));
+    }
+}
+
+// Queues approved comments with links
+pub struct Fetch;
+
+impl TransitionHook for Fetch {
+    fn on_transition(&self, state: &AppState, comment: &Comment, _: Option<State>, to: State) {
+        if to == State::Approved && comment.previews.is_empty() {
+            state.previews.queue(comment);
+        }
+    }
+}
+
+// The http(s) URLs of a text, without the punctuation of the sentence they
+// end, up to `max`
+#[cfg(feature = "previews")]
+fn links(text: &str, max: usize) -> Vec<&str> {
+    let mut links = Vec::new();
+    for word in text.split(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\'')) {
+        let start = match word.find("https://").or_else(|| word.find("http://")) {
+            Some(start) => start,
+            None => continue,
+        };
+        let link = word[start..].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']']);
+        if link.len() > "https://".len() && !links.contains(&link) {
+            links.push(link);
+            if links.len() == max {
+                break;
+            }
+        }
+    }
+    links
+}
+
+// Addresses a preview may be fetched from
+#[cfg(feature = "previews")]
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space, benchmarking and reserved
+                || a == 100 && (64..128).contains(&b)
+                || a == 198 && (18..20).contains(&b)
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local and link-local
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+// Resolves names to their public addresses only
+#[cfg(feature = "previews")]
+struct PublicResolver;
+
+#[cfg(feature = "previews")]
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            let addrs: reqwest::dns::Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+// Addresses given as such never go through the resolver
+#[cfg(feature = "previews")]
+fn is_allowed(url: &reqwest::Url) -> bool {
+    let host = match url.host_str() {
+        Some(host) => match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(ip) => is_public(ip),
+            Err(_) => true,
+        },
+        None => false,
+    };
+    host && matches!(url.scheme(), "http" | "https")
+}
+
+#[cfg(feature = "previews")]
+fn client(config: &PreviewsConfig) -> reqwest::Client {
+    let redirects = reqwest::redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.stop()
+        } else if is_allowed(attempt.url()) {
+            attempt.follow()
+        } else {
+            attempt.error("redirected to an address which isn't public")
+        }
+    });
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .connect_timeout(Duration::from_secs(config.timeout_secs))
+        .redirect(redirects)
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver))
+        .user_agent(concat!(
+            "little-nova/",
+            env!("CARGO_PKG_VERSION"),
+            " (link previews)"
+        ))
+        .build()
+        .expect("failed to build the link preview client")
+}
+
+// Fetches the previews of the queued comments, for the lifetime of the server
+#[cfg(feature = "previews")]
+pub async fn fetch_queued(state: SharedState) {
+    let mut queued = match state.previews.queued.lock().unwrap().take() {
+        Some(queued) => queued,
+        None => return,
+    };
+    let config = &state.config.previews;
+    let client = client(config);
+
+    while let Some(id) = queued.recv().await {
+        // The primary fetches them, see replication.rs
+        if state.replication.is_replica() {
+            continue;
+        }
+        let text = match state.db.read().unwrap().get(&id) {
+            Some(comment) => comment.text.clone(),
+            None => continue,
+        };
+
+        let mut previews = Vec::new();
+        for link in links(&text, config.max_per_comment) {
+            let preview = match state.previews.cached(link) {
+                Some(preview) => preview,
+                None => {
+                    let preview = fetch(&client, link).await;
+                    state.previews.cache(link, preview.clone());
+                    preview
+                }
+            };
+            previews.extend(preview);
+        }
+        if previews.is_empty() {
+            continue;
+        }
+
+        let mut comments = state.db.write().unwrap();
+        // Deleted or edited in the meantime
+        let comment = match comments.get(&id) {
+            Some(comment) if comment.text == text => comment,
+            _ => continue,
+        };
+        let mut comment = Comment::clone(comment);
+        comment.previews = previews;
+        comment.touch_unversioned();
+        let event = CommentEvent::Edited {
+            comment: Arc::new(comment),
+        };
+        if let Err(err) = events::commit(&state, &mut comments, event) {
+            tracing::error!(%id, "failed to save link previews: {}", err);
+        }
+    }
+}
+
+#[cfg(feature = "previews")]
+async fn fetch(client: &reqwest::Client, link: &str) -> Option<LinkPreview> {
+    let url = reqwest::Url::parse(link).ok().filter(is_allowed)?;
+    let page = match read_page(client, url.clone()).await {
+        Ok(page) => page?,
+        Err(err) => {
+            tracing::debug!(%url, "no link preview: {}", err);
+            return None;
+        }
+    };
+    let preview = parse(link, &url, &page);
+    if preview.is_none() {
+        tracing::debug!(%url, "no link preview: the page has no title");
+    }
+    preview
+}
+
+// The start of an HTML page, None for anything else
+#[cfg(feature = "previews")]
+async fn read_page(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+) -> Result<Option<String>, reqwest::Error> {
+    let mut response = client
+        .get(url)
+        .header(reqwest::header::ACCEPT, "text/html")
+        .send()
+        .await?
+        .error_for_status()?;
+    let html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    if !html {
+        return Ok(None);
+    }
+
+    let mut page = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        page.extend_from_slice(&chunk);
+        if page.len() >= MAX_PAGE_BYTES {
+            page.truncate(MAX_PAGE_BYTES);
+            break;
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&page).into_owned()))
+}
+
+#[cfg(feature = "previews")]
+fn parse(link: &str, url: &reqwest::Url, page: &str) -> Option<LinkPreview> {
+    let mut meta = HashMap::new();
+    let mut rest = page;
+    while let Some(start) = find_ignore_case(rest, "<meta") {
+        rest = &rest[start + "<meta".len()..];
+        let end = rest.find('>').unwrap_or(rest.len());
+        let attributes = attributes(&rest[..end]);
+        let key = attributes
+            .get("property")
+            .or_else(|| attributes.get("name"))
+            .map(|key| key.to_lowercase());
+        if let (Some(key), Some(content)) = (key, attributes.get("content")) {
+            meta.entry(key).or_insert_with(|| content.clone());
+        }
+    }
+    let title_tag = find_ignore_case(page, "<title").and_then(|start| {
+        let rest = &page[start..];
+        let open = rest.find('>')? + 1;
+        let close = find_ignore_case(rest, "</title")?;
+        (open <= close).then(|| decode(&rest[open..close]))
+    });
+
+    let cleaned = |text: &str, max: usize| {
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.chars().count() > max {
+            let mut text = text.chars().take(max - 1).collect::<String>();
+            text.push('…');
+            text
+        } else {
+            text
+        }
+    };
+    let title = meta
+        .get("og:title")
+        .cloned()
+        .or(title_tag)
+        .map(|title| cleaned(&title, MAX_TITLE_LEN))
+        .filter(|title| !title.is_empty())?;
+    let description = meta
+        .get("og:description")
+        .or_else(|| meta.get("description"))
+        .map(|description| cleaned(description, MAX_DESCRIPTION_LEN))
+        .filter(|description| !description.is_empty());
+    // Relative to the page
+    let image = meta
+        .get("og:image")
+        .and_then(|image| url.join(image).ok())
+        .filter(|image| image.scheme() == "https")
+        .map(String::from);
+    let site = meta
+        .get("og:site_name")
+        .map(|site| cleaned(site, MAX_TITLE_LEN))
+        .filter(|site| !site.is_empty())
+        .or_else(|| url.host_str().map(str::to_owned))
+        .unwrap_or_default();
+
+    Some(LinkPreview {
+        url: link.to_owned(),
+        title,
+        description,
+        image,
+        site,
+    })
+}
+
+#[cfg(feature = "previews")]
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+// name="value", name='value' and name=value, names in lower case
+#[cfg(feature = "previews")]
+fn attributes(tag: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = tag;
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals]
+            .rsplit(|c: char| c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        let value = rest[equals + 1..].trim_start();
+        let (value, after) = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let value = &value[1..];
+                let end = value.find(quote).unwrap_or(value.len());
+                (&value[..end], &value[(end + 1).min(value.len())..])
+            }
+            _ => {
+                let end = value
+                    .find(|c: char| c.is_whitespace() || c == '/')
+                    .unwrap_or(value.len());
+                (&value[..end], &value[end..])
+            }
+        };
+        attributes.insert(name, decode(value));
+        rest = after;
+    }
+    attributes
+}
+
+// The entities pages use in titles, the templates escape everything again
+#[cfg(feature = "previews")]
+fn decode(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_owned();
+    }
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';').filter(|&end| end <= 10) {
+            Some(end) => end,
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
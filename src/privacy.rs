@@ -0,0 +1,85 @@
+// How much of the commenter's IP address is kept, see [privacy] in the config
+use std::net::IpAddr;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::config::PrivacyConfig;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpStorage {
+    // Salted SHA-256, enough to recognize repeat visitors
+    #[default]
+    Hash,
+    // /24 for IPv4 and /48 for IPv6
+    Truncate,
+    // Not recorded at all
+    None,
+}
+
+pub struct IpPolicy {
+    storage: IpStorage,
+    salt: String,
+}
+
+impl IpPolicy {
+    pub fn new(config: &PrivacyConfig) -> Self {
+        let salt = match &config.ip_salt {
+            Some(salt) => salt.clone(),
+            None => {
+                if config.ip_storage == IpStorage::Hash {
+                    tracing::warn!(
+                        "[privacy] ip_salt is not set, hashed IP addresses won't match after a restart"
+                    );
+                }
+                Uuid::new_v4().to_string()
+            }
+        };
+        IpPolicy {
+            storage: config.ip_storage,
+            salt,
+        }
+    }
+
+    // The form stored on comments
+    pub fn store(&self, ip: IpAddr) -> Option<String> {
+        match self.storage {
+            IpStorage::Hash => {
+                let digest = Sha256::new()
+                    .chain_update(self.salt.as_bytes())
+                    .chain_update(ip.to_string().as_bytes())
+                    .finalize();
+                let hex = digest
+                    .iter()
+                    .take(16)
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>();
+                Some(format!("sha256:{}", hex))
+            }
+            IpStorage::Truncate => Some(truncate(ip).to_string()),
+            IpStorage::None => None,
+        }
+    }
+
+    // Truncated addresses are shared by many people, so only hashes are
+    // precise enough to look a single commenter up by
+    pub fn identifies(&self) -> bool {
+        self.storage == IpStorage::Hash
+    }
+}
+
+fn truncate(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => {
+            let mut segments = ip.segments();
+            segments[3..].fill(0);
+            IpAddr::from(segments)
+        }
+    }
+}
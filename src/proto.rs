@@ -0,0 +1,231 @@
+// Protobuf form of comments, for other services talking to little-nova
+// The messages mirror proto/little_nova.proto and are written out by hand,
+// so building needs no protoc. Keep the tags in sync with the schema
+// The service of the schema is in grpc.rs
+use std::sync::Arc;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::{codec::Protobuf, hal::Linked, pages::CommentCount, votes::CastVote, CommentStatus};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CreateComment {
+    #[prost(string, optional, tag = "1")]
+    pub title: Option<String>,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub text: String,
+    #[prost(string, tag = "4")]
+    pub utc: String,
+    #[prost(string, repeated, tag = "5")]
+    pub tags: Vec<String>,
+    #[prost(string, optional, tag = "6")]
+    pub slug: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    pub email: Option<String>,
+    #[prost(string, tag = "8")]
+    pub website: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum Status {
+    Approved = 0,
+    Pending = 1,
+    Draft = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Comment {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, optional, tag = "2")]
+    pub title: Option<String>,
+    #[prost(string, tag = "3")]
+    pub name: String,
+    #[prost(string, tag = "4")]
+    pub text: String,
+    #[prost(string, tag = "5")]
+    pub utc: String,
+    #[prost(string, repeated, tag = "6")]
+    pub tags: Vec<String>,
+    #[prost(string, optional, tag = "7")]
+    pub slug: Option<String>,
+    #[prost(string, tag = "8")]
+    pub site: String,
+    #[prost(enumeration = "Status", tag = "9")]
+    pub status: i32,
+    #[prost(int64, tag = "10")]
+    pub score: i64,
+    #[prost(string, optional, tag = "11")]
+    pub created_at: Option<String>,
+    #[prost(string, optional, tag = "12")]
+    pub updated_at: Option<String>,
+    #[prost(string, optional, tag = "13")]
+    pub ip: Option<String>,
+    #[prost(string, optional, tag = "14")]
+    pub country: Option<String>,
+    #[prost(float, optional, tag = "15")]
+    pub spam_score: Option<f32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CommentList {
+    #[prost(message, repeated, tag = "1")]
+    pub comments: Vec<Comment>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListRequest {
+    #[prost(string, tag = "1")]
+    pub site: String,
+    #[prost(string, optional, tag = "2")]
+    pub slug: Option<String>,
+    #[prost(enumeration = "Status", optional, tag = "3")]
+    pub status: Option<i32>,
+    #[prost(uint32, tag = "4")]
+    pub limit: u32,
+    #[prost(uint32, tag = "5")]
+    pub offset: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CreateRequest {
+    #[prost(string, tag = "1")]
+    pub site: String,
+    #[prost(message, optional, tag = "2")]
+    pub comment: Option<CreateComment>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WatchRequest {
+    #[prost(string, tag = "1")]
+    pub site: String,
+    #[prost(string, optional, tag = "2")]
+    pub slug: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub since: Option<String>,
+}
+
+impl From<Status> for CommentStatus {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Approved => CommentStatus::Approved,
+            Status::Pending => CommentStatus::Pending,
+            Status::Draft => CommentStatus::Draft,
+        }
+    }
+}
+
+fn format_time(utc: DateTime<Utc>) -> String {
+    utc.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+impl From<&crate::Comment> for Comment {
+    fn from(comment: &crate::Comment) -> Self {
+        let status = match comment.status {
+            CommentStatus::Approved => Status::Approved,
+            CommentStatus::Pending => Status::Pending,
+            CommentStatus::Draft => Status::Draft,
+        };
+        Comment {
+            id: comment.id.to_string(),
+            title: comment.title.clone(),
+            name: comment.name.clone(),
+            text: comment.text.clone(),
+            utc: format_time(comment.utc),
+            tags: comment.tags.clone(),
+            slug: comment.slug.clone(),
+            site: comment.site.clone(),
+            status: status as i32,
+            score: comment.score(),
+            created_at: comment.created_at.map(format_time),
+            updated_at: comment.updated_at.map(format_time),
+            ip: comment.ip.clone(),
+            country: comment.country.clone(),
+            spam_score: comment.spam_score,
+        }
+    }
+}
+
+impl TryFrom<CreateComment> for crate::CreateComment {
+    type Error = String;
+
+    fn try_from(input: CreateComment) -> Result<Self, Self::Error> {
+        let utc = DateTime::parse_from_rfc3339(&input.utc)
+            .map_err(|err| format!("invalid utc \"{}\": {}", input.utc, err))?;
+        Ok(crate::CreateComment {
+            title: input.title,
+            name: input.name,
+            text: input.text,
+            utc: utc.with_timezone(&Utc),
+            tags: input.tags,
+            slug: input.slug,
+            email: input.email,
+            website: input.website,
+            draft: false,
+        })
+    }
+}
+
+impl Protobuf for crate::Comment {
+    fn to_protobuf(&self) -> Option<Vec<u8>> {
+        Some(prost::Message::encode_to_vec(&Comment::from(self)))
+    }
+}
+
+impl Protobuf for Vec<Arc<crate::Comment>> {
+    fn to_protobuf(&self) -> Option<Vec<u8>> {
+        let list = CommentList {
+            comments: self
+                .iter()
+                .map(|comment| Comment::from(comment.as_ref()))
+                .collect(),
+        };
+        Some(prost::Message::encode_to_vec(&list))
+    }
+}
+
+// The links are left out
+impl Protobuf for Vec<Linked<Arc<crate::Comment>>> {
+    fn to_protobuf(&self) -> Option<Vec<u8>> {
+        let list = CommentList {
+            comments: self
+                .iter()
+                .map(|comment| Comment::from(comment.inner.as_ref()))
+                .collect(),
+        };
+        Some(prost::Message::encode_to_vec(&list))
+    }
+}
+
+impl Protobuf for crate::CreateComment {
+    fn from_protobuf(bytes: &[u8]) -> Option<Result<Self, String>> {
+        Some(
+            <CreateComment as prost::Message>::decode(bytes)
+                .map_err(|err| err.to_string())
+                .and_then(crate::CreateComment::try_from),
+        )
+    }
+}
+
+// No protobuf form, yet
+impl Protobuf for CastVote {}
+impl Protobuf for CommentCount {}
+impl Protobuf for serde_json::Value {}
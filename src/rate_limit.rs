@@ -0,0 +1,39 @@
+// Fixed window counters of new comments per site and client
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+// Forget old windows once this many clients are tracked
+const PRUNE_AT: usize = 10_000;
+
+#[derive(Default)]
+pub struct RateLimiter {
+    windows: Mutex<HashMap<(String, IpAddr), (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter::default()
+    }
+
+    // Count a request, false once the client is over `per_minute`
+    pub fn allow(&self, site: &str, client: IpAddr, per_minute: u32) -> bool {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= PRUNE_AT {
+            windows.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+        }
+
+        let (start, count) = windows.entry((site.to_owned(), client)).or_insert((now, 0));
+        if now.duration_since(*start) >= WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= per_minute
+    }
+}
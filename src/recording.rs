@@ -0,0 +1,352 @@
+// Recording of whole requests and responses, for finding out what a
+// misbehaving client actually sends. Off until turned on in [recording] or
+// with PUT /admin/recent-requests, the last `capacity` exchanges are kept in
+// memory and shown at GET /admin/recent-requests. Credentials, cookies and
+// email addresses are redacted before anything is kept
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::{
+    body::{self, Body, BoxBody, Bytes, HttpBody},
+    extract::Extension,
+    http::{header, HeaderMap, Request, Response},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+use crate::{
+    admin::Admin, config::RecordingConfig, extract::ParsedJson, request_id::RequestId,
+    state::SharedState,
+};
+
+// Headers, query parameters and JSON fields never kept
+const REDACTED_HEADERS: [header::HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+    header::SET_COOKIE,
+];
+const REDACTED_FIELDS: [&str; 5] = ["email", "token", "password", "secret", "ip_salt"];
+const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Exchange {
+    pub request_id: Option<Uuid>,
+    pub received: DateTime<Utc>,
+    // Until the whole response body was there
+    pub duration_ms: f64,
+    pub method: String,
+    pub uri: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Option<String>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: Option<String>,
+}
+
+pub struct Recorder {
+    enabled: AtomicBool,
+    capacity: usize,
+    max_body_bytes: usize,
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl Recorder {
+    pub fn new(config: &RecordingConfig) -> Self {
+        Recorder {
+            enabled: AtomicBool::new(config.enabled),
+            capacity: config.capacity,
+            max_body_bytes: config.max_body_bytes,
+            exchanges: Mutex::new(VecDeque::with_capacity(config.capacity)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn push(&self, exchange: Exchange) {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        while exchanges.len() >= self.capacity.max(1) {
+            exchanges.pop_front();
+        }
+        exchanges.push_back(exchange);
+    }
+
+    // Newest first
+    fn recent(&self) -> Vec<Exchange> {
+        let exchanges = self.exchanges.lock().unwrap();
+        exchanges.iter().rev().cloned().collect()
+    }
+
+    fn show_headers(&self, headers: &HeaderMap) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if REDACTED_HEADERS.contains(name) {
+                    REDACTED.to_owned()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    fn show_body(&self, headers: &HeaderMap, body: &[u8]) -> Option<String> {
+        if body.is_empty() {
+            return None;
+        }
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        let text = if content_type.contains("json") {
+            match serde_json::from_slice::<Value>(body) {
+                Ok(mut value) => {
+                    redact_json(&mut value);
+                    value.to_string()
+                }
+                // Kept as is, malformed JSON is what's often looked for
+                Err(_) => String::from_utf8_lossy(body).into_owned(),
+            }
+        } else if content_type.starts_with("application/x-www-form-urlencoded") {
+            redact_query(&String::from_utf8_lossy(body))
+        } else {
+            match std::str::from_utf8(body) {
+                Ok(text) => text.to_owned(),
+                Err(_) => return Some(format!("<{} bytes of {}>", body.len(), content_type)),
+            }
+        };
+        Some(truncate(text, self.max_body_bytes))
+    }
+}
+
+async fn collect<B: HttpBody<Data = Bytes> + Unpin>(mut body: B) -> Result<Bytes, B::Error> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk?);
+    }
+    Ok(Bytes::from(bytes))
+}
+
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if REDACTED_FIELDS.contains(&key.as_str()) && !value.is_null() {
+                    *value = Value::from(REDACTED);
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+fn redact_query(query: &str) -> String {
+    match serde_urlencoded::from_str::<Vec<(String, String)>>(query) {
+        Ok(params) => {
+            let params = params
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = if REDACTED_FIELDS.contains(&key.as_str()) {
+                        REDACTED.to_owned()
+                    } else {
+                        value
+                    };
+                    (key, value)
+                })
+                .collect::<Vec<_>>();
+            serde_urlencoded::to_string(params).unwrap_or_default()
+        }
+        Err(_) => query.to_owned(),
+    }
+}
+
+fn truncate(mut text: String, max: usize) -> String {
+    if text.len() <= max {
+        return text;
+    }
+    let len = text.len();
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    format!("{}... ({} bytes in all)", text, len)
+}
+
+#[derive(Clone)]
+pub struct RecordingLayer {
+    state: SharedState,
+}
+
+impl RecordingLayer {
+    pub fn new(state: SharedState) -> Self {
+        RecordingLayer { state }
+    }
+}
+
+impl<S> Layer<S> for RecordingLayer {
+    type Service = RecordingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RecordingService {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RecordingService<S> {
+    inner: S,
+    state: SharedState,
+}
+
+impl<S> Service<Request<Body>> for RecordingService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // The admin routes carry exports and tokens, and would record
+        // themselves being looked at
+        if !self.state.recorder.is_enabled() || req.uri().path().starts_with("/admin") {
+            return Box::pin(self.inner.call(req));
+        }
+
+        // The service which was made ready, a fresh clone stays behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let state = self.state.clone();
+
+        Box::pin(async move {
+            let started = Instant::now();
+            let received = Utc::now();
+            let (parts, request_body) = req.into_parts();
+            let request_body = match collect(request_body).await {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    tracing::debug!("failed to read a request body for recording: {}", err);
+                    Bytes::new()
+                }
+            };
+
+            let recorder = &state.recorder;
+            let mut exchange = Exchange {
+                request_id: parts.extensions.get::<RequestId>().map(|id| id.0),
+                received,
+                duration_ms: 0.0,
+                method: parts.method.to_string(),
+                uri: match parts.uri.query() {
+                    Some(query) => format!("{}?{}", parts.uri.path(), redact_query(query)),
+                    None => parts.uri.path().to_owned(),
+                },
+                request_headers: recorder.show_headers(&parts.headers),
+                request_body: recorder.show_body(&parts.headers, &request_body),
+                status: 0,
+                response_headers: Vec::new(),
+                response_body: None,
+            };
+
+            let response = inner
+                .call(Request::from_parts(parts, Body::from(request_body)))
+                .await?;
+
+            // Streamed bodies are collected too, in this mode it's worth it
+            let (parts, response_body) = response.into_parts();
+            let response_body = collect(response_body).await;
+            exchange.duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+            exchange.status = parts.status.as_u16();
+            exchange.response_headers = recorder.show_headers(&parts.headers);
+            let body = match response_body {
+                Ok(bytes) => {
+                    exchange.response_body = recorder.show_body(&parts.headers, &bytes);
+                    body::boxed(Body::from(bytes))
+                }
+                Err(err) => {
+                    exchange.response_body = Some(format!("<failed: {}>", err));
+                    // Cut off for the client as well, as it would have been
+                    let (sender, body) = Body::channel();
+                    sender.abort();
+                    body::boxed(body)
+                }
+            };
+            recorder.push(exchange);
+
+            Ok(Response::from_parts(parts, body))
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Toggle {
+    enabled: bool,
+}
+
+fn recording(state: &SharedState) -> Json<Value> {
+    Json(json!({
+        "enabled": state.recorder.is_enabled(),
+        "requests": state.recorder.recent(),
+    }))
+}
+
+// GET /admin/recent-requests
+pub async fn get_recent_requests(
+    _: Admin,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    recording(&state)
+}
+
+// PUT /admin/recent-requests {"enabled": true}
+// What was recorded so far stays until DELETE, also after turning it off
+pub async fn set_recording(
+    _: Admin,
+    ParsedJson(input): ParsedJson<Toggle>,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    state
+        .recorder
+        .enabled
+        .store(input.enabled, Ordering::Relaxed);
+    tracing::info!(enabled = input.enabled, "request recording toggled");
+    recording(&state)
+}
+
+// DELETE /admin/recent-requests
+pub async fn clear_recent_requests(
+    _: Admin,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    state.recorder.exchanges.lock().unwrap().clear();
+    recording(&state)
+}
@@ -0,0 +1,332 @@
+// Several instances sharing their comments through Redis, see [redis]. The
+// hash <prefix>:comments holds every comment as JSON, and every change goes
+// to it and is published on <prefix>:changes. Each instance still keeps all
+// comments in memory, writes its own changes through and folds in what the
+// others publish, which also wakes GET /poll and the gRPC watch
+//
+// At startup the comments come from Redis, or seed it while it has none yet.
+// The snapshot and the event log stay the instance's own, with its site
+// settings, rules and the history of the changes made on it. Only built with
+// the `redis` feature
+use std::{collections::HashMap, sync::Arc};
+#[cfg(feature = "redis")]
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "redis")]
+use futures_util::StreamExt;
+#[cfg(feature = "redis")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "redis")]
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{config::Config, Comment};
+#[cfg(feature = "redis")]
+use crate::{
+    events,
+    state::{AppState, SharedState},
+    storage,
+};
+
+type Comments = HashMap<Uuid, Arc<Comment>>;
+
+#[cfg(feature = "redis")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Change {
+    Put { comment: Arc<Comment> },
+    Delete { id: Uuid },
+}
+
+// As published
+#[cfg(feature = "redis")]
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    // Of the instance which made the change, which skips it
+    instance: Uuid,
+    #[serde(flatten)]
+    change: Change,
+}
+
+#[cfg(feature = "redis")]
+struct Connected {
+    client: redis::Client,
+    connection: redis::aio::ConnectionManager,
+    instance: Uuid,
+    comments_key: String,
+    channel: String,
+    // In the order they were made, written by `run`
+    changes: mpsc::UnboundedSender<Change>,
+    queued: Mutex<Option<mpsc::UnboundedReceiver<Change>>>,
+}
+
+pub struct RedisStore {
+    // None without [redis] url
+    #[cfg(feature = "redis")]
+    connected: Option<Connected>,
+}
+
+#[cfg(feature = "redis")]
+fn parse(comments: HashMap<String, String>) -> Comments {
+    comments
+        .into_iter()
+        .filter_map(|(id, json)| match serde_json::from_str::<Comment>(&json) {
+            Ok(comment) => Some((comment.id, Arc::new(comment))),
+            Err(err) => {
+                tracing::warn!(%id, "skipping a broken comment in Redis: {}", err);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(feature = "redis")]
+async fn load(connected: &Connected) -> redis::RedisResult<Comments> {
+    let comments = redis::cmd("HGETALL")
+        .arg(&connected.comments_key)
+        .query_async(&mut connected.connection.clone())
+        .await?;
+    Ok(parse(comments))
+}
+
+impl RedisStore {
+    // Replaces `comments`, those of the snapshot, with the shared ones.
+    // Retried as long as the storage is, see storage::open_with_retry
+    #[cfg(feature = "redis")]
+    pub async fn connect(config: &Config, comments: &mut Comments) -> Result<RedisStore, String> {
+        let url = match &config.redis.url {
+            Some(url) => url,
+            None => return Ok(RedisStore { connected: None }),
+        };
+        let client = redis::Client::open(url.as_str())
+            .map_err(|err| format!("invalid [redis] url: {}", err))?;
+
+        let deadline = Instant::now() + Duration::from_secs(config.storage.startup_retry_secs);
+        let mut delay = storage::FIRST_RETRY_DELAY;
+        let connection = loop {
+            match client.get_connection_manager().await {
+                Ok(connection) => break connection,
+                Err(err) if Instant::now() + delay < deadline => {
+                    tracing::warn!("Redis not available yet, retrying in {:?}: {}", delay, err);
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(storage::MAX_RETRY_DELAY);
+                }
+                Err(err) => return Err(format!("failed to connect to Redis: {}", err)),
+            }
+        };
+
+        let (changes, queued) = mpsc::unbounded_channel();
+        let prefix = &config.redis.prefix;
+        let connected = Connected {
+            client,
+            connection,
+            instance: Uuid::new_v4(),
+            comments_key: format!("{}:comments", prefix),
+            channel: format!("{}:changes", prefix),
+            changes,
+            queued: Mutex::new(Some(queued)),
+        };
+
+        let shared = load(&connected)
+            .await
+            .map_err(|err| format!("failed to read the comments from Redis: {}", err))?;
+        if shared.is_empty() && !comments.is_empty() {
+            tracing::info!(comments = comments.len(), "seeding Redis with the snapshot");
+            for comment in comments.values() {
+                connected.put(comment);
+            }
+        } else {
+            tracing::debug!(comments = shared.len(), "comments read from Redis");
+            *comments = shared;
+        }
+        Ok(RedisStore {
+            connected: Some(connected),
+        })
+    }
+
+    #[cfg(not(feature = "redis"))]
+    pub async fn connect(config: &Config, _: &mut Comments) -> Result<RedisStore, String> {
+        if config.redis.url.is_some() {
+            tracing::warn!(
+                "[redis] url is set, but little-nova was built without the `redis` feature"
+            );
+        }
+        Ok(RedisStore {})
+    }
+
+    // For the other instances, after the comment was stored here
+    #[cfg(feature = "redis")]
+    pub fn put(&self, comment: &Arc<Comment>) {
+        if let Some(connected) = &self.connected {
+            connected.put(comment);
+        }
+    }
+
+    #[cfg(not(feature = "redis"))]
+    pub fn put(&self, _: &Arc<Comment>) {}
+
+    #[cfg(feature = "redis")]
+    pub fn delete(&self, id: Uuid) {
+        if let Some(connected) = &self.connected {
+            connected.send(Change::Delete { id });
+        }
+    }
+
+    #[cfg(not(feature = "redis"))]
+    pub fn delete(&self, _: Uuid) {}
+}
+
+#[cfg(feature = "redis")]
+impl Connected {
+    fn put(&self, comment: &Arc<Comment>) {
+        self.send(Change::Put {
+            comment: comment.clone(),
+        });
+    }
+
+    fn send(&self, change: Change) {
+        // Only closed once the server stops
+        let _ = self.changes.send(change);
+    }
+
+    async fn write(&self, change: Change) -> redis::RedisResult<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        match &change {
+            Change::Put { comment } => {
+                let json = serde_json::to_string(comment).map_err(io_error)?;
+                pipe.hset(&self.comments_key, comment.id.to_string(), json)
+            }
+            Change::Delete { id } => pipe.hdel(&self.comments_key, id.to_string()),
+        }
+        .ignore();
+        let message = Message {
+            instance: self.instance,
+            change,
+        };
+        let message = serde_json::to_string(&message).map_err(io_error)?;
+        pipe.publish(&self.channel, message).ignore();
+        pipe.query_async(&mut self.connection.clone()).await
+    }
+}
+
+#[cfg(feature = "redis")]
+fn io_error(err: serde_json::Error) -> redis::RedisError {
+    std::io::Error::other(err).into()
+}
+
+// A change another instance made. Its hooks ran there, only what is derived
+// from the comments here is updated
+#[cfg(feature = "redis")]
+fn apply(state: &AppState, change: Change) {
+    let mut comments = state.db.write().unwrap();
+    let (old, new) = match change {
+        Change::Put { comment } => (comments.insert(comment.id, comment.clone()), Some(comment)),
+        Change::Delete { id } => (comments.remove(&id), None),
+    };
+    events::applied(state, old.as_ref(), new.as_ref());
+    drop(comments);
+    state.published.notify_waiters();
+}
+
+// After (re)subscribing, for what was published meanwhile. Deletions made
+// while the subscription was lost only show after a restart
+#[cfg(feature = "redis")]
+async fn catch_up(state: &AppState, connected: &Connected) {
+    let shared = match load(connected).await {
+        Ok(shared) => shared,
+        Err(err) => {
+            tracing::warn!("failed to read the comments from Redis: {}", err);
+            return;
+        }
+    };
+    let newer = {
+        let comments = state.db.read().unwrap();
+        shared
+            .into_values()
+            .filter(|comment| {
+                comments
+                    .get(&comment.id)
+                    .is_none_or(|ours| comment.updated_at() > ours.updated_at())
+            })
+            .collect::<Vec<_>>()
+    };
+    if !newer.is_empty() {
+        tracing::debug!(comments = newer.len(), "caught up with Redis");
+    }
+    for comment in newer {
+        apply(state, Change::Put { comment });
+    }
+}
+
+#[cfg(feature = "redis")]
+async fn write_changes(state: SharedState) {
+    let connected = match &state.redis.connected {
+        Some(connected) => connected,
+        None => return,
+    };
+    let mut queued = match connected.queued.lock().unwrap().take() {
+        Some(queued) => queued,
+        None => return,
+    };
+    while let Some(change) = queued.recv().await {
+        // Kept until written, so the others see the changes in order
+        let mut delay = storage::FIRST_RETRY_DELAY;
+        while let Err(err) = connected.write(change.clone()).await {
+            tracing::error!(
+                "failed to write a change to Redis, retrying in {:?}: {}",
+                delay,
+                err
+            );
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(storage::MAX_RETRY_DELAY);
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+async fn follow_changes(state: SharedState) {
+    let connected = match &state.redis.connected {
+        Some(connected) => connected,
+        None => return,
+    };
+    loop {
+        let subscribed = match connected.client.get_async_pubsub().await {
+            Ok(mut pubsub) => pubsub.subscribe(&connected.channel).await.map(|()| pubsub),
+            Err(err) => Err(err),
+        };
+        match subscribed {
+            Ok(mut pubsub) => {
+                catch_up(&state, connected).await;
+                let mut messages = pubsub.on_message();
+                while let Some(message) = messages.next().await {
+                    let message = message
+                        .get_payload::<String>()
+                        .map_err(|err| err.to_string())
+                        .and_then(|payload| {
+                            serde_json::from_str::<Message>(&payload).map_err(|err| err.to_string())
+                        });
+                    match message {
+                        Ok(message) if message.instance != connected.instance => {
+                            apply(&state, message.change)
+                        }
+                        Ok(_) => {}
+                        Err(err) => tracing::warn!("skipping a broken change from Redis: {}", err),
+                    }
+                }
+                tracing::warn!("lost the Redis subscription, subscribing again");
+            }
+            Err(err) => tracing::warn!("failed to subscribe to Redis: {}", err),
+        }
+        tokio::time::sleep(storage::MAX_RETRY_DELAY).await;
+    }
+}
+
+// Runs for the lifetime of the server
+#[cfg(feature = "redis")]
+pub async fn run(state: SharedState) {
+    tokio::join!(write_changes(state.clone()), follow_changes(state));
+}
@@ -0,0 +1,245 @@
+// Read replicas: an instance with [replication] primary set follows the
+// primary's GET /admin/replication and mirrors every comment, site setting
+// and spam rule, so reads can be spread over several instances. Replicas
+// refuse new comments, votes and moderation, those go to the primary
+//
+// The first request has no `since` and gets everything, each later one the
+// changes since the `until` of the previous answer. Deletions come as
+// tombstones, which are kept for 30 days; a replica further behind is
+// answered with 410 and starts over
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::Admin, blocked_names::AddedBlockedName, changes::Tombstone, config::SiteSettings,
+    errors::ApiError, extract::ParsedQuery, notifications::NotificationPreferences,
+    rules::AddedRule, state::SharedState, Comment,
+};
+
+// State of a replica, unused on a primary
+#[derive(Default)]
+pub struct Replication {
+    following: AtomicBool,
+    // When the last changes were applied
+    synced_at: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl Replication {
+    pub fn is_replica(&self) -> bool {
+        self.following.load(Ordering::Relaxed)
+    }
+
+    // Replicas aren't ready until they have everything once
+    pub fn is_synced(&self) -> bool {
+        !self.is_replica() || self.synced_at.lock().unwrap().is_some()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplicationQuery {
+    since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Batch {
+    // Everything instead of the changes, replacing what the replica has
+    full: bool,
+    // The `since` of the next request
+    until: DateTime<Utc>,
+    // Created or changed, whole as stored
+    comments: Vec<Arc<Comment>>,
+    tombstones: Vec<Tombstone>,
+    // Always all of them, there are few
+    sites: HashMap<String, SiteSettings>,
+    rules: Vec<AddedRule>,
+    #[serde(default)]
+    blocked_names: Vec<AddedBlockedName>,
+    #[serde(default)]
+    notifications: Option<NotificationPreferences>,
+}
+
+// GET /admin/replication?since=<until of the previous answer>
+pub async fn get_replication(
+    _: Admin,
+    ParsedQuery(query): ParsedQuery<ReplicationQuery>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let comments = state.db.read().unwrap();
+    let until = Utc::now();
+    let batch = match query.since {
+        Some(since) => Batch {
+            full: false,
+            until,
+            comments: comments
+                .values()
+                .filter(|comment| comment.updated_at() > since)
+                .cloned()
+                .collect(),
+            tombstones: state.tombstones.since(since).ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::GONE,
+                    "changes.too_old",
+                    "since is too long ago, start over without it",
+                )
+            })?,
+            sites: state.sites.persisted(),
+            rules: state.rules.added(),
+            blocked_names: state.blocked_names.added(),
+            notifications: state.notifications.changed(),
+        },
+        None => Batch {
+            full: true,
+            until,
+            comments: comments.values().cloned().collect(),
+            tombstones: state.tombstones.all(),
+            sites: state.sites.persisted(),
+            rules: state.rules.added(),
+            blocked_names: state.blocked_names.added(),
+            notifications: state.notifications.changed(),
+        },
+    };
+    drop(comments);
+
+    Ok(Json(batch))
+}
+
+// Comments changed and deleted
+#[cfg(feature = "replication")]
+fn apply(state: &SharedState, batch: Batch) -> (usize, usize) {
+    if batch.full {
+        let contents = crate::storage::Contents {
+            comments: batch
+                .comments
+                .into_iter()
+                .map(|comment| (comment.id, comment))
+                .collect(),
+            sites: batch.sites,
+            rules: batch.rules,
+            blocked_names: batch.blocked_names,
+            notifications: batch.notifications,
+            tombstones: batch.tombstones,
+            last_event: 0,
+        };
+        return crate::backup::replace(state, contents);
+    }
+
+    let changed = batch.comments.len();
+    let mut deleted = 0;
+    {
+        let mut db = state.db.write().unwrap();
+        for comment in batch.comments {
+            state.storage.mark_changed(comment.id);
+            match db.insert(comment.id, comment.clone()) {
+                Some(old) => {
+                    state.stats.replace(&old, &comment);
+                    state.trending.replace(&old, &comment);
+                    state.search_index.replace(&old, &comment);
+                }
+                None => {
+                    state.stats.add(&comment);
+                    state.trending.add(&comment);
+                    state.search_index.add(&comment);
+                }
+            }
+        }
+        for tombstone in &batch.tombstones {
+            if let Some(comment) = db.remove(&tombstone.id) {
+                state.storage.mark_changed(comment.id);
+                state.stats.remove(&comment);
+                state.trending.remove(&comment);
+                state.search_index.remove(&comment);
+                // For clients following /changes on the replica
+                state.tombstones.add(&comment);
+                deleted += 1;
+            }
+        }
+        state.sites.restore(&state.config, batch.sites);
+        state.rules.restore(batch.rules);
+        state.blocked_names.restore(batch.blocked_names);
+        state.notifications.restore(batch.notifications);
+    }
+    if changed > 0 || deleted > 0 {
+        state.storage.mark_dirty();
+        state.published.notify_waiters();
+    }
+    (changed, deleted)
+}
+
+#[cfg(feature = "replication")]
+enum Fetched {
+    Batch(Box<Batch>),
+    // Too far behind, see get_replication
+    Gone,
+}
+
+#[cfg(feature = "replication")]
+async fn fetch(
+    client: &reqwest::Client,
+    url: &str,
+    token: Option<&str>,
+    since: Option<DateTime<Utc>>,
+) -> Result<Fetched, reqwest::Error> {
+    let mut request = match since {
+        Some(since) => {
+            let query =
+                serde_urlencoded::to_string([("since", since.to_rfc3339())]).unwrap_or_default();
+            client.get(format!("{}?{}", url, query))
+        }
+        None => client.get(url),
+    };
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?;
+    if response.status() == reqwest::StatusCode::GONE {
+        return Ok(Fetched::Gone);
+    }
+    Ok(Fetched::Batch(response.error_for_status()?.json().await?))
+}
+
+// Runs for the lifetime of the replica
+#[cfg(feature = "replication")]
+pub async fn follow(state: SharedState, primary: String) {
+    let config = &state.config.replication;
+    state.replication.following.store(true, Ordering::Relaxed);
+
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(30))
+        .build()
+        .expect("failed to build the replication client");
+    let url = format!("{}/admin/replication", primary.trim_end_matches('/'));
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_secs(config.interval_secs.max(1)));
+    let mut since = None;
+
+    loop {
+        interval.tick().await;
+        match fetch(&client, &url, config.token.as_deref(), since).await {
+            Ok(Fetched::Batch(batch)) => {
+                let (full, until) = (batch.full, batch.until);
+                let (changed, deleted) = apply(&state, *batch);
+                if full {
+                    tracing::info!(comments = changed, "replicated everything from {}", primary);
+                } else if changed > 0 || deleted > 0 {
+                    tracing::debug!(changed, deleted, "replicated changes");
+                }
+                since = Some(until);
+                *state.replication.synced_at.lock().unwrap() = Some(Utc::now());
+            }
+            Ok(Fetched::Gone) => {
+                tracing::warn!("too far behind {}, replicating everything again", primary);
+                since = None;
+            }
+            Err(err) => tracing::warn!("failed to replicate from {}: {}", primary, err),
+        }
+    }
+}
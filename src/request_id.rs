@@ -0,0 +1,33 @@
+use std::fmt;
+
+use axum::http::{header::HeaderName, HeaderValue, Request};
+use uuid::Uuid;
+
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+// Id attached to every request, for correlating logs and error reports
+#[derive(Debug, Clone, Copy)]
+pub struct RequestId(pub Uuid);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// Reuse the id given by a proxy in front of us, otherwise generate a new one
+pub fn set_request_id<B>(mut req: Request<B>) -> Request<B> {
+    let id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| Uuid::parse_str(value).ok())
+        .unwrap_or_else(Uuid::new_v4);
+
+    req.headers_mut().insert(
+        REQUEST_ID_HEADER.clone(),
+        HeaderValue::from_str(&id.to_string()).unwrap(),
+    );
+    req.extensions_mut().insert(RequestId(id));
+    req
+}
@@ -0,0 +1,199 @@
+// Spam rules which reject or queue new comments
+// Rules come from [[rules]] in the config and from the admin API, which
+// moderators use to react to new spam without a restart
+use std::sync::RwLock;
+
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    admin::Admin,
+    errors::ApiError,
+    extract::{Validate, ValidatedJson},
+    state::SharedState,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Rule {
+    // Only applies to this site, to every site while unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site: Option<String>,
+    // Matches comments with more links than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_links: Option<usize>,
+    // Matches comments linking to these domains or their subdomains
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked_domains: Vec<String>,
+    pub action: RuleAction,
+}
+
+// Ordered by severity, the most severe matching rule wins
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    // Wait for an admin to approve
+    Queue,
+    Reject,
+}
+
+// Added through the admin API, the id is for deleting it again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddedRule {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub rule: Rule,
+}
+
+impl Validate for Rule {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        if self.max_links.is_none() && self.blocked_domains.is_empty() {
+            return Err((
+                "rule.invalid",
+                "A rule needs max_links or blocked_domains".to_owned(),
+            ));
+        }
+        if let Some(domain) = self.blocked_domains.iter().find(|domain| {
+            domain.is_empty() || domain.contains(['/', ':']) || domain.to_lowercase() != **domain
+        }) {
+            return Err((
+                "rule.invalid_domain",
+                format!(
+                    "Invalid domain \"{}\", expected a lowercase host like \"spam.example\"",
+                    domain
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn matches(&self, site: &str, hosts: &[String]) -> bool {
+        if self.site.as_ref().is_some_and(|only| only != site) {
+            return false;
+        }
+        let too_many = self.max_links.is_some_and(|max| hosts.len() > max);
+        let blocked = hosts.iter().any(|host| {
+            self.blocked_domains.iter().any(|domain| {
+                host == domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+        });
+        too_many || blocked
+    }
+}
+
+pub struct Rules {
+    configured: Vec<Rule>,
+    added: RwLock<Vec<AddedRule>>,
+}
+
+impl Rules {
+    pub fn new(configured: Vec<Rule>, added: Vec<AddedRule>) -> Self {
+        Rules {
+            configured,
+            added: RwLock::new(added),
+        }
+    }
+
+    // Persisted in the snapshot
+    pub fn added(&self) -> Vec<AddedRule> {
+        self.added.read().unwrap().clone()
+    }
+
+    // Replaces the rules added through the admin API, see backup.rs
+    pub fn restore(&self, added: Vec<AddedRule>) {
+        *self.added.write().unwrap() = added;
+    }
+
+    // As through the admin API, the storage still has to be marked dirty
+    pub fn add(&self, rule: Rule) -> AddedRule {
+        let added = AddedRule {
+            id: Uuid::new_v4(),
+            rule,
+        };
+        self.added.write().unwrap().push(added.clone());
+        added
+    }
+
+    // The action of the most severe rule matching the comment, if any
+    pub fn check(&self, site: &str, title: Option<&str>, text: &str) -> Option<RuleAction> {
+        let mut hosts = link_hosts(text);
+        hosts.extend(title.map(link_hosts).unwrap_or_default());
+
+        let added = self.added.read().unwrap();
+        self.configured
+            .iter()
+            .chain(added.iter().map(|added| &added.rule))
+            .filter(|rule| rule.matches(site, &hosts))
+            .map(|rule| rule.action)
+            .max()
+    }
+}
+
+// Host of every http(s) link in `text`, lowercased
+pub fn link_hosts(text: &str) -> Vec<String> {
+    let text = text.to_lowercase();
+    text.split(|c: char| c.is_whitespace() || "<>\"'()[]".contains(c))
+        .filter_map(|word| {
+            let start = word.find("http://").or_else(|| word.find("https://"))?;
+            let (_, rest) = word[start..].split_once("://")?;
+            let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+            // Strip the user info and the port
+            let host = authority.rsplit('@').next().unwrap_or(authority);
+            let host = host.split(':').next().unwrap_or(host);
+            Some(host.trim_end_matches('.').to_owned())
+        })
+        .collect()
+}
+
+pub async fn get_rules(_: Admin, Extension(state): Extension<SharedState>) -> impl IntoResponse {
+    Json(json!({
+        "configured": state.rules.configured,
+        "added": state.rules.added(),
+    }))
+}
+
+pub async fn add_rule(
+    _: Admin,
+    ValidatedJson(rule): ValidatedJson<Rule>,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let added = state.rules.add(rule);
+    state.storage.mark_dirty();
+    tracing::info!(id = %added.id, "spam rule added");
+
+    (StatusCode::CREATED, Json(added))
+}
+
+// Rules from the config can only be removed there
+pub async fn delete_rule(
+    _: Admin,
+    Path(id): Path<Uuid>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut added = state.rules.added.write().unwrap();
+    let index = added
+        .iter()
+        .position(|added| added.id == id)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "rule.not_found", "No such rule"))?;
+    added.remove(index);
+    drop(added);
+
+    state.storage.mark_dirty();
+    tracing::info!(%id, "spam rule deleted");
+
+    Ok(StatusCode::NO_CONTENT)
+}
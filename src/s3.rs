@@ -0,0 +1,275 @@
+// Attachments in an S3 bucket, or any store speaking its API such as MinIO,
+// see [attachments.s3]. Requests are signed with AWS Signature Version 4.
+// GET /attachments/<key> redirects to a presigned URL, so once stored the
+// files never go through this server again
+use std::io;
+
+use axum::{async_trait, body::Bytes};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+
+use crate::{attachments::AttachmentStore, config::S3Config, trace_context};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+pub struct S3 {
+    client: reqwest::Client,
+    // Scheme and host of the requests, with the bucket for virtual hosts
+    base: String,
+    host: String,
+    // Of the objects, the bucket stays out of it with virtual hosts
+    path: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    presign_secs: u64,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Everything but the unreserved characters, and '/' unless it separates the
+// segments of a path
+fn uri_encode(text: &str, path: bool) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if path => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn amz_date(now: DateTime<Utc>) -> String {
+    now.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+// What the signature covers of a request. `headers` are "name:value\n"
+// lines, sorted by name
+struct Canonical<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    headers: &'a str,
+    signed_headers: &'a str,
+    payload_hash: &'a str,
+}
+
+fn failed(err: impl std::fmt::Display) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+impl S3 {
+    pub fn new(config: &S3Config) -> Result<S3, String> {
+        if config.bucket.is_empty() || config.access_key.is_empty() || config.secret_key.is_empty()
+        {
+            return Err("[attachments.s3] needs a bucket, access_key and secret_key".to_owned());
+        }
+        let endpoint = Url::parse(&config.endpoint)
+            .map_err(|err| format!("invalid endpoint {}: {}", config.endpoint, err))?;
+        let mut host = endpoint
+            .host_str()
+            .ok_or_else(|| format!("endpoint {} has no host", config.endpoint))?
+            .to_owned();
+        if let Some(port) = endpoint.port() {
+            host = format!("{}:{}", host, port);
+        }
+        let mut path = endpoint.path().trim_end_matches('/').to_owned();
+        if config.path_style {
+            path = format!("{}/{}", path, uri_encode(&config.bucket, false));
+        } else {
+            host = format!("{}.{}", config.bucket, host);
+        }
+        path = format!("{}/{}", path, uri_encode(&config.prefix, true));
+
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .build()
+            .map_err(|err| format!("failed to build the S3 client: {}", err))?;
+        Ok(S3 {
+            client,
+            base: format!("{}://{}", endpoint.scheme(), host),
+            host,
+            path,
+            region: config.region.clone(),
+            access_key: config.access_key.clone(),
+            secret_key: config.secret_key.clone(),
+            presign_secs: config.presign_secs,
+        })
+    }
+
+    fn scope(&self, now: DateTime<Utc>) -> String {
+        format!("{}/{}/s3/aws4_request", now.format("%Y%m%d"), self.region)
+    }
+
+    // Keys are made of unreserved characters, see attachments::content_type_of
+    fn object(&self, key: &str) -> String {
+        format!("{}{}", self.path, uri_encode(key, false))
+    }
+
+    fn signature(&self, now: DateTime<Utc>, request: &Canonical) -> String {
+        let canonical = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            request.method,
+            request.path,
+            request.query,
+            request.headers,
+            request.signed_headers,
+            request.payload_hash
+        );
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            ALGORITHM,
+            amz_date(now),
+            self.scope(now),
+            hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let date = now.format("%Y%m%d").to_string();
+        let key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        let key = hmac(&key, &self.region);
+        let key = hmac(&key, "s3");
+        let key = hmac(&key, "aws4_request");
+        hex(&hmac(&key, &string_to_sign))
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        body: Option<(&str, Bytes)>,
+    ) -> io::Result<reqwest::Response> {
+        let now = Utc::now();
+        let payload = body.as_ref().map(|(_, data)| data.as_ref()).unwrap_or(&[]);
+        let payload_hash = hex(&Sha256::digest(payload));
+        let path = self.object(key);
+        let headers = format!(
+            "host:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n",
+            self.host,
+            payload_hash,
+            amz_date(now)
+        );
+        let signature = self.signature(
+            now,
+            &Canonical {
+                method: method.as_str(),
+                path: &path,
+                query: "",
+                headers: &headers,
+                signed_headers: SIGNED_HEADERS,
+                payload_hash: &payload_hash,
+            },
+        );
+        let authorization = format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM,
+            self.access_key,
+            self.scope(now),
+            SIGNED_HEADERS,
+            signature
+        );
+
+        let mut request = self
+            .client
+            .request(method, format!("{}{}", self.base, path))
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date(now))
+            .header(reqwest::header::AUTHORIZATION, authorization);
+        for (name, value) in trace_context::outbound_headers() {
+            request = request.header(name, value);
+        }
+        if let Some((content_type, data)) = body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, content_type)
+                .body(data);
+        }
+        request.send().await.map_err(failed)
+    }
+
+    fn presign(&self, key: &str, now: DateTime<Utc>) -> String {
+        let path = self.object(key);
+        let credential = format!("{}/{}", self.access_key, self.scope(now));
+        // Sorted by name, as signed
+        let query = format!(
+            "X-Amz-Algorithm={}&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+            ALGORITHM,
+            uri_encode(&credential, false),
+            amz_date(now),
+            self.presign_secs
+        );
+        let headers = format!("host:{}\n", self.host);
+        let signature = self.signature(
+            now,
+            &Canonical {
+                method: "GET",
+                path: &path,
+                query: &query,
+                headers: &headers,
+                signed_headers: "host",
+                payload_hash: "UNSIGNED-PAYLOAD",
+            },
+        );
+        format!(
+            "{}{}?{}&X-Amz-Signature={}",
+            self.base, path, query, signature
+        )
+    }
+}
+
+// Anything but a success is an error, with the start of the body S3 sent
+async fn check(response: reqwest::Response) -> io::Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(failed(format!(
+        "S3 answered {}: {}",
+        status,
+        body.chars().take(200).collect::<String>()
+    )))
+}
+
+#[async_trait]
+impl AttachmentStore for S3 {
+    async fn put(&self, key: &str, content_type: &str, data: Bytes) -> io::Result<()> {
+        let response = self
+            .send(Method::PUT, key, Some((content_type, data)))
+            .await?;
+        check(response).await.map(drop)
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Option<Bytes>> {
+        let response = self.send(Method::GET, key, None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let data = check(response).await?.bytes().await.map_err(failed)?;
+        Ok(Some(data))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        let response = self.send(Method::DELETE, key, None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        check(response).await.map(drop)
+    }
+
+    fn presigned_url(&self, key: &str) -> Option<String> {
+        Some(self.presign(key, Utc::now()))
+    }
+}
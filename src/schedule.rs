@@ -0,0 +1,124 @@
+// Scheduled publishing: a held comment given a `publish_at` is approved at
+// that time, e.g. for an announcement staged in the guestbook beforehand.
+// The scheduler looks every few seconds, so it is published up to
+// SCHEDULE_INTERVAL late. Approving it by hand before works as usual
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    admin::Admin,
+    domain::{self, Transition, TransitionError},
+    errors::ApiError,
+    events::{self, CommentEvent},
+    extract::{Validate, ValidatedJson},
+    preconditions::{self, IfMatch},
+    state::SharedState,
+    Comment, CommentStatus,
+};
+
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Schedule {
+    // null takes the schedule back, the comment stays held
+    publish_at: Option<DateTime<Utc>>,
+}
+
+impl Validate for Schedule {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        match self.publish_at {
+            Some(publish_at) if publish_at <= Utc::now() => Err((
+                "schedule.in_the_past",
+                "publish_at has to be in the future".to_owned(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// PUT /admin/comments/:id/schedule {"publish_at": "2024-01-01T09:00:00Z"}
+pub async fn schedule_comment(
+    _: Admin,
+    Path(id): Path<Uuid>,
+    if_match: IfMatch,
+    ValidatedJson(input): ValidatedJson<Schedule>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut comments = state.db.write().unwrap();
+    let comment = comments.get(&id).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "comment.not_found",
+            "No such comment",
+        )
+    })?;
+    if_match.check(Some(comment))?;
+    if comment.status != CommentStatus::Pending {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "comment.invalid_transition",
+            "Only held comments can be scheduled",
+        ));
+    }
+    let mut scheduled = Comment::clone(comment);
+    scheduled.publish_at = input.publish_at;
+    scheduled.touch();
+    let event = CommentEvent::Edited {
+        comment: Arc::new(scheduled),
+    };
+    let comment = events::commit(&state, &mut comments, event)
+        .map_err(events::failed)?
+        .expect("an edited comment is stored");
+    drop(comments);
+    tracing::info!(%id, publish_at = ?input.publish_at, "comment scheduled");
+
+    Ok((preconditions::etag(&comment), Json(comment)))
+}
+
+// Approves held comments whose time has come
+pub async fn publish_scheduled_periodically(state: SharedState) {
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+    loop {
+        interval.tick().await;
+        // The primary publishes them, see replication.rs
+        if state.replication.is_replica() {
+            continue;
+        }
+
+        let now = Utc::now();
+        let due = state
+            .db
+            .read()
+            .unwrap()
+            .values()
+            .filter(|comment| comment.status == CommentStatus::Pending)
+            .filter(|comment| comment.publish_at.is_some_and(|at| at <= now))
+            .map(|comment| comment.id)
+            .collect::<Vec<_>>();
+        if due.is_empty() {
+            continue;
+        }
+
+        let mut comments = state.db.write().unwrap();
+        for id in due {
+            match domain::transition(&state, &mut comments, id, Transition::Approve) {
+                // Approved or deleted in between
+                Ok(_) | Err(TransitionError::NotFound | TransitionError::Invalid { .. }) => {}
+                Err(err) => {
+                    tracing::error!(%id, "failed to publish a scheduled comment: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+// Search of a site's listed comments, as `GET /search?q=helo world`. Words
+// match with up to [search] max_distance typos (edits), so "helo world" still
+// finds "hello world", words of fewer than MIN_FUZZY_LEN characters only
+// exactly. Every word of the query has to match, in the title or the text.
+// The closest matches come first, then the newest, with the matched words of
+// the title and text between <mark> and </mark>, escaped like text_html:
+//
+//   {"query": "helo world", "total": 1,
+//    "results": [{"id": "0190...", ..., "relevance": 1.5,
+//                 "highlight": {"title": null, "text": "<mark>Hello</mark> <mark>world</mark>!"}}]}
+//
+// With [search] index_dir, a tantivy index finds them instead, see
+// search_index.rs, and the relevance is its score
+use std::{collections::HashMap, ops::Range, sync::Arc};
+
+use axum::{
+    body::{Bytes, Full},
+    extract::Extension,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    changes::PublicComment,
+    codec::Format,
+    errors::ApiError,
+    extract::{Validate, ValidatedQuery},
+    markup,
+    sites::Site,
+    state::SharedState,
+    Comment,
+};
+
+const MAX_LIMIT: usize = 50;
+const DEFAULT_LIMIT: usize = 20;
+
+// Shorter words have too many neighbours, "cat" is one edit from "car"
+const MIN_FUZZY_LEN: usize = 4;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SearchQuery {
+    q: String,
+    // 20 by default
+    limit: Option<usize>,
+    // Typos to allow, [search] max_distance by default and at most
+    distance: Option<usize>,
+}
+
+impl Validate for SearchQuery {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        if words(&self.q).next().is_none() {
+            return Err(("search.empty_query", "q has no words".to_owned()));
+        }
+        match self.limit {
+            Some(limit) if limit == 0 || limit > MAX_LIMIT => Err((
+                "request.invalid_limit",
+                format!("limit must be between 1 and {}", MAX_LIMIT),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    #[serde(flatten)]
+    comment: PublicComment,
+    // Higher is closer, 1 for every word of the query which matched exactly
+    relevance: f64,
+    highlight: Highlight,
+}
+
+#[derive(Serialize)]
+struct Highlight {
+    title: Option<String>,
+    text: String,
+}
+
+// Lowercased, with where they are
+pub fn words(text: &str) -> impl Iterator<Item = (Range<usize>, String)> + '_ {
+    let mut chars = text.char_indices().peekable();
+    std::iter::from_fn(move || {
+        while chars.next_if(|(_, c)| !c.is_alphanumeric()).is_some() {}
+        let start = chars.peek()?.0;
+        let mut end = start;
+        while let Some((index, c)) = chars.next_if(|(_, c)| c.is_alphanumeric()) {
+            end = index + c.len_utf8();
+        }
+        Some((start..end, text[start..end].to_lowercase()))
+    })
+}
+
+// Levenshtein distance, None when it is more than `max`
+fn distance(a: &[char], b: &[char], max: usize) -> Option<usize> {
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        // Every path through the rest costs at least this much
+        if current.iter().min().is_some_and(|least| *least > max) {
+            return None;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    Some(previous[b.len()]).filter(|distance| *distance <= max)
+}
+
+struct Term {
+    chars: Vec<char>,
+    max: usize,
+}
+
+impl Term {
+    fn new(word: &str, distance: usize) -> Self {
+        let chars = word.chars().collect::<Vec<_>>();
+        let max = if chars.len() < MIN_FUZZY_LEN {
+            0
+        } else {
+            distance
+        };
+        Term { chars, max }
+    }
+}
+
+// The relevance, None unless every term matches, and the words of each field
+// to highlight
+fn matches(terms: &[Term], fields: &[&str]) -> (Option<f64>, Vec<Vec<Range<usize>>>) {
+    let mut best = vec![None::<usize>; terms.len()];
+    let mut highlighted = vec![Vec::new(); fields.len()];
+    for (field, text) in fields.iter().enumerate() {
+        for (range, word) in words(text) {
+            let word = word.chars().collect::<Vec<_>>();
+            let mut matched = false;
+            for (term, best) in terms.iter().zip(best.iter_mut()) {
+                if let Some(found) = distance(&term.chars, &word, term.max) {
+                    *best = Some(best.map_or(found, |best: usize| best.min(found)));
+                    matched = true;
+                }
+            }
+            if matched {
+                highlighted[field].push(range);
+            }
+        }
+    }
+    let relevance = best
+        .into_iter()
+        .map(|found| found.map(|found| 1.0 / (1 + found) as f64))
+        .sum::<Option<f64>>();
+    (relevance, highlighted)
+}
+
+// Without the index, the comments with every term, closest first
+fn scan<'a>(
+    comments: &'a Comments,
+    site: &str,
+    terms: &[Term],
+    limit: usize,
+) -> (usize, Vec<(&'a Arc<Comment>, f64)>) {
+    let mut found = crate::newest_first(comments.values().filter(|entry| entry.is_listed(site)))
+        .into_iter()
+        .filter_map(|comment| Some((comment, matches(terms, &fields(comment)).0?)))
+        .collect::<Vec<_>>();
+    // Stable, so equally close comments stay newest first
+    found.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let total = found.len();
+    found.truncate(limit);
+    (total, found)
+}
+
+fn highlight(text: &str, ranges: &[Range<usize>]) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut written = 0;
+    for range in ranges {
+        markup::escape(&text[written..range.start], &mut html);
+        html.push_str("<mark>");
+        markup::escape(&text[range.clone()], &mut html);
+        html.push_str("</mark>");
+        written = range.end;
+    }
+    markup::escape(&text[written..], &mut html);
+    html
+}
+
+pub async fn search(
+    ValidatedQuery(query): ValidatedQuery<SearchQuery>,
+    site: Site,
+    format: Format,
+    Extension(state): Extension<SharedState>,
+) -> Response<Full<Bytes>> {
+    let max_distance = state.config.search.max_distance;
+    let distance = query.distance.unwrap_or(max_distance);
+    if distance > max_distance {
+        let err = ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "search.invalid_distance",
+            format!("distance must be at most {}", max_distance),
+        );
+        return (site.cors_headers(), err).into_response();
+    }
+    let terms = words(&query.q)
+        .map(|(_, word)| Term::new(&word, distance))
+        .collect::<Vec<_>>();
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+
+    let indexed = state
+        .search_index
+        .search(&site.key, &query.q, distance, limit);
+    let comments = state.db.read().unwrap();
+    let (total, found) = match indexed {
+        Some(Ok((total, hits))) => {
+            let found = hits
+                .into_iter()
+                .filter_map(|(id, score)| {
+                    // Changed since it was searched
+                    let comment = comments
+                        .get(&id)
+                        .filter(|entry| entry.is_listed(&site.key))?;
+                    Some((comment, f64::from(score)))
+                })
+                .collect();
+            (total, found)
+        }
+        Some(Err(err)) => {
+            tracing::error!("failed to search the index: {}", err);
+            let err = ApiError::internal("Failed to search");
+            return (site.cors_headers(), err).into_response();
+        }
+        None => scan(&comments, &site.key, &terms, limit),
+    };
+
+    let results = found
+        .into_iter()
+        .map(|(comment, relevance)| {
+            let (_, highlighted) = matches(&terms, &fields(comment));
+            let title = comment
+                .title
+                .as_ref()
+                .map(|title| highlight(title, &highlighted[0]));
+            SearchResult {
+                comment: PublicComment::new(comment, &state),
+                relevance,
+                highlight: Highlight {
+                    title,
+                    text: highlight(&comment.text, &highlighted[1]),
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+    drop(comments);
+
+    (
+        site.cors_headers(),
+        format.encode(serde_json::json!({
+            "query": query.q,
+            "total": total,
+            "results": results,
+        })),
+    )
+        .into_response()
+}
+
+type Comments = HashMap<Uuid, Arc<Comment>>;
+
+// Title, text
+fn fields(comment: &Comment) -> [&str; 2] {
+    [comment.title.as_deref().unwrap_or_default(), &comment.text]
+}
@@ -0,0 +1,311 @@
+// Full-text index of the listed comments for /search, in [search] index_dir,
+// for sites with too many comments to go through on every search. Ranked by
+// BM25 with [search] title_boost on the title, and takes "phrase queries".
+// Kept up to date on every change, and rebuilt from the store on startup when
+// it is missing or doesn't have as many comments as the store. Only built with
+// the `tantivy` feature, without index_dir /search looks at every comment
+#[cfg(feature = "tantivy")]
+use std::sync::Mutex;
+
+use uuid::Uuid;
+
+use crate::{config::SearchConfig, Comment};
+
+#[cfg(feature = "tantivy")]
+use tantivy::{
+    collector::{Count, TopDocs},
+    query::{BooleanQuery, Occur, Query, QueryParser, TermQuery},
+    schema::{Field, IndexRecordOption, Schema, Value, STORED, STRING, TEXT},
+    Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term,
+};
+
+// Of the writer, the least tantivy takes
+#[cfg(feature = "tantivy")]
+const WRITER_MEMORY: usize = 15_000_000;
+
+// The most typos tantivy looks for, larger [search] max_distance only apply
+// without the index
+#[cfg(feature = "tantivy")]
+const MAX_DISTANCE: usize = 2;
+
+// Number of matches of a search, and the ids of the best ones with their
+// score, best first
+pub type Found = (usize, Vec<(Uuid, f32)>);
+
+pub struct SearchIndex {
+    #[cfg(feature = "tantivy")]
+    index: Option<Tantivy>,
+}
+
+#[cfg(feature = "tantivy")]
+struct Tantivy {
+    index: Index,
+    writer: Mutex<IndexWriter>,
+    reader: IndexReader,
+    fields: Fields,
+    title_boost: f32,
+}
+
+#[cfg(feature = "tantivy")]
+struct Fields {
+    id: Field,
+    site: Field,
+    title: Field,
+    text: Field,
+}
+
+#[cfg(feature = "tantivy")]
+impl Fields {
+    fn schema() -> Schema {
+        let mut schema = Schema::builder();
+        schema.add_text_field("id", STRING | STORED);
+        schema.add_text_field("site", STRING);
+        schema.add_text_field("title", TEXT);
+        schema.add_text_field("text", TEXT);
+        schema.build()
+    }
+
+    fn of(schema: &Schema) -> tantivy::Result<Fields> {
+        Ok(Fields {
+            id: schema.get_field("id")?,
+            site: schema.get_field("site")?,
+            title: schema.get_field("title")?,
+            text: schema.get_field("text")?,
+        })
+    }
+}
+
+impl SearchIndex {
+    // `comments` are all of the store, for rebuilding the index
+    #[cfg(feature = "tantivy")]
+    pub fn open<'a>(
+        config: &SearchConfig,
+        comments: impl Iterator<Item = &'a Comment>,
+    ) -> Result<SearchIndex, String> {
+        let dir = match &config.index_dir {
+            Some(dir) => dir,
+            None => return Ok(SearchIndex { index: None }),
+        };
+        let failed =
+            |err: &dyn std::fmt::Display| format!("failed to open {}: {}", dir.display(), err);
+        std::fs::create_dir_all(dir).map_err(|err| failed(&err))?;
+        let directory = tantivy::directory::MmapDirectory::open(dir).map_err(|err| failed(&err))?;
+        let index = match Index::exists(&directory).map_err(|err| failed(&err))? {
+            true => Index::open(directory),
+            false => Index::create(directory, Fields::schema(), Default::default()),
+        }
+        .map_err(|err| failed(&err))?;
+        // E.g. of an older version. Not deleted here, in case index_dir was
+        // set to a directory holding more
+        let fields = Fields::of(&index.schema()).map_err(|err| {
+            format!(
+                "{} isn't an index of this version, delete it to have it rebuilt ({})",
+                dir.display(),
+                err
+            )
+        })?;
+        let writer = index
+            .writer_with_num_threads(1, WRITER_MEMORY)
+            .map_err(|err| failed(&err))?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()
+            .map_err(|err| failed(&err))?;
+        let tantivy = Tantivy {
+            index,
+            writer: Mutex::new(writer),
+            reader,
+            fields,
+            title_boost: config.title_boost,
+        };
+
+        let listed = comments
+            .filter(|comment| comment.is_listed(&comment.site))
+            .collect::<Vec<_>>();
+        let indexed = tantivy.reader.searcher().num_docs() as usize;
+        if indexed != listed.len() {
+            tracing::info!(
+                "rebuilding the search index of {} comments, it had {}",
+                listed.len(),
+                indexed
+            );
+            tantivy
+                .rebuild(listed.into_iter())
+                .map_err(|err| format!("failed to rebuild the search index: {}", err))?;
+        }
+        Ok(SearchIndex {
+            index: Some(tantivy),
+        })
+    }
+
+    #[cfg(not(feature = "tantivy"))]
+    pub fn open<'a>(
+        config: &SearchConfig,
+        _comments: impl Iterator<Item = &'a Comment>,
+    ) -> Result<SearchIndex, String> {
+        if config.index_dir.is_some() {
+            tracing::warn!(
+                "[search] index_dir is set, but little-nova was built without the `tantivy` feature"
+            );
+        }
+        Ok(SearchIndex {})
+    }
+
+    // Only listed comments are indexed, so call this again when one gets
+    // approved
+    pub fn add(&self, comment: &Comment) {
+        self.update(None, Some(comment));
+    }
+
+    pub fn remove(&self, comment: &Comment) {
+        self.update(Some(comment), None);
+    }
+
+    // For changes to a stored comment, e.g. approving it
+    pub fn replace(&self, old: &Comment, new: &Comment) {
+        self.update(Some(old), Some(new));
+    }
+
+    // After all the comments were swapped, see backup.rs
+    #[cfg(all(feature = "tantivy", feature = "replication"))]
+    pub fn rebuild<'a>(&self, comments: impl Iterator<Item = &'a Comment>) {
+        if let Some(tantivy) = &self.index {
+            let listed = comments.filter(|comment| comment.is_listed(&comment.site));
+            if let Err(err) = tantivy.rebuild(listed) {
+                tracing::error!("failed to rebuild the search index: {}", err);
+            }
+        }
+    }
+
+    #[cfg(all(not(feature = "tantivy"), feature = "replication"))]
+    pub fn rebuild<'a>(&self, _comments: impl Iterator<Item = &'a Comment>) {}
+
+    #[cfg(feature = "tantivy")]
+    fn update(&self, old: Option<&Comment>, new: Option<&Comment>) {
+        if let Some(tantivy) = &self.index {
+            let new = new.filter(|new| new.is_listed(&new.site));
+            if let Err(err) = tantivy.update(old, new) {
+                tracing::error!("failed to update the search index: {}", err);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "tantivy"))]
+    fn update(&self, _old: Option<&Comment>, _new: Option<&Comment>) {}
+
+    // None without an index, Some(Err) when searching it failed
+    #[cfg(feature = "tantivy")]
+    pub fn search(
+        &self,
+        site: &str,
+        query: &str,
+        distance: usize,
+        limit: usize,
+    ) -> Option<Result<Found, String>> {
+        let tantivy = self.index.as_ref()?;
+        Some(
+            tantivy
+                .search(site, query, distance, limit)
+                .map_err(|err| err.to_string()),
+        )
+    }
+
+    #[cfg(not(feature = "tantivy"))]
+    pub fn search(
+        &self,
+        _site: &str,
+        _query: &str,
+        _distance: usize,
+        _limit: usize,
+    ) -> Option<Result<Found, String>> {
+        None
+    }
+}
+
+#[cfg(feature = "tantivy")]
+impl Tantivy {
+    fn document(&self, comment: &Comment) -> TantivyDocument {
+        let mut document = TantivyDocument::default();
+        document.add_text(self.fields.id, comment.id.to_string());
+        document.add_text(self.fields.site, &comment.site);
+        if let Some(title) = &comment.title {
+            document.add_text(self.fields.title, title);
+        }
+        document.add_text(self.fields.text, &comment.text);
+        document
+    }
+
+    fn update(&self, old: Option<&Comment>, new: Option<&Comment>) -> tantivy::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for comment in old.iter().chain(new.iter()) {
+            writer.delete_term(Term::from_field_text(
+                self.fields.id,
+                &comment.id.to_string(),
+            ));
+        }
+        if let Some(new) = new {
+            writer.add_document(self.document(new))?;
+        }
+        writer.commit()?;
+        self.reader.reload()
+    }
+
+    fn rebuild<'a>(&self, listed: impl Iterator<Item = &'a Comment>) -> tantivy::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.delete_all_documents()?;
+        for comment in listed {
+            writer.add_document(self.document(comment))?;
+        }
+        writer.commit()?;
+        self.reader.reload()
+    }
+
+    fn search(
+        &self,
+        site: &str,
+        query: &str,
+        distance: usize,
+        limit: usize,
+    ) -> tantivy::Result<Found> {
+        let fields = &self.fields;
+        let mut parser = QueryParser::for_index(&self.index, vec![fields.title, fields.text]);
+        // Every word has to match, as without the index
+        parser.set_conjunction_by_default();
+        parser.set_field_boost(fields.title, self.title_boost);
+        let distance = distance.min(MAX_DISTANCE) as u8;
+        if distance > 0 {
+            for field in [fields.title, fields.text] {
+                parser.set_field_fuzzy(field, false, distance, false);
+            }
+        }
+        // Syntax errors are searched for as words
+        let (query, _) = parser.parse_query_lenient(query);
+        let site = TermQuery::new(
+            Term::from_field_text(fields.site, site),
+            IndexRecordOption::Basic,
+        );
+        let query = BooleanQuery::new(vec![
+            (Occur::Must, Box::new(site) as Box<dyn Query>),
+            (Occur::Must, query),
+        ]);
+
+        let searcher = self.reader.searcher();
+        let (top, total) = searcher.search(
+            &query,
+            &(TopDocs::with_limit(limit).order_by_score(), Count),
+        )?;
+        let mut hits = Vec::with_capacity(top.len());
+        for (score, address) in top {
+            let document = searcher.doc::<TantivyDocument>(address)?;
+            let id = document
+                .get_first(fields.id)
+                .and_then(|id| id.as_str())
+                .and_then(|id| id.parse().ok());
+            if let Some(id) = id {
+                hits.push((id, score));
+            }
+        }
+        Ok((total, hits))
+    }
+}
@@ -0,0 +1,214 @@
+// Secrets from an external store instead of the config, see [vault]. They
+// are fetched once at startup, over the settings from the config and the
+// environment, and again every refresh_secs. The admin token, the signing
+// secret and the TLS certificate are swapped while running, the others are
+// taken up by the next start
+//
+// The store holds them by these names:
+//
+//   admin_token, signing_secret, ip_salt, pow_secret, replication_token,
+//   sentry_dsn, smtp_url, s3_access_key, s3_secret_key, tls_cert, tls_key
+//
+// tls_cert and tls_key are PEM, written to the [tls] cert and key files
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::async_trait;
+#[cfg(feature = "tls")]
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::{
+    config::{Config, TlsConfig, VaultConfig},
+    state::SharedState,
+};
+
+#[async_trait]
+pub trait SecretsProvider: Send + Sync {
+    // Every secret there is, by name
+    async fn fetch(&self) -> io::Result<HashMap<String, String>>;
+
+    // Keeps the provider's own login alive, before every refresh
+    async fn renew(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "vault")]
+pub fn provider(config: &VaultConfig) -> Result<Option<Arc<dyn SecretsProvider>>, String> {
+    match &config.addr {
+        Some(_) => Ok(Some(Arc::new(crate::vault::Vault::new(config)?))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "vault"))]
+pub fn provider(config: &VaultConfig) -> Result<Option<Arc<dyn SecretsProvider>>, String> {
+    if config.addr.is_some() {
+        tracing::warn!(
+            "[vault] addr is set, but little-nova was built without the `vault` feature"
+        );
+    }
+    Ok(None)
+}
+
+// Over what the config says, before anything is started
+pub fn apply(config: &mut Config, secrets: &HashMap<String, String>) -> io::Result<()> {
+    let secret = |name: &str| secrets.get(name).cloned();
+    if let Some(token) = secret("admin_token") {
+        config.admin.token = Some(token);
+    }
+    if let Some(secret) = secret("signing_secret") {
+        config.signing.secret = Some(secret);
+    }
+    if let Some(salt) = secret("ip_salt") {
+        config.privacy.ip_salt = Some(salt);
+    }
+    if let Some(secret) = secret("pow_secret") {
+        config.pow.secret = Some(secret);
+    }
+    if let Some(token) = secret("replication_token") {
+        config.replication.token = Some(token);
+    }
+    if let Some(dsn) = secret("sentry_dsn") {
+        config.sentry.dsn = Some(dsn);
+    }
+    if let Some(url) = secret("smtp_url") {
+        config.email.smtp_url = Some(url);
+    }
+    if let Some(s3) = &mut config.attachments.s3 {
+        if let Some(key) = secret("s3_access_key") {
+            s3.access_key = key;
+        }
+        if let Some(key) = secret("s3_secret_key") {
+            s3.secret_key = key;
+        }
+    }
+    write_tls(&config.tls, secrets)?;
+    Ok(())
+}
+
+// Readable by us only, and never half written
+fn write_private(path: &Path, data: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(&tmp)?, data.as_bytes())?;
+    std::fs::rename(&tmp, path)
+}
+
+// true when the files changed
+fn write_tls(config: &TlsConfig, secrets: &HashMap<String, String>) -> io::Result<bool> {
+    let (cert, key) = match (secrets.get("tls_cert"), secrets.get("tls_key")) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(false),
+    };
+    let unchanged = std::fs::read_to_string(&config.cert).is_ok_and(|old| old == *cert)
+        && std::fs::read_to_string(&config.key).is_ok_and(|old| old == *key);
+    if unchanged {
+        return Ok(false);
+    }
+    write_private(&config.key, key)?;
+    write_private(&config.cert, cert)?;
+    Ok(true)
+}
+
+// The ones swapped while running
+pub struct Secrets {
+    admin_token: RwLock<Option<String>>,
+    signing_secret: RwLock<Option<String>>,
+    #[cfg(feature = "tls")]
+    tls: RwLock<Option<RustlsConfig>>,
+}
+
+impl Secrets {
+    pub fn new(config: &Config) -> Self {
+        Secrets {
+            admin_token: RwLock::new(config.admin.token.clone()),
+            signing_secret: RwLock::new(config.signing.secret.clone()),
+            #[cfg(feature = "tls")]
+            tls: RwLock::new(None),
+        }
+    }
+
+    pub fn admin_token(&self) -> Option<String> {
+        self.admin_token.read().unwrap().clone()
+    }
+
+    pub fn signing_secret(&self) -> Option<String> {
+        self.signing_secret.read().unwrap().clone()
+    }
+
+    // The certificate being served, reloaded when it changes
+    #[cfg(feature = "tls")]
+    pub fn serve_tls(&self, tls: RustlsConfig) {
+        *self.tls.write().unwrap() = Some(tls);
+    }
+
+    // New TLS files are the caller's to load, true then
+    fn update(&self, config: &Config, secrets: &HashMap<String, String>) -> bool {
+        for (name, current) in [
+            ("admin_token", &self.admin_token),
+            ("signing_secret", &self.signing_secret),
+        ] {
+            let secret = match secrets.get(name) {
+                Some(secret) => secret,
+                None => continue,
+            };
+            let mut current = current.write().unwrap();
+            if current.as_ref() != Some(secret) {
+                *current = Some(secret.clone());
+                tracing::info!(name, "secret rotated");
+            }
+        }
+
+        match write_tls(&config.tls, secrets) {
+            Ok(rotated) => {
+                if rotated {
+                    tracing::info!("TLS certificate rotated");
+                }
+                rotated
+            }
+            Err(err) => {
+                tracing::error!("failed to write the TLS certificate: {}", err);
+                false
+            }
+        }
+    }
+
+    // For new connections, see tls::reload
+    #[cfg(feature = "tls")]
+    pub fn reload_tls(&self, server_config: Arc<rustls::ServerConfig>) {
+        if let Some(tls) = &*self.tls.read().unwrap() {
+            tls.reload_from_config(server_config);
+        }
+    }
+}
+
+// Fetches the secrets again every refresh_secs, keeping the ones there are
+// while the store can't be reached
+pub async fn refresh(state: SharedState, provider: Arc<dyn SecretsProvider>) {
+    let interval = Duration::from_secs(state.config.vault.refresh_secs.max(1));
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(err) = provider.renew().await {
+            tracing::warn!("failed to renew the secrets login: {}", err);
+        }
+        match provider.fetch().await {
+            Ok(secrets) => {
+                if state.secrets.update(&state.config, &secrets) {
+                    #[cfg(feature = "tls")]
+                    crate::tls::reload(&state);
+                }
+            }
+            Err(err) => tracing::warn!("failed to refresh secrets: {}", err),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// Checks run before binding the listener, so misconfiguration is reported
+// at startup instead of as a panic in the middle of a request
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::{collections::HashMap, sync::Arc};
+
+use askama::Template;
+use chrono::prelude::*;
+#[cfg(feature = "tls")]
+use rustls::{sign, SignatureScheme};
+use uuid::Uuid;
+
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::{
+    config::Config,
+    dashboard::{self, DashboardTemplate},
+    extract::Validate,
+    i18n::Locale,
+    markup::Markup,
+    oembed::OEmbedTemplate,
+    pages::EmbedTemplate,
+    sitemap::{SitemapTemplate, SitemapUrl},
+    sites::{self, DEFAULT_SITE},
+    stats::Stats,
+    storage::Storage,
+    votes::Vote,
+    Comment, CommentEntriesTemplate, CommentStatus, CommentTemplate, ErrorTemplate, PageMeta, Sort,
+};
+
+// Schemes we can verify against the certificate with webpki
+#[cfg(feature = "tls")]
+const SCHEMES: [SignatureScheme; 4] = [
+    SignatureScheme::ED25519,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::RSA_PSS_SHA256,
+];
+
+// Returns every failed check, not just the first one
+pub fn run(config: &Config, storage: &Storage) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+
+    if let Err(err) = check_templates(config) {
+        errors.push(format!(
+            "failed to render templates with sample data: {}",
+            err
+        ));
+    }
+    #[cfg(feature = "tls")]
+    if let Err(err) = check_tls(&config.tls.cert, &config.tls.key)
+        .and_then(|_| tls::server_config(&config.tls).map(drop))
+    {
+        errors.push(format!("{} (see [tls] in the config)", err));
+    }
+    if let Err(err) = storage.check() {
+        errors.push(format!(
+            "comment storage is not writable: {} (see [storage] in the config)",
+            err
+        ));
+    }
+
+    if config.basic_auth.user.is_some() {
+        match &config.basic_auth.password_hash {
+            None => errors.push("[basic_auth] user is set without a password_hash".to_owned()),
+            Some(hash) if bcrypt::verify("", hash).is_err() => {
+                errors.push("[basic_auth] password_hash is not a bcrypt hash".to_owned())
+            }
+            Some(_) => {}
+        }
+    }
+    if config.redis.url.is_some() && config.replication.primary.is_some() {
+        errors.push(
+            "[redis] url and [replication] primary are both set, a replica follows its primary only"
+                .to_owned(),
+        );
+    }
+    if !(0.0..=1.0).contains(&config.tracing.ratio) {
+        errors.push("[tracing] ratio is not between 0 and 1".to_owned());
+    }
+    if config.spam.reject_score < config.spam.queue_score {
+        errors.push("[spam] reject_score is lower than queue_score".to_owned());
+    }
+    for (index, rule) in config.rules.iter().enumerate() {
+        if let Err((_, err)) = rule.validate() {
+            errors.push(format!(
+                "{} (see [[rules]] #{} in the config)",
+                err,
+                index + 1
+            ));
+        }
+    }
+    for (key, settings) in &config.sites {
+        if let Err(err) = sites::validate(key, settings) {
+            errors.push(format!("{} (see [sites.{}] in the config)", err, key));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn check_templates(config: &Config) -> Result<(), askama::Error> {
+    let comment = Comment {
+        id: Uuid::nil(),
+        title: Some("self-check".to_owned()),
+        name: "self-check".to_owned(),
+        text: "self-check".to_owned(),
+        utc: Utc::now(),
+        tags: vec!["self-check".to_owned()],
+        slug: Some("self-check".to_owned()),
+        email: None,
+        visitor: None,
+        ip: None,
+        country: None,
+        spam_score: None,
+        votes: HashMap::from([(Uuid::nil(), Vote::Up)]),
+        created_at: None,
+        updated_at: None,
+        site: DEFAULT_SITE.to_owned(),
+        status: CommentStatus::Approved,
+        publish_at: None,
+        listed_at: None,
+        mentions: Vec::new(),
+        attachments: Vec::new(),
+        previews: Vec::new(),
+        version: 0,
+    };
+
+    SitemapTemplate {
+        urls: vec![SitemapUrl {
+            loc: "/self-check".to_owned(),
+            lastmod: None,
+        }],
+    }
+    .render()?;
+
+    let markup = Arc::new(Markup::new(&config.display));
+
+    DashboardTemplate::default().render()?;
+    let stats = Stats::new(std::iter::once(&comment));
+    stats.reject(DEFAULT_SITE, comment.utc);
+    dashboard::charts(&stats, DEFAULT_SITE.to_owned(), 30).render()?;
+
+    // Every locale, so a broken translation shows up too
+    for i18n in Locale::ALL {
+        CommentEntriesTemplate {
+            root: "/s/self-check".to_owned(),
+            meta: PageMeta::new(&config.site, "self-check", "self-check", "/"),
+            total: 1,
+            entries: vec![Arc::new(comment.clone())],
+            tag: Some("self-check".to_owned()),
+            sort: Sort::Top,
+            sort_href: "/?sort=top".to_owned(),
+            page: 2,
+            page_size: 1,
+            total_pages: 3,
+            prev_href: Some("/?offset=0".to_owned()),
+            next_href: Some("/?offset=2".to_owned()),
+            markup: markup.clone(),
+            tz: config.display.timezone,
+            visitor_timezone: config.display.visitor_timezone,
+            i18n,
+        }
+        .render()?;
+
+        CommentTemplate {
+            root: "/s/self-check".to_owned(),
+            meta: PageMeta::new(&config.site, "self-check", "self-check", "/"),
+            id: comment.id,
+            title: comment.title.clone(),
+            name: comment.name.clone(),
+            text_html: comment.text_html("", &markup),
+            utc: comment.utc,
+            tags: comment.tags.clone(),
+            score: comment.score(),
+            index_offset: 0,
+            tz: config.display.timezone,
+            visitor_timezone: config.display.visitor_timezone,
+            i18n,
+        }
+        .render()?;
+
+        EmbedTemplate {
+            root: "/s/self-check".to_owned(),
+            slug: "self-check".to_owned(),
+            entries: vec![Arc::new(comment.clone())],
+            closed: false,
+            markup: markup.clone(),
+            tz: config.display.timezone,
+            visitor_timezone: config.display.visitor_timezone,
+            i18n,
+        }
+        .render()?;
+
+        OEmbedTemplate {
+            url: "/self-check".to_owned(),
+            name: comment.name.clone(),
+            text: comment.text.clone(),
+            utc: comment.utc,
+            site_name: config.site.name.clone(),
+            tz: config.display.timezone,
+            i18n,
+        }
+        .render()?;
+
+        ErrorTemplate {
+            message: i18n.t("error.not_found"),
+            i18n,
+        }
+        .render()?;
+    }
+
+    Ok(())
+}
+
+// Sign a message with the key and verify it with the certificate,
+// which proves they parse and belong together, then the rest of the chain
+#[cfg(feature = "tls")]
+fn check_tls(cert_path: &Path, key_path: &Path) -> Result<(), String> {
+    let certs = tls::load_certs(cert_path)?;
+    let cert = &certs[0].0;
+    let key = tls::load_key(key_path)?;
+    tls::check_chain(cert_path, &certs)?;
+
+    let signer = sign::any_supported_type(&key)
+        .ok()
+        .and_then(|key| key.choose_scheme(&SCHEMES))
+        .ok_or_else(|| format!("unsupported private key type in {}", key_path.display()))?;
+
+    let message = b"little-nova self-check";
+    let signature = signer
+        .sign(message)
+        .map_err(|err| format!("failed to sign with {}: {}", key_path.display(), err))?;
+
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        _ => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    };
+
+    webpki::EndEntityCert::try_from(cert.as_slice())
+        .map_err(|err| {
+            format!(
+                "failed to parse certificate {}: {:?}",
+                cert_path.display(),
+                err
+            )
+        })?
+        .verify_signature(algorithm, message, &signature)
+        .map_err(|_| {
+            format!(
+                "private key {} does not match certificate {}",
+                key_path.display(),
+                cert_path.display()
+            )
+        })
+}
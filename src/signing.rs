@@ -0,0 +1,154 @@
+// Signed comments from a trusted frontend, see [signing]. With a secret set,
+// POST /create needs two headers:
+//
+//   X-Little-Nova-Timestamp: <unix seconds>
+//   X-Little-Nova-Signature: sha256=<hex of HMAC-SHA256(secret, "<timestamp>.<body>")>
+//
+// The frontend's server adds them to the form posts it passes on, so the
+// form stays public while requests made to the API directly are refused
+use axum::{
+    async_trait,
+    body::{Bytes, HttpBody},
+    extract::{Extension, FromRequest, RequestParts},
+    http::StatusCode,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    attachments::MAX_COMMENT_BYTES, auth_log::Failure, errors::ApiError, identity::ClientIp,
+    state::SharedState,
+};
+
+pub const TIMESTAMP_HEADER: &str = "x-little-nova-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-little-nova-signature";
+
+fn header<'a, B>(req: &'a RequestParts<B>, name: &str) -> Option<&'a str> {
+    req.headers()
+        .and_then(|headers| headers.get(name))
+        .and_then(|value| value.to_str().ok())
+}
+
+pub fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+// A valid signature while [signing] has a secret. The body is read for it and
+// put back for the extractors after this one
+pub struct Signed;
+
+#[async_trait]
+impl<B> FromRequest<B> for Signed
+where
+    B: HttpBody<Data = Bytes> + From<Bytes> + Unpin + Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<SharedState>::from_request(req)
+            .await
+            .map_err(|_| ApiError::internal("App state is not available"))?;
+        let secret = match state.secrets.signing_secret() {
+            Some(secret) => secret,
+            None => return Ok(Signed),
+        };
+
+        match verify(req, &state, &secret).await {
+            Ok(()) => Ok(Signed),
+            Err(rejection) => {
+                if rejection.status == StatusCode::UNAUTHORIZED {
+                    let client = ClientIp::from_request(req).await.ok();
+                    state.auth_log.failed_request(
+                        Failure::Signature,
+                        client.map(|ClientIp(ip)| ip),
+                        req,
+                    );
+                }
+                Err(rejection)
+            }
+        }
+    }
+}
+
+async fn verify<B>(
+    req: &mut RequestParts<B>,
+    state: &SharedState,
+    secret: &str,
+) -> Result<(), ApiError>
+where
+    B: HttpBody<Data = Bytes> + From<Bytes> + Unpin + Send,
+{
+    let config = &state.config.signing;
+    let unsigned = || {
+        ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "signature.missing",
+            "The request is not signed",
+        )
+    };
+    let timestamp = header(req, TIMESTAMP_HEADER).ok_or_else(unsigned)?;
+    let signature = header(req, SIGNATURE_HEADER)
+        .and_then(|signature| signature.strip_prefix("sha256="))
+        .and_then(unhex)
+        .ok_or_else(unsigned)?;
+    let age = timestamp
+        .parse::<i64>()
+        .map(|signed| Utc::now().timestamp() - signed)
+        .map_err(|_| unsigned())?;
+    if age.unsigned_abs() > config.max_age_secs {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "signature.expired",
+            "The signature is too old",
+        ));
+    }
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+
+    // As much as a comment with all its attachments, and the multipart
+    // boundaries and headers around them
+    let limit = state.config.attachments.max_files * state.config.attachments.max_bytes
+        + MAX_COMMENT_BYTES
+        + 64 * 1024;
+    let body = req
+        .body_mut()
+        .ok_or_else(|| ApiError::internal("The body was taken already"))?;
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "request.unreadable",
+                "Failed to read the body",
+            )
+        })?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request.too_large",
+                "The request is too large",
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    mac.update(&bytes);
+    mac.verify_slice(&signature).map_err(|_| {
+        ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "signature.invalid",
+            "The signature is wrong",
+        )
+    })?;
+
+    *body = B::from(Bytes::from(bytes));
+    Ok(())
+}
@@ -0,0 +1,163 @@
+// The comments of a site most alike one of them, as `GET /<id>/similar`, to
+// spot recurring spam templates and the same feedback given twice. Compared
+// by the cosine of their tf-idf vectors over the words of title and text, so
+// words every comment has count for little:
+//
+//   {"comments": [{"id": "0190...", ..., "similarity": 0.83}, ...]}
+//
+// Only listed comments, and none which have no word in common
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    changes::PublicComment,
+    codec::Format,
+    errors::ApiError,
+    extract::{Validate, ValidatedQuery},
+    search,
+    sites::Site,
+    state::SharedState,
+    Comment,
+};
+
+const MAX_LIMIT: usize = 50;
+const DEFAULT_LIMIT: usize = 5;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SimilarQuery {
+    // 5 by default
+    limit: Option<usize>,
+}
+
+impl Validate for SimilarQuery {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        match self.limit {
+            Some(limit) if limit == 0 || limit > MAX_LIMIT => Err((
+                "request.invalid_limit",
+                format!("limit must be between 1 and {}", MAX_LIMIT),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Similar {
+    #[serde(flatten)]
+    comment: PublicComment,
+    // Between 0 and 1 for the same words as often
+    similarity: f64,
+}
+
+// Times each word occurs
+fn counts(comment: &Comment) -> HashMap<String, f64> {
+    let mut counts = HashMap::new();
+    let title = comment.title.as_deref().unwrap_or_default();
+    for (_, word) in search::words(title).chain(search::words(&comment.text)) {
+        *counts.entry(word).or_default() += 1.0;
+    }
+    counts
+}
+
+// Weighted by how rare the words are, and the length of that vector
+fn weigh<'a>(
+    counts: &'a HashMap<String, f64>,
+    idf: &HashMap<&str, f64>,
+) -> (HashMap<&'a str, f64>, f64) {
+    let weights = counts
+        .iter()
+        .map(|(word, count)| {
+            let word = word.as_str();
+            (word, count * idf.get(word).copied().unwrap_or_default())
+        })
+        .collect::<HashMap<_, _>>();
+    let norm = weights
+        .values()
+        .map(|weight| weight * weight)
+        .sum::<f64>()
+        .sqrt();
+    (weights, norm)
+}
+
+pub async fn get_similar(
+    Path(id): Path<Uuid>,
+    ValidatedQuery(query): ValidatedQuery<SimilarQuery>,
+    site: Site,
+    format: Format,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let comments = state.db.read().unwrap();
+    if !comments
+        .get(&id)
+        .is_some_and(|comment| comment.is_listed(&site.key))
+    {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "comment.not_found",
+            "No such comment",
+        ));
+    }
+
+    let listed = comments
+        .values()
+        .filter(|comment| comment.is_listed(&site.key))
+        .map(|comment| (comment, counts(comment)))
+        .collect::<Vec<_>>();
+    let mut frequencies = HashMap::<&str, usize>::new();
+    for (_, counts) in &listed {
+        for word in counts.keys() {
+            *frequencies.entry(word).or_default() += 1;
+        }
+    }
+    // Smoothed, so a word of every comment still counts a little
+    let total = listed.len() as f64;
+    let idf = frequencies
+        .into_iter()
+        .map(|(word, frequency)| (word, ((1.0 + total) / (1.0 + frequency as f64)).ln() + 1.0))
+        .collect::<HashMap<_, _>>();
+
+    let mut vectors = listed
+        .iter()
+        .map(|(comment, counts)| (*comment, weigh(counts, &idf)))
+        .collect::<Vec<_>>();
+    let position = vectors
+        .iter()
+        .position(|(comment, _)| comment.id == id)
+        .unwrap_or_default();
+    let (_, (target, target_norm)) = vectors.swap_remove(position);
+
+    let mut similar = vectors
+        .into_iter()
+        .filter_map(|(comment, (weights, norm))| {
+            let dot = target
+                .iter()
+                .filter_map(|(word, weight)| Some(weight * weights.get(word)?))
+                .sum::<f64>();
+            let similarity = dot / (target_norm * norm);
+            (similarity > 0.0).then_some((comment, similarity))
+        })
+        .collect::<Vec<_>>();
+    // The id breaks ties so the order is stable between requests
+    similar.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| b.0.id.cmp(&a.0.id)));
+    let similar = similar
+        .into_iter()
+        .take(query.limit.unwrap_or(DEFAULT_LIMIT))
+        .map(|(comment, similarity)| Similar {
+            comment: PublicComment::new(comment, &state),
+            similarity,
+        })
+        .collect::<Vec<_>>();
+    drop(comments);
+
+    Ok((
+        site.cors_headers(),
+        format.encode(serde_json::json!({ "comments": similar })),
+    ))
+}
@@ -0,0 +1,110 @@
+// sitemap.xml with the index and the thread of every page
+use std::{collections::HashMap, sync::Mutex};
+
+use axum::{
+    extract::Extension,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+};
+
+use askama::Template;
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::{errors::ApiError, sites::Site, state::SharedState};
+
+#[derive(Template)]
+#[template(path = "sitemap.xml")]
+pub struct SitemapTemplate {
+    pub urls: Vec<SitemapUrl>,
+}
+
+pub struct SitemapUrl {
+    pub loc: String,
+    // W3C datetime of the newest comment
+    pub lastmod: Option<String>,
+}
+
+// Rendered on the first request after a write, see Storage::revision
+// One per site key
+#[derive(Default)]
+pub struct SitemapCache {
+    cached: Mutex<HashMap<String, (u64, String)>>,
+}
+
+impl SitemapCache {
+    pub fn new() -> Self {
+        SitemapCache::default()
+    }
+}
+
+pub async fn get_sitemap(
+    site: Site,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    // Sitemaps need absolute URLs
+    if state.config.site.base_url.is_none() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "sitemap.disabled",
+            "Set [site] base_url to enable the sitemap",
+        ));
+    }
+
+    let revision = state.storage.revision();
+    let mut cached = state.sitemap.cached.lock().unwrap();
+    let xml = match cached.get(&site.key) {
+        Some((cached_revision, xml)) if *cached_revision == revision => xml.clone(),
+        _ => {
+            let xml = render(&state, &site).map_err(|err| {
+                tracing::error!("failed to render sitemap: {}", err);
+                ApiError::internal("Failed to render sitemap")
+            })?;
+            cached.insert(site.key, (revision, xml.clone()));
+            xml
+        }
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/xml; charset=utf-8"),
+    );
+    Ok((headers, xml))
+}
+
+fn render(state: &SharedState, site: &Site) -> Result<String, askama::Error> {
+    let config = &state.config.site;
+    let comments = state.db.read().unwrap();
+
+    let mut newest = None;
+    let mut pages: HashMap<&str, DateTime<Utc>> = HashMap::new();
+    for comment in comments
+        .values()
+        .filter(|comment| comment.is_listed(&site.key))
+    {
+        newest = newest.max(Some(comment.utc));
+        if let Some(slug) = &comment.slug {
+            let lastmod = pages.entry(slug).or_insert(comment.utc);
+            *lastmod = (*lastmod).max(comment.utc);
+        }
+    }
+
+    let mut slugs = pages.into_iter().collect::<Vec<_>>();
+    slugs.sort_unstable();
+
+    let lastmod = |utc: DateTime<Utc>| utc.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let mut urls = vec![SitemapUrl {
+        loc: config.url(&format!("{}/", site.root)).unwrap_or_default(),
+        lastmod: newest.map(lastmod),
+    }];
+    urls.extend(slugs.into_iter().map(|(slug, utc)| {
+        SitemapUrl {
+            loc: config
+                .url(&format!("{}/embed/{}", site.root, slug))
+                .unwrap_or_default(),
+            lastmod: Some(lastmod(utc)),
+        }
+    }));
+
+    SitemapTemplate { urls }.render()
+}
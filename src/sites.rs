@@ -0,0 +1,414 @@
+// Several independent sites can share one instance. A request picks its site
+// with the X-Site-Key header or a /s/<key>/ path prefix, and only sees the
+// comments of that site
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+    time::Duration,
+};
+
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, Path, RequestParts},
+    http::{
+        header::{self, HeaderName},
+        uri::PathAndQuery,
+        HeaderMap, HeaderValue, Request, StatusCode, Uri,
+    },
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+
+use crate::{
+    admin::Admin,
+    config::{Config, SiteSettings},
+    domain::{self, Transition},
+    errors::ApiError,
+    extract::{Validate, ValidatedJson},
+    state::SharedState,
+    stats, theme,
+};
+
+pub const DEFAULT_SITE: &str = "default";
+
+pub static SITE_KEY_HEADER: HeaderName = HeaderName::from_static("x-site-key");
+
+// Prefix of the links into the site, see resolve_site
+pub fn root(key: &str) -> String {
+    if key == DEFAULT_SITE {
+        String::new()
+    } else {
+        format!("/s/{}", key)
+    }
+}
+
+// How often expired comments are looked for
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Settings of every known site
+// Sites start out as configured in [sites]. Changes made through the admin
+// API are saved with the comments and win over the config on the next start
+pub struct Sites {
+    settings: RwLock<HashMap<String, SiteSettings>>,
+    // Sites put through the admin API
+    persisted: RwLock<HashMap<String, SiteSettings>>,
+}
+
+impl Sites {
+    pub fn new(config: &Config, persisted: HashMap<String, SiteSettings>) -> Self {
+        let mut settings = config.sites.clone();
+        settings.extend(persisted.clone());
+        settings.entry(DEFAULT_SITE.to_owned()).or_default();
+        Sites {
+            settings: RwLock::new(settings),
+            persisted: RwLock::new(persisted),
+        }
+    }
+
+    pub fn persisted(&self) -> HashMap<String, SiteSettings> {
+        self.persisted.read().unwrap().clone()
+    }
+
+    // Start over from the config and `persisted`, see backup.rs
+    pub fn restore(&self, config: &Config, persisted: HashMap<String, SiteSettings>) {
+        let restored = Sites::new(config, persisted);
+        *self.settings.write().unwrap() = restored.settings.into_inner().unwrap();
+        *self.persisted.write().unwrap() = restored.persisted.into_inner().unwrap();
+    }
+
+    fn put(&self, key: &str, settings: SiteSettings) {
+        self.settings
+            .write()
+            .unwrap()
+            .insert(key.to_owned(), settings.clone());
+        self.persisted
+            .write()
+            .unwrap()
+            .insert(key.to_owned(), settings);
+    }
+
+    // Sites from the config come back on the next start
+    fn remove(&self, key: &str) -> Option<SiteSettings> {
+        self.persisted.write().unwrap().remove(key);
+        self.settings.write().unwrap().remove(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<SiteSettings> {
+        self.settings.read().unwrap().get(key).cloned()
+    }
+
+    fn all(&self) -> Vec<(String, SiteSettings)> {
+        self.settings
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, settings)| (key.clone(), settings.clone()))
+            .collect()
+    }
+}
+
+// Site keys share the rules of page slugs, they end up in paths too
+pub fn validate_key(key: &str) -> Result<(), String> {
+    match crate::pages::normalize(Some(key.to_owned()))? {
+        Some(normalized) if normalized == key => Ok(()),
+        _ => Err(format!("Invalid site key \"{}\"", key)),
+    }
+}
+
+pub fn validate(key: &str, settings: &SiteSettings) -> Result<(), String> {
+    validate_key(key)?;
+    settings.validate().map_err(|(_, message)| message)
+}
+
+impl Validate for SiteSettings {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        if let Some(theme) = &self.theme {
+            theme::bundled(theme).map_err(|err| ("site.unknown_theme", err))?;
+        }
+        if let Some(origin) = self
+            .allowed_origins
+            .iter()
+            .find(|origin| !origin.contains("://") || origin.ends_with('/'))
+        {
+            return Err((
+                "site.invalid_origin",
+                format!(
+                    "Invalid origin \"{}\", expected e.g. \"https://blog.example.com\"",
+                    origin
+                ),
+            ));
+        }
+        if let Some(country) = self
+            .queue_countries
+            .iter()
+            .find(|country| country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()))
+        {
+            return Err((
+                "site.invalid_country",
+                format!(
+                    "Invalid country \"{}\", expected an ISO code like \"JP\"",
+                    country
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Site {
+    // Requests without an Origin header (not from a browser) are let through,
+    // as are pages of this server such as /embed
+    pub fn allows_origin(&self) -> bool {
+        let origin = match &self.origin {
+            Some(origin) if !self.settings.allowed_origins.is_empty() => origin,
+            _ => return true,
+        };
+        let own = self.host.as_ref().is_some_and(|host| {
+            origin
+                .split_once("://")
+                .is_some_and(|(_, authority)| authority == host)
+        });
+        own || self
+            .settings
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == origin)
+    }
+
+    // For JSON read by pages of the site, which are usually on another origin
+    pub fn cors_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let allow_origin = match &self.origin {
+            _ if self.settings.allowed_origins.is_empty() => Some(HeaderValue::from_static("*")),
+            Some(origin) if self.allows_origin() => HeaderValue::from_str(origin).ok(),
+            _ => None,
+        };
+        if let Some(allow_origin) = allow_origin {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            // Replaces the Vary of codec.rs, so it lists accept again
+            headers.insert(header::VARY, HeaderValue::from_static("origin, accept"));
+        }
+        headers
+    }
+
+    pub fn is_closed(&self, slug: Option<&str>) -> bool {
+        self.settings.is_closed(slug)
+    }
+}
+
+pub async fn get_sites(_: Admin, Extension(state): Extension<SharedState>) -> impl IntoResponse {
+    Json(state.sites.all().into_iter().collect::<HashMap<_, _>>())
+}
+
+fn unknown_site() -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "site.not_found", "Unknown site")
+}
+
+pub async fn get_site(
+    _: Admin,
+    Path(key): Path<String>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    state.sites.get(&key).map(Json).ok_or_else(unknown_site)
+}
+
+// Creates or replaces a site, applied to the next request
+pub async fn put_site(
+    _: Admin,
+    Path(key): Path<String>,
+    ValidatedJson(settings): ValidatedJson<SiteSettings>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    validate_key(&key)
+        .map_err(|err| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "site.invalid_key", err))?;
+
+    state.sites.put(&key, settings.clone());
+    state.storage.mark_dirty();
+    tracing::info!(site = %key, "site settings changed");
+
+    Ok(Json(settings))
+}
+
+// The comments of the site are kept, but not shown while it is gone
+pub async fn delete_site(
+    _: Admin,
+    Path(key): Path<String>,
+    Extension(state): Extension<SharedState>,
+) -> Result<impl IntoResponse, ApiError> {
+    if key == DEFAULT_SITE {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "site.default",
+            "The default site can't be deleted",
+        ));
+    }
+
+    state.sites.remove(&key).ok_or_else(unknown_site)?;
+    state.storage.mark_dirty();
+    tracing::info!(site = %key, "site deleted");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Set by `resolve_site` before routing
+#[derive(Debug, Clone)]
+struct SiteKey {
+    key: String,
+    // Prefix of every link pointing back into the site
+    root: String,
+}
+
+// Must run before routing, since it strips the /s/<key> prefix from the path
+pub fn resolve_site<B>(mut req: Request<B>) -> Request<B> {
+    let prefixed = req.uri().path().strip_prefix("/s/").map(|rest| {
+        let (key, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        (key.to_owned(), path.to_owned())
+    });
+
+    let site = match prefixed {
+        Some((key, path)) => {
+            let path = if path.is_empty() {
+                "/".to_owned()
+            } else {
+                path
+            };
+            let path_and_query = match req.uri().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+            let mut parts = req.uri().clone().into_parts();
+            parts.path_and_query = path_and_query.parse::<PathAndQuery>().ok();
+            if let Ok(uri) = Uri::from_parts(parts) {
+                *req.uri_mut() = uri;
+            }
+            SiteKey {
+                root: format!("/s/{}", key),
+                key,
+            }
+        }
+        None => SiteKey {
+            key: req
+                .headers()
+                .get(&SITE_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or(DEFAULT_SITE)
+                .to_owned(),
+            root: String::new(),
+        },
+    };
+
+    req.extensions_mut().insert(site);
+    req
+}
+
+// Extractor for the site of the request, rejecting unknown site keys
+#[derive(Debug, Clone)]
+pub struct Site {
+    pub key: String,
+    pub root: String,
+    pub settings: SiteSettings,
+    // Origin header of the request
+    pub origin: Option<String>,
+    // Host the request was sent to
+    host: Option<String>,
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for Site
+where
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<SharedState>::from_request(req)
+            .await
+            .map_err(|_| ApiError::internal("App state is not available"))?;
+
+        let SiteKey { key, root } = req
+            .extensions()
+            .and_then(|extensions| extensions.get::<SiteKey>())
+            .cloned()
+            .unwrap_or_else(|| SiteKey {
+                key: DEFAULT_SITE.to_owned(),
+                root: String::new(),
+            });
+
+        let settings = state.sites.get(&key).ok_or_else(unknown_site)?;
+
+        let header = |name| {
+            req.headers()
+                .and_then(|headers| headers.get(name))
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let origin = header(header::ORIGIN);
+        // HTTP/2 requests carry the host in the URI instead of a Host header
+        let host = header(header::HOST)
+            .or_else(|| req.uri().authority().map(|authority| authority.to_string()));
+
+        Ok(Site {
+            key,
+            root,
+            settings,
+            origin,
+            host,
+        })
+    }
+}
+
+// Deletes comments received longer ago than the retention of their site
+pub async fn purge_expired_periodically(state: SharedState) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for (key, settings) in state.sites.all() {
+            let days = match settings.retention_days {
+                Some(days) => days,
+                None => continue,
+            };
+            let cutoff = Utc::now() - chrono::Duration::days(days as i64);
+
+            let (purged, seq) = {
+                let mut comments = state.db.write().unwrap();
+                let expired = comments
+                    .values()
+                    .filter(|comment| comment.site == key && stats::received(comment) < cutoff)
+                    .map(|comment| comment.id)
+                    .collect::<HashSet<_>>();
+                let mut purged = HashSet::new();
+                for id in &expired {
+                    match domain::transition(&state, &mut comments, *id, Transition::Expire) {
+                        Ok(_) => {
+                            purged.insert(*id);
+                        }
+                        Err(err) => {
+                            tracing::error!("failed to delete an expired comment: {}", err);
+                            break;
+                        }
+                    }
+                }
+                let seq = state.storage.events.seq();
+                (purged, seq)
+            };
+
+            if !purged.is_empty() {
+                // Their content is past retention in the event log too
+                let state = state.clone();
+                let count = purged.len();
+                let result =
+                    tokio::task::spawn_blocking(move || state.storage.events.forget(&purged, seq))
+                        .await;
+                if let Ok(Err(err)) = result {
+                    tracing::error!(
+                        "failed to drop expired comments from the event log: {}",
+                        err
+                    );
+                }
+                tracing::info!(site = %key, purged = count, "deleted comments past retention");
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// The comments in an embedded sled database instead of a JSON snapshot, see
+// [storage] backend. [storage] path is then the database's directory, with
+// three trees:
+//
+//   comments   the comment id (16 bytes, time-ordered for v7 ids) -> JSON
+//   slugs      <site> 0 <slug> 0 <comment id> -> nothing, the comments of a
+//              page in the order they were posted, for tools reading the
+//              database while the server is stopped
+//   meta       "schema_version", "sites", "rules", "blocked_names",
+//              "notifications", "tombstones", "last_event" -> JSON
+//
+// A flush only writes the comments which changed since the last one, see
+// mark_changed. Migrations run on the same schema as snapshots, see
+// storage.rs. Only built with the `sled` feature
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    state::AppState,
+    storage::{Contents, StorageError, SCHEMA_VERSION},
+    Comment,
+};
+
+pub struct SledStore {
+    path: PathBuf,
+    comments: sled::Tree,
+    slugs: sled::Tree,
+    meta: sled::Tree,
+    db: sled::Db,
+    // Ids of the comments to write on the next flush
+    changed: Mutex<HashSet<Uuid>>,
+}
+
+// What the slug index needs of a stored comment
+#[derive(Deserialize)]
+struct Page {
+    site: String,
+    slug: Option<String>,
+}
+
+fn slug_key(id: Uuid, site: &str, slug: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(site.len() + slug.len() + 18);
+    key.extend_from_slice(site.as_bytes());
+    key.push(0);
+    key.extend_from_slice(slug.as_bytes());
+    key.push(0);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+impl SledStore {
+    // With the contents as a snapshot would have them, for storage::parse
+    pub fn open(path: &Path) -> Result<(SledStore, Option<Value>), StorageError> {
+        let failed = |err| sled_error(path, err);
+        let db = sled::open(path).map_err(failed)?;
+        let store = SledStore {
+            path: path.to_owned(),
+            comments: db.open_tree("comments").map_err(failed)?,
+            slugs: db.open_tree("slugs").map_err(failed)?,
+            meta: db.open_tree("meta").map_err(failed)?,
+            db,
+            changed: Mutex::new(HashSet::new()),
+        };
+        let snapshot = store.read()?;
+        Ok((store, snapshot))
+    }
+
+    // None while it was never written
+    fn read(&self) -> Result<Option<Value>, StorageError> {
+        let failed = |err| sled_error(&self.path, err);
+        let invalid = |err| StorageError::Parse(self.path.clone(), err);
+        let version = match self.meta.get("schema_version").map_err(failed)? {
+            Some(version) => serde_json::from_slice::<Value>(&version).map_err(invalid)?,
+            None => return Ok(None),
+        };
+
+        let mut snapshot = serde_json::Map::new();
+        snapshot.insert("schema_version".to_owned(), version);
+        for entry in self.meta.iter() {
+            let (key, value) = entry.map_err(failed)?;
+            let key = String::from_utf8_lossy(&key).into_owned();
+            snapshot
+                .entry(key)
+                .or_insert(serde_json::from_slice(&value).map_err(invalid)?);
+        }
+        let comments = self
+            .comments
+            .iter()
+            .values()
+            .map(|value| serde_json::from_slice::<Value>(&value.map_err(failed)?).map_err(invalid))
+            .collect::<Result<Vec<_>, _>>()?;
+        snapshot.insert("comments".to_owned(), Value::Array(comments));
+        Ok(Some(Value::Object(snapshot)))
+    }
+
+    // Written on the next flush, or deleted when it's gone by then
+    pub fn mark_changed(&self, id: Uuid) {
+        self.changed.lock().unwrap().insert(id);
+    }
+
+    fn put(&self, comment: &Comment) -> Result<(), StorageError> {
+        let failed = |err| sled_error(&self.path, err);
+        let json = serde_json::to_vec(comment)
+            .map_err(|err| StorageError::Parse(self.path.clone(), err))?;
+        let old = self
+            .comments
+            .insert(comment.id.as_bytes(), json)
+            .map_err(failed)?;
+        self.unindex(comment.id, old)?;
+        if let Some(slug) = &comment.slug {
+            self.slugs
+                .insert(slug_key(comment.id, &comment.site, slug), &[])
+                .map_err(failed)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, id: Uuid) -> Result<(), StorageError> {
+        let old = self
+            .comments
+            .remove(id.as_bytes())
+            .map_err(|err| sled_error(&self.path, err))?;
+        self.unindex(id, old)
+    }
+
+    // Drops the index entry of the comment as it was stored
+    fn unindex(&self, id: Uuid, old: Option<sled::IVec>) -> Result<(), StorageError> {
+        let page = old.and_then(|old| serde_json::from_slice::<Page>(&old).ok());
+        if let Some(Page {
+            site,
+            slug: Some(slug),
+        }) = page
+        {
+            self.slugs
+                .remove(slug_key(id, &site, &slug))
+                .map_err(|err| sled_error(&self.path, err))?;
+        }
+        Ok(())
+    }
+
+    fn put_meta(&self, key: &str, value: impl serde::Serialize) -> Result<(), StorageError> {
+        let json = serde_json::to_vec(&value)
+            .map_err(|err| StorageError::Parse(self.path.clone(), err))?;
+        self.meta
+            .insert(key, json)
+            .map_err(|err| sled_error(&self.path, err))?;
+        Ok(())
+    }
+
+    // The changed comments and everything else, as storage::snapshot holds.
+    // Taken under the comments lock, so last_event covers what was written
+    pub fn write(&self, state: &AppState) -> Result<(), StorageError> {
+        let comments = state.db.read().unwrap();
+        let changed = std::mem::take(&mut *self.changed.lock().unwrap());
+        let result = self.write_changed(state, &comments, &changed);
+        drop(comments);
+        if result.is_err() {
+            // Tried again on the next flush
+            self.changed.lock().unwrap().extend(changed);
+        }
+        result
+    }
+
+    fn write_changed(
+        &self,
+        state: &AppState,
+        comments: &HashMap<Uuid, Arc<Comment>>,
+        changed: &HashSet<Uuid>,
+    ) -> Result<(), StorageError> {
+        for id in changed {
+            match comments.get(id) {
+                Some(comment) => self.put(comment)?,
+                None => self.remove(*id)?,
+            }
+        }
+        self.put_meta("sites", state.sites.persisted())?;
+        self.put_meta("rules", state.rules.added())?;
+        self.put_meta("blocked_names", state.blocked_names.added())?;
+        self.put_meta("notifications", state.notifications.changed())?;
+        self.put_meta("tombstones", state.tombstones.all())?;
+        self.put_meta("last_event", state.storage.events.seq())?;
+        self.put_meta("schema_version", SCHEMA_VERSION)?;
+        self.flush()
+    }
+
+    // Everything in `contents` and nothing else, see Storage::save
+    pub fn save(&self, contents: &Contents, last_event: u64) -> Result<(), StorageError> {
+        let failed = |err| sled_error(&self.path, err);
+        self.comments.clear().map_err(failed)?;
+        self.slugs.clear().map_err(failed)?;
+        for comment in contents.comments.values() {
+            self.put(comment)?;
+        }
+        self.put_meta("sites", &contents.sites)?;
+        self.put_meta("rules", &contents.rules)?;
+        self.put_meta("blocked_names", &contents.blocked_names)?;
+        self.put_meta("notifications", &contents.notifications)?;
+        self.put_meta("tombstones", &contents.tombstones)?;
+        self.put_meta("last_event", last_event)?;
+        self.put_meta("schema_version", SCHEMA_VERSION)?;
+        self.flush()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.db
+            .flush()
+            .map(drop)
+            .map_err(|err| sled_error(&self.path, err))
+    }
+}
+
+// I/O errors are retried at startup, see storage::open_with_retry
+fn sled_error(path: &Path, err: sled::Error) -> StorageError {
+    match err {
+        sled::Error::Io(err) => StorageError::Io(path.to_owned(), err),
+        err => StorageError::Backend(path.to_owned(), err.to_string()),
+    }
+}
@@ -0,0 +1,193 @@
+// Spam checks for new comments, run one after another
+// Every check adds to a score, which decides whether a comment is shown,
+// waits for an admin or is refused, see [spam] in the config
+use std::sync::Arc;
+
+use axum::async_trait;
+
+use crate::{
+    config::SpamConfig,
+    rules::{RuleAction, Rules},
+};
+
+// What the checks see of a new comment
+pub struct Candidate<'a> {
+    pub site: &'a str,
+    pub title: Option<&'a str>,
+    pub name: &'a str,
+    pub text: &'a str,
+    // Hidden form field, only bots fill it in
+    pub honeypot: &'a str,
+}
+
+// Async so checks can ask remote services
+#[async_trait]
+pub trait SpamCheck: Send + Sync {
+    // Shown in the logs
+    fn name(&self) -> &'static str;
+
+    // 0.0 for a clean comment
+    async fn score(&self, candidate: &Candidate<'_>) -> f32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Approve,
+    Queue,
+    Reject,
+}
+
+pub struct SpamFilter {
+    checks: Vec<Box<dyn SpamCheck>>,
+    queue_score: f32,
+    reject_score: f32,
+}
+
+impl SpamFilter {
+    pub fn new(config: &SpamConfig, rules: Arc<Rules>) -> Self {
+        let mut checks: Vec<Box<dyn SpamCheck>> = vec![
+            Box::new(Honeypot {
+                score: config.honeypot_score,
+            }),
+            Box::new(Wordlist {
+                words: config
+                    .words
+                    .iter()
+                    .map(|word| word.to_lowercase())
+                    .collect(),
+                score: config.word_score,
+            }),
+            Box::new(LinkRules {
+                rules,
+                queue_score: config.queue_score,
+                reject_score: config.reject_score,
+            }),
+        ];
+        checks.extend(classifier(config));
+        SpamFilter {
+            checks,
+            queue_score: config.queue_score,
+            reject_score: config.reject_score,
+        }
+    }
+
+    // Combined score of every check
+    pub async fn score(&self, candidate: &Candidate<'_>) -> f32 {
+        let mut total = 0.0;
+        for check in &self.checks {
+            let score = check.score(candidate).await;
+            if score > 0.0 {
+                tracing::debug!(check = check.name(), score, "spam check matched");
+            }
+            total += score;
+        }
+        total
+    }
+
+    pub fn verdict(&self, score: f32) -> Verdict {
+        if score >= self.reject_score {
+            Verdict::Reject
+        } else if score >= self.queue_score {
+            Verdict::Queue
+        } else {
+            Verdict::Approve
+        }
+    }
+}
+
+// With [spam.classifier] url, see classifier.rs
+#[cfg(feature = "classifier")]
+fn classifier(config: &SpamConfig) -> Option<Box<dyn SpamCheck>> {
+    let url = config.classifier.url.as_ref()?;
+    match crate::classifier::Classifier::new(&config.classifier, url, config.queue_score) {
+        Ok(classifier) => Some(Box::new(classifier)),
+        Err(err) => {
+            tracing::error!("{} (see [spam.classifier] in the config)", err);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "classifier"))]
+fn classifier(config: &SpamConfig) -> Option<Box<dyn SpamCheck>> {
+    if let Some(url) = &config.classifier.url {
+        tracing::warn!(
+            "[spam.classifier] url {} is set, but little-nova was built without the `classifier` feature",
+            url
+        );
+    }
+    None
+}
+
+struct Honeypot {
+    score: f32,
+}
+
+#[async_trait]
+impl SpamCheck for Honeypot {
+    fn name(&self) -> &'static str {
+        "honeypot"
+    }
+
+    async fn score(&self, candidate: &Candidate<'_>) -> f32 {
+        if candidate.honeypot.is_empty() {
+            0.0
+        } else {
+            self.score
+        }
+    }
+}
+
+// Scores every listed word found in the comment once
+struct Wordlist {
+    words: Vec<String>,
+    score: f32,
+}
+
+#[async_trait]
+impl SpamCheck for Wordlist {
+    fn name(&self) -> &'static str {
+        "wordlist"
+    }
+
+    async fn score(&self, candidate: &Candidate<'_>) -> f32 {
+        let text = [
+            candidate.title.unwrap_or_default(),
+            candidate.name,
+            candidate.text,
+        ]
+        .join(" ")
+        .to_lowercase();
+        let found = self
+            .words
+            .iter()
+            .filter(|word| text.contains(word.as_str()))
+            .count();
+        found as f32 * self.score
+    }
+}
+
+// The spam rules of rules.rs, a matching rule scores its action's threshold
+struct LinkRules {
+    rules: Arc<Rules>,
+    queue_score: f32,
+    reject_score: f32,
+}
+
+#[async_trait]
+impl SpamCheck for LinkRules {
+    fn name(&self) -> &'static str {
+        "rules"
+    }
+
+    async fn score(&self, candidate: &Candidate<'_>) -> f32 {
+        match self
+            .rules
+            .check(candidate.site, candidate.title, candidate.text)
+        {
+            Some(RuleAction::Reject) => self.reject_score,
+            Some(RuleAction::Queue) => self.queue_score,
+            None => 0.0,
+        }
+    }
+}
@@ -0,0 +1,78 @@
+// The address answers before the comments are loaded, see
+// storage::open_with_retry, so load balancers and orchestrators see the
+// server coming up instead of a refused connection: GET /ready with 503 and
+// what it waits for, every other request with 503 server.starting. Requests
+// go to the app once it is set
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{self, Body, BoxBody},
+    http::{Request, Response, StatusCode},
+    response::IntoResponse,
+    Router,
+};
+use tower::{Service, ServiceExt};
+
+use crate::errors::ApiError;
+
+#[derive(Clone)]
+pub struct Startup {
+    app: Arc<Mutex<Option<Router>>>,
+    // Answered by GET /ready meanwhile
+    waiting: Arc<Mutex<String>>,
+}
+
+impl Startup {
+    pub fn new() -> Self {
+        Startup {
+            app: Arc::new(Mutex::new(None)),
+            waiting: Arc::new(Mutex::new("starting".to_owned())),
+        }
+    }
+
+    pub fn waiting_for(&self, reason: String) {
+        *self.waiting.lock().unwrap() = reason;
+    }
+
+    pub fn serve(&self, app: Router) {
+        *self.app.lock().unwrap() = Some(app);
+    }
+}
+
+impl Service<Request<Body>> for Startup {
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let app = self.app.lock().unwrap().clone();
+        if let Some(app) = app {
+            return Box::pin(app.oneshot(req));
+        }
+        let response = if req.uri().path() == "/ready" {
+            let waiting = self.waiting.lock().unwrap().clone();
+            (StatusCode::SERVICE_UNAVAILABLE, waiting)
+                .into_response()
+                .map(body::boxed)
+        } else {
+            ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server.starting",
+                "The server is starting, try again shortly",
+            )
+            .into_response()
+            .map(body::boxed)
+        };
+        Box::pin(async move { Ok(response) })
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicI64},
+    Arc,
+};
+
+use tokio::sync::Notify;
+
+use crate::{
+    attachments::Attachments,
+    auth_log::AuthLog,
+    basic_auth::BasicAuth,
+    blocked_names::BlockedNames,
+    changes::Tombstones,
+    config::Config,
+    domain::Hooks,
+    drain::Drain,
+    geoip::GeoIp,
+    logging::ReloadHandle,
+    markup::Markup,
+    notifications::Notifications,
+    page_cache::{ListCache, PageCache},
+    pow::Pow,
+    previews::Previews,
+    privacy::IpPolicy,
+    rate_limit::RateLimiter,
+    recording::Recorder,
+    redis_store::RedisStore,
+    replication::Replication,
+    rules::Rules,
+    search_index::SearchIndex,
+    secrets::Secrets,
+    sitemap::SitemapCache,
+    sites::Sites,
+    spam::SpamFilter,
+    stats::Stats,
+    storage::Storage,
+    tarpit::Tarpit,
+    theme::Theme,
+    trending::Trending,
+    Db,
+};
+
+// Everything handlers share, added to the router as a single extension
+// New shared resources should become fields here
+pub struct AppState {
+    pub db: Db,
+    pub storage: Storage,
+    pub sites: Sites,
+    pub config: Config,
+    pub theme: Theme,
+    // Shared with the templates, see markup.rs
+    pub markup: Arc<Markup>,
+    // Files of comments, see attachments.rs
+    pub attachments: Attachments,
+    // Fetched for approved comments, see previews.rs
+    pub previews: Previews,
+    pub log_reload: ReloadHandle,
+    pub sitemap: SitemapCache,
+    pub pages: PageCache,
+    pub lists: ListCache,
+    pub rate_limiter: RateLimiter,
+    pub tarpit: Tarpit,
+    pub basic_auth: BasicAuth,
+    pub auth_log: AuthLog,
+    pub pow: Pow,
+    pub secrets: Secrets,
+    pub ip_policy: IpPolicy,
+    pub geoip: GeoIp,
+    pub rules: Arc<Rules>,
+    pub blocked_names: BlockedNames,
+    pub notifications: Notifications,
+    pub spam: SpamFilter,
+    pub stats: Stats,
+    pub trending: Trending,
+    // Of /search, see search_index.rs
+    pub search_index: SearchIndex,
+    pub tombstones: Tombstones,
+    pub recorder: Recorder,
+    // Refuses new comments and votes, see control.rs
+    pub maintenance: AtomicBool,
+    pub drain: Drain,
+    // Unix time the TLS certificate chain runs out, 0 while unknown, see
+    // tls.rs
+    pub tls_expires: AtomicI64,
+    pub replication: Replication,
+    // Shares the comments with other instances, see redis_store.rs
+    pub redis: RedisStore,
+    // Woken when a comment becomes visible, see poll.rs
+    pub published: Notify,
+    // Run on every change of a comment's status, see domain.rs
+    pub hooks: Hooks,
+}
+
+pub type SharedState = Arc<AppState>;
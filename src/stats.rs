@@ -0,0 +1,422 @@
+// Comment statistics for the admin API, kept per site and day
+// Updated on every change instead of being computed from the Db on request
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::Duration as StdDuration,
+};
+
+use axum::{extract::Extension, response::IntoResponse, Json};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::Admin,
+    extract::{ParsedQuery, Validate, ValidatedQuery},
+    sites::{Site, DEFAULT_SITE},
+    state::SharedState,
+    Comment, CommentStatus,
+};
+
+// Longest range a single request may ask for
+const MAX_BUCKETS: usize = 366;
+
+// How often the depth of the moderation queue is sampled, and for how long
+// the samples are kept
+const QUEUE_SAMPLE_INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+const QUEUE_SAMPLES: usize = 7 * 24;
+
+#[derive(Default)]
+struct Day {
+    approved: usize,
+    pending: usize,
+    // Refused by the spam checks, only counted since the start
+    rejected: usize,
+    // Comments per commenter, by a hash of their identity
+    commenters: HashMap<u64, usize>,
+    // Comments per page slug
+    pages: HashMap<String, usize>,
+}
+
+// Of all the comments of a site, for the summary
+#[derive(Default)]
+struct Totals {
+    approved: usize,
+    pending: usize,
+    // Comments by when they were received, so the last one is known after
+    // it is deleted
+    received: BTreeMap<DateTime<Utc>, usize>,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    days: Mutex<HashMap<String, BTreeMap<NaiveDate, Day>>>,
+    totals: Mutex<HashMap<String, Totals>>,
+    // Pending comments per site over time, oldest first
+    queue: Mutex<HashMap<String, VecDeque<QueueSample>>>,
+}
+
+// When, and how many comments were pending
+type QueueSample = (DateTime<Utc>, usize);
+
+impl Stats {
+    pub fn new<'a>(comments: impl Iterator<Item = &'a Comment>) -> Self {
+        let stats = Stats::default();
+        for comment in comments {
+            stats.add(comment);
+        }
+        stats
+    }
+
+    pub fn add(&self, comment: &Comment) {
+        let mut days = self.days.lock().unwrap();
+        let day = day_mut(&mut days, &comment.site, received(comment));
+        match comment.status {
+            CommentStatus::Approved => day.approved += 1,
+            CommentStatus::Pending => day.pending += 1,
+            // Counted once published
+            CommentStatus::Draft => return,
+        }
+        *day.commenters.entry(commenter(comment)).or_default() += 1;
+        if let Some(slug) = &comment.slug {
+            *day.pages.entry(slug.clone()).or_default() += 1;
+        }
+        drop(days);
+
+        let mut totals = self.totals.lock().unwrap();
+        let totals = totals.entry(comment.site.clone()).or_default();
+        match comment.status {
+            CommentStatus::Approved => totals.approved += 1,
+            _ => totals.pending += 1,
+        }
+        *totals.received.entry(received(comment)).or_default() += 1;
+    }
+
+    pub fn remove(&self, comment: &Comment) {
+        let mut days = self.days.lock().unwrap();
+        let day = day_mut(&mut days, &comment.site, received(comment));
+        match comment.status {
+            CommentStatus::Approved => day.approved = day.approved.saturating_sub(1),
+            CommentStatus::Pending => day.pending = day.pending.saturating_sub(1),
+            CommentStatus::Draft => return,
+        }
+        let key = commenter(comment);
+        if let Some(count) = day.commenters.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                day.commenters.remove(&key);
+            }
+        }
+        if let Some(slug) = &comment.slug {
+            if let Some(count) = day.pages.get_mut(slug) {
+                *count -= 1;
+                if *count == 0 {
+                    day.pages.remove(slug);
+                }
+            }
+        }
+        drop(days);
+
+        let mut totals = self.totals.lock().unwrap();
+        let totals = match totals.get_mut(&comment.site) {
+            Some(totals) => totals,
+            None => return,
+        };
+        match comment.status {
+            CommentStatus::Approved => totals.approved = totals.approved.saturating_sub(1),
+            _ => totals.pending = totals.pending.saturating_sub(1),
+        }
+        let at = received(comment);
+        if let Some(count) = totals.received.get_mut(&at) {
+            *count -= 1;
+            if *count == 0 {
+                totals.received.remove(&at);
+            }
+        }
+    }
+
+    // For changes to a stored comment, e.g. approving it
+    pub fn replace(&self, old: &Comment, new: &Comment) {
+        self.remove(old);
+        self.add(new);
+    }
+
+    // `utc` is when the comment was received, see received
+    pub fn reject(&self, site: &str, utc: DateTime<Utc>) {
+        let mut days = self.days.lock().unwrap();
+        day_mut(&mut days, site, utc).rejected += 1;
+    }
+
+    // Refused by the spam checks since `first`, per site, for digest.rs
+    #[cfg(feature = "email")]
+    pub fn rejected_since(&self, first: NaiveDate) -> HashMap<String, usize> {
+        let days = self.days.lock().unwrap();
+        days.iter()
+            .map(|(site, days)| {
+                let rejected = days.range(first..).map(|(_, day)| day.rejected).sum();
+                (site.clone(), rejected)
+            })
+            .collect()
+    }
+
+    // Received comments per day from `first` to `last`
+    pub fn daily(&self, site: &str, first: NaiveDate, last: NaiveDate) -> Vec<(NaiveDate, usize)> {
+        let days = self.days.lock().unwrap();
+        first
+            .iter_days()
+            .take_while(|date| *date <= last)
+            .map(|date| {
+                let count = days
+                    .get(site)
+                    .and_then(|days| days.get(&date))
+                    .map_or(0, |day| day.approved + day.pending + day.rejected);
+                (date, count)
+            })
+            .collect()
+    }
+
+    // Pages with the most comments since `first`, most first
+    pub fn top_pages(&self, site: &str, first: NaiveDate, limit: usize) -> Vec<(String, usize)> {
+        let days = self.days.lock().unwrap();
+        let mut pages = HashMap::<&str, usize>::new();
+        for (_, day) in days
+            .get(site)
+            .into_iter()
+            .flat_map(|days| days.range(first..))
+        {
+            for (slug, count) in &day.pages {
+                *pages.entry(slug).or_default() += count;
+            }
+        }
+        let mut pages = pages
+            .into_iter()
+            .map(|(slug, count)| (slug.to_owned(), count))
+            .collect::<Vec<_>>();
+        pages.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        pages.truncate(limit);
+        pages
+    }
+
+    pub fn queue_samples(&self, site: &str) -> Vec<QueueSample> {
+        let queue = self.queue.lock().unwrap();
+        queue
+            .get(site)
+            .map(|samples| samples.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn sample_queue(&self, at: DateTime<Utc>) {
+        let pending = self
+            .days
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(site, days)| (site.clone(), days.values().map(|day| day.pending).sum()))
+            .collect::<Vec<(String, usize)>>();
+
+        let mut queue = self.queue.lock().unwrap();
+        for (site, pending) in pending {
+            let samples = queue.entry(site).or_default();
+            if samples.len() == QUEUE_SAMPLES {
+                samples.pop_front();
+            }
+            samples.push_back((at, pending));
+        }
+    }
+}
+
+// Runs for the lifetime of the server
+pub async fn sample_queue_periodically(state: SharedState) {
+    let mut interval = tokio::time::interval(QUEUE_SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.stats.sample_queue(Utc::now());
+    }
+}
+
+fn day_mut<'a>(
+    days: &'a mut HashMap<String, BTreeMap<NaiveDate, Day>>,
+    site: &str,
+    utc: DateTime<Utc>,
+) -> &'a mut Day {
+    days.entry(site.to_owned())
+        .or_default()
+        .entry(utc.date_naive())
+        .or_default()
+}
+
+// Server time, or the client's for comments from before it was kept
+pub fn received(comment: &Comment) -> DateTime<Utc> {
+    comment.created_at.unwrap_or(comment.utc)
+}
+
+// Only a hash, the stats don't hold personal data
+fn commenter(comment: &Comment) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match (&comment.visitor, &comment.email, &comment.ip) {
+        (Some(visitor), _, _) => visitor.hash(&mut hasher),
+        (None, Some(email), _) => email.hash(&mut hasher),
+        (None, None, Some(ip)) => ip.hash(&mut hasher),
+        (None, None, None) => comment.name.hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    #[default]
+    Day,
+    // Weeks start on Monday
+    Week,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct StatsQuery {
+    site: Option<String>,
+    bucket: Option<Bucket>,
+    // Number of buckets up to today, 30 days or 12 weeks by default
+    limit: Option<usize>,
+}
+
+impl Validate for StatsQuery {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        match self.limit {
+            Some(limit) if limit == 0 || limit > MAX_BUCKETS => Err((
+                "request.invalid_limit",
+                format!("limit must be between 1 and {}", MAX_BUCKETS),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BucketStats {
+    start: NaiveDate,
+    comments: usize,
+    approved: usize,
+    pending: usize,
+    rejected: usize,
+    // Distinct commenters within the bucket
+    commenters: usize,
+    // Share of the received comments which are approved, None without any
+    approval_rate: Option<f64>,
+}
+
+pub async fn get_stats(
+    _: Admin,
+    ValidatedQuery(query): ValidatedQuery<StatsQuery>,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let site = query.site.unwrap_or_else(|| DEFAULT_SITE.to_owned());
+    let bucket = query.bucket.unwrap_or_default();
+    let (step, default_limit) = match bucket {
+        Bucket::Day => (1, 30),
+        Bucket::Week => (7, 12),
+    };
+    let limit = query.limit.unwrap_or(default_limit);
+
+    let today = Utc::now().date_naive();
+    let current = match bucket {
+        Bucket::Day => today,
+        Bucket::Week => today - Duration::days(today.weekday().num_days_from_monday() as i64),
+    };
+    let first = current - Duration::days(step * (limit as i64 - 1));
+
+    let days = state.stats.days.lock().unwrap();
+    let site_days = days.get(&site);
+    let buckets = (0..limit as i64)
+        .map(|index| {
+            let start = first + Duration::days(step * index);
+            let end = start + Duration::days(step);
+            let mut stats = BucketStats {
+                start,
+                comments: 0,
+                approved: 0,
+                pending: 0,
+                rejected: 0,
+                commenters: 0,
+                approval_rate: None,
+            };
+            let mut commenters = HashSet::<u64>::new();
+            for (_, day) in site_days
+                .into_iter()
+                .flat_map(|days| days.range(start..end))
+            {
+                stats.approved += day.approved;
+                stats.pending += day.pending;
+                stats.rejected += day.rejected;
+                commenters.extend(day.commenters.keys().copied());
+            }
+            stats.comments = stats.approved + stats.pending + stats.rejected;
+            stats.commenters = commenters.len();
+            stats.approval_rate =
+                (stats.comments > 0).then(|| stats.approved as f64 / stats.comments as f64);
+            stats
+        })
+        .collect::<Vec<_>>();
+
+    Json(serde_json::json!({
+        "site": site,
+        "bucket": bucket,
+        "buckets": buckets,
+    }))
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SummaryQuery {
+    site: Option<String>,
+}
+
+// Counts of a site in one cheap call, for dashboards and uptime checks. From
+// the totals kept on every change, so it doesn't go through the comments.
+// Public with only the approved comments, what waits for moderation is at
+// /admin/stats/summary
+pub async fn get_summary(
+    site: Site,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let approved = state
+        .stats
+        .totals
+        .lock()
+        .unwrap()
+        .get(&site.key)
+        .map_or(0, |totals| totals.approved);
+
+    // Dashboards are usually on another origin
+    (
+        site.cors_headers(),
+        Json(serde_json::json!({
+            "site": site.key,
+            "approved": approved,
+        })),
+    )
+}
+
+pub async fn get_admin_summary(
+    _: Admin,
+    ParsedQuery(query): ParsedQuery<SummaryQuery>,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let site = query.site.unwrap_or_else(|| DEFAULT_SITE.to_owned());
+    let totals = state.stats.totals.lock().unwrap();
+    let (approved, pending, last_comment_at) = match totals.get(&site) {
+        Some(totals) => (
+            totals.approved,
+            totals.pending,
+            totals.received.keys().next_back().copied(),
+        ),
+        None => (0, 0, None),
+    };
+
+    Json(serde_json::json!({
+        "site": site,
+        "total": approved + pending,
+        "approved": approved,
+        "pending": pending,
+        "last_comment_at": last_comment_at,
+    }))
+}
@@ -0,0 +1,522 @@
+// Comments are kept in memory and persisted as a JSON snapshot file, or in a
+// sled database, see sled_store.rs
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    blocked_names::AddedBlockedName,
+    changes::Tombstone,
+    config::{SiteSettings, StorageBackend, StorageConfig},
+    events::EventLog,
+    notifications::NotificationPreferences,
+    rules::AddedRule,
+    sites::DEFAULT_SITE,
+    startup::Startup,
+    state::{AppState, SharedState},
+    Comment,
+};
+
+// Bump when the persisted format changes and add a migration below
+pub const SCHEMA_VERSION: u64 = 3;
+
+// (version, migration) pairs, each upgrading a snapshot from `version` to
+// `version + 1`. Snapshots are migrated step by step on load
+type Migration = fn(&mut Value);
+const MIGRATIONS: &[(u64, Migration)] = &[(1, add_tags), (2, add_sites)];
+
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    schema_version: u64,
+    comments: Vec<&'a Comment>,
+    // Site settings changed through the admin API
+    sites: HashMap<String, SiteSettings>,
+    // Spam rules added through the admin API
+    rules: Vec<AddedRule>,
+    // Blocked names added through the admin API
+    blocked_names: Vec<AddedBlockedName>,
+    // Notification preferences changed through the admin API
+    notifications: Option<NotificationPreferences>,
+    // Deleted comments, see changes.rs
+    tombstones: Vec<Tombstone>,
+    // Of the last event folded into the comments, see events.rs
+    last_event: u64,
+}
+
+// What a snapshot holds
+#[derive(Default)]
+pub struct Contents {
+    pub comments: HashMap<Uuid, Arc<Comment>>,
+    pub sites: HashMap<String, SiteSettings>,
+    pub rules: Vec<AddedRule>,
+    pub blocked_names: Vec<AddedBlockedName>,
+    pub notifications: Option<NotificationPreferences>,
+    pub tombstones: Vec<Tombstone>,
+    pub last_event: u64,
+}
+
+pub struct Storage {
+    // None keeps comments in memory only
+    path: Option<PathBuf>,
+    flush_interval: Duration,
+    // Set on every write, cleared when the snapshot is saved
+    dirty: AtomicBool,
+    // Bumped on every write, lets caches notice changes
+    revision: AtomicU64,
+    pub events: EventLog,
+    // Schema version of what was loaded, while older than SCHEMA_VERSION
+    migrated_from: Option<u64>,
+    // Instead of the snapshot, with [storage] backend = "sled"
+    #[cfg(feature = "sled")]
+    sled: Option<crate::sled_store::SledStore>,
+}
+
+impl Storage {
+    // Load the snapshot, migrating it to the current schema if needed, and
+    // replay the events after it
+    pub fn open(config: &StorageConfig) -> Result<(Storage, Contents), StorageError> {
+        let path = config.path.clone();
+        let mut dirty = false;
+        let mut contents = Contents::default();
+        let mut migrated_from = None;
+
+        #[cfg(feature = "sled")]
+        let (mut sled, mut loaded) = (None, Vec::new());
+        if config.backend == StorageBackend::Sled {
+            #[cfg(feature = "sled")]
+            if let Some(path) = &path {
+                let (store, snapshot) = crate::sled_store::SledStore::open(path)?;
+                if let Some(snapshot) = snapshot {
+                    let version;
+                    (contents, version) = parse_value(snapshot, path)?;
+                    loaded = contents.comments.keys().copied().collect();
+                    if version < SCHEMA_VERSION {
+                        dirty = true;
+                        migrated_from = Some(version);
+                        tracing::info!(
+                            "migrating {} from schema version {} to {}",
+                            path.display(),
+                            version,
+                            SCHEMA_VERSION
+                        );
+                    }
+                }
+                sled = Some(store);
+            }
+            #[cfg(not(feature = "sled"))]
+            return Err(StorageError::Backend(
+                path.unwrap_or_default(),
+                "[storage] backend is \"sled\", but little-nova was built without the `sled` feature"
+                    .to_owned(),
+            ));
+        } else if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+            let text = fs::read(path).map_err(|err| StorageError::Io(path.clone(), err))?;
+            let version;
+            (contents, version) = parse(&text, path)?;
+            if version < SCHEMA_VERSION {
+                // Keep the original around in case the upgrade goes wrong
+                let backup = path.with_extension(format!("v{}.bak", version));
+                fs::copy(path, &backup).map_err(|err| StorageError::Io(backup.clone(), err))?;
+                dirty = true;
+                migrated_from = Some(version);
+
+                tracing::info!(
+                    "migrated {} from schema version {} to {} (backup at {})",
+                    path.display(),
+                    version,
+                    SCHEMA_VERSION,
+                    backup.display()
+                );
+            }
+        }
+
+        let (events, replayed) = EventLog::open(path.as_deref(), &mut contents).map_err(|err| {
+            let log = path
+                .as_deref()
+                .unwrap_or(Path::new(""))
+                .with_extension("events.jsonl");
+            StorageError::Io(log, err)
+        })?;
+        // Every comment is written again in the new schema, and after events
+        // as they don't say which comments they touched
+        #[cfg(feature = "sled")]
+        if let Some(sled) = sled.as_ref().filter(|_| dirty || replayed) {
+            loaded
+                .into_iter()
+                .chain(contents.comments.keys().copied())
+                .for_each(|id| sled.mark_changed(id));
+        }
+
+        let storage = Storage {
+            path,
+            flush_interval: Duration::from_secs(config.flush_interval_secs),
+            dirty: AtomicBool::new(dirty || replayed),
+            revision: AtomicU64::new(0),
+            events,
+            migrated_from,
+            #[cfg(feature = "sled")]
+            sled,
+        };
+        Ok((storage, contents))
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
+        self.revision.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // For the sled database, which only writes the comments which changed
+    #[cfg(feature = "sled")]
+    pub fn mark_changed(&self, id: Uuid) {
+        if let Some(sled) = &self.sled {
+            sled.mark_changed(id);
+        }
+    }
+
+    #[cfg(not(feature = "sled"))]
+    pub fn mark_changed(&self, _: Uuid) {}
+
+    pub fn revision(&self) -> u64 {
+        self.revision.load(Ordering::Relaxed)
+    }
+
+    // Write the snapshot if anything changed since the last flush
+    pub fn flush(&self, state: &AppState) -> Result<(), StorageError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        // Applied before the write reads the comments, so the snapshot has them
+        let seq = self.events.seq();
+        #[cfg(feature = "chaos")]
+        let result = if crate::chaos::roll(state.config.chaos.storage_failure_rate) {
+            Err(StorageError::Io(
+                path.clone(),
+                io::Error::other("failure injected by [chaos]"),
+            ))
+        } else {
+            self.write(path, state)
+        };
+        #[cfg(not(feature = "chaos"))]
+        let result = self.write(path, state);
+        if result.is_err() {
+            // Retry on the next flush
+            self.dirty.store(true, Ordering::Relaxed);
+        } else if let Err(err) = self.events.compact(seq) {
+            tracing::error!("failed to compact the event log: {}", err);
+        }
+        result
+    }
+
+    fn write(&self, path: &Path, state: &AppState) -> Result<(), StorageError> {
+        #[cfg(feature = "sled")]
+        if let Some(sled) = &self.sled {
+            return sled.write(state);
+        }
+        write_snapshot(path, state)
+    }
+
+    pub fn migrated_from(&self) -> Option<u64> {
+        self.migrated_from
+    }
+
+    // Where the snapshot is saved, if anywhere
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // Replace the snapshot with `contents`, for changes made while the server
+    // is stopped, see cli.rs
+    pub fn save(&self, contents: &Contents) -> Result<(), StorageError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        #[cfg(feature = "sled")]
+        if let Some(sled) = &self.sled {
+            return sled.save(contents, self.events.seq());
+        }
+        let snapshot = Snapshot {
+            schema_version: SCHEMA_VERSION,
+            comments: contents.comments.values().map(Arc::as_ref).collect(),
+            sites: contents.sites.clone(),
+            rules: contents.rules.clone(),
+            blocked_names: contents.blocked_names.clone(),
+            notifications: contents.notifications.clone(),
+            tombstones: contents.tombstones.clone(),
+            last_event: self.events.seq(),
+        };
+        let json =
+            serde_json::to_vec(&snapshot).map_err(|err| StorageError::Parse(path.clone(), err))?;
+        write_atomically(path, json)
+    }
+
+    // Make sure the snapshot directory exists and is writable
+    pub fn check(&self) -> Result<(), StorageError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let probe = path.with_extension("probe");
+        fs::write(&probe, b"")
+            .and_then(|_| fs::remove_file(&probe))
+            .map_err(|err| StorageError::Io(probe, err))
+    }
+}
+
+// v2: comments carry tags
+fn add_tags(snapshot: &mut Value) {
+    for comment in comments_mut(snapshot) {
+        comment
+            .entry("tags")
+            .or_insert_with(|| Value::Array(Vec::new()));
+    }
+}
+
+// v3: comments belong to a site and may wait for moderation
+fn add_sites(snapshot: &mut Value) {
+    for comment in comments_mut(snapshot) {
+        comment
+            .entry("site")
+            .or_insert_with(|| Value::from(DEFAULT_SITE));
+        comment
+            .entry("status")
+            .or_insert_with(|| Value::from("approved"));
+    }
+}
+
+fn comments_mut(snapshot: &mut Value) -> impl Iterator<Item = &mut serde_json::Map<String, Value>> {
+    snapshot["comments"]
+        .as_array_mut()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+}
+
+fn migrate(snapshot: &mut Value, from: u64) -> Result<(), String> {
+    for version in from..SCHEMA_VERSION {
+        let (_, migration) = MIGRATIONS
+            .iter()
+            .find(|(from, _)| *from == version)
+            .ok_or_else(|| format!("no migration from schema version {}", version))?;
+        migration(snapshot);
+    }
+    snapshot["schema_version"] = SCHEMA_VERSION.into();
+    Ok(())
+}
+
+// Contents of a snapshot, migrated to the current schema, and the schema
+// version it had. `origin` only names it in errors
+pub fn parse(json: &[u8], origin: &Path) -> Result<(Contents, u64), StorageError> {
+    let snapshot =
+        serde_json::from_slice(json).map_err(|err| StorageError::Parse(origin.to_owned(), err))?;
+    parse_value(snapshot, origin)
+}
+
+pub fn parse_value(mut snapshot: Value, origin: &Path) -> Result<(Contents, u64), StorageError> {
+    let invalid = |err| StorageError::Parse(origin.to_owned(), err);
+
+    let version = snapshot
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    if version > SCHEMA_VERSION {
+        return Err(StorageError::NewerSchema(origin.to_owned(), version));
+    }
+    if version < SCHEMA_VERSION {
+        migrate(&mut snapshot, version)
+            .map_err(|err| StorageError::Migration(origin.to_owned(), err))?;
+    }
+
+    let comments: Vec<Comment> =
+        serde_json::from_value(snapshot["comments"].take()).map_err(invalid)?;
+    let comments = comments
+        .into_iter()
+        .map(|comment| (comment.id, Arc::new(comment)))
+        .collect();
+    // Older snapshots have no site settings
+    let sites = match snapshot["sites"].take() {
+        Value::Null => HashMap::new(),
+        sites => serde_json::from_value(sites).map_err(invalid)?,
+    };
+
+    let rules = match snapshot["rules"].take() {
+        Value::Null => Vec::new(),
+        rules => serde_json::from_value(rules).map_err(invalid)?,
+    };
+
+    let blocked_names = match snapshot["blocked_names"].take() {
+        Value::Null => Vec::new(),
+        blocked_names => serde_json::from_value(blocked_names).map_err(invalid)?,
+    };
+
+    // Also null while unchanged
+    let notifications =
+        serde_json::from_value(snapshot["notifications"].take()).map_err(invalid)?;
+
+    let tombstones = match snapshot["tombstones"].take() {
+        Value::Null => Vec::new(),
+        tombstones => serde_json::from_value(tombstones).map_err(invalid)?,
+    };
+
+    Ok((
+        Contents {
+            comments,
+            sites,
+            rules,
+            blocked_names,
+            notifications,
+            tombstones,
+            // Older snapshots come from before the event log
+            last_event: snapshot["last_event"].as_u64().unwrap_or(0),
+        },
+        version,
+    ))
+}
+
+// The snapshot of everything in memory, taken under the comments lock so
+// it is consistent
+pub fn snapshot(state: &AppState) -> serde_json::Result<Vec<u8>> {
+    let comments = state.db.read().unwrap();
+    let snapshot = Snapshot {
+        schema_version: SCHEMA_VERSION,
+        comments: comments.values().map(Arc::as_ref).collect(),
+        sites: state.sites.persisted(),
+        rules: state.rules.added(),
+        blocked_names: state.blocked_names.added(),
+        notifications: state.notifications.changed(),
+        tombstones: state.tombstones.all(),
+        last_event: state.storage.events.seq(),
+    };
+    serde_json::to_vec(&snapshot)
+}
+
+fn write_snapshot(path: &Path, state: &AppState) -> Result<(), StorageError> {
+    let json = snapshot(state).map_err(|err| StorageError::Parse(path.to_owned(), err))?;
+    write_atomically(path, json)
+}
+
+// Write to a temporary file first so a crash never leaves a truncated snapshot
+pub fn write_atomically(path: &Path, json: Vec<u8>) -> Result<(), StorageError> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, json)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|err| StorageError::Io(path.to_owned(), err))
+}
+
+// Seq of the last event a follower of the log handled, see webhooks.rs.
+// None before its first start
+#[cfg(any(feature = "webhooks", feature = "email"))]
+pub fn read_cursor(path: &Path) -> io::Result<Option<u64>> {
+    match fs::read_to_string(path) {
+        Ok(text) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// Delay before the first retry at startup, doubled up to MAX_RETRY_DELAY
+pub const FIRST_RETRY_DELAY: Duration = Duration::from_millis(250);
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// Storage::open, retried for up to [storage] startup_retry_secs while the
+// files can't be read or written, e.g. while their volume is still being
+// mounted, which GET /ready tells meanwhile. Broken or newer snapshots fail
+// right away
+pub async fn open_with_retry(
+    config: &StorageConfig,
+    startup: &Startup,
+) -> Result<(Storage, Contents), StorageError> {
+    let deadline = Instant::now() + Duration::from_secs(config.startup_retry_secs);
+    let mut delay = FIRST_RETRY_DELAY;
+    loop {
+        let opened = Storage::open(config)
+            .and_then(|(storage, contents)| storage.check().map(|()| (storage, contents)));
+        match opened {
+            Err(StorageError::Io(path, err)) if Instant::now() + delay < deadline => {
+                tracing::warn!(
+                    "storage not available yet, retrying in {:?}: {}: {}",
+                    delay,
+                    path.display(),
+                    err
+                );
+                startup.waiting_for(format!(
+                    "storage not available yet: {}: {}",
+                    path.display(),
+                    err
+                ));
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+// Runs for the lifetime of the server
+pub async fn flush_periodically(state: SharedState) {
+    let mut interval = tokio::time::interval(state.storage.flush_interval);
+    loop {
+        interval.tick().await;
+
+        let state = state.clone();
+        let result = tokio::task::spawn_blocking(move || state.storage.flush(&state)).await;
+        if let Ok(Err(err)) = result {
+            tracing::error!("failed to save comments: {}", err);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, serde_json::Error),
+    Migration(PathBuf, String),
+    NewerSchema(PathBuf, u64),
+    // Of the sled database
+    Backend(PathBuf, String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            StorageError::Parse(path, err) => {
+                write!(f, "invalid snapshot {}: {}", path.display(), err)
+            }
+            StorageError::Migration(path, err) => {
+                write!(f, "failed to migrate {}: {}", path.display(), err)
+            }
+            StorageError::NewerSchema(path, version) => write!(
+                f,
+                "{} has schema version {}, newer than the supported {}; upgrade little-nova",
+                path.display(),
+                version,
+                SCHEMA_VERSION
+            ),
+            StorageError::Backend(path, err) => write!(f, "{}: {}", path.display(), err),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
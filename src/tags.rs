@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use axum::{extract::Extension, response::IntoResponse, Json};
+use serde::Serialize;
+
+use crate::{sites::Site, state::SharedState};
+
+// Keep tags short enough to render as chips and use as filter keys
+pub const MAX_TAGS: usize = 5;
+pub const MAX_TAG_LEN: usize = 32;
+
+// Trim, lowercase and dedup tags, rejecting ones which can't be used in `?tag=`
+pub fn normalize(tags: Vec<String>) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = Vec::new();
+
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() {
+            continue;
+        }
+        if tag.chars().count() > MAX_TAG_LEN {
+            return Err(format!(
+                "Tag \"{}\" is longer than {} characters",
+                tag, MAX_TAG_LEN
+            ));
+        }
+        if !tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "Tag \"{}\" may only contain letters, digits, '-' and '_'",
+                tag
+            ));
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+
+    if normalized.len() > MAX_TAGS {
+        return Err(format!("At most {} tags are allowed", MAX_TAGS));
+    }
+
+    Ok(normalized)
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagCount {
+    tag: String,
+    count: usize,
+}
+
+// Number of comments per tag, most used first
+pub async fn get_tag_cloud(
+    site: Site,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+
+    let db = state.db.read().unwrap();
+    for tag in db
+        .values()
+        .filter(|comment| comment.is_listed(&site.key))
+        .flat_map(|comment| &comment.tags)
+    {
+        *counts.entry(tag).or_default() += 1;
+    }
+
+    let mut cloud = counts
+        .into_iter()
+        .map(|(tag, count)| TagCount {
+            tag: tag.to_owned(),
+            count,
+        })
+        .collect::<Vec<_>>();
+    cloud.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+
+    Json(cloud)
+}
@@ -0,0 +1,205 @@
+// Clients which keep tripping the rate limit or the spam checks are put in a
+// tarpit for a while, see [tarpit]. Their requests are answered slowly, and
+// once they made `max_requests` in there with 429 only, which keeps naive
+// bots waiting instead of posting. The admin routes are left alone
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{self, BoxBody},
+    http::{header, Request, Response, StatusCode},
+    response::IntoResponse,
+};
+use tower::{Layer, Service};
+
+use crate::{
+    auth_log::Failure, config::TarpitConfig, errors::ApiError, identity, state::SharedState,
+};
+
+// Forget clients without recent strikes once this many are tracked
+const PRUNE_AT: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slowdown {
+    None,
+    Delay(Duration),
+    // With the seconds until the client is let out
+    Refuse(u64),
+}
+
+#[derive(Debug, Default)]
+struct Client {
+    // Of the current window
+    strikes: Vec<Instant>,
+    // While in the tarpit
+    until: Option<Instant>,
+    requests: u32,
+}
+
+pub struct Tarpit {
+    config: TarpitConfig,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+}
+
+impl Tarpit {
+    pub fn new(config: &TarpitConfig) -> Self {
+        Tarpit {
+            config: config.clone(),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // A refused comment, `strikes` of them within `window_secs` and the
+    // client is in
+    pub fn strike(&self, client: IpAddr, reason: &str) {
+        if !self.config.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window_secs);
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= PRUNE_AT {
+            clients.retain(|_, client| {
+                client.until.is_some_and(|until| now < until)
+                    || client
+                        .strikes
+                        .iter()
+                        .any(|at| now.duration_since(*at) < window)
+            });
+        }
+
+        let entry = clients.entry(client).or_default();
+        // Already in, which doesn't start over
+        if entry.until.is_some_and(|until| now < until) {
+            return;
+        }
+        entry.strikes.retain(|at| now.duration_since(*at) < window);
+        entry.strikes.push(now);
+        if entry.strikes.len() >= self.config.strikes as usize {
+            entry.strikes.clear();
+            entry.until = Some(now + Duration::from_secs(self.config.duration_secs));
+            entry.requests = 0;
+            tracing::info!(%client, reason, "client put in the tarpit");
+        }
+    }
+
+    // Counts the request of a client in the tarpit
+    pub fn slowdown(&self, client: IpAddr) -> Slowdown {
+        if !self.config.enabled {
+            return Slowdown::None;
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let entry = match clients.get_mut(&client) {
+            Some(entry) => entry,
+            None => return Slowdown::None,
+        };
+        let until = match entry.until {
+            Some(until) if now < until => until,
+            Some(_) => {
+                entry.until = None;
+                return Slowdown::None;
+            }
+            None => return Slowdown::None,
+        };
+
+        entry.requests = entry.requests.saturating_add(1);
+        if entry.requests > self.config.max_requests {
+            Slowdown::Refuse(until.duration_since(now).as_secs().max(1))
+        } else {
+            Slowdown::Delay(Duration::from_millis(self.config.delay_ms))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TarpitLayer {
+    state: SharedState,
+}
+
+impl TarpitLayer {
+    pub fn new(state: SharedState) -> Self {
+        TarpitLayer { state }
+    }
+}
+
+impl<S> Layer<S> for TarpitLayer {
+    type Service = TarpitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TarpitService {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TarpitService<S> {
+    inner: S,
+    state: SharedState,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for TarpitService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let client = identity::client_ip(&self.state, &req);
+        let slowdown = match client {
+            Some(client) if !req.uri().path().starts_with("/admin") => {
+                self.state.tarpit.slowdown(client)
+            }
+            _ => Slowdown::None,
+        };
+
+        match slowdown {
+            Slowdown::None => Box::pin(self.inner.call(req)),
+            // Not polled until the delay is over, so the handler runs late too
+            Slowdown::Delay(delay) => {
+                let response = self.inner.call(req);
+                Box::pin(async move {
+                    tokio::time::sleep(delay).await;
+                    response.await
+                })
+            }
+            Slowdown::Refuse(retry_after) => {
+                self.state.auth_log.failure(
+                    Failure::Tarpit,
+                    client,
+                    req.method().as_str(),
+                    req.uri().path(),
+                );
+                Box::pin(async move {
+                    let mut response = ApiError::new(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "request.rate_limited",
+                        "Too many requests, try again later",
+                    )
+                    .into_response()
+                    .map(body::boxed);
+                    response
+                        .headers_mut()
+                        .insert(header::RETRY_AFTER, retry_after.into());
+                    Ok(response)
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// Themes are stylesheets served at /theme.css
+// The HTML templates are compiled in by askama, so a theme only changes the styling
+use std::{fs, path::PathBuf};
+
+use axum::{
+    extract::Extension,
+    http::{header, HeaderMap, HeaderValue},
+    response::IntoResponse,
+};
+
+use crate::{config::ThemeConfig, sites::Site, state::SharedState};
+
+const BUNDLED: &[(&str, &str)] = &[
+    ("default", include_str!("../themes/default.css")),
+    ("dark", include_str!("../themes/dark.css")),
+];
+
+// File looked up in `theme.dir`
+const THEME_FILE: &str = "theme.css";
+
+// Sites can pick one of these, see [sites] in the config
+pub fn bundled(name: &str) -> Result<&'static str, String> {
+    BUNDLED
+        .iter()
+        .find(|(bundled, _)| *bundled == name)
+        .map(|(_, css)| *css)
+        .ok_or_else(|| {
+            let names = BUNDLED.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            format!(
+                "unknown theme \"{}\", bundled themes are: {}",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+pub struct Theme {
+    css: String,
+    // Set when the theme comes from a directory and should be re-read on every request
+    reload_path: Option<PathBuf>,
+}
+
+impl Theme {
+    pub fn load(config: &ThemeConfig) -> Result<Theme, String> {
+        if let Some(dir) = &config.dir {
+            let path = dir.join(THEME_FILE);
+            let css = fs::read_to_string(&path)
+                .map_err(|err| format!("failed to read theme {}: {}", path.display(), err))?;
+            return Ok(Theme {
+                css,
+                reload_path: config.reload.then_some(path),
+            });
+        }
+
+        bundled(&config.name).map(|css| Theme {
+            css: css.to_owned(),
+            reload_path: None,
+        })
+    }
+
+    pub fn css(&self) -> String {
+        match &self.reload_path {
+            // Keep serving the last good version while the file is being edited
+            Some(path) => fs::read_to_string(path).unwrap_or_else(|err| {
+                tracing::warn!("failed to reload theme {}: {}", path.display(), err);
+                self.css.clone()
+            }),
+            None => self.css.clone(),
+        }
+    }
+}
+
+pub async fn get_theme_css(
+    site: Site,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/css; charset=utf-8"),
+    );
+    // A site's own theme replaces the server wide one
+    let css = match site.settings.theme.as_deref().map(bundled) {
+        Some(Ok(css)) => css.to_owned(),
+        _ => state.theme.css(),
+    };
+    let cache_control = if state.theme.reload_path.is_some() {
+        "no-cache"
+    } else {
+        "public, max-age=3600"
+    };
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(cache_control),
+    );
+
+    (headers, css)
+}
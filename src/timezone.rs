@@ -0,0 +1,108 @@
+// Timezone the pages show times in, [display] timezone unless
+// [display] visitor_timezone is on and the visitor's browser said otherwise.
+// The pages' script keeps the browser's IANA timezone in a cookie, so it
+// applies from the next page on, or its offset where the browser can't tell:
+//
+//   little_nova_timezone=Europe/Berlin
+//   little_nova_timezone=+09:00
+//
+// A named timezone shows each time with the offset it had then, so times
+// from before and after a change of daylight saving time are both right.
+// Comments are stored in UTC either way
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, RequestParts},
+    http::header,
+};
+use chrono::{DateTime, FixedOffset, Utc};
+#[cfg(feature = "email")]
+use chrono::{NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+
+use crate::{config, state::SharedState};
+
+// Also in create-comment.js
+const TIMEZONE_COOKIE: &str = "little_nova_timezone";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Timezone {
+    // Of the IANA database, e.g. "Europe/Berlin"
+    Named(Tz),
+    Fixed(FixedOffset),
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Timezone::Named(Tz::UTC)
+    }
+}
+
+impl Timezone {
+    // An IANA name, "UTC" or an offset like "+09:00"
+    pub fn parse(text: &str) -> Option<Timezone> {
+        match text.parse::<Tz>() {
+            Ok(tz) => Some(Timezone::Named(tz)),
+            Err(_) => config::parse_offset(text).map(Timezone::Fixed),
+        }
+    }
+
+    // The time as the clocks here showed it
+    pub fn at(&self, utc: &DateTime<Utc>) -> DateTime<FixedOffset> {
+        match self {
+            Timezone::Named(tz) => utc.with_timezone(tz).fixed_offset(),
+            Timezone::Fixed(offset) => utc.with_timezone(offset),
+        }
+    }
+
+    // When the clocks here show `local`, the earlier time when they show it
+    // twice and None when they skip it. For the digests, see digest.rs
+    #[cfg(feature = "email")]
+    pub fn when_local(&self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            Timezone::Named(tz) => tz
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|at| at.with_timezone(&Utc)),
+            Timezone::Fixed(offset) => offset
+                .from_local_datetime(&local)
+                .single()
+                .map(|at| at.with_timezone(&Utc)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DisplayTimezone(pub Timezone);
+
+#[async_trait]
+impl<B> FromRequest<B> for DisplayTimezone
+where
+    B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let (timezone, visitor_timezone) = match Extension::<SharedState>::from_request(req).await {
+            Ok(Extension(state)) => (
+                state.config.display.timezone,
+                state.config.display.visitor_timezone,
+            ),
+            Err(_) => (Timezone::default(), false),
+        };
+        if !visitor_timezone {
+            return Ok(DisplayTimezone(timezone));
+        }
+        let visitor = req
+            .headers()
+            .into_iter()
+            .flat_map(|headers| headers.get_all(header::COOKIE))
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == TIMEZONE_COOKIE)
+            .and_then(|(_, value)| Timezone::parse(value));
+        Ok(DisplayTimezone(visitor.unwrap_or(timezone)))
+    }
+}
@@ -0,0 +1,306 @@
+// The rustls setup of the HTTPS listener, from [tls]: versions, cipher
+// suites and ALPN protocols, rustls' defaults while unset. Only built with
+// the `tls` feature
+//
+// The chain is checked at startup, see self_check.rs, and watched while
+// running: its expiry is logged once it is close and shown by GET
+// /admin/metrics, and a changed OCSP response file is stapled again
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{TimeZone, Utc};
+use rustls::{
+    version::{TLS12, TLS13},
+    Certificate, PrivateKey, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion,
+    ALL_CIPHER_SUITES,
+};
+use x509_parser::certificate::X509Certificate;
+
+use crate::{
+    config::TlsConfig,
+    state::{AppState, SharedState},
+};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Signatures we can verify within the chain with webpki
+static ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+];
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("failed to open {}: {}", path.display(), err))
+}
+
+// The chain as in the file, the server's certificate first
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|err| format!("failed to read certificate {}: {}", path.display(), err))?;
+    if certs.is_empty() {
+        return Err(format!("no PEM certificate found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+pub fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    rustls_pemfile::read_all(&mut open(path)?)
+        .map_err(|err| format!("failed to read private key {}: {}", path.display(), err))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) => {
+                Some(PrivateKey(key))
+            }
+            _ => None,
+        })
+        .ok_or_else(|| format!("no RSA or PKCS#8 private key found in {}", path.display()))
+}
+
+const FROM_TLS12: &[&SupportedProtocolVersion] = &[&TLS13, &TLS12];
+const FROM_TLS13: &[&SupportedProtocolVersion] = &[&TLS13];
+
+fn versions(config: &TlsConfig) -> Result<&'static [&'static SupportedProtocolVersion], String> {
+    match config.min_version.as_str() {
+        "1.2" => Ok(FROM_TLS12),
+        "1.3" => Ok(FROM_TLS13),
+        version => Err(format!(
+            "[tls] min_version \"{}\" is not \"1.2\" or \"1.3\"",
+            version
+        )),
+    }
+}
+
+// By their IANA names, e.g. "TLS13_AES_256_GCM_SHA384"
+fn cipher_suites(config: &TlsConfig) -> Result<Vec<SupportedCipherSuite>, String> {
+    if config.cipher_suites.is_empty() {
+        return Ok(ALL_CIPHER_SUITES.to_vec());
+    }
+    config
+        .cipher_suites
+        .iter()
+        .map(|name| {
+            ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| {
+                    suite
+                        .suite()
+                        .as_str()
+                        .is_some_and(|known| known.eq_ignore_ascii_case(name))
+                })
+                .copied()
+                .ok_or_else(|| format!("[tls] cipher suite \"{}\" is not supported", name))
+        })
+        .collect()
+}
+
+pub struct Chain {
+    // Unix time the first of its certificates runs out
+    pub expires: i64,
+    // Issued by a CA, but without the intermediates up to it
+    pub incomplete: bool,
+}
+
+fn is_self_signed(cert: &X509Certificate) -> bool {
+    cert.issuer().as_raw() == cert.subject().as_raw()
+}
+
+// Every certificate valid now and signed by the next one
+pub fn check_chain(path: &Path, certs: &[Certificate]) -> Result<Chain, String> {
+    let parsed = certs
+        .iter()
+        .map(|cert| {
+            x509_parser::parse_x509_certificate(&cert.0)
+                .map(|(_, cert)| cert)
+                .map_err(|err| format!("failed to parse certificate {}: {}", path.display(), err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let now = Utc::now().timestamp();
+    for cert in &parsed {
+        let validity = cert.validity();
+        if validity.not_after.timestamp() < now {
+            return Err(format!(
+                "certificate \"{}\" in {} expired on {}",
+                cert.subject(),
+                path.display(),
+                validity.not_after
+            ));
+        }
+        if validity.not_before.timestamp() > now {
+            return Err(format!(
+                "certificate \"{}\" in {} is not valid before {}",
+                cert.subject(),
+                path.display(),
+                validity.not_before
+            ));
+        }
+    }
+    for pair in parsed.windows(2) {
+        if pair[0].issuer().as_raw() != pair[1].subject().as_raw() {
+            return Err(format!(
+                "certificate \"{}\" in {} is not followed by its issuer \"{}\"",
+                pair[0].subject(),
+                path.display(),
+                pair[0].issuer()
+            ));
+        }
+    }
+
+    // The last one is trusted, whether it is the root or an intermediate
+    if let [leaf, intermediates @ .., last] = certs {
+        let anchor = webpki::TrustAnchor::try_from_cert_der(&last.0);
+        let intermediates: Vec<&[u8]> = intermediates.iter().map(|cert| &cert.0[..]).collect();
+        anchor
+            .and_then(|anchor| {
+                webpki::EndEntityCert::try_from(&leaf.0[..])?.verify_is_valid_tls_server_cert(
+                    ALGORITHMS,
+                    &webpki::TlsServerTrustAnchors(&[anchor]),
+                    &intermediates,
+                    webpki::Time::from_seconds_since_unix_epoch(now as u64),
+                )
+            })
+            .map_err(|err| {
+                format!(
+                    "the certificate chain in {} does not verify: {:?}",
+                    path.display(),
+                    err
+                )
+            })?;
+    }
+
+    Ok(Chain {
+        expires: parsed
+            .iter()
+            .map(|cert| cert.validity().not_after.timestamp())
+            .min()
+            .unwrap_or_default(),
+        incomplete: parsed.len() == 1 && !is_self_signed(&parsed[0]),
+    })
+}
+
+pub fn server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>, String> {
+    let versions = versions(config)?;
+    let suites = cipher_suites(config)?;
+    if !suites
+        .iter()
+        .any(|suite| versions.contains(&suite.version()))
+    {
+        return Err(format!(
+            "[tls] none of the cipher_suites are for TLS {} or later",
+            config.min_version
+        ));
+    }
+
+    let certs = load_certs(&config.cert)?;
+    let key = load_key(&config.key)?;
+    // Stapled as it is, whoever fetches it keeps it fresh
+    let ocsp = match &config.ocsp {
+        Some(path) => std::fs::read(path).map_err(|err| {
+            format!(
+                "failed to read the OCSP response {}: {}",
+                path.display(),
+                err
+            )
+        })?,
+        None => Vec::new(),
+    };
+    let mut server = ServerConfig::builder()
+        .with_cipher_suites(&suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .map_err(|err| format!("invalid [tls] settings: {}", err))?
+        .with_no_client_auth()
+        .with_single_cert_with_ocsp_and_sct(certs, key, ocsp, Vec::new())
+        .map_err(|err| format!("failed to use {}: {}", config.cert.display(), err))?;
+    server.alpn_protocols = config
+        .alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    Ok(Arc::new(server))
+}
+
+// Takes the expiry of the chain in the files for the metric
+pub fn inspect(state: &AppState) {
+    let path = &state.config.tls.cert;
+    match load_certs(path).and_then(|certs| check_chain(path, &certs)) {
+        Ok(chain) => {
+            if chain.incomplete {
+                tracing::warn!(
+                    "{} has no intermediate certificates, clients may not trust it",
+                    path.display()
+                );
+            }
+            state.tls_expires.store(chain.expires, Ordering::Relaxed);
+        }
+        Err(err) => tracing::error!("{}", err),
+    }
+}
+
+// After the certificate or the OCSP response changed
+pub fn reload(state: &AppState) {
+    match server_config(&state.config.tls) {
+        Ok(server_config) => state.secrets.reload_tls(server_config),
+        Err(err) => {
+            tracing::error!("failed to load the new TLS certificate: {}", err);
+            return;
+        }
+    }
+    inspect(state);
+}
+
+fn ocsp_modified(config: &TlsConfig) -> Option<SystemTime> {
+    let path = config.ocsp.as_ref()?;
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+pub async fn watch(state: SharedState) {
+    let mut stapled = ocsp_modified(&state.config.tls);
+    loop {
+        let expires = state.tls_expires.load(Ordering::Relaxed);
+        let left = expires - Utc::now().timestamp();
+        let date = Utc
+            .timestamp_opt(expires, 0)
+            .single()
+            .unwrap_or_default()
+            .to_rfc3339();
+        if expires == 0 {
+            // Not read, the error is logged already
+        } else if left <= 0 {
+            tracing::error!(expires = %date, "the TLS certificate expired");
+        } else if left < state.config.tls.expiry_warning_days as i64 * 24 * 60 * 60 {
+            tracing::warn!(
+                expires = %date,
+                "the TLS certificate expires in {} days, renew it",
+                left / (24 * 60 * 60)
+            );
+        }
+
+        tokio::time::sleep(WATCH_INTERVAL).await;
+        let modified = ocsp_modified(&state.config.tls);
+        if modified != stapled {
+            stapled = modified;
+            tracing::info!("stapling the new OCSP response");
+            reload(&state);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+// W3C trace context, https://www.w3.org/TR/trace-context/. A traceparent
+// from the client or a proxy is carried on to the outbound HTTP calls made
+// while answering the request, e.g. to S3, with little-nova's own span id as
+// the parent, and kept with its events for the webhooks delivered later, so a tracing system shows the whole comment pipeline as one
+// trace. tracestate goes along unchanged. Requests without one start a new
+// trace. Link previews fetch third-party pages a commenter linked to, so
+// they get neither
+//
+// The request span also takes the comment, page and moderation decision a
+// handler deals with, to filter the logs by them, see record_comment
+//
+// Whether a trace is sampled is decided once at its head, see [tracing]:
+// unsampled requests have no request span and log no request events, and
+// say so in the flags of the traceparent they send on
+use std::{
+    fmt::Display,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::http::{HeaderMap, Request, Response};
+use tower::{Layer, Service};
+use tower_http::trace::{DefaultOnRequest, DefaultOnResponse, OnRequest, OnResponse};
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::config::{Sampler, TracingConfig};
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+const SAMPLED: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    // "00-<trace id>-<our span id>-<flags>", as sent on
+    pub traceparent: String,
+    pub tracestate: Option<String>,
+    pub sampled: bool,
+}
+
+tokio::task_local! {
+    // Of the request being answered
+    static CURRENT: TraceContext;
+}
+
+fn is_hex(text: &str, len: usize) -> bool {
+    text.len() == len
+        && text
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_zero(text: &str) -> bool {
+    text.bytes().all(|byte| byte == b'0')
+}
+
+// The trace id and flags of a valid traceparent
+fn parse(traceparent: &str) -> Option<(&str, u8)> {
+    let mut fields = traceparent.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+    // Later versions may add fields, version 00 has exactly these
+    let valid = is_hex(version, 2)
+        && version != "ff"
+        && (version != "00" || fields.next().is_none())
+        && is_hex(trace_id, 32)
+        && !is_zero(trace_id)
+        && is_hex(parent_id, 16)
+        && !is_zero(parent_id)
+        && is_hex(flags, 2);
+    valid.then(|| (trace_id, u8::from_str_radix(flags, 16).unwrap_or_default()))
+}
+
+// The same for every service which samples this share of the trace ids
+fn within_ratio(trace_id: &str, ratio: f64) -> bool {
+    let low = u64::from_str_radix(&trace_id[16..], 16).unwrap_or_default();
+    ratio >= 1.0 || (low as f64) < ratio * u64::MAX as f64
+}
+
+impl TraceContext {
+    // A missing or malformed traceparent starts a new trace. tracestate may
+    // be split over several headers
+    pub fn from_values<'a>(
+        config: &TracingConfig,
+        traceparent: Option<&str>,
+        tracestate: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        let parent = traceparent.and_then(parse);
+        let trace_id = match parent {
+            Some((trace_id, _)) => trace_id.to_owned(),
+            None => Uuid::new_v4().simple().to_string(),
+        };
+        let sampled = match (config.sampler, parent) {
+            (Sampler::Always, _) => true,
+            (Sampler::Parent, Some((_, flags))) => flags & SAMPLED != 0,
+            (Sampler::Ratio | Sampler::Parent, _) => within_ratio(&trace_id, config.ratio),
+        };
+        let flags = match parent {
+            Some((_, flags)) => flags & !SAMPLED,
+            None => 0,
+        } | if sampled { SAMPLED } else { 0 };
+
+        let span_id = &Uuid::new_v4().simple().to_string()[..16];
+        let tracestate = tracestate
+            .into_iter()
+            .map(str::trim)
+            .filter(|state| !state.is_empty())
+            .collect::<Vec<_>>()
+            .join(",");
+        TraceContext {
+            traceparent: format!("00-{}-{}-{:02x}", trace_id, span_id, flags),
+            tracestate: (parent.is_some() && !tracestate.is_empty()).then_some(tracestate),
+            sampled,
+        }
+    }
+
+    pub fn from_headers(config: &TracingConfig, headers: &HeaderMap) -> Self {
+        TraceContext::from_values(
+            config,
+            headers
+                .get(TRACEPARENT)
+                .and_then(|value| value.to_str().ok()),
+            headers
+                .get_all(TRACESTATE)
+                .iter()
+                .filter_map(|value| value.to_str().ok()),
+        )
+    }
+}
+
+// Runs the future within the context, if there is one
+pub async fn scope<F: Future>(context: Option<TraceContext>, future: F) -> F::Output {
+    match context {
+        Some(context) => CURRENT.scope(context, future).await,
+        None => future.await,
+    }
+}
+
+// Of the request being answered, none outside of one
+pub fn current() -> Option<TraceContext> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+// For an outbound call, none outside of a traced request
+#[cfg(feature = "s3")]
+pub fn outbound_headers() -> Vec<(&'static str, String)> {
+    match current() {
+        Some(context) => headers(&context.traceparent, context.tracestate.as_deref()),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(any(feature = "s3", feature = "webhooks"))]
+pub fn headers(traceparent: &str, tracestate: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut headers = vec![(TRACEPARENT, traceparent.to_owned())];
+    if let Some(tracestate) = tracestate {
+        headers.push((TRACESTATE, tracestate.to_owned()));
+    }
+    headers
+}
+
+// The span of tower-http which TraceLayer makes, with the trace context sent
+// on, so logs can be matched to the trace. None for unsampled requests
+pub fn make_span<B>(req: &Request<B>) -> Span {
+    let context = req.extensions().get::<TraceContext>();
+    if context.is_some_and(|context| !context.sampled) {
+        return Span::none();
+    }
+    let span = tracing::debug_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        traceparent = tracing::field::Empty,
+        tracestate = tracing::field::Empty,
+        comment_id = tracing::field::Empty,
+        slug = tracing::field::Empty,
+        moderation = tracing::field::Empty,
+        spam_score = tracing::field::Empty,
+    );
+    if let Some(context) = context {
+        span.record("traceparent", context.traceparent.as_str());
+        if let Some(tracestate) = &context.tracestate {
+            span.record("tracestate", tracestate.as_str());
+        }
+    }
+    span
+}
+
+// Onto the span of the request being answered, none outside of one
+pub fn record_comment(id: Uuid, slug: Option<&str>) {
+    Span::current().record("comment_id", tracing::field::display(id));
+    if let Some(slug) = slug {
+        record_page(slug);
+    }
+}
+
+pub fn record_page(slug: &str) {
+    Span::current().record("slug", slug);
+}
+
+// The status a comment got, or "rejected" by the spam checks
+pub fn record_moderation(decision: impl Display, spam_score: Option<f32>) {
+    let span = Span::current();
+    span.record("moderation", tracing::field::display(decision));
+    if let Some(spam_score) = spam_score {
+        span.record("spam_score", spam_score);
+    }
+}
+
+// TraceLayer's events, in sampled requests only
+pub fn on_request<B>(req: &Request<B>, span: &Span) {
+    if !span.is_none() {
+        DefaultOnRequest::new().on_request(req, span);
+    }
+}
+
+pub fn on_response<B>(res: &Response<B>, latency: Duration, span: &Span) {
+    if !span.is_none() {
+        DefaultOnResponse::new().on_response(res, latency, span);
+    }
+}
+
+// Reads the context of every request, for make_span and the handlers
+#[derive(Clone)]
+pub struct TraceContextLayer {
+    config: TracingConfig,
+}
+
+impl TraceContextLayer {
+    pub fn new(config: &TracingConfig) -> Self {
+        TraceContextLayer {
+            config: config.clone(),
+        }
+    }
+}
+
+impl<S> Layer<S> for TraceContextLayer {
+    type Service = TraceContextService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceContextService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TraceContextService<S> {
+    inner: S,
+    config: TracingConfig,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for TraceContextService<S>
+where
+    S: Service<Request<ReqBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let context = TraceContext::from_headers(&self.config, req.headers());
+        req.extensions_mut().insert(context.clone());
+        Box::pin(scope(Some(context), self.inner.call(req)))
+    }
+}
@@ -0,0 +1,193 @@
+// Most discussed pages over the last day or week, for "trending" sidebars
+// Comments are counted per hour they were received, so old hours simply drop
+// off the end
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use axum::{extract::Extension, response::IntoResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::Format,
+    extract::{Validate, ValidatedQuery},
+    sites::Site,
+    state::SharedState,
+    stats, Comment,
+};
+
+// The longest window
+const HOURS: i64 = 7 * 24;
+const MAX_LIMIT: usize = 50;
+
+// (hour since the epoch, comments), oldest first
+type Hours = VecDeque<(i64, usize)>;
+
+#[derive(Default)]
+pub struct Trending {
+    // Site key -> page slug -> hours
+    pages: Mutex<HashMap<String, HashMap<String, Hours>>>,
+}
+
+impl Trending {
+    pub fn new<'a>(comments: impl Iterator<Item = &'a Comment>) -> Self {
+        let trending = Trending::default();
+        for comment in comments {
+            trending.add(comment);
+        }
+        trending
+    }
+
+    // Only listed comments count, so call this again when one gets approved
+    pub fn add(&self, comment: &Comment) {
+        self.update(comment, |count| *count += 1);
+    }
+
+    pub fn remove(&self, comment: &Comment) {
+        self.update(comment, |count| *count = count.saturating_sub(1));
+    }
+
+    // For changes to a stored comment, e.g. approving it
+    pub fn replace(&self, old: &Comment, new: &Comment) {
+        self.remove(old);
+        self.add(new);
+    }
+
+    fn update(&self, comment: &Comment, change: impl FnOnce(&mut usize)) {
+        let slug = match &comment.slug {
+            Some(slug) if comment.is_listed(&comment.site) => slug,
+            _ => return,
+        };
+        let now = hour(Utc::now());
+        // A legacy comment's time comes from the client, one in the future
+        // must not keep a page on top
+        let at = hour(stats::received(comment)).min(now);
+        if at <= now - HOURS {
+            return;
+        }
+
+        let mut pages = self.pages.lock().unwrap();
+        let hours = pages
+            .entry(comment.site.clone())
+            .or_default()
+            .entry(slug.clone())
+            .or_default();
+        prune(hours, now);
+        let index = match hours.iter().position(|(hour, _)| *hour >= at) {
+            Some(index) if hours[index].0 == at => index,
+            Some(index) => {
+                hours.insert(index, (at, 0));
+                index
+            }
+            None => {
+                hours.push_back((at, 0));
+                hours.len() - 1
+            }
+        };
+        change(&mut hours[index].1);
+    }
+
+    // Pages with the most comments in the last `hours`, most first
+    fn top(&self, site: &str, hours: i64, limit: usize) -> Vec<TrendingPage> {
+        let now = hour(Utc::now());
+        let mut pages = self.pages.lock().unwrap();
+        let site_pages = match pages.get_mut(site) {
+            Some(site_pages) => site_pages,
+            None => return Vec::new(),
+        };
+        site_pages.retain(|_, counts| {
+            prune(counts, now);
+            !counts.is_empty()
+        });
+
+        let mut top = site_pages
+            .iter()
+            .map(|(slug, counts)| TrendingPage {
+                slug: slug.clone(),
+                comments: counts
+                    .iter()
+                    .filter(|(hour, _)| *hour > now - hours)
+                    .map(|(_, count)| count)
+                    .sum(),
+            })
+            .filter(|page| page.comments > 0)
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| {
+            b.comments
+                .cmp(&a.comments)
+                .then_with(|| a.slug.cmp(&b.slug))
+        });
+        top.truncate(limit);
+        top
+    }
+}
+
+fn hour(utc: DateTime<Utc>) -> i64 {
+    utc.timestamp().div_euclid(60 * 60)
+}
+
+fn prune(hours: &mut Hours, now: i64) {
+    while hours.front().is_some_and(|(hour, _)| *hour <= now - HOURS) {
+        hours.pop_front();
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Window {
+    #[default]
+    #[serde(rename = "24h")]
+    Day,
+    #[serde(rename = "7d")]
+    Week,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct TrendingQuery {
+    window: Option<Window>,
+    // 10 by default
+    limit: Option<usize>,
+}
+
+impl Validate for TrendingQuery {
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        match self.limit {
+            Some(limit) if limit == 0 || limit > MAX_LIMIT => Err((
+                "request.invalid_limit",
+                format!("limit must be between 1 and {}", MAX_LIMIT),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TrendingPage {
+    slug: String,
+    comments: usize,
+}
+
+pub async fn get_trending(
+    ValidatedQuery(query): ValidatedQuery<TrendingQuery>,
+    site: Site,
+    format: Format,
+    Extension(state): Extension<SharedState>,
+) -> impl IntoResponse {
+    let window = query.window.unwrap_or_default();
+    let limit = query.limit.unwrap_or(10);
+    let hours = match window {
+        Window::Day => 24,
+        Window::Week => HOURS,
+    };
+
+    let pages = state.trending.top(&site.key, hours, limit);
+
+    (
+        site.cors_headers(),
+        format.encode(serde_json::json!({
+            "window": window,
+            "pages": pages,
+        })),
+    )
+}
@@ -0,0 +1,167 @@
+// Secrets from HashiCorp Vault, see secrets.rs. They are one secret of a KV
+// engine, version 2 unless kv_version says 1, read with a token or an
+// AppRole login. The token is renewed before every refresh, and an AppRole
+// logs in again once it has run out. Only built with the `vault` feature
+use std::{collections::HashMap, io, sync::Mutex};
+
+use axum::async_trait;
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+
+use crate::{config::VaultConfig, secrets::SecretsProvider};
+
+pub struct Vault {
+    client: reqwest::Client,
+    addr: String,
+    namespace: Option<String>,
+    // Of the secret, e.g. "secret/data/little-nova"
+    path: String,
+    kv_version: u8,
+    approle: Option<(String, String)>,
+    token: Mutex<Option<String>>,
+}
+
+fn failed(err: impl std::fmt::Display) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+impl Vault {
+    pub fn new(config: &VaultConfig) -> Result<Vault, String> {
+        let addr = config.addr.clone().unwrap_or_default();
+        let approle = match (&config.role_id, &config.secret_id) {
+            (Some(role_id), Some(secret_id)) => Some((role_id.clone(), secret_id.clone())),
+            (None, None) => None,
+            _ => return Err("[vault] needs both role_id and secret_id for AppRole".to_owned()),
+        };
+        if approle.is_none() && config.token.is_none() {
+            return Err("[vault] needs a token, or role_id and secret_id".to_owned());
+        }
+        let mount = config.mount.trim_matches('/');
+        let secret = config.path.trim_matches('/');
+        let path = match config.kv_version {
+            1 => format!("{}/{}", mount, secret),
+            2 => format!("{}/data/{}", mount, secret),
+            version => return Err(format!("[vault] kv_version {} is not 1 or 2", version)),
+        };
+
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .map_err(|err| format!("failed to build the Vault client: {}", err))?;
+        Ok(Vault {
+            client,
+            addr: addr.trim_end_matches('/').to_owned(),
+            namespace: config.namespace.clone(),
+            path,
+            kv_version: config.kv_version,
+            approle,
+            token: Mutex::new(config.token.clone()),
+        })
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> io::Result<reqwest::Response> {
+        let mut request = self
+            .client
+            .request(method, format!("{}/v1/{}", self.addr, path));
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        if let Some(token) = token {
+            request = request.header("X-Vault-Token", token);
+        }
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        request.send().await.map_err(failed)
+    }
+
+    async fn login(&self) -> io::Result<String> {
+        let (role_id, secret_id) = self
+            .approle
+            .as_ref()
+            .ok_or_else(|| failed("the Vault token was refused"))?;
+        let response = self
+            .request(
+                Method::POST,
+                "auth/approle/login",
+                None,
+                Some(json!({ "role_id": role_id, "secret_id": secret_id })),
+            )
+            .await?;
+        let body = check(response).await?;
+        let token = body["auth"]["client_token"]
+            .as_str()
+            .ok_or_else(|| failed("the AppRole login has no client_token"))?
+            .to_owned();
+        *self.token.lock().unwrap() = Some(token.clone());
+        Ok(token)
+    }
+
+    async fn token(&self) -> io::Result<String> {
+        let token = self.token.lock().unwrap().clone();
+        match token {
+            Some(token) => Ok(token),
+            None => self.login().await,
+        }
+    }
+
+    async fn read(&self, token: &str) -> io::Result<reqwest::Response> {
+        self.request(Method::GET, &self.path, Some(token), None)
+            .await
+    }
+}
+
+// The JSON of a successful response, Vault's errors otherwise
+async fn check(response: reqwest::Response) -> io::Result<Value> {
+    let status = response.status();
+    let body = response.json::<Value>().await.unwrap_or_default();
+    if status.is_success() {
+        return Ok(body);
+    }
+    Err(failed(format!(
+        "Vault answered {}: {}",
+        status, body["errors"]
+    )))
+}
+
+#[async_trait]
+impl SecretsProvider for Vault {
+    async fn fetch(&self) -> io::Result<HashMap<String, String>> {
+        let mut response = self.read(&self.token().await?).await?;
+        // The AppRole token ran out
+        if response.status() == StatusCode::FORBIDDEN && self.approle.is_some() {
+            response = self.read(&self.login().await?).await?;
+        }
+        let body = check(response).await?;
+        let data = match self.kv_version {
+            1 => &body["data"],
+            _ => &body["data"]["data"],
+        };
+        let data = data
+            .as_object()
+            .ok_or_else(|| failed(format!("no secret at {}", self.path)))?;
+        Ok(data
+            .iter()
+            .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_owned())))
+            .collect())
+    }
+
+    async fn renew(&self) -> io::Result<()> {
+        let token = self.token().await?;
+        let response = self
+            .request(Method::POST, "auth/token/renew-self", Some(&token), None)
+            .await?;
+        match check(response).await {
+            Ok(_) => Ok(()),
+            // Logging in again gets a fresh one
+            Err(_) if self.approle.is_some() => self.login().await.map(drop),
+            Err(err) => Err(err),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// Up and down votes on comments, one per visitor (see identity.rs)
+use axum::{
+    body::{self, BoxBody},
+    extract::{Extension, Path},
+    http::{header, HeaderMap, Response, StatusCode},
+    response::IntoResponse,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    codec::{Format, Payload},
+    errors::ApiError,
+    events::{self, CommentEvent},
+    identity::Visitor,
+    sites::Site,
+    state::SharedState,
+    trace_context, Comment,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Vote {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CastVote {
+    // null takes the visitor's vote back
+    vote: Option<Vote>,
+}
+
+impl Comment {
+    // Up votes minus down votes
+    pub fn score(&self) -> i64 {
+        self.votes
+            .values()
+            .map(|vote| match vote {
+                Vote::Up => 1,
+                Vote::Down => -1,
+            })
+            .sum()
+    }
+}
+
+// Voting again replaces the visitor's earlier vote. Without the cookie every
+// request would be a new visitor, so a vote without it only hands it out
+pub async fn vote(
+    Path(id): Path<Uuid>,
+    site: Site,
+    visitor: Visitor,
+    format: Format,
+    Payload(input): Payload<CastVote>,
+    Extension(state): Extension<SharedState>,
+) -> Result<Response<BoxBody>, ApiError> {
+    if !site.allows_origin() {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "site.origin_not_allowed",
+            "Votes can't be cast from this origin",
+        ));
+    }
+    if let Some(cookie) = visitor.set_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::SET_COOKIE, cookie);
+        let refused = ApiError::new(
+            StatusCode::FORBIDDEN,
+            "vote.cookie_required",
+            "Vote again with the cookie of this response",
+        );
+        return Ok((headers, refused).into_response().map(body::boxed));
+    }
+
+    let mut comments = state.db.write().unwrap();
+    let comment = comments
+        .get(&id)
+        .filter(|comment| comment.is_listed(&site.key))
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "comment.not_found",
+                "No such comment",
+            )
+        })?;
+    trace_context::record_comment(comment.id, comment.slug.as_deref());
+    let event = CommentEvent::Voted {
+        id,
+        visitor: visitor.token,
+        vote: input.vote,
+    };
+    let voted = events::commit(&state, &mut comments, event).map_err(events::failed)?;
+    drop(comments);
+    let score = voted.map_or(0, |comment| comment.score());
+
+    Ok(format
+        .encode(json!({ "id": id, "score": score, "vote": input.vote }))
+        .into_response()
+        .map(body::boxed))
+}
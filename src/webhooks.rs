@@ -0,0 +1,266 @@
+// Every change to a comment POSTed to [webhooks] url as JSON, one event of
+// the event log per request, with only what visitors see of the comment:
+//
+//   {"seq": 42, "at": "2026-10-14T10:11:19Z", "type": "created",
+//    "site": "blog", "status": "approved", "comment": {...}}
+//   {"seq": 43, ..., "type": "edited", "site": ..., "status": ..., "comment": {...}}
+//   {"seq": 44, ..., "type": "restored", "site": ..., "status": ..., "comment": {...}}
+//   {"seq": 45, ..., "type": "approved", "id": "..."}
+//   {"seq": 46, ..., "type": "published", "id": "...", "status": "pending"}
+//   {"seq": 47, ..., "type": "deleted", "id": "...", "reason": "retention"}
+//   {"seq": 48, ..., "type": "voted", "id": "...", "vote": "up"}
+//   {"seq": 49, ..., "type": "refused", "id": "...", "site": ..., "slug": ...}
+//
+// The comment is the one of /changes and /poll, see changes.rs, the reason
+// of a deletion one of rejected, spam, retention and restored, left out when
+// a moderator deleted it, and the vote null when a visitor took theirs back.
+// Who voted isn't sent.
+//
+// The event log is the outbox: an event is appended before its change is
+// applied, and the seq of the last one delivered is kept in
+// <snapshot>.webhooks, so no notification is lost when the server crashes or
+// the receiver is down. Deliveries are retried in order until the receiver
+// answers with 2xx, and after a crash the last one may come twice, which
+// receivers can tell by `seq`. A delivery carries the traceparent and
+// tracestate of the request its event came from, see trace_context.rs. Events are those of this instance only, a
+// replica or other instances sharing Redis deliver their own. Those of
+// notifications not chosen for webhooks are skipped, see notifications.rs.
+// Only built with the `webhooks` feature
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    changes::PublicComment,
+    events::{CommentEvent, DeleteReason, Recorded},
+    notifications,
+    state::{AppState, SharedState},
+    storage, trace_context,
+    votes::Vote,
+    CommentStatus,
+};
+
+// Between looking for new events
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Read from the log at once
+const BATCH: usize = 100;
+
+// Which holds the event log, see EventLog::hold
+const FOLLOWER: &str = "webhooks";
+
+#[derive(Serialize)]
+struct Delivery {
+    seq: u64,
+    at: DateTime<Utc>,
+    #[serde(flatten)]
+    event: PublicEvent,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum PublicEvent {
+    Created {
+        site: String,
+        status: CommentStatus,
+        comment: PublicComment,
+    },
+    Edited {
+        site: String,
+        status: CommentStatus,
+        comment: PublicComment,
+    },
+    Restored {
+        site: String,
+        status: CommentStatus,
+        comment: PublicComment,
+    },
+    Approved {
+        id: Uuid,
+    },
+    Published {
+        id: Uuid,
+        status: CommentStatus,
+    },
+    Deleted {
+        id: Uuid,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<DeleteReason>,
+    },
+    Voted {
+        id: Uuid,
+        vote: Option<Vote>,
+    },
+    Refused {
+        id: Uuid,
+        site: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        slug: Option<String>,
+    },
+}
+
+impl Delivery {
+    fn new(recorded: &Recorded, state: &AppState) -> Self {
+        let event = match &recorded.event {
+            CommentEvent::Created { comment } => PublicEvent::Created {
+                site: comment.site.clone(),
+                status: comment.status,
+                comment: PublicComment::new(comment, state),
+            },
+            CommentEvent::Edited { comment } => PublicEvent::Edited {
+                site: comment.site.clone(),
+                status: comment.status,
+                comment: PublicComment::new(comment, state),
+            },
+            CommentEvent::Restored { comment } => PublicEvent::Restored {
+                site: comment.site.clone(),
+                status: comment.status,
+                comment: PublicComment::new(comment, state),
+            },
+            CommentEvent::Approved { id } => PublicEvent::Approved { id: *id },
+            CommentEvent::Published { id, status } => PublicEvent::Published {
+                id: *id,
+                status: *status,
+            },
+            CommentEvent::Deleted { id, reason } => PublicEvent::Deleted {
+                id: *id,
+                reason: *reason,
+            },
+            CommentEvent::Voted { id, vote, .. } => PublicEvent::Voted {
+                id: *id,
+                vote: *vote,
+            },
+            CommentEvent::Refused { id, site, slug, .. } => PublicEvent::Refused {
+                id: *id,
+                site: site.clone(),
+                slug: slug.clone(),
+            },
+        };
+        Delivery {
+            seq: recorded.seq,
+            at: recorded.at,
+            event,
+        }
+    }
+}
+
+// Also lets the event log be compacted up to `seq`
+fn save_cursor(state: &AppState, path: &Path, seq: u64) {
+    state.storage.events.hold(FOLLOWER, seq);
+    if let Err(err) = storage::write_atomically(path, seq.to_string().into_bytes()) {
+        tracing::error!("failed to save the webhook position: {}", err);
+    }
+}
+
+async fn post(
+    client: &reqwest::Client,
+    url: &str,
+    recorded: &Recorded,
+    delivery: &Delivery,
+) -> Result<(), String> {
+    let mut request = client.post(url).json(delivery);
+    if let Some(traceparent) = &recorded.traceparent {
+        for (name, value) in trace_context::headers(traceparent, recorded.tracestate.as_deref()) {
+            request = request.header(name, value);
+        }
+    }
+    let response = request.send().await.map_err(|err| err.to_string())?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(format!("answered with {}", status)),
+    }
+}
+
+// Runs for the lifetime of the server
+pub async fn deliver(state: SharedState, url: String) {
+    let cursor: PathBuf = match state.storage.path() {
+        Some(path) => path.with_extension("webhooks"),
+        None => {
+            tracing::warn!("[webhooks] url is set, but without [storage] path there is no event log to deliver from");
+            return;
+        }
+    };
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(state.config.webhooks.timeout_secs))
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            tracing::error!("failed to build the webhook client: {}", err);
+            state.storage.events.release(FOLLOWER);
+            return;
+        }
+    };
+
+    // The first start delivers what happens from now on, not the history
+    let mut delivered = match storage::read_cursor(&cursor) {
+        Ok(Some(seq)) => {
+            state.storage.events.hold(FOLLOWER, seq);
+            seq
+        }
+        Ok(None) => {
+            let seq = state.storage.events.seq();
+            save_cursor(&state, &cursor, seq);
+            seq
+        }
+        Err(err) => {
+            tracing::error!("failed to read {}: {}", cursor.display(), err);
+            state.storage.events.release(FOLLOWER);
+            return;
+        }
+    };
+
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let latest = state.storage.events.seq();
+        if latest <= delivered {
+            continue;
+        }
+        let reading = state.clone();
+        let pending =
+            tokio::task::spawn_blocking(move || reading.storage.events.since(delivered, BATCH))
+                .await
+                .map_err(io::Error::other)
+                .and_then(|result| result);
+        let pending = match pending {
+            Ok(pending) => pending,
+            Err(err) => {
+                tracing::error!("failed to read the events to deliver: {}", err);
+                continue;
+            }
+        };
+        if pending.is_empty() {
+            // Dropped from the log meanwhile, see EventLog::forget
+            delivered = latest;
+            save_cursor(&state, &cursor, delivered);
+        }
+
+        for recorded in pending {
+            let chosen = notifications::kind(&recorded.event)
+                .is_none_or(|kind| state.notifications.get().webhooks.contains(&kind));
+            let mut delay = storage::FIRST_RETRY_DELAY;
+            if chosen {
+                let delivery = Delivery::new(&recorded, &state);
+                while let Err(err) = post(&client, &url, &recorded, &delivery).await {
+                    tracing::warn!(
+                        seq = recorded.seq,
+                        "failed to deliver a webhook, retrying in {:?}: {}",
+                        delay,
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(storage::MAX_RETRY_DELAY);
+                }
+            }
+            delivered = recorded.seq;
+            save_cursor(&state, &cursor, delivered);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+// little-nova embed loader
+//
+//   <div data-little-nova-slug="my-post" data-little-nova-site="blog"></div>
+//   <script src="https://comments.example.com/static/embed.js" async></script>
+//
+// Replaces every such element with an iframe showing the comments of that page,
+// data-little-nova-site is only needed when the instance hosts several sites
+(function() {
+    var script = document.currentScript;
+    var origin = new URL(script.src).origin;
+
+    var frames = [];
+    document.querySelectorAll('[data-little-nova-slug]').forEach(function(element) {
+        var slug = element.getAttribute('data-little-nova-slug');
+        var site = element.getAttribute('data-little-nova-site');
+        var root = site ? '/s/' + encodeURIComponent(site) : '';
+        var frame = document.createElement('iframe');
+        frame.src = origin + root + '/embed/' + encodeURIComponent(slug);
+        frame.title = 'Comments';
+        frame.style.width = '100%';
+        frame.style.border = 'none';
+        frame.setAttribute('scrolling', 'no');
+        element.appendChild(frame);
+        frames.push(frame);
+    });
+
+    // The embedded page reports its height whenever it changes
+    window.addEventListener('message', function(event) {
+        if (event.origin !== origin || !event.data || event.data.type !== 'little-nova:height') {
+            return;
+        }
+        frames.forEach(function(frame) {
+            if (frame.contentWindow === event.source) {
+                frame.style.height = event.data.height + 'px';
+            }
+        });
+    });
+})();
@@ -6,7 +6,99 @@ var parse_json = function(data) {
     return return_json;
 }
 
+// Copies the absolute URL of a comment
+var copy_permalink = function(id) {
+    var url = window.location.origin + $('#send-comment').data('root') + '/' + id;
+    navigator.clipboard.writeText(url).then(function() {
+        alert($('#send-comment').data('copied') + ' ' + url);
+    });
+}
+
+// Votes for a comment and shows its new score. The first vote of a visitor
+// only gets the cookie, so it is sent again
+var vote = function(root, id, direction, again) {
+    $.ajax({
+        url: root + '/' + id + '/vote',
+        type: 'post',
+        contentType: 'application/json',
+        dataType: 'json',
+        data: JSON.stringify({ vote: direction }),
+        success: function(result) {
+            $('#score-' + id).text(result.score);
+        },
+        error: function(xhr) {
+            var code = xhr.responseJSON && xhr.responseJSON.code;
+            if (code === 'vote.cookie_required' && !again) {
+                vote(root, id, direction, true);
+            }
+        }
+    });
+}
+
+// Leading zero bits of a hash
+var zero_bits = function(hash) {
+    var bits = 0;
+    for (var index = 0; index < hash.length; index++) {
+        if (hash[index] !== 0) {
+            return bits + Math.clz32(hash[index]) - 24;
+        }
+        bits += 8;
+    }
+    return bits;
+}
+
+// Finds a nonce for a challenge of GET /challenge, see pow.rs
+var solve = async function(challenge, difficulty) {
+    var encoder = new TextEncoder();
+    for (var nonce = 0; ; nonce++) {
+        var data = encoder.encode(challenge + ':' + nonce);
+        var hash = new Uint8Array(await crypto.subtle.digest('SHA-256', data));
+        if (zero_bits(hash) >= difficulty) {
+            return challenge + ':' + nonce;
+        }
+    }
+}
+
+// Gets the proof of work the server asks for, none while it asks for none
+var proof_of_work = function(root, done) {
+    $.ajax({
+        url: root + '/challenge',
+        dataType: 'json',
+        success: function(result) {
+            solve(result.challenge, result.difficulty).then(done);
+        },
+        error: function() {
+            done(null);
+        }
+    });
+}
+
+// Keeps the browser's timezone in the cookie the server shows times in, see
+// timezone.rs: its IANA name, e.g. "Europe/Berlin", or the offset of now,
+// e.g. "+09:00", where the browser doesn't tell
+var remember_timezone = function() {
+    var timezone = null;
+    try {
+        timezone = Intl.DateTimeFormat().resolvedOptions().timeZone;
+    } catch (e) {}
+    if (!timezone) {
+        var minutes = -new Date().getTimezoneOffset();
+        var sign = minutes < 0 ? '-' : '+';
+        var pad = function(n) { return ('0' + n).slice(-2); };
+        timezone = sign + pad(Math.floor(Math.abs(minutes) / 60)) + ':' + pad(Math.abs(minutes) % 60);
+    }
+    if (document.cookie.split('; ').indexOf('little_nova_timezone=' + timezone) === -1) {
+        // Two years, like the visitor cookie
+        document.cookie = 'little_nova_timezone=' + timezone
+            + '; Max-Age=63072000; Path=/; Secure; SameSite=None';
+    }
+}
+
 $(document).ready(function() {
+    if ($('#send-comment').data('visitor-timezone')) {
+        remember_timezone();
+    }
+
     $('#send-comment').submit(function(event) {
         // Cancel sending in HTML 
         event.preventDefault();
@@ -29,41 +121,57 @@ $(document).ready(function() {
 
         // Convert to appropriate Json 
         var data = parse_json(array);
+
+        // Tags are sent as a list
+        data.tags = (data.tags || '').split(',').filter(function(tag) {
+            return tag.trim() !== '';
+        });
         console.log(data);
 
-        // Send
-        $.ajax({
-            url: form.attr('action'),
-            type: form.attr('method'),
-            contentType: 'application/json',
-            dataType: "json",
-            data: JSON.stringify(data),
-            timeout: 10000,  // milliseconds 
-    
-            // Before send
-            beforeSend: function(xhr, settings) {
-                // Disable the button to prevent double transmission 
-                console.log(data);
-                button.attr('disabled', true);
-            },
+        // Disable the button while working out the proof of work
+        button.attr('disabled', true);
+        proof_of_work(form.data('root'), function(proof) {
+            // Send
+            $.ajax({
+                url: form.attr('action'),
+                type: form.attr('method'),
+                contentType: 'application/json',
+                dataType: "json",
+                data: JSON.stringify(data),
+                timeout: 10000,  // milliseconds 
+        
+                // Before send
+                beforeSend: function(xhr, settings) {
+                    // Disable the button to prevent double transmission 
+                    console.log(data);
+                    button.attr('disabled', true);
+                    if (proof) {
+                        xhr.setRequestHeader('X-Proof-Of-Work', proof);
+                    }
+                },
 
-            // After response 
-            complete: function(xhr, text_status) {
-                // Enable button and allow resend
-                button.attr('disabled', false);
-            },
-            
-            // Processing when communication is successful 
-            success: function(result, text_status, xhr) {
-                // Initialize input value 
-                form[0].reset();
-                alert('The comment was sent successfully');
-            },
-    
-            // Processing when communication fails 
-            error: function(xhr, text_status, error) {
-                alert('Failed to send comment');
-            }
+                // After response 
+                complete: function(xhr, text_status) {
+                    // Enable button and allow resend
+                    button.attr('disabled', false);
+                },
+                
+                // Processing when communication is successful 
+                success: function(result, text_status, xhr) {
+                    // Initialize input value 
+                    form[0].reset();
+                    alert(form.data('sent'));
+                    // The embedded page shows the new comment right away
+                    if (form.data('reload')) {
+                        location.reload();
+                    }
+                },
+        
+                // Processing when communication fails 
+                error: function(xhr, text_status, error) {
+                    alert(form.data('failed'));
+                }
+            });
         });
     });   
 });
\ No newline at end of file